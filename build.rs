@@ -0,0 +1,58 @@
+use std::{
+    env,
+    path::Path,
+    process::Command,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Exposes the git commit, build timestamp, enabled cargo features and
+/// target triple of the build to the crate as environment variables.
+fn main() {
+    // Only paths that exist are tracked, since cargo reruns the script on
+    // every build for a missing path. Refs are packed into packed-refs by
+    // git gc.
+    for git_path in [".git/HEAD", ".git/refs", ".git/packed-refs"] {
+        if Path::new(git_path).exists() {
+            println!("cargo:rerun-if-changed={git_path}");
+        }
+    }
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+
+    // Source trees without git metadata, like a packaged crate, report an
+    // unknown commit
+    let git_commit = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|commit| commit.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // Honors SOURCE_DATE_EPOCH for reproducible builds
+    let build_timestamp = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_secs())
+        });
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|feature| feature.to_lowercase().replace('_', "-"))
+        })
+        .filter(|feature| feature != "default")
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=GATEWAY_GIT_COMMIT={git_commit}");
+    println!("cargo:rustc-env=GATEWAY_BUILD_TIMESTAMP={build_timestamp}");
+    println!("cargo:rustc-env=GATEWAY_FEATURES={}", features.join(","));
+    println!(
+        "cargo:rustc-env=GATEWAY_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+}
@@ -0,0 +1,12 @@
+//! Fuzzes LoRaWAN PHY payload parsing and routing information extraction
+#![no_main]
+use gateway_rs::Packet;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    for direction in [lorawan::Direction::Uplink, lorawan::Direction::Downlink] {
+        if let Ok(frame) = Packet::parse_frame(direction, data) {
+            let _ = Packet::routing_information(&frame);
+        }
+    }
+});
@@ -0,0 +1,12 @@
+//! Fuzzes conversion of semtech push_data rx packets, as received from the
+//! packet forwarder, into gateway packets.
+#![no_main]
+use gateway_rs::Packet;
+use libfuzzer_sys::fuzz_target;
+use semtech_udp::push_data::RxPk;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(rxpk) = serde_json::from_slice::<RxPk>(data) {
+        let _ = Packet::try_from(rxpk);
+    }
+});
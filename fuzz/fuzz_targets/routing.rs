@@ -0,0 +1,20 @@
+//! Fuzzes decoding of routing protos as streamed by validators, including
+//! router uri, key and filter conversion.
+#![no_main]
+use gateway_rs::{router::Routing, KeyedUri};
+use helium_proto::{Message, RoutingAddress};
+use libfuzzer_sys::fuzz_target;
+use slog::{o, Logger};
+
+fuzz_target!(|data: &[u8]| {
+    let logger = Logger::root(slog::Discard, o!());
+    if let Ok(proto) = helium_proto::Routing::decode(data) {
+        let _ = Routing::from_proto(&logger, &proto);
+        for address in proto.addresses {
+            let _ = KeyedUri::try_from(address);
+        }
+    }
+    if let Ok(address) = RoutingAddress::decode(data) {
+        let _ = KeyedUri::try_from(address);
+    }
+});
@@ -5,6 +5,9 @@ pub enum LoraWanError {
     InvalidPacketType(u8),
     InvalidFPortForFopts,
     InvalidPacketSize(super::MType, usize),
+    InvalidMajorVersion(u8),
+    InvalidDirection(super::MType),
+    InvalidRejoinType(u8),
     Io(io::Error),
 }
 
@@ -16,6 +19,11 @@ impl fmt::Display for LoraWanError {
             LoraWanError::InvalidPacketSize(mtype, s) => {
                 write!(f, "Invalid packet size {} for type {:?}", s, mtype)
             }
+            LoraWanError::InvalidMajorVersion(v) => write!(f, "Invalid major version: {}", v),
+            LoraWanError::InvalidDirection(mtype) => {
+                write!(f, "Invalid direction for type {:?}", mtype)
+            }
+            LoraWanError::InvalidRejoinType(v) => write!(f, "Invalid rejoin type: {}", v),
             LoraWanError::Io(err) => err.fmt(f),
         }
     }
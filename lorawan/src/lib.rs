@@ -20,6 +20,7 @@ pub enum MType {
     UnconfirmedDown,
     ConfirmedUp,
     ConfirmedDown,
+    RejoinRequest,
     Invalid(u8),
 }
 
@@ -32,6 +33,7 @@ impl From<u8> for MType {
             0b011 => MType::UnconfirmedDown,
             0b100 => MType::ConfirmedUp,
             0b101 => MType::ConfirmedDown,
+            0b110 => MType::RejoinRequest,
             _ => MType::Invalid(v),
         }
     }
@@ -62,6 +64,9 @@ const JOIN_REQUEST_LEN: usize = 23;
 const JOIN_ACCEPT_LEN: usize = 17;
 const JOIN_ACCEPT_WITH_CFLIST_LEN: usize = 33;
 const DATA_MIN_LEN: usize = 12;
+// Type 0 and 2 rejoin requests
+const REJOIN_REQUEST_LEN: usize = 19;
+const REJOIN_REQUEST_TYPE1_LEN: usize = 24;
 
 impl PHYPayload {
     pub fn read(direction: Direction, reader: &mut dyn io::Read) -> Result<Self, LoraWanError> {
@@ -80,12 +85,34 @@ impl PHYPayload {
             | MType::UnconfirmedDown
             | MType::ConfirmedUp
             | MType::ConfirmedDown => phy_len < DATA_MIN_LEN,
+            MType::RejoinRequest => {
+                phy_len != REJOIN_REQUEST_LEN && phy_len != REJOIN_REQUEST_TYPE1_LEN
+            }
             MType::Invalid(_) => false,
         };
         if invalid {
             return Err(LoraWanError::InvalidPacketSize(packet_type, phy_len));
         } else if let MType::Invalid(s) = packet_type {
             return Err(LoraWanError::InvalidPacketType(s));
+        } else if mhdr.major() != 0 {
+            // Only LoRaWAN R1 frames are defined
+            return Err(LoraWanError::InvalidMajorVersion(mhdr.major()));
+        }
+        let wrong_direction = match direction {
+            Direction::Uplink => matches!(
+                packet_type,
+                MType::JoinAccept | MType::UnconfirmedDown | MType::ConfirmedDown
+            ),
+            Direction::Downlink => matches!(
+                packet_type,
+                MType::JoinRequest
+                    | MType::UnconfirmedUp
+                    | MType::ConfirmedUp
+                    | MType::RejoinRequest
+            ),
+        };
+        if wrong_direction {
+            return Err(LoraWanError::InvalidDirection(packet_type));
         }
 
         // indexing with subtraction won't fail because of length checks above
@@ -110,6 +137,7 @@ pub enum PHYPayloadFrame {
     MACPayload(MACPayload),
     JoinRequest(JoinRequest),
     JoinAccept(JoinAccept),
+    RejoinRequest(RejoinRequest),
 }
 
 impl PHYPayloadFrame {
@@ -121,6 +149,7 @@ impl PHYPayloadFrame {
         let res = match packet_type {
             MType::JoinRequest => Self::JoinRequest(JoinRequest::read(reader)?),
             MType::JoinAccept => Self::JoinAccept(JoinAccept::read(reader)?),
+            MType::RejoinRequest => Self::RejoinRequest(RejoinRequest::read(reader)?),
             _ => Self::MACPayload(MACPayload::read(packet_type, direction, reader)?),
         };
         Ok(res)
@@ -325,6 +354,99 @@ impl JoinRequest {
     }
 }
 
+/// A LoRaWAN 1.1 rejoin request
+pub enum RejoinRequest {
+    /// Type 0 or 2 rejoin request, identifying the device by its DevEUI and
+    /// the NetID of its current network
+    NetId {
+        rejoin_type: u8,
+        net_id: [u8; 3],
+        dev_eui: u64,
+        rj_count0: u16,
+    },
+    /// Type 1 rejoin request, identifying the device by its JoinEUI and DevEUI
+    JoinEui {
+        join_eui: u64,
+        dev_eui: u64,
+        rj_count1: u16,
+    },
+}
+
+impl fmt::Debug for RejoinRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> result::Result<(), fmt::Error> {
+        match self {
+            Self::NetId {
+                rejoin_type,
+                net_id,
+                dev_eui,
+                rj_count0,
+            } => f
+                .debug_struct("RejoinRequest")
+                .field("rejoin_type", rejoin_type)
+                .field("net_id", net_id)
+                .field("dev_eui", &format_args!("{:#08x}", dev_eui))
+                .field("rj_count0", rj_count0)
+                .finish(),
+            Self::JoinEui {
+                join_eui,
+                dev_eui,
+                rj_count1,
+            } => f
+                .debug_struct("RejoinRequest")
+                .field("rejoin_type", &1)
+                .field("join_eui", &format_args!("{:#08x}", join_eui))
+                .field("dev_eui", &format_args!("{:#08x}", dev_eui))
+                .field("rj_count1", rj_count1)
+                .finish(),
+        }
+    }
+}
+
+impl RejoinRequest {
+    pub fn read(reader: &mut dyn io::Read) -> Result<Self, LoraWanError> {
+        let rejoin_type = reader.read_u8()?;
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+        // Frame length including mhdr, rejoin type and mic
+        let phy_len = data.len() + 6;
+        let expected_len = match rejoin_type {
+            0 | 2 => REJOIN_REQUEST_LEN,
+            1 => REJOIN_REQUEST_TYPE1_LEN,
+            other => return Err(LoraWanError::InvalidRejoinType(other)),
+        };
+        if phy_len != expected_len {
+            return Err(LoraWanError::InvalidPacketSize(
+                MType::RejoinRequest,
+                phy_len,
+            ));
+        }
+        let mut data = &data[..];
+        let res = if rejoin_type == 1 {
+            Self::JoinEui {
+                join_eui: data.read_u64::<LittleEndian>()?,
+                dev_eui: data.read_u64::<LittleEndian>()?,
+                rj_count1: data.read_u16::<LittleEndian>()?,
+            }
+        } else {
+            let mut net_id = [0u8; 3];
+            io::Read::read_exact(&mut data, &mut net_id)?;
+            Self::NetId {
+                rejoin_type,
+                net_id,
+                dev_eui: data.read_u64::<LittleEndian>()?,
+                rj_count0: data.read_u16::<LittleEndian>()?,
+            }
+        };
+        Ok(res)
+    }
+
+    pub fn dev_eui(&self) -> u64 {
+        match self {
+            Self::NetId { dev_eui, .. } | Self::JoinEui { dev_eui, .. } => *dev_eui,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct JoinAccept {
     pub app_nonce: [u8; 3],
@@ -360,7 +482,88 @@ mod test {
     #[test]
     fn test_read() {
         let mut data = &base64::decode("IL1ciMu7b3ZOP5Q1cBA7isI=").unwrap()[..];
-        let payload = PHYPayload::read(Direction::Uplink, &mut data).unwrap();
+        let payload = PHYPayload::read(Direction::Downlink, &mut data).unwrap();
         eprintln!("PAYLOAD {:?}", payload);
     }
+
+    #[test]
+    fn test_read_rejoin() {
+        // Type 0 rejoin request
+        let mut data = &[
+            0xC0, 0, 0x13, 0, 0, 8, 7, 6, 5, 4, 3, 2, 1, 2, 0, 1, 2, 3, 4,
+        ][..];
+        let payload = PHYPayload::read(Direction::Uplink, &mut data).unwrap();
+        assert!(matches!(
+            payload.payload,
+            PHYPayloadFrame::RejoinRequest(RejoinRequest::NetId {
+                rejoin_type: 0,
+                net_id: [0x13, 0, 0],
+                dev_eui: 0x0102030405060708,
+                rj_count0: 2,
+            })
+        ));
+
+        // Type 1 rejoin request
+        let mut data = &[
+            0xC0, 1, 8, 7, 6, 5, 4, 3, 2, 1, 1, 2, 3, 4, 5, 6, 7, 8, 3, 0, 1, 2, 3, 4,
+        ][..];
+        let payload = PHYPayload::read(Direction::Uplink, &mut data).unwrap();
+        assert!(matches!(
+            payload.payload,
+            PHYPayloadFrame::RejoinRequest(RejoinRequest::JoinEui {
+                join_eui: 0x0102030405060708,
+                dev_eui: 0x0807060504030201,
+                rj_count1: 3,
+            })
+        ));
+    }
+
+    #[test]
+    fn test_read_invalid() {
+        // A join accept is not a valid uplink
+        let mut data = &base64::decode("IL1ciMu7b3ZOP5Q1cBA7isI=").unwrap()[..];
+        assert!(matches!(
+            PHYPayload::read(Direction::Uplink, &mut data),
+            Err(LoraWanError::InvalidDirection(MType::JoinAccept))
+        ));
+
+        // Unconfirmed up with an unknown major version
+        let mut data = &[0x41, 1, 2, 3, 4, 0, 0, 0, 1, 2, 3, 4][..];
+        assert!(matches!(
+            PHYPayload::read(Direction::Uplink, &mut data),
+            Err(LoraWanError::InvalidMajorVersion(1))
+        ));
+
+        // Unconfirmed up without room for a mic
+        let mut data = &[0x40, 1, 2, 3, 4, 0, 0, 0][..];
+        assert!(matches!(
+            PHYPayload::read(Direction::Uplink, &mut data),
+            Err(LoraWanError::InvalidPacketSize(MType::UnconfirmedUp, 8))
+        ));
+
+        // Rejoin requests are uplink only
+        let mut data = &[
+            0xC0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 2, 3,
+        ][..];
+        assert!(matches!(
+            PHYPayload::read(Direction::Downlink, &mut data),
+            Err(LoraWanError::InvalidDirection(MType::RejoinRequest))
+        ));
+
+        // Type 1 rejoin request with the length of a type 0 request
+        let mut data = &[
+            0xC0, 1, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 1, 2, 3, 4,
+        ][..];
+        assert!(matches!(
+            PHYPayload::read(Direction::Uplink, &mut data),
+            Err(LoraWanError::InvalidPacketSize(MType::RejoinRequest, 19))
+        ));
+
+        // Proprietary frames are not forwarded
+        let mut data = &[0xE0, 1, 2, 3, 4, 0, 0, 0, 1, 2, 3, 4][..];
+        assert!(matches!(
+            PHYPayload::read(Direction::Uplink, &mut data),
+            Err(LoraWanError::InvalidPacketType(7))
+        ));
+    }
 }
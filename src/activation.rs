@@ -0,0 +1,88 @@
+use slog::{warn, Logger};
+use std::{
+    collections::HashMap,
+    env, fs,
+    os::unix::io::{FromRawFd, RawFd},
+    process,
+};
+
+/// The first file descriptor passed by systemd socket activation
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Socket activation descriptor name of a packet forwarder UDP socket, which
+/// is not supported
+pub const FORWARDER_FD_NAME: &str = "forwarder";
+
+/// File descriptors passed by systemd socket activation, keyed by the
+/// FileDescriptorName= of their socket units
+pub type ListenFds = HashMap<String, RawFd>;
+
+/// Returns the file descriptors passed to this process by systemd socket
+/// activation. Returns no descriptors if the process was not socket
+/// activated.
+///
+/// The activation variables are removed from the environment so descriptors
+/// are only taken once and not passed on to child processes. This must be
+/// called before any other threads are started, since changing the
+/// environment is not thread safe.
+pub fn listen_fds() -> ListenFds {
+    let for_this_process = env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        == Some(process::id());
+    let count = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse::<RawFd>().ok())
+        .unwrap_or(0);
+    let names = env::var("LISTEN_FDNAMES").unwrap_or_default();
+    for var in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        env::remove_var(var);
+    }
+    if !for_this_process {
+        return HashMap::new();
+    }
+    named_fds(count, &names)
+}
+
+/// Closes the given descriptors that were not taken by any of the servers.
+/// The packet forwarder socket can not be adopted since the packet forwarder
+/// runtime binds its own socket from the listen address.
+pub fn close_unused(fds: ListenFds, logger: &Logger) {
+    for (name, fd) in fds {
+        if name == FORWARDER_FD_NAME {
+            warn!(logger, "socket activated packet forwarder socket is not supported, closing it";
+                "fd" => fd);
+        } else {
+            warn!(logger, "closing unrecognized socket activated descriptor";
+                "name" => &name,
+                "fd" => fd);
+        }
+        // Safety: systemd passed this descriptor to this process and it was
+        // not taken by any server
+        drop(unsafe { fs::File::from_raw_fd(fd) });
+    }
+}
+
+fn named_fds(count: RawFd, names: &str) -> ListenFds {
+    let mut names = names.split(':').filter(|name| !name.is_empty());
+    (0..count)
+        .map(|offset| {
+            let name = names.next().unwrap_or("unknown").to_string();
+            (name, LISTEN_FDS_START + offset)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn names() {
+        let fds = named_fds(2, "api:api_socket");
+        assert_eq!(Some(&3), fds.get("api"));
+        assert_eq!(Some(&4), fds.get("api_socket"));
+        assert!(named_fds(0, "api").is_empty());
+        assert_eq!(Some(&3), named_fds(1, "").get("unknown"));
+    }
+}
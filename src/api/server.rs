@@ -3,45 +3,120 @@ use super::{
     HeightReq, HeightRes, PubkeyReq, PubkeyRes, RegionReq, RegionRes, SignReq, SignRes,
 };
 use crate::{
-    router::dispatcher, settings::StakingMode, Error, Keypair, PublicKey, Result, Settings,
-    TxnEnvelope, TxnFee, TxnFeeConfig, CONFIG_FEE_KEYS,
+    activation::ListenFds, router::dispatcher, settings::StakingMode, Error, Keypair, PublicKey,
+    RequestSigner, Result, Settings, TxnEnvelope, TxnFee, TxnFeeConfig, CONFIG_FEE_KEYS,
 };
 use futures::TryFutureExt;
-use helium_crypto::Sign;
 use helium_proto::services::local::{Api, Server};
-use helium_proto::{BlockchainTxnAddGatewayV1, Message};
-use slog::{info, o, Logger};
-use std::sync::Arc;
+use helium_proto::BlockchainTxnAddGatewayV1;
+use slog::{info, o, warn, Logger};
+use std::{
+    fs, io,
+    net::SocketAddr,
+    os::unix::{
+        fs::MetadataExt,
+        io::{FromRawFd, RawFd},
+    },
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    net::{TcpListener, TcpStream, UnixListener, UnixStream},
+    time,
+};
 use tonic::{self, transport::Server as TransportServer, Request, Response, Status};
 
 pub type ApiResult<T> = std::result::Result<Response<T>, Status>;
 
+/// Socket activation descriptor names of the api tcp listener and unix socket
+pub const API_FD_NAME: &str = "api";
+pub const API_SOCKET_FD_NAME: &str = "api_socket";
+
+/// Wait before accepting connections again after an accept error
+const ACCEPT_ERROR_WAIT: Duration = Duration::from_secs(1);
+
 pub struct LocalServer {
     dispatcher: dispatcher::MessageSender,
     keypair: Arc<Keypair>,
+    signer: RequestSigner,
     onboarding_key: PublicKey,
     listen_port: u16,
+    listen_socket: Option<PathBuf>,
+    // Listeners passed by systemd socket activation
+    activated_fd: Option<RawFd>,
+    activated_socket_fd: Option<RawFd>,
 }
 
 impl LocalServer {
-    pub fn new(dispatcher: dispatcher::MessageSender, settings: &Settings) -> Result<Self> {
+    /// Creates the local API server. Its socket activated listeners are taken
+    /// from the given activation descriptors.
+    pub fn new(
+        dispatcher: dispatcher::MessageSender,
+        settings: &Settings,
+        activated: &mut ListenFds,
+    ) -> Result<Self> {
         Ok(Self {
+            activated_fd: activated.remove(API_FD_NAME),
+            activated_socket_fd: activated.remove(API_SOCKET_FD_NAME),
             keypair: settings.keypair.clone(),
+            signer: RequestSigner::from(settings.keypair.clone()),
             onboarding_key: settings.onboarding_key(),
             listen_port: settings.api,
+            listen_socket: settings.api_socket.clone(),
             dispatcher,
         })
     }
 
     pub async fn run(self, shutdown: triggered::Listener, logger: &Logger) -> Result {
-        let addr = listen_addr(self.listen_port).parse().unwrap();
+        let addr: SocketAddr = listen_addr(self.listen_port).parse().unwrap();
         let logger = logger.new(o!("module" => "api", "listen" => addr));
         info!(logger, "starting");
-        TransportServer::builder()
-            .add_service(Server::new(self))
-            .serve_with_shutdown(addr, shutdown)
-            .map_err(Error::from)
-            .await
+        // Listeners passed by systemd socket activation are used instead of
+        // binding our own
+        let tcp_listener = match self.activated_fd {
+            Some(fd) => {
+                info!(logger, "using socket activated listener");
+                // Safety: systemd passed this descriptor to this process and
+                // activation descriptors are only taken once
+                let listener = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                TcpListener::from_std(listener)?
+            }
+            None => TcpListener::bind(addr).await?,
+        };
+        let listen_socket = self.listen_socket.clone();
+        let activated_socket_fd = self.activated_socket_fd;
+        let server = Server::new(self);
+        let tcp = TransportServer::builder()
+            .add_service(server.clone())
+            .serve_with_incoming_shutdown(
+                tcp_incoming(tcp_listener, logger.clone()),
+                shutdown.clone(),
+            )
+            .map_err(Error::from);
+        let (listener, socket_path) = match (activated_socket_fd, listen_socket) {
+            (Some(fd), Some(socket_path)) => {
+                // Safety: as for the tcp listener above
+                let listener = unsafe { std::os::unix::net::UnixListener::from_raw_fd(fd) };
+                listener.set_nonblocking(true)?;
+                (UnixListener::from_std(listener)?, socket_path)
+            }
+            (_, Some(socket_path)) => {
+                // Remove a stale socket from a previous run before binding
+                let _ = fs::remove_file(&socket_path);
+                (UnixListener::bind(&socket_path)?, socket_path)
+            }
+            (_, None) => return tcp.await,
+        };
+        let owner = fs::metadata(&socket_path)?.uid();
+        info!(logger, "listening on socket";
+            "socket" => socket_path.to_string_lossy().into_owned());
+        let uds = TransportServer::builder()
+            .add_service(server)
+            .serve_with_incoming_shutdown(authorized_peers(listener, owner, logger), shutdown)
+            .map_err(Error::from);
+        tokio::try_join!(tcp, uds).map(|_| ())
     }
 
     async fn _get_config<T>(&self, keys: &[T]) -> std::result::Result<Vec<ConfigValue>, Status>
@@ -59,6 +134,63 @@ impl LocalServer {
     }
 }
 
+/// Returns a stream of accepted tcp connections. Accept errors, such as
+/// running out of file descriptors, are logged and accepting is resumed after
+/// a wait, since an error yielded to the server would end it.
+fn tcp_incoming(
+    listener: TcpListener,
+    logger: Logger,
+) -> impl futures::Stream<Item = io::Result<TcpStream>> {
+    futures::stream::unfold(listener, move |listener| {
+        let logger = logger.clone();
+        async move {
+            loop {
+                match listener.accept().await {
+                    Ok((stream, _)) => return Some((Ok(stream), listener)),
+                    Err(err) => accept_failed(&err, &logger).await,
+                }
+            }
+        }
+    })
+}
+
+/// Waits before accepting again after the given accept error
+async fn accept_failed(err: &io::Error, logger: &Logger) {
+    warn!(logger, "accept error: {err:?}");
+    time::sleep(ACCEPT_ERROR_WAIT).await;
+}
+
+/// Returns a stream of accepted unix socket connections from peers running
+/// as the given owner uid or root. Connections from other peers are closed
+/// right away.
+fn authorized_peers(
+    listener: UnixListener,
+    owner: u32,
+    logger: Logger,
+) -> impl futures::Stream<Item = io::Result<UnixStream>> {
+    futures::stream::unfold(listener, move |listener| {
+        let logger = logger.clone();
+        async move {
+            loop {
+                let stream = match listener.accept().await {
+                    Ok((stream, _)) => stream,
+                    Err(err) => {
+                        accept_failed(&err, &logger).await;
+                        continue;
+                    }
+                };
+                match stream.peer_cred() {
+                    Ok(cred) if cred.uid() == owner || cred.uid() == 0 => {
+                        return Some((Ok(stream), listener))
+                    }
+                    Ok(cred) => warn!(logger, "rejecting socket peer"; "uid" => cred.uid()),
+                    Err(err) => warn!(logger, "rejecting socket peer: {err:?}"),
+                }
+            }
+        }
+    })
+}
+
 #[tonic::async_trait]
 impl Api for LocalServer {
     async fn pubkey(&self, _request: Request<PubkeyReq>) -> ApiResult<PubkeyRes> {
@@ -84,7 +216,7 @@ impl Api for LocalServer {
         let data = request.into_inner().data;
         let signature = self
             .keypair
-            .sign(&data)
+            .sign_audited(&data, "data", "api")
             .map_err(|_err| Status::internal("Failed signing data"))?;
         let reply = SignRes { signature };
         Ok(Response::new(reply))
@@ -116,11 +248,11 @@ impl Api for LocalServer {
             .txn_fee(&fee_config)
             .map_err(|_err| Status::internal("Failed to get txn fees"))?;
 
-        let signature = self
-            .keypair
-            .sign(&txn.encode_to_vec())
-            .map_err(|_err| Status::internal("Failed signing txn"))?;
-        txn.gateway_signature = signature;
+        let txn = self
+            .signer
+            .sign(txn)
+            .map_err(|_err| Status::internal("Failed signing txn"))
+            .await?;
 
         let add_gateway_txn = txn
             .in_envelope_vec()
@@ -135,9 +267,7 @@ impl Api for LocalServer {
             .keypair
             .ecdh(&public_key)
             .map_err(|_err| Status::internal("Failed ecdh"))?;
-        let reply = EcdhRes {
-            secret: secret.as_bytes().to_vec(),
-        };
+        let reply = EcdhRes { secret };
         Ok(Response::new(reply))
     }
 
@@ -154,8 +284,8 @@ impl Api for LocalServer {
             .map_err(|err| Status::internal(format!("{err}")))
             .await?;
         Ok(Response::new(HeightRes {
-            height: reply.height,
-            block_age: reply.block_age,
+            height: reply.block.height,
+            block_age: reply.block.block_age,
             gateway: Some(reply.gateway.into()),
             gateway_version: reply.gateway_version,
         }))
@@ -0,0 +1,174 @@
+use crate::Result;
+use futures::future::{self, BoxFuture, FutureExt};
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A source of the current time. Components that make time based decisions
+/// take a clock rather than reading the system time directly, so tests can
+/// control time and wall clock time can be disciplined by a GPS time source.
+pub trait Clock: Send + Sync {
+    /// Monotonic time used for intervals and deadlines
+    fn now(&self) -> Instant;
+    /// Wall clock time
+    fn system_time(&self) -> SystemTime;
+    /// Waits for the given duration, for example between reconnect attempts
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()>;
+}
+
+pub type SharedClock = Arc<dyn Clock>;
+
+/// Seconds from the unix epoch to the GPS epoch of 1980-01-06
+const GPS_EPOCH_OFFSET: u64 = 315_964_800;
+/// Leap seconds GPS time is ahead of UTC
+const GPS_LEAP_SECONDS: u64 = 18;
+
+/// Converts a GPS timestamp in milliseconds since the GPS epoch, as reported
+/// by a concentrator in its `tmms` field, to wall clock time
+pub fn gps_time(tmms: u64) -> SystemTime {
+    UNIX_EPOCH
+        + Duration::from_secs(GPS_EPOCH_OFFSET - GPS_LEAP_SECONDS)
+        + Duration::from_millis(tmms)
+}
+
+/// Returns the current unix time in seconds, as stored in persisted records
+pub fn unix_now() -> Result<u64> {
+    Ok(SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs())
+}
+
+/// Returns a shared clock that reads the system time
+pub fn system() -> SharedClock {
+    Arc::new(SystemClock)
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+/// A system clock whose wall clock time is corrected by the offset to GPS
+/// time, as last reported by a GPS equipped concentrator. Until the first GPS
+/// time is reported it behaves like the system clock.
+#[derive(Debug, Default)]
+pub struct GpsClock {
+    // Signed offset of GPS time from the system time
+    offset: Mutex<Option<(Duration, bool)>>,
+}
+
+impl GpsClock {
+    /// Records the GPS time of an event that happened at the current system
+    /// time.
+    pub fn discipline(&self, gps_time: SystemTime) {
+        let now = SystemTime::now();
+        let offset = match gps_time.duration_since(now) {
+            Ok(ahead) => (ahead, true),
+            Err(err) => (err.duration(), false),
+        };
+        *self.offset.lock().expect("clock lock") = Some(offset);
+    }
+}
+
+impl Clock for GpsClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn system_time(&self) -> SystemTime {
+        let now = SystemTime::now();
+        match *self.offset.lock().expect("clock lock") {
+            Some((offset, true)) => now + offset,
+            Some((offset, false)) => now - offset,
+            None => now,
+        }
+    }
+
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        tokio::time::sleep(duration).boxed()
+    }
+}
+
+/// A clock that only moves when advanced, for tests
+#[derive(Debug)]
+pub struct MockClock {
+    times: Mutex<(Instant, SystemTime)>,
+}
+
+impl Default for MockClock {
+    fn default() -> Self {
+        Self {
+            times: Mutex::new((Instant::now(), SystemTime::now())),
+        }
+    }
+}
+
+impl MockClock {
+    pub fn advance(&self, duration: Duration) {
+        let mut times = self.times.lock().expect("clock lock");
+        times.0 += duration;
+        times.1 += duration;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Instant {
+        self.times.lock().expect("clock lock").0
+    }
+
+    fn system_time(&self) -> SystemTime {
+        self.times.lock().expect("clock lock").1
+    }
+
+    /// Advances the clock by the given duration without waiting
+    fn sleep(&self, duration: Duration) -> BoxFuture<'static, ()> {
+        self.advance(duration);
+        future::ready(()).boxed()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn gps_offset() {
+        let clock = GpsClock::default();
+        let ahead = Duration::from_secs(18);
+        clock.discipline(SystemTime::now() + ahead);
+        let offset = clock
+            .system_time()
+            .duration_since(SystemTime::now())
+            .expect("gps time ahead");
+        assert!(offset <= ahead && offset > ahead - Duration::from_secs(1));
+
+        clock.discipline(SystemTime::now() - ahead);
+        assert!(clock.system_time() < SystemTime::now() - ahead + Duration::from_secs(1));
+    }
+
+    #[test]
+    fn gps_epoch() {
+        let gps_epoch = UNIX_EPOCH + Duration::from_secs(315_964_800 - 18);
+        assert_eq!(gps_epoch, gps_time(0));
+        assert_eq!(gps_epoch + Duration::from_millis(1500), gps_time(1500));
+    }
+
+    #[test]
+    fn mock_advance() {
+        let clock = MockClock::default();
+        let start = clock.now();
+        clock.advance(Duration::from_secs(60));
+        assert_eq!(Duration::from_secs(60), clock.now() - start);
+    }
+}
@@ -23,17 +23,17 @@ pub struct Cmd {
 }
 
 impl Cmd {
-    pub async fn run(&self, settings: Settings) -> Result {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
         let mut client = LocalClient::new(settings.api).await?;
 
         let txn = client
             .add_gateway(&self.owner, &self.payer, &self.mode)
             .await?;
-        print_txn(&self.mode, &txn)
+        print_txn(&self.mode, &txn, format)
     }
 }
 
-fn print_txn(mode: &StakingMode, txn: &BlockchainTxnAddGatewayV1) -> Result {
+fn print_txn(mode: &StakingMode, txn: &BlockchainTxnAddGatewayV1, format: OutputFormat) -> Result {
     let table = json!({
         "mode": mode.to_string(),
         "address": PublicKey::from_bytes(&txn.gateway)?.to_string(),
@@ -43,5 +43,5 @@ fn print_txn(mode: &StakingMode, txn: &BlockchainTxnAddGatewayV1) -> Result {
         "staking fee": txn.staking_fee,
         "txn": txn.in_envelope_vec()?.to_b64(),
     });
-    print_json(&table)
+    print_json(&table, format)
 }
@@ -0,0 +1,432 @@
+use crate::{
+    cmd::{region::chain_region_params, region::running_region, *},
+    gateway::DOWNLINK_TIMEOUT_SECS,
+    Error, Packet, Result, Settings,
+};
+use futures::stream::{FuturesUnordered, StreamExt};
+use rust_decimal::prelude::ToPrimitive;
+use semtech_udp::{
+    push_data::CRC,
+    server_runtime::{Error as SemtechError, Event, UdpRuntime},
+    MacAddress,
+};
+use serde_json::json;
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::PathBuf,
+    time::Duration,
+};
+use structopt::StructOpt;
+use tokio::{
+    task::JoinHandle,
+    time::{self, Instant},
+};
+
+/// Commands on the attached packet forwarder
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    Test(Test),
+    Config(Config),
+}
+
+/// Run a protocol conformance test against the attached packet forwarder.
+///
+/// This listens on the configured packet forwarder address, so the gateway
+/// service must be stopped while the test runs. Synthetic downlinks are
+/// transmitted in reply to received uplinks.
+#[derive(Debug, StructOpt)]
+pub struct Test {
+    /// Number of seconds to run the test for
+    #[structopt(long, default_value = "60")]
+    duration: u64,
+
+    /// Maximum number of synthetic downlinks to send
+    #[structopt(long, default_value = "3")]
+    downlinks: u32,
+
+    /// Transmit power in dBm for synthetic downlinks
+    #[structopt(long, default_value = "14")]
+    tx_power: u32,
+}
+
+/// Generate the channel plan of a semtech packet forwarder `global_conf.json`
+/// for the region of the running gateway, or the configured region if the
+/// gateway is not running.
+///
+/// Only the channel plan, antenna gain and server settings are generated.
+/// Hardware specific settings like radio types, rssi offsets, clock source
+/// and tx gain tables have to be merged in from the concentrator vendor's
+/// configuration. US915 and AU915 require the subband setting, other regions
+/// take their channels from the region parameters of a seed validator.
+#[derive(Debug, StructOpt)]
+pub struct Config {
+    /// File to write the configuration to instead of printing it
+    #[structopt(long, short)]
+    output: Option<PathBuf>,
+
+    /// Address the packet forwarder sends to. Defaults to the configured
+    /// listen address, or localhost if the gateway listens on all addresses
+    #[structopt(long)]
+    server_address: Option<String>,
+}
+
+// Maximum offset of a channel from the center frequency of its radio
+const MAX_IF_HZ: i64 = 400_000;
+// Number of multi spreading factor channels of a SX1301 concentrator
+const MULTI_SF_CHANNELS: usize = 8;
+
+// Delay after an uplink to schedule a synthetic downlink at, matching the
+// lorawan rx1 window
+const DOWNLINK_DELAY_US: u64 = 1_000_000;
+const DOWNLINK_PAYLOAD: &[u8] = b"helium_gateway forwarder test";
+
+/// A synthetic downlink being sent, resolving to its dispatch result and the
+/// time it took
+type SentDownlink = JoinHandle<(std::result::Result<(), SemtechError>, Duration)>;
+
+#[derive(Debug, Default)]
+struct Report {
+    clients: Vec<String>,
+    push_data: u32,
+    crc_errors: u32,
+    stats: u32,
+    parse_errors: u32,
+    timestamp_regressions: u32,
+    last_timestamp: Option<u32>,
+    downlinks_sent: u32,
+    downlinks_acked: u32,
+    downlink_errors: Vec<String>,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        match self {
+            Cmd::Test(cmd) => cmd.run(settings, format).await,
+            Cmd::Config(cmd) => cmd.run(settings, format).await,
+        }
+    }
+}
+
+impl Test {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        let mut udp_runtime = UdpRuntime::new(&settings.listen).await?;
+        let mut report = Report::default();
+        // Downlinks are sent concurrently so waiting for their tx_ack does not
+        // hold up receiving uplinks
+        let mut downlinks: FuturesUnordered<SentDownlink> = FuturesUnordered::new();
+        let deadline = time::sleep(Duration::from_secs(self.duration));
+        tokio::pin!(deadline);
+        loop {
+            tokio::select! {
+                _ = &mut deadline => break,
+                Some(sent) = downlinks.next(), if !downlinks.is_empty() => report.record_downlink(sent),
+                event = udp_runtime.recv() => match event {
+                    Event::NewClient((mac, addr)) | Event::UpdateClient((mac, addr)) => {
+                        report.clients.push(format!("{mac} {addr}"));
+                    }
+                    Event::PacketReceived(rxpk, mac) => {
+                        report.push_data += 1;
+                        if rxpk.get_crc_status() != &CRC::OK {
+                            report.crc_errors += 1;
+                            continue;
+                        }
+                        report.check_timestamp(*rxpk.get_timestamp());
+                        if report.downlinks_sent < self.downlinks {
+                            let uplink = helium_proto::Packet {
+                                frequency: *rxpk.get_frequency() as f32,
+                                datarate: rxpk.get_datarate().to_string(),
+                                timestamp: *rxpk.get_timestamp() as u64,
+                                ..Default::default()
+                            };
+                            if let Some(sent) = self.send_downlink(&udp_runtime, mac, uplink)? {
+                                report.downlinks_sent += 1;
+                                downlinks.push(sent);
+                            }
+                        }
+                    }
+                    Event::StatReceived(_, _) => report.stats += 1,
+                    Event::UnableToParseUdpFrame(_, _) => report.parse_errors += 1,
+                    Event::ClientDisconnected(_) | Event::NoClientWithMac(_, _) => (),
+                }
+            }
+        }
+        // Downlinks still in flight at the deadline are waited for, which
+        // takes at most the downlink timeout
+        while let Some(sent) = downlinks.next().await {
+            report.record_downlink(sent);
+        }
+        print_json(&report.to_json(), format)
+    }
+
+    /// Starts sending a synthetic downlink in reply to the given uplink.
+    /// Returns None if the downlink can not be transmitted.
+    fn send_downlink(
+        &self,
+        udp_runtime: &UdpRuntime,
+        mac: MacAddress,
+        uplink: helium_proto::Packet,
+    ) -> Result<Option<SentDownlink>> {
+        let downlink = Packet::from(helium_proto::Packet {
+            timestamp: (uplink.timestamp + DOWNLINK_DELAY_US) & u32::MAX as u64,
+            payload: DOWNLINK_PAYLOAD.to_vec(),
+            ..uplink
+        });
+        let txpk = match downlink.to_pull_resp(false, self.tx_power)? {
+            Some(txpk) => txpk,
+            None => return Ok(None),
+        };
+        let mut pull_resp = udp_runtime.prepare_empty_downlink(mac);
+        pull_resp.set_packet(txpk);
+        Ok(Some(tokio::spawn(async move {
+            let started = Instant::now();
+            let dispatched = pull_resp
+                .dispatch(Some(Duration::from_secs(DOWNLINK_TIMEOUT_SECS)))
+                .await;
+            (dispatched, started.elapsed())
+        })))
+    }
+}
+
+impl Config {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        let region = running_region(settings.api)
+            .await
+            .unwrap_or(settings.region);
+        let region_params = chain_region_params(&settings).await;
+        let (channels, lora_std) = if region.has_subbands() {
+            let subband = settings
+                .subband
+                .ok_or_else(|| Error::custom(format!("{region} requires the subband setting")))?;
+            let mut channels = region.subband_channels(Some(subband));
+            // The 500kHz channel follows the 125kHz channels
+            let lora_std = channels.pop();
+            (channels, lora_std)
+        } else {
+            let region_params = region_params.as_ref().map_err(|err| {
+                Error::custom(format!("no region params for {region} channels: {err}"))
+            })?;
+            let channels = region_params
+                .params
+                .iter()
+                .filter(|param| param.bandwidth == 125_000)
+                .map(|param| param.channel_frequency as f64 / 1_000_000.0)
+                .collect();
+            (channels, None)
+        };
+        let mut sx1301_conf = channel_plan(&channels, lora_std)?;
+        if let Ok(region_params) = &region_params {
+            sx1301_conf["antenna_gain"] = json!(region_params.gain.round().to_i64());
+        }
+        let listen: SocketAddr = settings.listen.parse()?;
+        let server_address = self
+            .server_address
+            .clone()
+            .unwrap_or_else(|| server_address(&listen));
+        let conf = json!({
+            "SX1301_conf": sx1301_conf,
+            "gateway_conf": {
+                "server_address": server_address,
+                "serv_port_up": listen.port(),
+                "serv_port_down": listen.port(),
+            },
+        });
+        match &self.output {
+            Some(path) => {
+                fs::write(path, serde_json::to_string_pretty(&conf)?)?;
+                Ok(())
+            }
+            None => print_json(&conf, format),
+        }
+    }
+}
+
+/// Returns the address a packet forwarder on the same host reaches the given
+/// listen address at
+fn server_address(listen: &SocketAddr) -> String {
+    match listen.ip() {
+        IpAddr::V4(ip) if ip.is_unspecified() => Ipv4Addr::LOCALHOST.to_string(),
+        IpAddr::V6(ip) if ip.is_unspecified() => Ipv6Addr::LOCALHOST.to_string(),
+        ip => ip.to_string(),
+    }
+}
+
+/// Builds the radio and channel sections of a SX1301 configuration. The
+/// sorted 125kHz channels are split over the two radios, each centered on its
+/// half of the channels. The optional 500kHz channel goes to the radio with
+/// the nearest center frequency.
+fn channel_plan(channels: &[f64], lora_std: Option<f64>) -> Result<serde_json::Value> {
+    let hz = |mhz: f64| (mhz * 1_000_000.0).round() as i64;
+    let mut channels: Vec<i64> = channels.iter().map(|mhz| hz(*mhz)).collect();
+    channels.sort_unstable();
+    if channels.is_empty() || channels.len() > MULTI_SF_CHANNELS {
+        return Err(Error::custom(format!(
+            "unsupported number of channels: {}",
+            channels.len()
+        )));
+    }
+    let (radio_0, radio_1) = channels.split_at((channels.len() + 1) / 2);
+    let center = |radio: &[i64]| {
+        radio
+            .first()
+            .zip(radio.last())
+            .map(|(lo, hi)| (lo + hi) / 2)
+    };
+    let centers = [center(radio_0), center(radio_1)];
+
+    let mut conf = json!({ "lorawan_public": true });
+    for (radio, center) in centers.iter().enumerate() {
+        conf[format!("radio_{radio}")] = match center {
+            Some(center) => json!({
+                "enable": true,
+                "freq": center,
+                "tx_enable": radio == 0,
+            }),
+            None => json!({ "enable": false }),
+        };
+    }
+    let radio_channels = radio_0
+        .iter()
+        .map(|channel| (0, *channel))
+        .chain(radio_1.iter().map(|channel| (1, *channel)));
+    for (index, (radio, channel)) in radio_channels.enumerate() {
+        let offset = channel - centers[radio].unwrap_or_default();
+        if offset.abs() > MAX_IF_HZ {
+            return Err(Error::custom(format!(
+                "channel {channel} too far from radio {radio}"
+            )));
+        }
+        conf[format!("chan_multiSF_{index}")] = json!({
+            "enable": true,
+            "radio": radio,
+            "if": offset,
+        });
+    }
+    conf["chan_Lora_std"] = match lora_std.map(hz) {
+        Some(channel) => {
+            let (radio, center) = centers
+                .iter()
+                .enumerate()
+                .filter_map(|(radio, center)| center.map(|center| (radio, center)))
+                .min_by_key(|(_, center)| (channel - center).abs())
+                .expect("radio center");
+            json!({
+                "enable": true,
+                "radio": radio,
+                "if": channel - center,
+                "bandwidth": 500_000,
+                "spread_factor": 8,
+            })
+        }
+        None => json!({ "enable": false }),
+    };
+    conf["chan_FSK"] = json!({ "enable": false });
+    Ok(conf)
+}
+
+impl Report {
+    /// Tracks the concentrator timestamp of received packets, which is
+    /// expected to increase apart from wrapping around the 32 bit counter.
+    fn check_timestamp(&mut self, timestamp: u32) {
+        if let Some(last) = self.last_timestamp {
+            if timestamp.wrapping_sub(last) > u32::MAX / 2 {
+                self.timestamp_regressions += 1;
+            }
+        }
+        self.last_timestamp = Some(timestamp);
+    }
+
+    fn record_downlink(
+        &mut self,
+        sent: std::result::Result<
+            (std::result::Result<(), SemtechError>, Duration),
+            tokio::task::JoinError,
+        >,
+    ) {
+        match sent {
+            Ok((Ok(()), _)) => self.downlinks_acked += 1,
+            Ok((Err(SemtechError::Ack(err)), _)) => {
+                self.downlink_errors.push(format!("tx_ack error: {err:?}"))
+            }
+            Ok((Err(err), elapsed)) => self.downlink_errors.push(format!(
+                "no valid tx_ack after {}ms: {err:?}",
+                elapsed.as_millis()
+            )),
+            Err(err) => self.downlink_errors.push(format!("downlink failed: {err}")),
+        }
+    }
+
+    fn passed(&self) -> bool {
+        !self.clients.is_empty()
+            && self.push_data > 0
+            && self.parse_errors == 0
+            && self.timestamp_regressions == 0
+            && self.downlinks_acked == self.downlinks_sent
+    }
+
+    fn to_json(&self) -> serde_json::Value {
+        json!({
+            "passed": self.passed(),
+            "clients": self.clients,
+            "push_data": self.push_data,
+            "crc_errors": self.crc_errors,
+            "stats": self.stats,
+            "parse_errors": self.parse_errors,
+            "timestamp_regressions": self.timestamp_regressions,
+            "downlinks": {
+                "sent": self.downlinks_sent,
+                "acked": self.downlinks_acked,
+                "errors": self.downlink_errors,
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn channel_plan_radios() {
+        let channels = [867.1, 867.3, 867.5, 867.7, 867.9, 868.1, 868.3, 868.5];
+        let conf = channel_plan(&channels, None).expect("channel plan");
+        assert_eq!(json!(867_400_000), conf["radio_0"]["freq"]);
+        assert_eq!(json!(868_200_000), conf["radio_1"]["freq"]);
+        assert_eq!(json!(true), conf["radio_0"]["tx_enable"]);
+        assert_eq!(json!(0), conf["chan_multiSF_0"]["radio"]);
+        assert_eq!(json!(-300_000), conf["chan_multiSF_0"]["if"]);
+        assert_eq!(json!(1), conf["chan_multiSF_7"]["radio"]);
+        assert_eq!(json!(300_000), conf["chan_multiSF_7"]["if"]);
+        assert_eq!(json!(false), conf["chan_Lora_std"]["enable"]);
+    }
+
+    #[test]
+    fn channel_plan_lora_std() {
+        // US915 subband 2
+        let channels = [903.9, 904.1, 904.3, 904.5, 904.7, 904.9, 905.1, 905.3];
+        let conf = channel_plan(&channels, Some(904.6)).expect("channel plan");
+        assert_eq!(json!(0), conf["chan_Lora_std"]["radio"]);
+        assert_eq!(json!(400_000), conf["chan_Lora_std"]["if"]);
+        assert_eq!(json!(500_000), conf["chan_Lora_std"]["bandwidth"]);
+
+        // A single radio is enough for few channels
+        let conf = channel_plan(&[868.1], None).expect("channel plan");
+        assert_eq!(json!(false), conf["radio_1"]["enable"]);
+    }
+
+    #[test]
+    fn channel_plan_unsupported() {
+        assert!(channel_plan(&[], None).is_err());
+        assert!(channel_plan(&[868.1; 9], None).is_err());
+        // Channels too far apart to share a radio
+        assert!(channel_plan(&[863.0, 864.0, 869.0], None).is_err());
+    }
+
+    #[test]
+    fn server_addresses() {
+        let address = |listen: &str| server_address(&listen.parse().expect("listen"));
+        assert_eq!("127.0.0.1", address("0.0.0.0:1680"));
+        assert_eq!("::1", address("[::]:1680"));
+        assert_eq!("10.0.0.2", address("10.0.0.2:1680"));
+    }
+}
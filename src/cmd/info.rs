@@ -2,6 +2,7 @@ use crate::{
     api::{HeightRes, LocalClient},
     cmd::*,
     keyed_uri::KeyedUri,
+    router::dispatcher,
     service::gateway::GatewayVersion,
     settings::{self, Settings},
     Error, Region, Result,
@@ -11,17 +12,29 @@ use helium_crypto::PublicKey;
 
 use serde_json::json;
 use std::collections::HashMap;
-use std::{fmt, str::FromStr};
+use std::{
+    fmt, fs,
+    path::PathBuf,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
 use structopt::StructOpt;
 
 #[derive(Debug, Clone)]
 pub enum InfoKey {
     Fw,
+    Features,
+    Build,
     Key,
     OnboardingKey,
     Name,
     Gateway,
     Region,
+    Status,
+    Traces,
+    Signatures,
+    Validators,
+    Usage,
 }
 
 #[derive(Debug, Clone)]
@@ -29,6 +42,12 @@ pub struct InfoKeys(pub(crate) Vec<InfoKey>);
 
 /// Info command. Retrieve all or a subset of information from the running
 /// service.
+///
+/// Keys that describe the running dispatcher, such as status, traces,
+/// signatures, validators and usage, are read from the dispatcher snapshot
+/// the service refreshes every 15 seconds in its runtime dir. The status key
+/// also reports how many seconds old the snapshot is, and the build of the
+/// running service, which the build key reports for this binary.
 #[derive(Debug, StructOpt)]
 pub struct Cmd {
     /// Information keys to fetch
@@ -36,38 +55,56 @@ pub struct Cmd {
         long,
         short,
         multiple = false,
-        default_value = "fw,key,onboarding,name,region,gateway"
+        default_value = "fw,features,build,key,onboarding,name,region,gateway"
     )]
     pub keys: InfoKeys,
 }
 
 impl Cmd {
-    pub async fn run(&self, settings: Settings) -> Result {
-        let mut info_cache = InfoCache::new(settings.update.platform.clone(), settings.api);
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        let mut info_cache = InfoCache::new(
+            settings.update.platform.clone(),
+            settings.api,
+            dispatcher::snapshot_path(&settings.cache),
+        );
         let mut info: HashMap<String, serde_json::Value> = HashMap::new();
         for key in &self.keys.0 {
             info.insert(key.to_string(), key.to_status(&mut info_cache).await?);
         }
-        print_json(&info)
+        print_json(&info, format)
     }
 }
 
 const INFO_FW: &str = "fw";
+const INFO_FEATURES: &str = "features";
+const INFO_BUILD: &str = "build";
 const INFO_KEY: &str = "key";
 const INFO_ONBOARDING_KEY: &str = "onboarding";
 const INFO_NAME: &str = "name";
 const INFO_GATEWAY: &str = "gateway";
 const INFO_REGION: &str = "region";
+const INFO_STATUS: &str = "status";
+const INFO_TRACES: &str = "traces";
+const INFO_SIGNATURES: &str = "signatures";
+const INFO_VALIDATORS: &str = "validators";
+const INFO_USAGE: &str = "usage";
 
 impl fmt::Display for InfoKey {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let s = match self {
             Self::Fw => INFO_FW,
+            Self::Features => INFO_FEATURES,
+            Self::Build => INFO_BUILD,
             Self::Key => INFO_KEY,
             Self::OnboardingKey => INFO_ONBOARDING_KEY,
             Self::Name => INFO_NAME,
             Self::Gateway => INFO_GATEWAY,
             Self::Region => INFO_REGION,
+            Self::Status => INFO_STATUS,
+            Self::Traces => INFO_TRACES,
+            Self::Signatures => INFO_SIGNATURES,
+            Self::Validators => INFO_VALIDATORS,
+            Self::Usage => INFO_USAGE,
         };
         f.write_str(s)
     }
@@ -87,11 +124,18 @@ impl FromStr for InfoKey {
     fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
         match s {
             INFO_FW => Ok(Self::Fw),
+            INFO_FEATURES => Ok(Self::Features),
+            INFO_BUILD => Ok(Self::Build),
             INFO_KEY => Ok(Self::Key),
             INFO_ONBOARDING_KEY => Ok(Self::OnboardingKey),
             INFO_NAME => Ok(Self::Name),
             INFO_GATEWAY => Ok(Self::Gateway),
             INFO_REGION => Ok(Self::Region),
+            INFO_STATUS => Ok(Self::Status),
+            INFO_TRACES => Ok(Self::Traces),
+            INFO_SIGNATURES => Ok(Self::Signatures),
+            INFO_VALIDATORS => Ok(Self::Validators),
+            INFO_USAGE => Ok(Self::Usage),
             invalid => Err(InfoKeyParseError(invalid.to_string())),
         }
     }
@@ -113,19 +157,23 @@ impl std::str::FromStr for InfoKeys {
 struct InfoCache {
     platform: String,
     port: u16,
+    snapshot_path: PathBuf,
     public_keys: Option<(PublicKey, PublicKey)>,
     height: Option<HeightRes>,
     region: Option<Region>,
+    snapshot: Option<serde_json::Value>,
 }
 
 impl InfoCache {
-    fn new(platform: String, port: u16) -> Self {
+    fn new(platform: String, port: u16, snapshot_path: PathBuf) -> Self {
         Self {
             platform,
             port,
+            snapshot_path,
             public_keys: None,
             height: None,
             region: None,
+            snapshot: None,
         }
     }
 
@@ -191,6 +239,20 @@ impl InfoCache {
         self.region = Some(region);
         Ok(region)
     }
+
+    /// Returns the dispatcher snapshot last written by the running service
+    fn snapshot(&mut self) -> Result<&serde_json::Value> {
+        if self.snapshot.is_none() {
+            let data = fs::read(&self.snapshot_path).map_err(|err| {
+                Error::custom(format!(
+                    "no dispatcher snapshot at {}: {err}",
+                    self.snapshot_path.display()
+                ))
+            })?;
+            self.snapshot = Some(serde_json::from_slice(&data)?);
+        }
+        Ok(self.snapshot.as_ref().expect("snapshot"))
+    }
 }
 
 impl InfoKey {
@@ -201,6 +263,12 @@ impl InfoKey {
                 let version = settings::version();
                 json!(format!("{platform}-{version}"))
             }
+            Self::Features => {
+                json!(settings::features())
+            }
+            Self::Build => {
+                json!(settings::build_info())
+            }
             Self::Key => {
                 json!(cache.public_key().await?.to_string())
             }
@@ -225,6 +293,36 @@ impl InfoKey {
             Self::Region => {
                 json!(cache.region().await?.to_string())
             }
+            Self::Status => {
+                let snapshot = cache.snapshot()?;
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs());
+                let routers: Vec<serde_json::Value> = snapshot["routers"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(|router| {
+                        json!({
+                            "oui": router["oui"],
+                            "uri": router["uri"],
+                            "queue_depth": router["queue_depth"],
+                            "throttled_downlinks": router["throttled_downlinks"],
+                        })
+                    })
+                    .collect();
+                json!({
+                    "age": now.saturating_sub(snapshot["taken"].as_u64().unwrap_or_default()),
+                    "queue_depth": snapshot["queue_depth"],
+                    "routers": routers,
+                    "streams": snapshot["streams"],
+                    "build": snapshot["build"],
+                })
+            }
+            Self::Traces => cache.snapshot()?["traces"].clone(),
+            Self::Signatures => cache.snapshot()?["signatures"].clone(),
+            Self::Validators => cache.snapshot()?["validators"].clone(),
+            Self::Usage => cache.snapshot()?["usage"].clone(),
         };
         Ok(v)
     }
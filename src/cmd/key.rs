@@ -1,5 +1,8 @@
 use crate::{
-    cmd::info::{self, InfoKey, InfoKeys},
+    cmd::{
+        info::{self, InfoKey, InfoKeys},
+        OutputFormat,
+    },
     Result, Settings,
 };
 use structopt::StructOpt;
@@ -15,18 +18,18 @@ pub enum Cmd {
 pub struct Info {}
 
 impl Cmd {
-    pub async fn run(&self, settings: Settings) -> Result {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
         match self {
-            Cmd::Info(cmd) => cmd.run(settings).await,
+            Cmd::Info(cmd) => cmd.run(settings, format).await,
         }
     }
 }
 
 impl Info {
-    pub async fn run(&self, settings: Settings) -> Result {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
         let cmd = info::Cmd {
             keys: InfoKeys(vec![InfoKey::Name, InfoKey::Key, InfoKey::OnboardingKey]),
         };
-        cmd.run(settings).await
+        cmd.run(settings, format).await
     }
 }
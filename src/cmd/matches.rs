@@ -0,0 +1,143 @@
+use crate::{
+    cmd::*,
+    router::{handoff::RoutingHandoff, Routing},
+    service::{
+        dns,
+        gateway::{GatewayService, HealthTracker, Response},
+        resolver::Resolver,
+    },
+    storage, Error, Result, Settings,
+};
+use futures::StreamExt;
+use helium_proto::{routing_information::Data as RoutingData, Eui};
+use serde_json::json;
+use slog::Logger;
+use structopt::StructOpt;
+
+/// Report which OUIs and routers would receive packets for a DevAddr or EUI
+///
+/// The routing table last applied by the gateway is used. If the gateway has
+/// not stored a routing table, for example when its cache is read only, the
+/// routing table is fetched from one of the configured seed validators.
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    Devaddr(Devaddr),
+    Eui(EuiArgs),
+}
+
+/// Match a DevAddr (hex) against the routing table
+#[derive(Debug, StructOpt)]
+pub struct Devaddr {
+    #[structopt(parse(try_from_str = parse_hex_u32))]
+    devaddr: u32,
+}
+
+/// Match a DevEUI and AppEUI (hex) against the routing table
+#[derive(Debug, StructOpt)]
+pub struct EuiArgs {
+    #[structopt(parse(try_from_str = parse_hex_u64))]
+    deveui: u64,
+    #[structopt(parse(try_from_str = parse_hex_u64))]
+    appeui: u64,
+}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings, format: OutputFormat, logger: &Logger) -> Result {
+        let routing_data = match self {
+            Self::Devaddr(Devaddr { devaddr }) => RoutingData::Devaddr(*devaddr),
+            Self::Eui(EuiArgs { deveui, appeui }) => RoutingData::Eui(Eui {
+                deveui: *deveui,
+                appeui: *appeui,
+            }),
+        };
+        let (source, height, routings) = match stored_routings(&settings, logger) {
+            Some((height, routings)) => ("gateway", height, routings),
+            None => {
+                let (height, routings) = fetch_routings(&settings, logger).await?;
+                ("seed", height, routings)
+            }
+        };
+        let matches: Vec<serde_json::Value> = routings
+            .iter()
+            .filter(|routing| routing.matches_routing_data(&Some(routing_data.clone())))
+            .map(|routing| {
+                let routers: Vec<String> =
+                    routing.uris.iter().map(|uri| uri.uri.to_string()).collect();
+                json!({
+                    "oui": routing.oui,
+                    "routers": routers,
+                })
+            })
+            .collect();
+        // Unmatched packets are sent to the default routers, if configured
+        let default_routers: Vec<String> = if matches.is_empty() {
+            settings
+                .routers
+                .iter()
+                .flatten()
+                .map(|uri| uri.uri.to_string())
+                .collect()
+        } else {
+            vec![]
+        };
+        print_json(
+            &json!({
+                "source": source,
+                "height": height,
+                "matches": matches,
+                "default_routers": default_routers,
+            }),
+            format,
+        )
+    }
+}
+
+/// Returns the height and routing table last applied by the gateway, if it
+/// stored one
+fn stored_routings(settings: &Settings, logger: &Logger) -> Option<(u64, Vec<Routing>)> {
+    let store = storage::open(&settings.cache);
+    let table = RoutingHandoff::load_table(store.as_ref())?;
+    let routings = table
+        .routings()
+        .and_then(|protos| {
+            protos
+                .iter()
+                .map(|proto| Routing::from_proto(logger, proto))
+                .collect()
+        })
+        .ok()?;
+    Some((table.height, routings))
+}
+
+/// Fetches the current height and routing table from a random seed validator
+async fn fetch_routings(settings: &Settings, logger: &Logger) -> Result<(u64, Vec<Routing>)> {
+    let seeds = dns::seed_uris(&settings.gateways, settings.timeouts.connect(), logger).await;
+    let mut gateway = GatewayService::select_seed(
+        &seeds,
+        &Resolver::new(settings.timeouts.connect()),
+        &HealthTracker::default(),
+        settings.timeouts,
+        &settings.tls,
+    )
+    .await?;
+    let response = gateway
+        .routing(0)
+        .await?
+        .next()
+        .await
+        .ok_or_else(|| Error::custom("empty routing stream"))??;
+    let routings = response
+        .routings()?
+        .iter()
+        .map(|proto| Routing::from_proto(logger, proto))
+        .collect::<Result<_>>()?;
+    Ok((response.height(), routings))
+}
+
+fn parse_hex_u32(s: &str) -> std::result::Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(s.trim_start_matches("0x"), 16)
+}
+
+fn parse_hex_u64(s: &str) -> std::result::Result<u64, std::num::ParseIntError> {
+    u64::from_str_radix(s.trim_start_matches("0x"), 16)
+}
@@ -1,12 +1,53 @@
 pub mod add;
+pub mod forwarder;
 pub mod info;
 pub mod key;
+pub mod matches;
+pub mod region;
+pub mod seeds;
 pub mod server;
 pub mod update;
 
-use crate::Result;
+use crate::{Error, Result};
+use std::{fmt, str::FromStr};
 
-pub(crate) fn print_json<T: ?Sized + serde::Serialize>(value: &T) -> Result {
-    println!("{}", serde_json::to_string_pretty(value)?);
+/// Output format of command results
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable output, with JSON results pretty printed
+    Text,
+    /// A single line of JSON per result for scripts
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            unsupported => Err(Error::custom(format!(
+                "unsupported output format: \"{unsupported}\""
+            ))),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Text => f.write_str("text"),
+            Self::Json => f.write_str("json"),
+        }
+    }
+}
+
+pub(crate) fn print_json<T: ?Sized + serde::Serialize>(value: &T, format: OutputFormat) -> Result {
+    let output = match format {
+        OutputFormat::Text => serde_json::to_string_pretty(value)?,
+        OutputFormat::Json => serde_json::to_string(value)?,
+    };
+    println!("{output}");
     Ok(())
 }
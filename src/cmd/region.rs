@@ -0,0 +1,118 @@
+use crate::{
+    api::LocalClient,
+    cmd::*,
+    service::{
+        dns,
+        gateway::{GatewayService, HealthTracker, Response},
+        resolver::Resolver,
+    },
+    Error, Region, RegionParams, RequestSigner, Result, Settings,
+};
+use futures::StreamExt;
+use rust_decimal::Decimal;
+use serde_json::json;
+use structopt::StructOpt;
+
+/// Commands on the gateway region
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    Plan(Plan),
+}
+
+/// Print the channel plan of the region of the running gateway, or of the
+/// configured region if the gateway is not running.
+///
+/// Channel frequencies and power limits are fetched from one of the
+/// configured seed validators. Use the output to cross-check the channel plan
+/// of the packet forwarder.
+#[derive(Debug, StructOpt)]
+pub struct Plan {}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        match self {
+            Cmd::Plan(cmd) => cmd.run(settings, format).await,
+        }
+    }
+}
+
+impl Plan {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        let (region, source) = match running_region(settings.api).await {
+            Ok(region) => (region, "gateway"),
+            Err(_) => (settings.region, "settings"),
+        };
+        let mut plan = json!({
+            "region": region.to_string(),
+            "source": source,
+            "subband": settings.subband,
+            "datarates": region.uplink_datarates(),
+            "dwell_time_ms": region.dwell_time_limit().map(|limit| limit.as_millis() as u64),
+        });
+        if let Some((frequency, datarate)) = region.rx2_window() {
+            plan["rx2"] = json!({
+                "frequency": frequency,
+                "datarate": datarate,
+            });
+        }
+        if region.has_subbands() {
+            plan["uplink_channels"] = json!(region.subband_channels(settings.subband));
+        }
+        plan["chain"] = match chain_region_params(&settings).await {
+            Ok(region_params) => region_params_json(&region_params),
+            Err(err) => json!({ "error": err.to_string() }),
+        };
+        print_json(&plan, format)
+    }
+}
+
+pub(crate) async fn running_region(port: u16) -> Result<Region> {
+    LocalClient::new(port).await?.region().await
+}
+
+/// Fetches the region parameters for this gateway from a random seed
+/// validator
+pub(crate) async fn chain_region_params(settings: &Settings) -> Result<RegionParams> {
+    let seeds = dns::seed_uris(
+        &settings.gateways,
+        settings.timeouts.connect(),
+        &slog_scope::logger(),
+    )
+    .await;
+    let mut gateway = GatewayService::select_seed(
+        &seeds,
+        &Resolver::new(settings.timeouts.connect()),
+        &HealthTracker::default(),
+        settings.timeouts,
+        &settings.tls,
+    )
+    .await?;
+    let response = gateway
+        .region_params(RequestSigner::from(settings.keypair.clone()))
+        .await?
+        .next()
+        .await
+        .ok_or_else(|| Error::custom("empty region params stream"))??;
+    response.region_params()
+}
+
+fn region_params_json(region_params: &RegionParams) -> serde_json::Value {
+    let channels: Vec<serde_json::Value> = region_params
+        .params
+        .iter()
+        .map(|param| {
+            json!({
+                "frequency": param.channel_frequency as f64 / 1_000_000.0,
+                "bandwidth_khz": param.bandwidth / 1000,
+                "max_eirp": Decimal::new(param.max_eirp as i64, 1).to_string(),
+            })
+        })
+        .collect();
+    json!({
+        "region": region_params.region.to_string(),
+        "gain": region_params.gain.to_string(),
+        "max_eirp": region_params.max_eirp().map(|max_eirp| max_eirp.to_string()),
+        "tx_power": region_params.tx_power(),
+        "channels": channels,
+    })
+}
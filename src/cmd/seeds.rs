@@ -0,0 +1,115 @@
+use crate::{
+    cmd::*,
+    service::{
+        dns,
+        gateway::{GatewayService, HealthTracker},
+        resolver::Resolver,
+    },
+    settings::{TimeoutSettings, TlsSettings},
+    KeyedUri, Result, Settings,
+};
+use futures::future;
+use serde_json::json;
+use structopt::StructOpt;
+use tokio::time::Instant;
+
+/// Commands on the configured seed validators
+#[derive(Debug, StructOpt)]
+pub enum Cmd {
+    Check(Check),
+}
+
+/// Check reachability, height, version and validator listing of every
+/// configured seed validator
+#[derive(Debug, StructOpt)]
+pub struct Check {}
+
+impl Cmd {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        match self {
+            Cmd::Check(cmd) => cmd.run(settings, format).await,
+        }
+    }
+}
+
+impl Check {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        let health = HealthTracker::default();
+        let resolver = Resolver::new(settings.timeouts.connect());
+        let seeds = dns::seed_uris(
+            &settings.gateways,
+            settings.timeouts.connect(),
+            &slog_scope::logger(),
+        )
+        .await;
+        let checks = seeds.iter().map(|uri| {
+            check_seed(
+                uri,
+                &resolver,
+                health.clone(),
+                settings.timeouts,
+                &settings.tls,
+            )
+        });
+        let results: Vec<serde_json::Value> = future::join_all(checks).await;
+        print_json(&results, format)
+    }
+}
+
+async fn check_seed(
+    uri: &KeyedUri,
+    resolver: &Resolver,
+    health: HealthTracker,
+    timeouts: TimeoutSettings,
+    tls: &TlsSettings,
+) -> serde_json::Value {
+    let mut status = json!({
+        "uri": uri.uri.to_string(),
+        "key": uri.pubkey.to_string(),
+    });
+    let started = Instant::now();
+    match seed_status(uri, resolver, health, timeouts, tls).await {
+        Ok(result) => {
+            status["reachable"] = json!(true);
+            status["height"] = json!(result.height);
+            status["block_age"] = json!(result.block_age);
+            status["version"] = json!(result.version);
+            status["validators"] = json!(result.validators);
+            status["health"] = json!(result.health);
+        }
+        Err(err) => {
+            status["reachable"] = json!(false);
+            status["error"] = json!(err.to_string());
+        }
+    }
+    status["elapsed_ms"] = json!(started.elapsed().as_millis() as u64);
+    status
+}
+
+struct SeedStatus {
+    height: u64,
+    block_age: u64,
+    version: Option<u64>,
+    validators: usize,
+    health: f64,
+}
+
+async fn seed_status(
+    uri: &KeyedUri,
+    resolver: &Resolver,
+    health: HealthTracker,
+    timeouts: TimeoutSettings,
+    tls: &TlsSettings,
+) -> Result<SeedStatus> {
+    let mut service = GatewayService::resolve(uri, resolver, health, timeouts, tls).await?;
+    let block = service.height().await?;
+    let version = service.version().await?;
+    let validators = service.validators(1).await?.len();
+    Ok(SeedStatus {
+        height: block.height,
+        block_age: block.block_age,
+        version,
+        validators,
+        health: service.health().score(&uri.pubkey),
+    })
+}
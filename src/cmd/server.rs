@@ -1,5 +1,6 @@
 use crate::*;
 use slog::Logger;
+use std::sync::{atomic::AtomicBool, Arc};
 use structopt::StructOpt;
 
 /// Run the gateway service
@@ -11,8 +12,10 @@ impl Cmd {
         &self,
         shutdown: &triggered::Listener,
         settings: Settings,
+        activated: activation::ListenFds,
+        restart: Arc<AtomicBool>,
         logger: &Logger,
     ) -> Result {
-        server::run(shutdown, &settings, logger).await
+        server::run(shutdown, &settings, activated, restart, logger).await
     }
 }
@@ -1,6 +1,10 @@
-use crate::*;
+use crate::{
+    cmd::{print_json, OutputFormat},
+    *,
+};
 use futures::{StreamExt, TryStreamExt};
 use releases::{self, Channel};
+use serde_json::json;
 use std::{env, path::PathBuf};
 use structopt::StructOpt;
 
@@ -36,16 +40,16 @@ pub struct Download {
 }
 
 impl Cmd {
-    pub async fn run(&self, settings: Settings) -> Result {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
         match self {
-            Cmd::List(cmd) => cmd.run(settings).await,
-            Cmd::Download(cmd) => cmd.run(settings).await,
+            Cmd::List(cmd) => cmd.run(settings, format).await,
+            Cmd::Download(cmd) => cmd.run(settings, format).await,
         }
     }
 }
 
 impl List {
-    pub async fn run(&self, settings: Settings) -> Result {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
         let channel = self.channel.clone().unwrap_or(settings.update.channel);
         let platform = self.platform.clone().unwrap_or(settings.update.platform);
         let mut releases =
@@ -53,19 +57,59 @@ impl List {
                 r.in_channel(&channel) && r.asset_for_platform(&platform).is_some()
             })
             .take(self.count.unwrap_or(10));
+        let mut listed = vec![];
         while let Some(Ok(release)) = releases.next().await {
-            if settings::version() == release.version {
-                println!("{} (*)", release.version);
-            } else {
-                println!("{}", release.version);
+            let current = settings::version() == release.version;
+            match format {
+                OutputFormat::Json => listed.push(json!({
+                    "version": release.version.to_string(),
+                    "current": current,
+                })),
+                OutputFormat::Text if current => println!("{} (*)", release.version),
+                OutputFormat::Text => println!("{}", release.version),
             }
         }
-        Ok(())
+        match format {
+            OutputFormat::Json => print_json(&listed, format),
+            OutputFormat::Text => Ok(()),
+        }
     }
 }
 
 impl Download {
-    pub async fn run(&self, settings: Settings) -> Result {
+    pub async fn run(&self, settings: Settings, format: OutputFormat) -> Result {
+        match self.download(&settings).await {
+            Ok(download_path) if format == OutputFormat::Json => print_json(
+                &json!({
+                    "version": self.version.to_string(),
+                    "path": download_path.to_string_lossy(),
+                }),
+                format,
+            ),
+            Ok(download_path) => {
+                println!("Downloaded to: {}", download_path.to_string_lossy());
+                Ok(())
+            }
+            Err(reason) => {
+                match format {
+                    OutputFormat::Json => print_json(
+                        &json!({
+                            "version": self.version.to_string(),
+                            "error": reason,
+                        }),
+                        format,
+                    )?,
+                    OutputFormat::Text => eprintln!("{reason}"),
+                }
+                Err(Error::custom(reason))
+            }
+        }
+    }
+
+    /// Downloads the release asset of the requested version for the
+    /// configured platform. Returns the path the asset was downloaded to, or
+    /// the reason the download failed.
+    async fn download(&self, settings: &Settings) -> std::result::Result<PathBuf, String> {
         let platform = settings.update.platform.clone();
         let version = self.version.clone();
         let channel = Channel::from_version(&version);
@@ -75,25 +119,23 @@ impl Download {
                     && r.in_channel(&channel)
                     && r.asset_for_platform(&platform).is_some()
             });
-        let platform = settings.update.platform.clone();
-        match releases.try_next().await {
-            Ok(Some(release)) => {
-                let asset = release
-                    .asset_for_platform(&platform)
-                    .expect("release asset");
-                let download_path = self
-                    .path
-                    .as_ref()
-                    .unwrap_or(&env::current_dir()?)
-                    .join(&asset.name);
-                match asset.download(&download_path).await {
-                    Ok(()) => println!("Downloaded to: {}", download_path.to_string_lossy()),
-                    Err(err) => eprintln!("Failed to download update: {:?}", err),
-                }
-            }
-            Ok(None) => eprintln!("No release found"),
-            Err(err) => eprintln!("Error finding release: {:?}", err),
+        let release = match releases.try_next().await {
+            Ok(Some(release)) => release,
+            Ok(None) => return Err("No release found".to_string()),
+            Err(err) => return Err(format!("Error finding release: {err:?}")),
+        };
+        let asset = release
+            .asset_for_platform(&settings.update.platform)
+            .expect("release asset");
+        let download_path = match &self.path {
+            Some(path) => path.clone(),
+            None => env::current_dir().map_err(|err| format!("Current directory: {err}"))?,
         }
-        Ok(())
+        .join(&asset.name);
+        asset
+            .download(&download_path)
+            .await
+            .map_err(|err| format!("Failed to download update: {err:?}"))?;
+        Ok(download_path)
     }
 }
@@ -0,0 +1,206 @@
+use crate::{curl, logger::LogTail, settings, Result};
+use serde::Serialize;
+use slog::{info, warn, Logger};
+use std::{
+    backtrace::Backtrace,
+    collections::BTreeMap,
+    fs, panic,
+    path::{Path, PathBuf},
+    sync::{Mutex, TryLockError},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// Number of most recent log records included in a crash report
+pub const CRASH_LOG_RECORDS: usize = 100;
+/// Number of crash reports kept in the crash folder. The oldest reports are
+/// removed when a new one is written.
+pub const CRASH_REPORTS_MAX: usize = 5;
+
+const CRASH_REPORT_PREFIX: &str = "crash-";
+const CRASH_REPORT_SUFFIX: &str = ".json";
+
+// Last known state of every subsystem, keyed by subsystem name
+static SUBSYSTEMS: Mutex<BTreeMap<&'static str, String>> = Mutex::new(BTreeMap::new());
+
+/// Records the state of a subsystem for inclusion in crash reports, for
+/// example the validator the dispatcher is connected to
+pub fn set_subsystem_state<S: Into<String>>(name: &'static str, state: S) {
+    let mut subsystems = match SUBSYSTEMS.lock() {
+        Ok(subsystems) => subsystems,
+        Err(err) => err.into_inner(),
+    };
+    subsystems.insert(name, state.into());
+}
+
+fn subsystem_states() -> BTreeMap<&'static str, String> {
+    match SUBSYSTEMS.try_lock() {
+        Ok(subsystems) => subsystems.clone(),
+        Err(TryLockError::Poisoned(err)) => err.into_inner().clone(),
+        Err(TryLockError::WouldBlock) => BTreeMap::new(),
+    }
+}
+
+/// A structured report of a panic
+#[derive(Debug, Serialize)]
+pub struct CrashReport {
+    pub version: String,
+    pub features: Vec<&'static str>,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+    pub thread: Option<String>,
+    pub message: String,
+    pub location: Option<String>,
+    pub backtrace: String,
+    /// The most recent log records, oldest first
+    pub log: Vec<serde_json::Value>,
+    /// The last known state of every subsystem
+    pub subsystems: BTreeMap<&'static str, String>,
+}
+
+impl CrashReport {
+    /// Creates a report of a panic with the given message and location,
+    /// capturing the backtrace of the current thread
+    pub fn new(message: String, location: Option<String>, log: &LogTail) -> Self {
+        Self {
+            version: settings::version().to_string(),
+            features: settings::features(),
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            thread: thread::current().name().map(str::to_string),
+            message,
+            location,
+            backtrace: Backtrace::force_capture().to_string(),
+            log: log.records(),
+            subsystems: subsystem_states(),
+        }
+    }
+
+    /// Writes this report to the given folder and removes the oldest reports
+    /// beyond the maximum number of kept reports
+    pub fn save(&self, dir: &Path) -> Result {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(format!(
+            "{CRASH_REPORT_PREFIX}{}{CRASH_REPORT_SUFFIX}",
+            self.timestamp
+        ));
+        fs::write(path, serde_json::to_vec(self)?)?;
+        let reports = pending(dir)?;
+        for report in reports
+            .iter()
+            .take(reports.len().saturating_sub(CRASH_REPORTS_MAX))
+        {
+            fs::remove_file(report)?;
+        }
+        Ok(())
+    }
+}
+
+/// Installs a panic hook that writes a crash report to the given folder
+/// before running the default hook. The release profile aborts on panic, so
+/// the report is written before the process aborts. Aborts that are not
+/// caused by a panic are not reported.
+pub fn install(dir: PathBuf, log: LogTail) {
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        let payload = info.payload();
+        let message = if let Some(message) = payload.downcast_ref::<&str>() {
+            message.to_string()
+        } else if let Some(message) = payload.downcast_ref::<String>() {
+            message.clone()
+        } else {
+            "unknown panic".to_string()
+        };
+        let location = info.location().map(|location| location.to_string());
+        let report = CrashReport::new(message, location, &log);
+        if let Err(err) = report.save(&dir) {
+            eprintln!("failed to write crash report: {err:?}");
+        }
+        default_hook(info);
+    }));
+}
+
+/// Returns the paths of the crash reports in the given folder, oldest first
+pub fn pending(dir: &Path) -> Result<Vec<PathBuf>> {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reports = vec![];
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if name.starts_with(CRASH_REPORT_PREFIX) && name.ends_with(CRASH_REPORT_SUFFIX) {
+            reports.push(dir.join(name));
+        }
+    }
+    // Report names hold fixed width millisecond timestamps for the
+    // foreseeable future, so they sort by age
+    reports.sort();
+    Ok(reports)
+}
+
+/// Posts the pending crash reports in the given folder to the given url,
+/// removing every report that was uploaded. Stops at the first failed
+/// upload so the remaining reports are tried again on the next start.
+pub async fn upload_pending(dir: &Path, url: &str, logger: &Logger) -> Result {
+    for report in pending(dir)? {
+        let name = report.to_string_lossy().into_owned();
+        let data = format!("@{name}");
+        let args = [
+            "-sS",
+            "-X",
+            "POST",
+            "-H",
+            "Content-Type: application/json",
+            "--data-binary",
+            data.as_str(),
+        ];
+        match curl::get(url, args, |_| Ok(())).await {
+            Ok(()) => {
+                info!(logger, "uploaded crash report"; "report" => &name);
+                fs::remove_file(&report)?;
+            }
+            Err(err) => {
+                warn!(logger, "crash report upload failed: {err:?}"; "report" => &name);
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn save() {
+        let dir = std::env::temp_dir().join(format!("crash_report_test_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let subsystems = BTreeMap::from([("dispatcher", "connected to validator".to_string())]);
+        for timestamp in 0..CRASH_REPORTS_MAX as u64 + 2 {
+            CrashReport {
+                version: settings::version().to_string(),
+                features: vec![],
+                timestamp: 1_000_000_000_000 + timestamp,
+                thread: None,
+                message: "test".to_string(),
+                location: None,
+                backtrace: String::new(),
+                log: vec![],
+                subsystems: subsystems.clone(),
+            }
+            .save(&dir)
+            .expect("saved report");
+        }
+        let reports = pending(&dir).expect("pending reports");
+        assert_eq!(CRASH_REPORTS_MAX, reports.len());
+        let report: serde_json::Value =
+            serde_json::from_slice(&fs::read(&reports[0]).expect("report")).expect("json");
+        assert_eq!(1_000_000_000_002u64, report["timestamp"]);
+        assert_eq!("connected to validator", report["subsystems"]["dispatcher"]);
+        fs::remove_dir_all(&dir).expect("remove crash dir");
+    }
+}
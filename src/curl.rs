@@ -17,7 +17,13 @@ where
         .arg(&url)
         .output()
         .map(move |result| match result {
-            Ok(output) => f(&output.stdout),
+            // With -f http errors also make curl exit with a failure status
+            Ok(output) if output.status.success() => f(&output.stdout),
+            Ok(output) => Err(Error::custom(format!(
+                "curl failed with {}: {}",
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
             Err(err) => Err(Error::from(err)),
         })
         .boxed()
@@ -25,6 +25,8 @@ pub enum Error {
     Time(#[from] std::time::SystemTimeError),
     #[error("region error")]
     Region(#[from] RegionError),
+    #[error("downlink error: {0}")]
+    Downlink(#[from] DownlinkError),
 }
 
 #[derive(Error, Debug)]
@@ -55,6 +57,8 @@ pub enum DecodeError {
     Semtech(#[from] semtech_udp::data_rate::ParseError),
     #[error("packet crc")]
     InvalidCrc,
+    #[error("invalid routing filter")]
+    InvalidFilter,
     #[error("unexpected transaction in envelope")]
     InvalidEnvelope,
 }
@@ -71,18 +75,51 @@ pub enum ServiceError {
     Channel,
     #[error("no service")]
     NoService,
+    #[error("resolving {host} failed: {reason}")]
+    Resolve { host: String, reason: String },
+    #[error("circuit open, retry in {retry_secs}s")]
+    CircuitOpen { retry_secs: u64 },
+    #[error("{0} timed out")]
+    Timeout(String),
     #[error("block age {block_age}s > {max_age}s")]
     Check { block_age: u64, max_age: u64 },
+    #[error("response not signed by {signer}")]
+    Unverified { signer: String },
+    #[error("validator version not accepted")]
+    VersionRejected,
+    #[error("validator error: {0}")]
+    Validator(#[from] ValidatorError),
     #[error("Unable to connect to local server. Check that `helium_gateway` is running.")]
     LocalClientConnect(helium_proto::services::Error),
 }
 
+/// Typed form of the error string in a validator `GatewayErrorRespV1`
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum ValidatorError {
+    #[error("not found")]
+    NotFound,
+    #[error("too old")]
+    TooOld,
+    #[error("invalid signature")]
+    InvalidSignature,
+    #[error("{0}")]
+    Other(String),
+}
+
 #[derive(Debug, Error)]
 pub enum RegionError {
     #[error("no region params found or active")]
     NoRegionParams,
 }
 
+#[derive(Debug, Error)]
+pub enum DownlinkError {
+    #[error("rx window {delay_ms}ms after uplink is not achievable")]
+    InvalidWindow { delay_ms: u64 },
+    #[error("rx windows passed {elapsed_ms}ms after uplink")]
+    TooLate { elapsed_ms: u64 },
+}
+
 macro_rules! from_err {
     ($to_type:ty, $from_type:ty) => {
         impl From<$from_type> for Error {
@@ -125,6 +162,10 @@ impl DecodeError {
         Error::Decode(DecodeError::InvalidCrc)
     }
 
+    pub fn invalid_filter() -> Error {
+        Error::Decode(DecodeError::InvalidFilter)
+    }
+
     pub fn prost_decode(msg: &'static str) -> Error {
         Error::Decode(prost::DecodeError::new(msg).into())
     }
@@ -134,12 +175,44 @@ impl DecodeError {
     }
 }
 
+impl ValidatorError {
+    pub fn from_error_resp(error: &[u8]) -> Self {
+        match error {
+            b"not_found" => Self::NotFound,
+            b"too_old" => Self::TooOld,
+            b"invalid_signature" => Self::InvalidSignature,
+            other => Self::Other(String::from_utf8_lossy(other).into_owned()),
+        }
+    }
+
+    /// Whether the request that caused this error is worth retrying. Known
+    /// errors describe the request itself and will fail the same way again,
+    /// so they should be dropped.
+    pub fn is_retryable(&self) -> bool {
+        matches!(self, Self::Other(_))
+    }
+}
+
 impl RegionError {
     pub fn no_region_params() -> Error {
         Error::Region(RegionError::NoRegionParams)
     }
 }
 
+impl DownlinkError {
+    pub fn invalid_window(delay: std::time::Duration) -> Error {
+        Error::Downlink(DownlinkError::InvalidWindow {
+            delay_ms: delay.as_millis() as u64,
+        })
+    }
+
+    pub fn too_late(elapsed: std::time::Duration) -> Error {
+        Error::Downlink(DownlinkError::TooLate {
+            elapsed_ms: elapsed.as_millis() as u64,
+        })
+    }
+}
+
 impl Error {
     /// Use as for custom or rare errors that don't quite deserve their own
     /// error
@@ -159,7 +232,104 @@ impl Error {
         Error::Service(ServiceError::LocalClientConnect(e))
     }
 
+    pub fn resolve<T: ToString>(host: &str, reason: T) -> Error {
+        Error::Service(ServiceError::Resolve {
+            host: host.to_string(),
+            reason: reason.to_string(),
+        })
+    }
+
+    pub fn circuit_open(retry_in: std::time::Duration) -> Error {
+        Error::Service(ServiceError::CircuitOpen {
+            retry_secs: retry_in.as_secs(),
+        })
+    }
+
+    pub fn timeout<T: ToString>(operation: T) -> Error {
+        Error::Service(ServiceError::Timeout(operation.to_string()))
+    }
+
     pub fn gateway_service_check(block_age: u64, max_age: u64) -> Error {
         Error::Service(ServiceError::Check { block_age, max_age })
     }
+
+    pub fn unverified(signer: &helium_crypto::PublicKey) -> Error {
+        Error::Service(ServiceError::Unverified {
+            signer: signer.to_string(),
+        })
+    }
+
+    pub fn version_rejected() -> Error {
+        Error::Service(ServiceError::VersionRejected)
+    }
+
+    pub fn validator_error(error: &[u8]) -> Error {
+        Error::Service(ServiceError::Validator(ValidatorError::from_error_resp(
+            error,
+        )))
+    }
+
+    /// Whether this error is a failure to verify a signed message, for
+    /// example a validator response signed with an unexpected key. Other
+    /// crypto errors, such as a failure to sign with the gateway key, are
+    /// not.
+    pub fn is_verification(&self) -> bool {
+        matches!(self, Error::Service(ServiceError::Unverified { .. }))
+    }
+
+    /// Whether this error is a transport failure or a timeout, meaning the
+    /// service could not be reached rather than that it answered with an
+    /// error
+    pub fn is_transport(&self) -> bool {
+        match self {
+            Error::Service(ServiceError::Service(_)) | Error::Service(ServiceError::Timeout(_)) => {
+                true
+            }
+            Error::Service(ServiceError::Rpc(status)) => matches!(
+                status.code(),
+                tonic::Code::Unavailable | tonic::Code::DeadlineExceeded | tonic::Code::Cancelled
+            ),
+            Error::IO(err) => err.kind() == std::io::ErrorKind::TimedOut,
+            _ => false,
+        }
+    }
+
+    /// Whether this error is a failure to resolve the host of a service
+    pub fn is_resolve(&self) -> bool {
+        matches!(self, Error::Service(ServiceError::Resolve { .. }))
+    }
+
+    /// Whether the operation that failed with this error is worth retrying.
+    /// Only validator errors are known not to be.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Error::Service(ServiceError::Validator(err)) => err.is_retryable(),
+            _ => true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn validator_error() {
+        assert_eq!(
+            ValidatorError::NotFound,
+            ValidatorError::from_error_resp(b"not_found")
+        );
+        assert_eq!(
+            ValidatorError::TooOld,
+            ValidatorError::from_error_resp(b"too_old")
+        );
+        assert_eq!(
+            ValidatorError::InvalidSignature,
+            ValidatorError::from_error_resp(b"invalid_signature")
+        );
+        let other = ValidatorError::from_error_resp(b"queued_poc");
+        assert_eq!(ValidatorError::Other("queued_poc".to_string()), other);
+        assert!(other.is_retryable());
+        assert!(!Error::validator_error(b"too_old").is_retryable());
+    }
 }
@@ -1,72 +1,172 @@
-use crate::{router::dispatcher, Error, Packet, RegionParams, Result, Settings};
-use futures::TryFutureExt;
+use crate::{
+    clock::{self, GpsClock},
+    crash,
+    error::DecodeError,
+    region::SUBBAND_COUNT,
+    router::dispatcher,
+    service::latency::LatencyWindow,
+    Error, Packet, Region, RegionParams, Result, Settings,
+};
+use futures::{stream::FuturesUnordered, StreamExt, TryFutureExt};
 use semtech_udp::{
+    push_data::{self, CRC},
     server_runtime::{Error as SemtechError, Event, UdpRuntime},
     tx_ack, MacAddress,
 };
+use serde_json::json;
 use slog::{debug, info, o, warn, Logger};
 use std::{
     convert::TryFrom,
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::{Arc, Mutex},
     time::{Duration, Instant},
 };
-use tokio::sync::mpsc;
+use tokio::{net::UdpSocket, sync::mpsc, task::JoinHandle};
 
 pub const DOWNLINK_TIMEOUT_SECS: u64 = 5;
 pub const UPLINK_TIMEOUT_SECS: u64 = 6;
 
+/// Upper bound on the downlink path latency used to schedule downlinks, so a
+/// few stalled downlinks do not push every downlink out of its rx1 window
+pub const DOWNLINK_PATH_LATENCY_MAX: Duration = Duration::from_millis(500);
+
 #[derive(Debug)]
 pub enum Message {
-    Downlink(Packet),
-    RegionParamsChanged(RegionParams),
+    Downlink { packet: Packet, received: Instant },
 }
 
 #[derive(Clone, Debug)]
-pub struct MessageSender(mpsc::Sender<Message>);
+pub struct MessageSender(mpsc::Sender<Message>, DownlinkLatency);
 pub type MessageReceiver = mpsc::Receiver<Message>;
 
 pub fn message_channel(size: usize) -> (MessageSender, MessageReceiver) {
     let (tx, rx) = mpsc::channel(size);
-    (MessageSender(tx), rx)
+    (MessageSender(tx, DownlinkLatency::default()), rx)
 }
 
 impl MessageSender {
-    pub async fn downlink(&self, packet: Packet) -> Result {
+    /// Queues a downlink received from a router at the given time
+    pub async fn downlink(&self, packet: Packet, received: Instant) -> Result {
         self.0
-            .send(Message::Downlink(packet))
+            .send(Message::Downlink { packet, received })
             .map_err(|_| Error::channel())
             .await
     }
 
-    pub async fn region_params_changed(&self, region_params: RegionParams) {
-        let _ = self
-            .0
-            .send(Message::RegionParamsChanged(region_params))
-            .await;
+    /// The downlink path latency measured by the gateway receiving from this
+    /// channel
+    pub fn downlink_latency(&self) -> DownlinkLatency {
+        self.1.clone()
+    }
+}
+
+/// Tracks the time from receiving a downlink from a router until the
+/// concentrator acknowledges it for transmission. Clones share the same
+/// samples.
+#[derive(Debug, Default, Clone)]
+pub struct DownlinkLatency(Arc<Mutex<LatencyWindow>>);
+
+impl DownlinkLatency {
+    pub fn record(&self, latency: Duration) {
+        self.0
+            .lock()
+            .expect("downlink latency lock")
+            .record(latency)
+    }
+
+    /// The p95 path latency, capped at `DOWNLINK_PATH_LATENCY_MAX`. Zero until
+    /// a downlink has been acknowledged.
+    pub fn typical(&self) -> Duration {
+        self.0
+            .lock()
+            .expect("downlink latency lock")
+            .p95()
+            .unwrap_or_default()
+            .min(DOWNLINK_PATH_LATENCY_MAX)
+    }
+}
+
+/// Returns the unspecified address with an ephemeral port in the address
+/// family of the given target, to bind a socket that sends to the target
+pub fn unspecified_for(target: &SocketAddr) -> SocketAddr {
+    match target {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
     }
 }
 
+/// Returns a socket connected to the given proprietary frame sink
+async fn connect_sink(sink: &str) -> Result<UdpSocket> {
+    let addr = tokio::net::lookup_host(sink)
+        .await?
+        .next()
+        .ok_or_else(|| Error::custom(format!("no address for proprietary sink {sink}")))?;
+    let socket = UdpSocket::bind(unspecified_for(&addr)).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
 pub struct Gateway {
     uplinks: dispatcher::MessageSender,
     messages: MessageReceiver,
+    events: dispatcher::Subscription,
     downlink_mac: MacAddress,
     udp_runtime: UdpRuntime,
     listen_address: String,
     region_params: Option<RegionParams>,
+    // Configured region, used until region params are received
+    region: Region,
+    subband: Option<u8>,
+    // Region params received while downlinks were in flight. These are applied
+    // once all in flight downlinks complete, or before the next downlink
+    // starts, to avoid changing transmit behavior in the middle of a downlink
+    // exchange.
+    pending_region_params: Option<(RegionParams, u64)>,
+    in_flight: FuturesUnordered<JoinHandle<()>>,
+    // Number of received frames dropped for failing lorawan validation
+    malformed_frames: u64,
+    // Number of receptions reported with a failed payload crc
+    crc_errors: u64,
+    proprietary_sink: Option<UdpSocket>,
+    downlink_latency: DownlinkLatency,
+    // Disciplined by the GPS time of received packets
+    clock: Arc<GpsClock>,
 }
 
 impl Gateway {
     pub async fn new(
         uplinks: dispatcher::MessageSender,
         messages: MessageReceiver,
+        downlink_latency: DownlinkLatency,
+        events: dispatcher::Subscription,
+        clock: Arc<GpsClock>,
         settings: &Settings,
     ) -> Result<Self> {
+        if let Some(subband) = settings.subband {
+            if !(1..=SUBBAND_COUNT).contains(&subband) {
+                return Err(Error::custom(format!("invalid subband {subband}")));
+            }
+        }
         let gateway = Gateway {
             uplinks,
+            events,
             downlink_mac: Default::default(),
             messages,
             listen_address: settings.listen.clone(),
             udp_runtime: UdpRuntime::new(&settings.listen).await?,
             region_params: None,
+            region: settings.region,
+            subband: settings.subband,
+            pending_region_params: None,
+            in_flight: FuturesUnordered::new(),
+            malformed_frames: 0,
+            crc_errors: 0,
+            proprietary_sink: match &settings.proprietary_sink {
+                Some(sink) => Some(connect_sink(sink).await?),
+                None => None,
+            },
+            downlink_latency,
+            clock,
         };
         Ok(gateway)
     }
@@ -74,6 +174,7 @@ impl Gateway {
     pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
         let logger = logger.new(o!("module" => "gateway"));
         info!(logger, "starting"; "listen" => &self.listen_address);
+        crash::set_subsystem_state("gateway", "waiting for packet forwarder");
         loop {
             tokio::select! {
                 _ = shutdown.clone() => {
@@ -82,6 +183,9 @@ impl Gateway {
                 },
                 event = self.udp_runtime.recv() =>
                     self.handle_udp_event(&logger, event).await?,
+                Some(_) = self.in_flight.next(), if !self.in_flight.is_empty() =>
+                    self.handle_downlink_done(&logger),
+                Some(event) = self.events.recv() => self.handle_event(&logger, event),
                 message = self.messages.recv() => match message {
                     Some(message) => self.handle_message(&logger, message).await,
                     None => {
@@ -94,6 +198,9 @@ impl Gateway {
     }
 
     async fn handle_udp_event(&mut self, logger: &Logger, event: Event) -> Result {
+        if let Event::PacketReceived(rxpk, _) = &event {
+            self.discipline_clock(rxpk);
+        }
         match event {
             Event::UnableToParseUdpFrame(e, buf) => {
                 warn!(
@@ -103,19 +210,39 @@ impl Gateway {
             }
             Event::NewClient((mac, addr)) => {
                 info!(logger, "new packet forwarder client: {mac}, {addr}");
+                crash::set_subsystem_state("gateway", format!("packet forwarder {mac} at {addr}"));
                 self.downlink_mac = mac;
             }
             Event::UpdateClient((mac, addr)) => {
-                info!(logger, "mac existed, but IP updated: {mac}, {addr}")
+                info!(logger, "mac existed, but IP updated: {mac}, {addr}");
+                crash::set_subsystem_state("gateway", format!("packet forwarder {mac} at {addr}"));
             }
             Event::ClientDisconnected((mac, addr)) => {
-                info!(logger, "disconnected packet forwarder: {mac}, {addr}")
+                info!(logger, "disconnected packet forwarder: {mac}, {addr}");
+                crash::set_subsystem_state("gateway", "waiting for packet forwarder");
+            }
+            Event::PacketReceived(rxpk, _gateway_mac)
+                if rxpk.get_crc_status() == &CRC::OK
+                    && Packet::is_proprietary_payload(rxpk.get_data()) =>
+            {
+                self.handle_proprietary(logger, rxpk).await
+            }
+            Event::PacketReceived(rxpk, _gateway_mac) if rxpk.get_crc_status() != &CRC::OK => {
+                self.handle_crc_error(logger, rxpk)
             }
             Event::PacketReceived(rxpk, _gateway_mac) => match Packet::try_from(rxpk) {
                 Ok(packet) if packet.is_longfi() => {
                     info!(logger, "ignoring longfi packet");
                 }
+                Ok(packet) if !self.in_subband(&packet) => {
+                    debug!(logger, "ignoring uplink outside subband {}", packet);
+                }
                 Ok(packet) => self.handle_uplink(logger, packet, Instant::now()).await,
+                Err(Error::Decode(DecodeError::LoraWan(err))) => {
+                    self.malformed_frames += 1;
+                    info!(logger, "dropping malformed frame: {err}";
+                        "malformed_frames" => self.malformed_frames);
+                }
                 Err(err) => {
                     warn!(logger, "ignoring push_data: {err:?}");
                 }
@@ -130,6 +257,33 @@ impl Gateway {
         Ok(())
     }
 
+    /// Disciplines the clock with the GPS time of the given packet, if the
+    /// concentrator reported one
+    fn discipline_clock(&self, rxpk: &push_data::RxPk) {
+        let tmms = serde_json::to_value(rxpk)
+            .ok()
+            .and_then(|rxpk| rxpk["tmms"].as_u64());
+        if let Some(tmms) = tmms {
+            self.clock.discipline(clock::gps_time(tmms));
+        }
+    }
+
+    /// Whether the uplink was received on a channel of the configured sub-band.
+    /// All uplinks are accepted when no sub-band is configured or the region
+    /// has no sub-bands.
+    fn in_subband(&self, packet: &Packet) -> bool {
+        let region = self
+            .region_params
+            .as_ref()
+            .map_or(self.region, |params| params.region);
+        match self.subband {
+            Some(subband) if region.has_subbands() => {
+                region.uplink_subband(packet.frequency as f64) == Some(subband)
+            }
+            _ => true,
+        }
+    }
+
     async fn handle_uplink(&mut self, logger: &Logger, packet: Packet, received: Instant) {
         info!(logger, "uplink {} from {}", packet, self.downlink_mac);
         match self.uplinks.uplink(packet, received).await {
@@ -138,18 +292,99 @@ impl Gateway {
         }
     }
 
+    /// Counts and logs receptions that failed the payload crc check. These are
+    /// not forwarded but their signal metadata helps diagnose antenna and
+    /// interference problems.
+    fn handle_crc_error(&mut self, logger: &Logger, rxpk: push_data::RxPk) {
+        self.crc_errors += 1;
+        let rssi = rxpk
+            .get_signal_rssi()
+            .unwrap_or_else(|| rxpk.get_channel_rssi());
+        debug!(logger, "ignoring crc error";
+            "crc_status" => format!("{:?}", rxpk.get_crc_status()),
+            "freq" => rxpk.get_frequency(),
+            "datarate" => rxpk.get_datarate().to_string(),
+            "rssi" => rssi,
+            "snr" => rxpk.get_snr(),
+            "crc_errors" => self.crc_errors);
+    }
+
+    async fn handle_proprietary(&self, logger: &Logger, rxpk: push_data::RxPk) {
+        let sink = match &self.proprietary_sink {
+            Some(sink) => sink,
+            None => {
+                debug!(logger, "ignoring proprietary frame");
+                return;
+            }
+        };
+        let rssi = rxpk
+            .get_signal_rssi()
+            .unwrap_or_else(|| rxpk.get_channel_rssi());
+        let frame = json!({
+            "tmst": rxpk.get_timestamp(),
+            "freq": rxpk.get_frequency(),
+            "datr": rxpk.get_datarate().to_string(),
+            "rssi": rssi,
+            "lsnr": rxpk.get_snr(),
+            "size": rxpk.get_data().len(),
+            "data": base64::encode(rxpk.get_data()),
+        });
+        match sink.send(frame.to_string().as_bytes()).await {
+            Ok(_) => debug!(logger, "forwarded proprietary frame"),
+            Err(err) => warn!(logger, "ignoring proprietary sink error: {err:?}"),
+        }
+    }
+
     async fn handle_message(&mut self, logger: &Logger, message: Message) {
         match message {
-            Message::Downlink(packet) => self.handle_downlink(logger, packet).await,
-            Message::RegionParamsChanged(region_params) => {
-                self.region_params = Some(region_params);
-                info!(logger, "updated region";
-                    "region" => RegionParams::to_string(&self.region_params));
+            Message::Downlink { packet, received } => {
+                self.handle_downlink(logger, packet, received).await
+            }
+        }
+    }
+
+    fn handle_event(&mut self, logger: &Logger, event: dispatcher::Event) {
+        match event {
+            dispatcher::Event::RegionParamsChanged(region_params, height) => {
+                if self.in_flight.is_empty() {
+                    self.apply_region_params(logger, region_params, height);
+                } else {
+                    info!(logger, "deferring region update until downlinks complete";
+                        "height" => height,
+                        "in_flight" => self.in_flight.len());
+                    self.pending_region_params = Some((region_params, height));
+                }
             }
+            dispatcher::Event::RoutingChanged(_) | dispatcher::Event::GatewayConnected(..) => (),
         }
     }
 
-    async fn handle_downlink(&mut self, logger: &Logger, downlink: Packet) {
+    fn handle_downlink_done(&mut self, logger: &Logger) {
+        if !self.in_flight.is_empty() {
+            return;
+        }
+        if let Some((region_params, height)) = self.pending_region_params.take() {
+            self.apply_region_params(logger, region_params, height);
+        }
+    }
+
+    fn apply_region_params(&mut self, logger: &Logger, region_params: RegionParams, height: u64) {
+        let before = self.region_params.replace(region_params);
+        info!(logger, "updated region";
+            "height" => height,
+            "region" => RegionParams::to_string(&self.region_params),
+            "tx_power" => self.region_params.as_ref().and_then(RegionParams::tx_power),
+            "previous_region" => RegionParams::to_string(&before),
+            "previous_tx_power" => before.as_ref().and_then(RegionParams::tx_power));
+    }
+
+    async fn handle_downlink(&mut self, logger: &Logger, downlink: Packet, received: Instant) {
+        // Downlinks in flight keep the tx power they were started with, so
+        // deferred region params are applied before a new downlink starts
+        // rather than waiting for a gap between downlinks that may never come
+        if let Some((region_params, height)) = self.pending_region_params.take() {
+            self.apply_region_params(logger, region_params, height);
+        }
         let region_params = if let Some(region_params) = &self.region_params {
             region_params
         } else {
@@ -169,7 +404,8 @@ impl Gateway {
             self.udp_runtime.prepare_empty_downlink(self.downlink_mac),
         );
         let logger = logger.clone();
-        tokio::spawn(async move {
+        let downlink_latency = self.downlink_latency.clone();
+        let downlink = tokio::spawn(async move {
             match downlink.to_pull_resp(false, tx_power).unwrap() {
                 None => (),
                 Some(txpk) => {
@@ -195,21 +431,25 @@ impl Gateway {
                                     downlink_rx2.get_destination_mac()
                                 );
                                 downlink_rx2.set_packet(txpk);
-                                if let Err(err) = downlink_rx2
+                                match downlink_rx2
                                     .dispatch(Some(Duration::from_secs(DOWNLINK_TIMEOUT_SECS)))
                                     .await
                                 {
-                                    warn!(logger, "ignoring rx2 downlink error: {:?}", err);
+                                    Ok(()) => downlink_latency.record(received.elapsed()),
+                                    Err(err) => {
+                                        warn!(logger, "ignoring rx2 downlink error: {:?}", err)
+                                    }
                                 }
                             }
                         }
                         Err(err) => {
                             warn!(logger, "ignoring rx1 downlink error: {:?}", err);
                         }
-                        Ok(()) => (),
+                        Ok(()) => downlink_latency.record(received.elapsed()),
                     }
                 }
             }
         });
+        self.in_flight.push(downlink);
     }
 }
@@ -1,6 +1,6 @@
 use crate::{PublicKey, Result};
 use http::Uri;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
     fmt,
     hash::{Hash, Hasher},
@@ -9,7 +9,7 @@ use std::{
 };
 
 /// A URI that has an associated public key
-#[derive(Clone, Deserialize, Eq)]
+#[derive(Clone, Deserialize, Serialize, Eq)]
 pub struct KeyedUri {
     #[serde(with = "http_serde::uri")]
     pub uri: Uri,
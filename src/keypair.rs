@@ -6,29 +6,96 @@ use helium_crypto::tee;
 #[cfg(feature = "tpm")]
 use helium_crypto::tpm;
 
-use helium_crypto::{KeyTag, KeyType, Network};
+use helium_crypto::{KeyTag, KeyType, Network, Sign};
 use http::Uri;
 use rand::rngs::OsRng;
-use serde::{de, Deserializer};
+use serde::{de, Deserializer, Serialize};
 #[cfg(feature = "ecc608")]
 use std::path::Path;
-use std::{collections::HashMap, convert::TryFrom, fmt, fs, io, path, str::FromStr};
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    fmt, fs,
+    io::{self, Write},
+    os::unix::fs::{OpenOptionsExt, PermissionsExt},
+    path,
+    str::FromStr,
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
 
+/// Number of most recent signing operations kept in the signing audit
+pub const SIGNING_AUDIT_SIZE: usize = 100;
+
+/// The gateway keypair. Signing is only possible through `sign_audited`,
+/// which records every signature in a bounded signing audit.
 #[derive(Debug)]
-pub struct Keypair(helium_crypto::Keypair);
+pub struct Keypair(helium_crypto::Keypair, Mutex<VecDeque<SigningRecord>>);
 pub type PublicKey = helium_crypto::PublicKey;
 
-pub fn load_from_file(path: &str) -> error::Result<Keypair> {
-    let data = fs::read(path)?;
-    Ok(helium_crypto::Keypair::try_from(&data[..])?.into())
+/// A fixed validator public key for tests
+#[cfg(test)]
+pub(crate) fn test_pubkey() -> PublicKey {
+    use std::str::FromStr;
+    PublicKey::from_str("11w77YQLhgUt8HUJrMtntGGr97RyXmot1ofs5Ct2ELTmbFoYsQa").expect("pubkey")
 }
 
-pub fn save_to_file(keypair: &Keypair, path: &str) -> io::Result<()> {
-    if let Some(parent) = path::PathBuf::from(path).parent() {
-        fs::create_dir_all(parent)?;
-    };
-    fs::write(path, &keypair.0.to_vec())?;
-    Ok(())
+/// A signing operation made with the gateway keypair
+#[derive(Debug, Clone, Serialize)]
+pub struct SigningRecord {
+    /// Type of the signed message
+    pub message: &'static str,
+    /// The subsystem that requested the signature
+    pub subsystem: &'static str,
+    /// Unix timestamp in milliseconds
+    pub timestamp: u64,
+}
+
+impl Keypair {
+    /// Signs the given data, recording the message type and requesting
+    /// subsystem in the signing audit
+    pub fn sign_audited(
+        &self,
+        data: &[u8],
+        message: &'static str,
+        subsystem: &'static str,
+    ) -> Result<Vec<u8>> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        {
+            let mut audit = self.1.lock().expect("signing audit lock");
+            if audit.len() >= SIGNING_AUDIT_SIZE {
+                audit.pop_front();
+            }
+            audit.push_back(SigningRecord {
+                message,
+                subsystem,
+                timestamp,
+            });
+        }
+        Ok(self.0.sign(data)?)
+    }
+
+    pub fn public_key(&self) -> &PublicKey {
+        self.0.public_key()
+    }
+
+    /// Returns the shared secret of this keypair and the given public key.
+    /// This is key agreement rather than signing, so it is not audited.
+    pub fn ecdh(&self, public_key: &PublicKey) -> Result<Vec<u8>> {
+        Ok(self.0.ecdh(public_key)?.as_bytes().to_vec())
+    }
+
+    /// Returns the most recent signing operations, oldest first
+    pub fn signing_audit(&self) -> Vec<SigningRecord> {
+        self.1
+            .lock()
+            .expect("signing audit lock")
+            .iter()
+            .cloned()
+            .collect()
+    }
 }
 
 macro_rules! uri_error {
@@ -40,9 +107,46 @@ macro_rules! uri_error {
     };
 }
 
+/// Loads a keypair from the given file. A world readable key file is
+/// restricted to its owner unless `insecure` is set.
+pub fn load_from_file(path: &str, insecure: bool) -> error::Result<Keypair> {
+    let mode = fs::metadata(path)?.permissions().mode();
+    if mode & 0o004 != 0 && !insecure {
+        // Keys are loaded with the settings, before logging is set up
+        eprintln!(
+            "restricting world readable key file \"{path}\" (mode {:o}) to its owner",
+            mode & 0o777
+        );
+        if let Err(err) = fs::set_permissions(path, fs::Permissions::from_mode(0o600)) {
+            eprintln!("unable to restrict key file \"{path}\": {err}");
+        }
+    }
+    let data = fs::read(path)?;
+    Ok(helium_crypto::Keypair::try_from(&data[..])?.into())
+}
+
+/// Saves a keypair to the given file, readable only by the owner. The key is
+/// written to a temporary file first and then renamed to avoid leaving a
+/// partially written key file behind.
+pub fn save_to_file(keypair: &Keypair, path: &str) -> io::Result<()> {
+    if let Some(parent) = path::PathBuf::from(path).parent() {
+        fs::create_dir_all(parent)?;
+    };
+    let tmp_path = format!("{path}.tmp");
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&tmp_path)?;
+    file.write_all(&keypair.0.to_vec())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
 impl From<helium_crypto::Keypair> for Keypair {
     fn from(v: helium_crypto::Keypair) -> Self {
-        Self(v)
+        Self(v, Mutex::new(VecDeque::with_capacity(SIGNING_AUDIT_SIZE)))
     }
 }
 
@@ -53,29 +157,32 @@ impl FromStr for Keypair {
             .parse()
             .map_err(|err| uri_error!("invalid keypair url \"{str}\": {err:?}"))?;
         match url.scheme_str() {
-            Some("file") | None => match load_from_file(url.path()) {
-                Ok(k) => Ok(k),
-                Err(Error::IO(io_error)) if io_error.kind() == std::io::ErrorKind::NotFound => {
-                    let args = KeypairArgs::from_uri(&url)?;
-                    let network = args.get::<Network>("network", Network::MainNet)?;
-                    let new_key: Keypair = helium_crypto::Keypair::generate(
-                        KeyTag {
-                            network,
-                            key_type: KeyType::Ed25519,
-                        },
-                        &mut OsRng,
-                    )
-                    .into();
-                    save_to_file(&new_key, url.path()).map_err(|err| {
-                        uri_error!("unable to save key file \"{}\": {err:?}", url.path())
-                    })?;
-                    Ok(new_key)
+            Some("file") | None => {
+                let args = KeypairArgs::from_uri(&url)?;
+                let insecure = args.get::<bool>("insecure", false)?;
+                match load_from_file(url.path(), insecure) {
+                    Ok(k) => Ok(k),
+                    Err(Error::IO(io_error)) if io_error.kind() == std::io::ErrorKind::NotFound => {
+                        let network = args.get::<Network>("network", Network::MainNet)?;
+                        let new_key: Keypair = helium_crypto::Keypair::generate(
+                            KeyTag {
+                                network,
+                                key_type: KeyType::Ed25519,
+                            },
+                            &mut OsRng,
+                        )
+                        .into();
+                        save_to_file(&new_key, url.path()).map_err(|err| {
+                            uri_error!("unable to save key file \"{}\": {err:?}", url.path())
+                        })?;
+                        Ok(new_key)
+                    }
+                    Err(err) => Err(uri_error!(
+                        "unable to load key file \"{}\": {err:?}",
+                        url.path()
+                    )),
                 }
-                Err(err) => Err(uri_error!(
-                    "unable to load key file \"{}\": {err:?}",
-                    url.path()
-                )),
-            },
+            }
             #[cfg(feature = "ecc608")]
             Some("ecc") => {
                 let args = KeypairArgs::from_uri(&url).map_err(error::DecodeError::keypair_uri)?;
@@ -130,13 +237,6 @@ impl FromStr for Keypair {
     }
 }
 
-impl std::ops::Deref for Keypair {
-    type Target = helium_crypto::Keypair;
-    fn deref(&self) -> &Self::Target {
-        &self.0
-    }
-}
-
 #[derive(Debug)]
 struct KeypairArgs(HashMap<String, String>);
 
@@ -213,4 +313,64 @@ mod tests {
                 .expect("network")
         );
     }
+
+    #[test]
+    fn signing_audit() {
+        let keypair: Keypair = helium_crypto::Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        )
+        .into();
+        for _ in 0..=SIGNING_AUDIT_SIZE {
+            keypair
+                .sign_audited(b"data", "data", "api")
+                .expect("signature");
+        }
+        keypair
+            .sign_audited(b"txn", "txn", "router")
+            .expect("signature");
+        let audit = keypair.signing_audit();
+        assert_eq!(SIGNING_AUDIT_SIZE, audit.len());
+        assert_eq!("router", audit[SIGNING_AUDIT_SIZE - 1].subsystem);
+    }
+
+    #[test]
+    fn key_file_permissions() {
+        let path = std::env::temp_dir().join("keypair_test.bin");
+        let path = path.to_str().expect("key path");
+        let keypair: Keypair = helium_crypto::Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        )
+        .into();
+        save_to_file(&keypair, path).expect("saved key");
+        let mode = fs::metadata(path)
+            .expect("key metadata")
+            .permissions()
+            .mode();
+        assert_eq!(0o600, mode & 0o777);
+        load_from_file(path, false).expect("loaded key");
+
+        // World readable keys are kept as is when insecure, and restricted
+        // to their owner otherwise
+        let file_mode = |path: &str| {
+            fs::metadata(path)
+                .expect("key metadata")
+                .permissions()
+                .mode()
+                & 0o777
+        };
+        fs::set_permissions(path, fs::Permissions::from_mode(0o644)).expect("permissions");
+        load_from_file(path, true).expect("insecure key");
+        assert_eq!(0o644, file_mode(path));
+        load_from_file(path, false).expect("restricted key");
+        assert_eq!(0o600, file_mode(path));
+        let _ = fs::remove_file(path);
+    }
 }
@@ -1,16 +1,23 @@
+pub mod activation;
+pub mod clock;
 pub mod cmd;
+pub mod crash;
 pub mod curl;
 pub mod error;
 pub mod gateway;
 pub mod keyed_uri;
 pub mod keypair;
+pub mod logger;
 pub mod packet;
 pub mod region;
 pub mod router;
+pub mod sequence;
 pub mod server;
 pub mod service;
 pub mod settings;
+pub mod shutdown;
 pub mod state_channel;
+pub mod storage;
 pub mod sync;
 pub mod updater;
 
@@ -19,7 +26,7 @@ mod traits;
 
 pub use error::{Error, Result};
 pub use keyed_uri::KeyedUri;
-pub use keypair::{Keypair, PublicKey};
+pub use keypair::{Keypair, PublicKey, SigningRecord};
 pub use packet::Packet;
 pub use region::{Region, RegionParams};
 pub use settings::{CacheSettings, Settings};
@@ -0,0 +1,553 @@
+use slog::{
+    BorrowedKV, Drain, Key, Level, Never, OwnedKVList, Record, RecordStatic,
+    SendSyncRefUnwindSafeDrain, SingleKV, KV,
+};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt, fs, io,
+    os::unix::fs::MetadataExt,
+    panic::UnwindSafe,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock, TryLockError},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+/// A drain that rate limits repeated warnings. Warnings are keyed by the
+/// location of the log statement that emitted them. A warning repeated
+/// within the configured interval is dropped, and the number of dropped
+/// repeats is included as a "repeated" value on the next warning from the
+/// same statement that is passed through. Counts of warnings that are not
+/// repeated again are flushed once per interval as a separate warning, on
+/// the next record of any level.
+///
+/// All other levels are passed through unchanged.
+pub struct RateLimit<D> {
+    drain: D,
+    interval: Duration,
+    entries: Mutex<HashMap<(&'static str, u32), RateLimitEntry>>,
+    flushed: Mutex<Instant>,
+}
+
+struct RateLimitEntry {
+    since: Instant,
+    suppressed: u64,
+}
+
+impl<D> RateLimit<D> {
+    pub fn new(drain: D, interval: Duration) -> Self {
+        Self {
+            drain,
+            interval,
+            entries: Mutex::new(HashMap::new()),
+            flushed: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Removes the entries whose interval has passed, at most once per
+    /// interval. Returns the keys and dropped record counts of the removed
+    /// entries that dropped records.
+    fn expired(&self) -> Vec<((&'static str, u32), u64)> {
+        let now = Instant::now();
+        {
+            let mut flushed = self.flushed.lock().expect("rate limit lock");
+            if now.duration_since(*flushed) < self.interval {
+                return vec![];
+            }
+            *flushed = now;
+        }
+        let mut expired = vec![];
+        let mut entries = self.entries.lock().expect("rate limit lock");
+        entries.retain(|key, entry| {
+            if now.duration_since(entry.since) < self.interval {
+                return true;
+            }
+            if entry.suppressed > 0 {
+                expired.push((*key, entry.suppressed));
+            }
+            false
+        });
+        expired
+    }
+
+    /// Returns None if a record with the given key should be dropped, or the
+    /// number of previously dropped records with that key otherwise.
+    fn check(&self, key: (&'static str, u32)) -> Option<u64> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().expect("rate limit lock");
+        match entries.get_mut(&key) {
+            Some(entry) if now.duration_since(entry.since) < self.interval => {
+                entry.suppressed += 1;
+                None
+            }
+            Some(entry) => {
+                let suppressed = entry.suppressed;
+                entry.since = now;
+                entry.suppressed = 0;
+                Some(suppressed)
+            }
+            None => {
+                entries.insert(
+                    key,
+                    RateLimitEntry {
+                        since: now,
+                        suppressed: 0,
+                    },
+                );
+                Some(0)
+            }
+        }
+    }
+}
+
+impl<D: Drain> Drain for RateLimit<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        for ((module, line), repeated) in self.expired() {
+            let location = format!("{module}:{line}");
+            self.drain.log(
+                &slog::record!(
+                    Level::Warning,
+                    "",
+                    &format_args!("suppressed repeated warnings"),
+                    slog::b!("location" => location, "repeated" => repeated)
+                ),
+                values,
+            )?;
+        }
+        if record.level() != Level::Warning {
+            return self.drain.log(record, values).map(|_| ());
+        }
+        match self.check((record.module(), record.line())) {
+            None => Ok(()),
+            Some(0) => self.drain.log(record, values).map(|_| ()),
+            Some(repeated) => {
+                let kv = (record.kv(), SingleKV("repeated", repeated));
+                let record_static = RecordStatic {
+                    location: record.location(),
+                    tag: record.tag(),
+                    level: record.level(),
+                };
+                let record = Record::new(&record_static, record.msg(), BorrowedKV(&kv));
+                self.drain.log(&record, values).map(|_| ())
+            }
+        }
+    }
+}
+
+/// A drain that samples debug and trace records of selected modules. For a
+/// module configured with a rate of N only every Nth record from each log
+/// statement in that module is passed through, with a "sampled" value of N so
+/// readers know the lines are a sample. Modules are matched on the last
+/// segment of their module path, for example "gateway" or "dispatcher".
+///
+/// All other records are passed through unchanged.
+pub struct Sample<D> {
+    drain: D,
+    rates: SampleRates,
+    counts: Mutex<HashMap<(&'static str, u32), u64>>,
+}
+
+/// Sample rates keyed by module name. The rates can be replaced while the
+/// logger is running, and clones share the same rates.
+#[derive(Debug, Clone, Default)]
+pub struct SampleRates(Arc<RwLock<HashMap<String, u64>>>);
+
+impl SampleRates {
+    pub fn new(rates: HashMap<String, u64>) -> Self {
+        Self(Arc::new(RwLock::new(rates)))
+    }
+
+    /// Replaces the sample rates
+    pub fn set(&self, rates: HashMap<String, u64>) {
+        *self.0.write().expect("sample rates lock") = rates;
+    }
+
+    fn get(&self, name: &str) -> Option<u64> {
+        self.0.read().expect("sample rates lock").get(name).copied()
+    }
+}
+
+impl<D> Sample<D> {
+    pub fn new(drain: D, rates: SampleRates) -> Self {
+        Self {
+            drain,
+            rates,
+            counts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn rate(&self, module: &str) -> Option<u64> {
+        let name = module.rsplit("::").next().unwrap_or(module);
+        self.rates.get(name).filter(|rate| *rate > 1)
+    }
+
+    /// Returns whether the next record with the given key should be passed
+    /// through at the given sample rate
+    fn check(&self, key: (&'static str, u32), rate: u64) -> bool {
+        let mut counts = self.counts.lock().expect("sample lock");
+        let count = counts.entry(key).or_insert(0);
+        let pass = *count % rate == 0;
+        *count = count.wrapping_add(1);
+        pass
+    }
+}
+
+impl<D: Drain> Drain for Sample<D> {
+    type Ok = ();
+    type Err = D::Err;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let rate = match record.level() {
+            Level::Debug | Level::Trace => self.rate(record.module()),
+            _ => None,
+        };
+        let rate = match rate {
+            Some(rate) => rate,
+            None => return self.drain.log(record, values).map(|_| ()),
+        };
+        if !self.check((record.module(), record.line()), rate) {
+            return Ok(());
+        }
+        let kv = (record.kv(), SingleKV("sampled", rate));
+        let record_static = RecordStatic {
+            location: record.location(),
+            tag: record.tag(),
+            level: record.level(),
+        };
+        let record = Record::new(&record_static, record.msg(), BorrowedKV(&kv));
+        self.drain.log(&record, values).map(|_| ())
+    }
+}
+
+/// A boxed log sink drain. Sinks are unwind safe so the fanout over them can
+/// be the root drain of a logger.
+pub type SinkDrain = Box<dyn SendSyncRefUnwindSafeDrain<Ok = (), Err = Never> + UnwindSafe>;
+
+/// A drain that passes every record to each of a list of drains, so logs can
+/// be written to several sinks with their own levels at once.
+pub struct Fanout(Vec<SinkDrain>);
+
+impl Fanout {
+    pub fn new(drains: Vec<SinkDrain>) -> Self {
+        Self(drains)
+    }
+}
+
+impl Drain for Fanout {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        for drain in &self.0 {
+            drain.log(record, values)?;
+        }
+        Ok(())
+    }
+}
+
+/// A drain that writes each record as a line of JSON for log collection. A
+/// line holds the unix timestamp, level, module and message of the record
+/// along with its key values.
+pub struct JsonLines<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: io::Write> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: io::Write> Drain for JsonLines<W> {
+    type Ok = ();
+    type Err = io::Error;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        let line = json_record(record, values)?;
+        let mut writer = self.writer.lock().expect("json lines lock");
+        serde_json::to_writer(&mut *writer, &line)?;
+        writer.write_all(b"\n")?;
+        writer.flush()
+    }
+}
+
+/// A log file that is appended to. The file is opened again when the file at
+/// its path was moved or removed, so the log can be rotated by logrotate
+/// without signalling the gateway.
+pub struct LogFile {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl LogFile {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        Ok(Self {
+            path: path.to_path_buf(),
+            file: Self::open_file(path)?,
+        })
+    }
+
+    fn open_file(path: &Path) -> io::Result<fs::File> {
+        fs::OpenOptions::new().create(true).append(true).open(path)
+    }
+
+    /// Whether the open file is still the file at the log path
+    fn is_current(&self) -> bool {
+        match (fs::metadata(&self.path), self.file.metadata()) {
+            (Ok(current), Ok(open)) => current.dev() == open.dev() && current.ino() == open.ino(),
+            _ => false,
+        }
+    }
+}
+
+impl io::Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if !self.is_current() {
+            self.file = Self::open_file(&self.path)?;
+        }
+        self.file.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// A drain that keeps the most recent records in memory in the same JSON form
+/// as `JsonLines`, so they can be included in a crash report. Clones share
+/// the same records.
+#[derive(Clone)]
+pub struct LogTail {
+    size: usize,
+    records: Arc<Mutex<VecDeque<serde_json::Value>>>,
+}
+
+impl LogTail {
+    pub fn new(size: usize) -> Self {
+        Self {
+            size,
+            records: Arc::new(Mutex::new(VecDeque::with_capacity(size))),
+        }
+    }
+
+    /// Returns the kept records, oldest first. Returns no records rather than
+    /// waiting if the records are being updated, since this is called from
+    /// the panic handler which may run while a record is being added.
+    pub fn records(&self) -> Vec<serde_json::Value> {
+        let records = match self.records.try_lock() {
+            Ok(records) => records,
+            Err(TryLockError::Poisoned(err)) => err.into_inner(),
+            Err(TryLockError::WouldBlock) => return vec![],
+        };
+        records.iter().cloned().collect()
+    }
+}
+
+impl Drain for LogTail {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, values: &OwnedKVList) -> Result<Self::Ok, Self::Err> {
+        if let Ok(line) = json_record(record, values) {
+            let mut records = self.records.lock().expect("log tail lock");
+            if records.len() >= self.size {
+                records.pop_front();
+            }
+            records.push_back(line.into());
+        }
+        Ok(())
+    }
+}
+
+/// Returns the unix timestamp, level, module and message of the given record
+/// along with its key values as a JSON object
+fn json_record(
+    record: &Record,
+    values: &OwnedKVList,
+) -> slog::Result<serde_json::Map<String, serde_json::Value>> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs_f64())
+        .unwrap_or_default();
+    let mut line = JsonKV(serde_json::Map::new());
+    line.0.insert("ts".to_string(), timestamp.into());
+    line.0
+        .insert("level".to_string(), record.level().as_str().into());
+    line.0.insert("module".to_string(), record.module().into());
+    line.0
+        .insert("msg".to_string(), record.msg().to_string().into());
+    values.serialize(record, &mut line)?;
+    record.kv().serialize(record, &mut line)?;
+    Ok(line.0)
+}
+
+/// Collects key values as JSON values, keeping numbers and booleans typed
+struct JsonKV(serde_json::Map<String, serde_json::Value>);
+
+impl JsonKV {
+    fn insert(&mut self, key: Key, value: impl Into<serde_json::Value>) -> slog::Result {
+        self.0.insert(key.to_string(), value.into());
+        Ok(())
+    }
+}
+
+impl slog::Serializer for JsonKV {
+    fn emit_arguments(&mut self, key: Key, val: &fmt::Arguments) -> slog::Result {
+        self.insert(key, val.to_string())
+    }
+
+    fn emit_usize(&mut self, key: Key, val: usize) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_u32(&mut self, key: Key, val: u32) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_u64(&mut self, key: Key, val: u64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_i32(&mut self, key: Key, val: i32) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_i64(&mut self, key: Key, val: i64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_f64(&mut self, key: Key, val: f64) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_bool(&mut self, key: Key, val: bool) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_str(&mut self, key: Key, val: &str) -> slog::Result {
+        self.insert(key, val)
+    }
+
+    fn emit_none(&mut self, key: Key) -> slog::Result {
+        self.insert(key, serde_json::Value::Null)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn rate_limit() {
+        let limit = RateLimit::new(slog::Discard, Duration::from_secs(60));
+        assert_eq!(Some(0), limit.check(("test", 1)));
+        assert_eq!(None, limit.check(("test", 1)));
+        assert_eq!(None, limit.check(("test", 1)));
+        assert_eq!(Some(0), limit.check(("test", 2)));
+
+        let no_limit = RateLimit::new(slog::Discard, Duration::ZERO);
+        assert_eq!(Some(0), no_limit.check(("test", 1)));
+        assert_eq!(Some(0), no_limit.check(("test", 1)));
+    }
+
+    #[test]
+    fn rate_limit_flush() {
+        let interval = Duration::from_secs(60);
+        let limit = RateLimit::new(slog::Discard, interval);
+        assert_eq!(Some(0), limit.check(("test", 1)));
+        assert_eq!(None, limit.check(("test", 1)));
+        assert_eq!(Some(0), limit.check(("test", 2)));
+        assert!(limit.expired().is_empty());
+
+        // Once the interval passed the dropped count is flushed without the
+        // warning being repeated, and entries without drops are forgotten
+        for entry in limit.entries.lock().expect("entries").values_mut() {
+            entry.since -= interval;
+        }
+        *limit.flushed.lock().expect("flushed") -= interval;
+        assert_eq!(vec![(("test", 1), 1)], limit.expired());
+        assert!(limit.entries.lock().expect("entries").is_empty());
+        assert_eq!(Some(0), limit.check(("test", 1)));
+    }
+
+    #[test]
+    fn sample() {
+        let rates = HashMap::from([("dispatcher".to_string(), 3), ("gateway".to_string(), 1)]);
+        let rates = SampleRates::new(rates);
+        let sample = Sample::new(slog::Discard, rates.clone());
+        assert_eq!(Some(3), sample.rate("gateway_rs::router::dispatcher"));
+        assert_eq!(None, sample.rate("gateway_rs::gateway"));
+        assert_eq!(None, sample.rate("gateway_rs::router::client"));
+
+        // Rates changed at runtime apply to the running drain
+        rates.set(HashMap::from([("client".to_string(), 5)]));
+        assert_eq!(None, sample.rate("gateway_rs::router::dispatcher"));
+        assert_eq!(Some(5), sample.rate("gateway_rs::router::client"));
+
+        let passed: Vec<bool> = (0..6).map(|_| sample.check(("test", 1), 3)).collect();
+        assert_eq!(vec![true, false, false, true, false, false], passed);
+        assert!(sample.check(("test", 2), 3));
+    }
+
+    #[test]
+    fn json_lines() {
+        let drain = JsonLines::new(Vec::new());
+        let values = slog::o!("module" => "test");
+        let values = OwnedKVList::from(values);
+        drain
+            .log(
+                &slog::record!(
+                    Level::Info,
+                    "",
+                    &format_args!("hello {}", "world"),
+                    slog::b!("count" => 3u64, "ok" => true)
+                ),
+                &values,
+            )
+            .expect("log");
+        let line = drain.writer.into_inner().expect("writer");
+        let line: serde_json::Value = serde_json::from_slice(&line).expect("json");
+        assert_eq!("hello world", line["msg"]);
+        assert_eq!("INFO", line["level"]);
+        assert_eq!(3, line["count"]);
+        assert_eq!(Some(true), line["ok"].as_bool());
+        assert_eq!("test", line["module"]);
+    }
+
+    #[test]
+    fn log_file_reopen() {
+        use std::io::Write;
+        let dir = std::env::temp_dir().join(format!("log_file_test_{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("dir");
+        let path = dir.join("gateway.log");
+        let rotated = dir.join("gateway.log.1");
+        let mut file = LogFile::open(&path).expect("log file");
+        file.write_all(b"first\n").expect("write");
+        fs::rename(&path, &rotated).expect("rotate");
+        file.write_all(b"second\n").expect("write");
+        assert_eq!("first\n", fs::read_to_string(&rotated).expect("rotated"));
+        assert_eq!("second\n", fs::read_to_string(&path).expect("reopened"));
+        fs::remove_dir_all(&dir).expect("cleanup");
+    }
+
+    #[test]
+    fn log_tail() {
+        let tail = LogTail::new(2);
+        let values = OwnedKVList::from(slog::o!());
+        for n in 0..3u64 {
+            tail.log(
+                &slog::record!(Level::Info, "", &format_args!("line {n}"), slog::b!()),
+                &values,
+            )
+            .expect("log");
+        }
+        let records = tail.clone().records();
+        assert_eq!(2, records.len());
+        assert_eq!("line 1", records[0]["msg"]);
+        assert_eq!("line 2", records[1]["msg"]);
+    }
+}
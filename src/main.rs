@@ -1,15 +1,39 @@
 use gateway_rs::{
-    cmd,
-    error::Result,
-    settings::{LogMethod, Settings},
+    activation::{self, ListenFds},
+    cmd::{self, OutputFormat},
+    crash,
+    error::{Error, Result},
+    logger::{Fanout, JsonLines, LogFile, LogTail, RateLimit, Sample, SampleRates, SinkDrain},
+    settings::{self, BundleStatus, LogMethod, LogSettings, LogSinkSettings, Settings},
+};
+use slog::{self, debug, error, info, o, warn, Drain, Logger, Never};
+use std::{
+    env, io,
+    os::unix::process::CommandExt,
+    path::{Path, PathBuf},
+    process,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
 };
-use slog::{self, debug, error, o, Drain, Logger};
-use std::{io, path::PathBuf};
 use structopt::StructOpt;
-use tokio::{io::AsyncReadExt, signal, time::Duration};
+use tokio::{
+    io::AsyncReadExt,
+    signal::{
+        self,
+        unix::{signal, SignalKind},
+    },
+    time::Duration,
+};
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = env!("CARGO_BIN_NAME"), version = env!("CARGO_PKG_VERSION"), about = "Helium Light Gateway")]
+#[structopt(
+    name = env!("CARGO_BIN_NAME"),
+    version = env!("CARGO_PKG_VERSION"),
+    long_version = settings::LONG_VERSION,
+    about = "Helium Light Gateway"
+)]
 pub struct Cli {
     /// Configuration folder to use. default.toml will be loaded first and any
     /// custom settings in settings.toml merged in.
@@ -26,6 +50,11 @@ pub struct Cli {
     #[structopt(long)]
     stdin: bool,
 
+    /// Output format of command results, text or json. The json format
+    /// prints each result as a single line of JSON for scripts
+    #[structopt(long, default_value = "text")]
+    format: OutputFormat,
+
     #[structopt(subcommand)]
     cmd: Cmd,
 }
@@ -37,6 +66,10 @@ pub enum Cmd {
     Update(cmd::update::Cmd),
     Server(cmd::server::Cmd),
     Add(Box<cmd::add::Cmd>),
+    Match(cmd::matches::Cmd),
+    Seeds(cmd::seeds::Cmd),
+    Forwarder(cmd::forwarder::Cmd),
+    Region(cmd::region::Cmd),
 }
 
 /// An empty timestamp function for when timestamp should not be included in
@@ -46,35 +79,91 @@ fn timestamp_none(_io: &mut dyn io::Write) -> io::Result<()> {
     Ok(())
 }
 
-fn mk_logger(settings: &Settings) -> Logger {
-    let async_drain = match settings.log.method {
+/// Creates the logger for the configured sinks. Records at the top level log
+/// level are also kept in the given log tail for crash reports. All sinks
+/// sample debug records at the given, shared sample rates.
+fn mk_logger(settings: &Settings, tail: LogTail, sample_rates: &SampleRates) -> Result<Logger> {
+    let mut sinks: Vec<SinkDrain> = settings
+        .log
+        .sinks()
+        .iter()
+        .map(|sink| mk_sink(sink, &settings.log, sample_rates))
+        .collect::<Result<Vec<SinkDrain>>>()?;
+    let level: slog::Level = settings.log.level.into();
+    sinks.push(Box::new(tail.filter_level(level).fuse()));
+    Ok(slog::Logger::root(Fanout::new(sinks), o!()))
+}
+
+fn mk_sink(
+    sink: &LogSinkSettings,
+    log: &LogSettings,
+    sample_rates: &SampleRates,
+) -> Result<SinkDrain> {
+    let level = sink.level.unwrap_or(log.level);
+    let timestamp = if sink.timestamp.unwrap_or(log.timestamp) {
+        slog_term::timestamp_local
+    } else {
+        timestamp_none
+    };
+    let drain = match sink.method {
         LogMethod::Syslog => {
-            let drain = slog_syslog::unix_3164(slog_syslog::Facility::LOG_USER)
-                .expect("syslog drain")
-                .fuse();
-            slog_async::Async::new(drain)
-                .build()
-                .filter_level(settings.log.level.into())
-                .fuse()
+            let drain = slog_syslog::unix_3164(slog_syslog::Facility::LOG_USER)?.fuse();
+            mk_async(drain, log, sample_rates, level.into())
         }
         LogMethod::Stdio => {
             let decorator = slog_term::PlainDecorator::new(io::stdout());
-            let timestamp = if settings.log.timestamp {
-                slog_term::timestamp_local
-            } else {
-                timestamp_none
-            };
             let drain = slog_term::FullFormat::new(decorator)
                 .use_custom_timestamp(timestamp)
                 .build()
                 .fuse();
-            slog_async::Async::new(drain)
+            mk_async(drain, log, sample_rates, level.into())
+        }
+        LogMethod::Stderr => {
+            let decorator = slog_term::PlainDecorator::new(io::stderr());
+            let drain = slog_term::FullFormat::new(decorator)
+                .use_custom_timestamp(timestamp)
                 .build()
-                .filter_level(settings.log.level.into())
-                .fuse()
+                .fuse();
+            mk_async(drain, log, sample_rates, level.into())
+        }
+        LogMethod::File => {
+            let path = sink
+                .path
+                .as_ref()
+                .ok_or_else(|| Error::custom("file log sink without path"))?;
+            // Write errors, such as a full disk, drop the record rather than
+            // taking down the logger
+            let file = LogFile::open(path)?;
+            mk_async(
+                JsonLines::new(file).ignore_res(),
+                log,
+                sample_rates,
+                level.into(),
+            )
         }
     };
-    slog::Logger::root(async_drain, o!())
+    Ok(drain)
+}
+
+/// Wraps the given sink drain in the rate limiting and sampling drains and
+/// moves logging to it off the calling thread
+fn mk_async<D>(
+    drain: D,
+    log: &LogSettings,
+    sample_rates: &SampleRates,
+    level: slog::Level,
+) -> SinkDrain
+where
+    D: Drain<Ok = (), Err = Never> + Send + 'static,
+{
+    let drain = RateLimit::new(drain, Duration::from_secs(log.rate_limit)).fuse();
+    let drain = Sample::new(drain, sample_rates.clone()).fuse();
+    Box::new(
+        slog_async::Async::new(drain)
+            .build()
+            .filter_level(level)
+            .fuse(),
+    )
 }
 
 pub fn main() -> Result {
@@ -85,53 +174,161 @@ pub fn main() -> Result {
             .start()
             .expect("daemon start");
     }
+    // Socket activation descriptors are taken after the daemon fork, which
+    // changes the process id they are passed for, and before the logger and
+    // runtime start any threads, since taking them clears the environment
+    let activated = activation::listen_fds();
 
     let settings = Settings::new(&cli.config)?;
-    let logger = mk_logger(&settings);
+    let log_tail = LogTail::new(crash::CRASH_LOG_RECORDS);
+    crash::install(settings.cache.crash_dir(), log_tail.clone());
+    let sample_rates = SampleRates::new(settings.log.sample.clone());
+    let logger = mk_logger(&settings, log_tail, &sample_rates)?;
     let scope_guard = slog_scope::set_global_logger(logger);
     let run_logger = slog_scope::logger().new(o!());
     slog_stdlog::init().expect("log init");
+    match &settings.bundle {
+        BundleStatus::Applied { signer, serial } => {
+            info!(&run_logger, "applied settings bundle";
+                "signer" => signer.to_string(),
+                "serial" => serial)
+        }
+        BundleStatus::Rejected { reason } => {
+            warn!(&run_logger, "rejected settings bundle: {reason}")
+        }
+        BundleStatus::Absent => (),
+    }
     let runtime = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
+    // Set on SIGUSR2 to restart the gateway in place once it has shut down
+    let restart = Arc::new(AtomicBool::new(false));
     // Start the runtime after the daemon fork
     let res = runtime.block_on(async {
         let (shutdown_trigger, shutdown_listener) = triggered::trigger();
+        reload_sample_rates(cli.config.clone(), sample_rates, run_logger.clone());
+        let mut restart_signals = signal(SignalKind::user_defined2())?;
+        let restart_requested = restart.clone();
         tokio::spawn(async move {
             let mut in_buf = [0u8; 64];
             let mut stdin = tokio::io::stdin();
             loop {
                 tokio::select!(
                     _ = signal::ctrl_c() => break,
+                    _ = restart_signals.recv() => {
+                        restart_requested.store(true, Ordering::SeqCst);
+                        break
+                    },
                     read = stdin.read(&mut in_buf), if cli.stdin => if let Ok(0) = read { break },
                 )
             }
             shutdown_trigger.trigger()
         });
-        run(cli, settings, &shutdown_listener, run_logger.clone()).await
+        run(
+            cli,
+            settings,
+            activated,
+            restart.clone(),
+            &shutdown_listener,
+            run_logger.clone(),
+        )
+        .await
     });
     runtime.shutdown_timeout(Duration::from_secs(0));
 
+    let mut failed = res.is_err();
     if let Err(e) = &res {
         error!(&run_logger, "{e}");
+    } else if restart.load(Ordering::SeqCst) {
+        info!(&run_logger, "restarting");
+        let err = restart_command().exec();
+        error!(&run_logger, "restart failed: {err}");
+        // Exit non-zero so a supervisor restarts the gateway instead
+        failed = true;
     };
     drop(scope_guard);
+    // Commands that failed, such as a failed update download, exit non-zero
+    // for scripts
+    if failed {
+        process::exit(1);
+    }
     Ok(())
 }
 
+/// Re-reads only the log sample rates from the settings in the given folder
+/// every time the process receives SIGHUP, so debug sampling can be tuned
+/// without a restart.
+fn reload_sample_rates(config: PathBuf, sample_rates: SampleRates, logger: Logger) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(err) => {
+            warn!(
+                logger,
+                "failed to register sample rate reload signal: {err:?}"
+            );
+            return;
+        }
+    };
+    tokio::spawn(async move {
+        while hangups.recv().await.is_some() {
+            match LogSettings::sample_rates(&config) {
+                Ok(rates) => {
+                    info!(logger, "reloaded log sample rates";
+                        "sample" => format!("{rates:?}"));
+                    sample_rates.set(rates);
+                }
+                Err(err) => warn!(logger, "failed to reload log sample rates: {err:?}"),
+            }
+        }
+    });
+}
+
+/// Returns the command to restart this process with. The restarted process
+/// runs the binary at the path this one was started from, which picks up a
+/// binary replaced by a self update, with the same arguments. It is not
+/// daemonized again since it replaces the already daemonized process.
+fn restart_command() -> process::Command {
+    let program = env::current_exe()
+        .map(|exe| replaced_exe_path(&exe))
+        .unwrap_or_else(|_| PathBuf::from(env!("CARGO_BIN_NAME")));
+    let mut command = process::Command::new(program);
+    command.args(env::args_os().skip(1).filter(|arg| arg != "--daemon"));
+    command
+}
+
+/// Returns the path of the binary at the given executable path. The path
+/// of an executable whose binary was replaced, such as by a self update,
+/// ends in " (deleted)" on Linux, while the replacement is at the original
+/// path.
+fn replaced_exe_path(exe: &Path) -> PathBuf {
+    exe.to_str()
+        .and_then(|exe| exe.strip_suffix(" (deleted)"))
+        .map_or_else(|| exe.to_path_buf(), PathBuf::from)
+}
+
 pub async fn run(
     cli: Cli,
     settings: Settings,
+    activated: ListenFds,
+    restart: Arc<AtomicBool>,
     shutdown_listener: &triggered::Listener,
     logger: Logger,
 ) -> Result {
     debug!(logger, "starting"; "settings" => &cli.config.to_str());
+    let format = cli.format;
     match cli.cmd {
-        Cmd::Key(cmd) => cmd.run(settings).await,
-        Cmd::Info(cmd) => cmd.run(settings).await,
-        Cmd::Update(cmd) => cmd.run(settings).await,
-        Cmd::Add(cmd) => cmd.run(settings).await,
-        Cmd::Server(cmd) => cmd.run(shutdown_listener, settings, &logger).await,
+        Cmd::Key(cmd) => cmd.run(settings, format).await,
+        Cmd::Info(cmd) => cmd.run(settings, format).await,
+        Cmd::Update(cmd) => cmd.run(settings, format).await,
+        Cmd::Add(cmd) => cmd.run(settings, format).await,
+        Cmd::Match(cmd) => cmd.run(settings, format, &logger).await,
+        Cmd::Seeds(cmd) => cmd.run(settings, format).await,
+        Cmd::Forwarder(cmd) => cmd.run(settings, format).await,
+        Cmd::Region(cmd) => cmd.run(settings, format).await,
+        Cmd::Server(cmd) => {
+            cmd.run(shutdown_listener, settings, activated, restart, &logger)
+                .await
+        }
     }
 }
@@ -1,16 +1,26 @@
-use crate::{error::DecodeError, Error, Result};
+use crate::{
+    error::{DecodeError, DownlinkError},
+    Error, Result,
+};
 use helium_proto::{
     packet::PacketType, routing_information::Data as RoutingData, BlockchainStateChannelResponseV1,
     Eui, RoutingInformation,
 };
-use lorawan::PHYPayloadFrame;
+use lorawan::{PHYPayloadFrame, RejoinRequest};
 use semtech_udp::{
     pull_resp,
     push_data::{self, CRC},
     CodingRate, DataRate, Modulation, StringOrNum,
 };
 use sha2::{Digest, Sha256};
-use std::{convert::TryFrom, fmt, ops::Deref, str::FromStr};
+use std::{convert::TryFrom, fmt, ops::Deref, str::FromStr, time::Duration};
+
+/// Earliest receive window after an uplink, the lorawan minimum rx1 delay
+const DOWNLINK_MIN_DELAY: Duration = Duration::from_secs(1);
+/// Latest receive window after an uplink, rx2 after the maximum rx1 delay
+const DOWNLINK_MAX_DELAY: Duration = Duration::from_secs(16);
+/// Time the packet forwarder needs to schedule a downlink ahead of its window
+const DOWNLINK_SCHEDULE_MARGIN: Duration = Duration::from_millis(50);
 
 #[derive(Debug, Clone)]
 pub struct Packet(helium_proto::Packet);
@@ -78,6 +88,14 @@ impl Packet {
         &self.0.routing
     }
 
+    /// Whether the given phy payload has the proprietary lorawan MHDR type
+    pub fn is_proprietary_payload(payload: &[u8]) -> bool {
+        const MTYPE_PROPRIETARY: u8 = 0b111;
+        payload
+            .first()
+            .map_or(false, |mhdr| mhdr >> 5 == MTYPE_PROPRIETARY)
+    }
+
     pub fn is_longfi(&self) -> bool {
         let mut decoded = [0xFE, 65];
         longfi::Datagram::decode(&self.0.payload, &mut decoded).is_ok()
@@ -100,6 +118,14 @@ impl Packet {
             PHYPayloadFrame::MACPayload(mac_payload) => {
                 Some(RoutingData::Devaddr(mac_payload.dev_addr()))
             }
+            PHYPayloadFrame::RejoinRequest(RejoinRequest::JoinEui {
+                join_eui, dev_eui, ..
+            }) => Some(RoutingData::Eui(Eui {
+                deveui: *dev_eui,
+                appeui: *join_eui,
+            })),
+            // Type 0 and 2 rejoin requests carry no JoinEUI to match the
+            // routing filters against
             _ => return Ok(None),
         };
         Ok(routing_data.map(|r| RoutingInformation { data: Some(r) }))
@@ -150,6 +176,70 @@ impl Packet {
         }))
     }
 
+    /// Checks that this downlink falls in an achievable receive window of the
+    /// uplink with the given concentrator timestamp, which was received
+    /// `elapsed` ago, allowing `path_latency` to get the downlink to the
+    /// concentrator. A downlink that can not make its rx1 window is moved to
+    /// its rx2 window if one is given and still ahead.
+    pub fn into_downlink_window(
+        mut self,
+        uplink_timestamp: u64,
+        elapsed: Duration,
+        path_latency: Duration,
+    ) -> Result<Self> {
+        // Concentrator timestamps are a wrapping 32 bit microsecond counter
+        let delay = |timestamp: u64| {
+            Duration::from_micros((timestamp as u32).wrapping_sub(uplink_timestamp as u32) as u64)
+        };
+        let reachable =
+            |delay: Duration| delay >= elapsed + path_latency + DOWNLINK_SCHEDULE_MARGIN;
+        let rx1_delay = delay(self.0.timestamp);
+        if !(DOWNLINK_MIN_DELAY..=DOWNLINK_MAX_DELAY).contains(&rx1_delay) {
+            return Err(DownlinkError::invalid_window(rx1_delay));
+        }
+        if reachable(rx1_delay) {
+            return Ok(self);
+        }
+        match self.0.rx2_window.take() {
+            Some(rx2)
+                if delay(rx2.timestamp) <= DOWNLINK_MAX_DELAY
+                    && reachable(delay(rx2.timestamp)) =>
+            {
+                Ok(Self(helium_proto::Packet {
+                    timestamp: rx2.timestamp,
+                    frequency: rx2.frequency,
+                    datarate: rx2.datarate,
+                    ..self.0
+                }))
+            }
+            _ => Err(DownlinkError::too_late(elapsed)),
+        }
+    }
+
+    /// Moves this downlink to its rx2 window if it has one. The more robust
+    /// rx2 data rate gives a downlink a better chance to reach a device at
+    /// the edge of coverage.
+    pub fn into_rx2_window(mut self) -> Self {
+        match self.0.rx2_window.take() {
+            Some(rx2) => Self(helium_proto::Packet {
+                timestamp: rx2.timestamp,
+                frequency: rx2.frequency,
+                datarate: rx2.datarate,
+                ..self.0
+            }),
+            None => self,
+        }
+    }
+
+    /// Sets the frequency and data rate this downlink is sent with
+    pub fn with_radio(self, frequency: f32, datarate: &str) -> Self {
+        Self(helium_proto::Packet {
+            frequency,
+            datarate: datarate.to_string(),
+            ..self.0
+        })
+    }
+
     pub fn from_state_channel_response(response: BlockchainStateChannelResponseV1) -> Option<Self> {
         response.downlink.map(Self)
     }
@@ -169,3 +259,69 @@ impl Packet {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn downlink(timestamp: u64, rx2_timestamp: Option<u64>) -> Packet {
+        Packet::from(helium_proto::Packet {
+            timestamp,
+            rx2_window: rx2_timestamp.map(|timestamp| helium_proto::Window {
+                timestamp,
+                frequency: 869.525,
+                datarate: "SF12BW125".to_string(),
+            }),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn downlink_window() {
+        let uplink = u32::MAX as u64 - 500_000;
+        let rx1 = (uplink + 1_000_000) & u32::MAX as u64;
+        let rx2 = (uplink + 2_000_000) & u32::MAX as u64;
+
+        // rx1 across a timestamp wrap is still ahead
+        let packet = downlink(rx1, Some(rx2))
+            .into_downlink_window(uplink, Duration::from_millis(200), Duration::ZERO)
+            .expect("rx1 window");
+        assert_eq!(rx1, packet.timestamp);
+
+        // rx1 missed, moved to rx2
+        let packet = downlink(rx1, Some(rx2))
+            .into_downlink_window(uplink, Duration::from_millis(1200), Duration::ZERO)
+            .expect("rx2 window");
+        assert_eq!(rx2, packet.timestamp);
+        assert!(packet.rx2_window.is_none());
+
+        // rx1 too close for the downlink path latency, moved to rx2
+        let packet = downlink(rx1, Some(rx2))
+            .into_downlink_window(
+                uplink,
+                Duration::from_millis(700),
+                Duration::from_millis(300),
+            )
+            .expect("rx2 window");
+        assert_eq!(rx2, packet.timestamp);
+
+        // Both windows missed
+        assert!(downlink(rx1, Some(rx2))
+            .into_downlink_window(uplink, Duration::from_millis(2200), Duration::ZERO)
+            .is_err());
+        // rx1 missed without rx2
+        assert!(downlink(rx1, None)
+            .into_downlink_window(uplink, Duration::from_millis(1200), Duration::ZERO)
+            .is_err());
+        // Timestamp before the uplink
+        assert!(downlink(uplink - 1000, None)
+            .into_downlink_window(uplink, Duration::ZERO, Duration::ZERO)
+            .is_err());
+
+        // Moved to rx2 up front, rx1 only downlinks are kept as is
+        let packet = downlink(rx1, Some(rx2)).into_rx2_window();
+        assert_eq!(rx2, packet.timestamp);
+        assert_eq!("SF12BW125", packet.datarate);
+        assert_eq!(rx1, downlink(rx1, None).into_rx2_window().timestamp);
+    }
+}
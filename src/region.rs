@@ -4,7 +4,7 @@ use helium_proto::{
 };
 use rust_decimal::Decimal;
 use serde::{de, Deserialize, Deserializer};
-use std::fmt;
+use std::{fmt, time::Duration};
 
 #[derive(Debug, Clone, Copy)]
 pub struct Region(ProtoRegion);
@@ -95,12 +95,119 @@ impl From<&Region> for i32 {
     }
 }
 
+/// Number of sub-bands in regions that have them
+pub const SUBBAND_COUNT: u8 = 8;
+
 impl Region {
     pub fn from_i32(v: i32) -> Result<Self> {
         ProtoRegion::from_i32(v)
             .map(Self)
             .ok_or_else(|| Error::custom(format!("unsupported region {v}")))
     }
+
+    /// Whether the region divides its uplink channels into sub-bands
+    pub fn has_subbands(&self) -> bool {
+        matches!(self.0, ProtoRegion::Us915 | ProtoRegion::Au915)
+    }
+
+    /// Returns the sub-band (1 to 8) of the given uplink frequency in MHz.
+    /// Each sub-band holds eight 125kHz channels and one 500kHz channel.
+    /// Returns None for regions without sub-bands and for frequencies that
+    /// are not an uplink channel.
+    pub fn uplink_subband(&self, frequency: f64) -> Option<u8> {
+        // First 125kHz and 500kHz uplink channel frequencies
+        let (base_125khz, base_500khz) = match self.0 {
+            ProtoRegion::Us915 => (902.3, 903.0),
+            ProtoRegion::Au915 => (915.2, 915.9),
+            _ => return None,
+        };
+        let channel = |base: f64, step: f64, count: u8| {
+            let n = ((frequency - base) / step).round();
+            let valid = n >= 0.0 && n < count as f64 && (frequency - base - n * step).abs() < 0.01;
+            valid.then_some(n as u8)
+        };
+        channel(base_125khz, 0.2, SUBBAND_COUNT * 8)
+            .map(|n| n / 8 + 1)
+            .or_else(|| channel(base_500khz, 1.6, SUBBAND_COUNT).map(|n| n + 1))
+    }
+
+    /// Returns the uplink channel frequencies in MHz of the given sub-band, or
+    /// of all sub-bands if none is given. Returns an empty list for regions
+    /// without sub-bands.
+    pub fn subband_channels(&self, subband: Option<u8>) -> Vec<f64> {
+        let (base_125khz, base_500khz) = match self.0 {
+            ProtoRegion::Us915 => (902.3, 903.0),
+            ProtoRegion::Au915 => (915.2, 915.9),
+            _ => return vec![],
+        };
+        // Round to 100kHz to avoid floating point noise in the frequencies
+        let mhz = |frequency: f64| (frequency * 10.0).round() / 10.0;
+        let in_subband = |n: u8| subband.map_or(true, |subband| n + 1 == subband);
+        let channels_125khz = (0..SUBBAND_COUNT * 8)
+            .filter(|n| in_subband(n / 8))
+            .map(|n| mhz(base_125khz + 0.2 * n as f64));
+        let channels_500khz = (0..SUBBAND_COUNT)
+            .filter(|n| in_subband(*n))
+            .map(|n| mhz(base_500khz + 1.6 * n as f64));
+        channels_125khz.chain(channels_500khz).collect()
+    }
+
+    /// The fixed rx2 window frequency in MHz and datarate of the region
+    pub fn rx2_window(&self) -> Option<(f64, &'static str)> {
+        let window = match self.0 {
+            ProtoRegion::Us915 | ProtoRegion::Au915 => (923.3, "SF12BW500"),
+            ProtoRegion::Eu868 => (869.525, "SF12BW125"),
+            ProtoRegion::Eu433 => (434.665, "SF12BW125"),
+            ProtoRegion::Cn470 => (505.3, "SF12BW125"),
+            ProtoRegion::Cn779 => (786.0, "SF12BW125"),
+            ProtoRegion::As9231 | ProtoRegion::As9231b => (923.2, "SF10BW125"),
+            ProtoRegion::As9232 => (921.4, "SF10BW125"),
+            ProtoRegion::As9233 => (916.6, "SF10BW125"),
+            ProtoRegion::As9234 => (917.3, "SF10BW125"),
+            ProtoRegion::Kr920 => (921.9, "SF12BW125"),
+            ProtoRegion::In865 => (866.55, "SF10BW125"),
+            ProtoRegion::Cd9001a => return None,
+        };
+        Some(window)
+    }
+
+    /// The uplink datarates of the region, indexed by lorawan datarate
+    pub fn uplink_datarates(&self) -> &'static [&'static str] {
+        match self.0 {
+            ProtoRegion::Us915 => &["SF10BW125", "SF9BW125", "SF8BW125", "SF7BW125", "SF8BW500"],
+            ProtoRegion::Au915 => &[
+                "SF12BW125",
+                "SF11BW125",
+                "SF10BW125",
+                "SF9BW125",
+                "SF8BW125",
+                "SF7BW125",
+                "SF8BW500",
+            ],
+            _ => &[
+                "SF12BW125",
+                "SF11BW125",
+                "SF10BW125",
+                "SF9BW125",
+                "SF8BW125",
+                "SF7BW125",
+            ],
+        }
+    }
+
+    /// The maximum uplink transmit time of a single packet, for regions that
+    /// limit it
+    pub fn dwell_time_limit(&self) -> Option<Duration> {
+        match self.0 {
+            ProtoRegion::Us915
+            | ProtoRegion::As9231
+            | ProtoRegion::As9231b
+            | ProtoRegion::As9232
+            | ProtoRegion::As9233
+            | ProtoRegion::As9234 => Some(Duration::from_millis(400)),
+            _ => None,
+        }
+    }
 }
 
 impl slog::Value for Region {
@@ -159,3 +266,40 @@ impl RegionParams {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn uplink_subband() {
+        let us915 = Region(ProtoRegion::Us915);
+        assert_eq!(Some(1), us915.uplink_subband(902.3));
+        assert_eq!(Some(2), us915.uplink_subband(903.9));
+        assert_eq!(Some(2), us915.uplink_subband(905.3));
+        assert_eq!(Some(2), us915.uplink_subband(904.6));
+        assert_eq!(Some(8), us915.uplink_subband(914.9));
+        // f32 frequencies as reported in packets
+        assert_eq!(Some(2), us915.uplink_subband(904.3_f32 as f64));
+        assert_eq!(None, us915.uplink_subband(902.4));
+        assert_eq!(None, us915.uplink_subband(923.3));
+
+        let au915 = Region(ProtoRegion::Au915);
+        assert_eq!(Some(2), au915.uplink_subband(916.8));
+        assert_eq!(None, Region(ProtoRegion::Eu868).uplink_subband(868.1));
+    }
+
+    #[test]
+    fn subband_channels() {
+        let us915 = Region(ProtoRegion::Us915);
+        assert_eq!(
+            vec![903.9, 904.1, 904.3, 904.5, 904.7, 904.9, 905.1, 905.3, 904.6],
+            us915.subband_channels(Some(2))
+        );
+        assert_eq!(72, us915.subband_channels(None).len());
+        for frequency in us915.subband_channels(Some(5)) {
+            assert_eq!(Some(5), us915.uplink_subband(frequency));
+        }
+        assert!(Region(ProtoRegion::Eu868).subband_channels(None).is_empty());
+    }
+}
@@ -1,14 +1,27 @@
 use crate::{
     error::Error,
     gateway,
-    router::{QuePacket, RouterStore},
-    service::router::RouterService,
+    router::{
+        dispatcher, qos::DOWNLINK_LIMIT_PERIOD, JoinAssist, OuiUsage, PacketLimit, QuePacket,
+        RouterQos, RouterStore,
+    },
+    sequence::SequenceId,
+    service::{resolver::Resolver, router::RouterService},
+    settings::TimeoutSettings,
     state_channel::StateChannelMessage,
-    Base64, CacheSettings, KeyedUri, Keypair, Packet, Region, Result,
+    storage::Store,
+    Base64, CacheSettings, KeyedUri, Packet, Region, RequestSigner, Result,
 };
 use futures::TryFutureExt;
+use helium_proto::{
+    blockchain_state_channel_message_v1::Msg, BlockchainStateChannelMessageV1,
+    BlockchainStateChannelResponseV1,
+};
 use slog::{debug, info, o, warn, Logger};
-use std::{sync::Arc, time::Instant};
+use std::{
+    sync::Arc,
+    time::{Instant, SystemTime},
+};
 use tokio::{
     sync::mpsc,
     time::{self, Duration, MissedTickBehavior},
@@ -20,7 +33,6 @@ pub const STATE_CHANNEL_CONNECT_INTERVAL: Duration = Duration::from_secs(60);
 #[derive(Debug)]
 pub enum Message {
     Uplink { packet: Packet, received: Instant },
-    RegionChanged(Region),
     Stop,
 }
 
@@ -28,16 +40,15 @@ pub enum Message {
 pub struct MessageSender(pub(crate) mpsc::Sender<Message>);
 pub type MessageReceiver = mpsc::Receiver<Message>;
 
+/// Size of the message channel of a router client
+pub const MESSAGE_QUEUE_SIZE: usize = 10;
+
 pub fn message_channel(size: usize) -> (MessageSender, MessageReceiver) {
     let (tx, rx) = mpsc::channel(size);
     (MessageSender(tx), rx)
 }
 
 impl MessageSender {
-    pub async fn region_changed(&self, region: Region) {
-        let _ = self.0.send(Message::RegionChanged(region)).await;
-    }
-
     pub async fn uplink(&self, packet: Packet, received: Instant) -> Result {
         self.0
             .send(Message::Uplink { packet, received })
@@ -48,52 +59,98 @@ impl MessageSender {
     pub async fn stop(&self) {
         let _ = self.0.send(Message::Stop).await;
     }
+
+    /// Returns the number of messages waiting to be handled by the client,
+    /// assuming the channel was created with `MESSAGE_QUEUE_SIZE`
+    pub fn queue_depth(&self) -> usize {
+        MESSAGE_QUEUE_SIZE.saturating_sub(self.0.capacity())
+    }
 }
 
 pub struct RouterClient {
     router: RouterService,
     oui: u32,
     region: Region,
-    keypair: Arc<Keypair>,
+    signer: RequestSigner,
     downlinks: gateway::MessageSender,
     store: RouterStore,
+    qos: RouterQos,
+    usage: OuiUsage,
+    joins: JoinAssist,
+    downlink_limit: PacketLimit,
+    connection: Option<(SequenceId, KeyedUri)>,
 }
 
 impl RouterClient {
+    #[allow(clippy::too_many_arguments)]
     pub async fn new(
         oui: u32,
         region: Region,
         uri: KeyedUri,
         downlinks: gateway::MessageSender,
-        keypair: Arc<Keypair>,
+        signer: RequestSigner,
         settings: CacheSettings,
+        store: Arc<dyn Store>,
+        qos: RouterQos,
+        usage: OuiUsage,
+        resolver: Resolver,
+        timeouts: TimeoutSettings,
+        downlink_limit: u32,
     ) -> Result<Self> {
-        let router = RouterService::new(uri)?;
-        let store = RouterStore::new(&settings);
+        let store = RouterStore::new(&settings, store, oui, &uri);
+        let router = RouterService::new(uri, resolver, timeouts);
         Ok(Self {
             router,
             oui,
             region,
-            keypair,
+            signer,
             downlinks,
             store,
+            qos,
+            usage,
+            joins: JoinAssist::default(),
+            downlink_limit: PacketLimit::new(downlink_limit, DOWNLINK_LIMIT_PERIOD),
+            connection: None,
         })
     }
 
-    pub async fn run(
-        &mut self,
-        mut messages: MessageReceiver,
-        shutdown: triggered::Listener,
-        logger: &Logger,
-    ) -> Result {
-        let logger = logger.new(o!(
+    /// Sets the gateway connection the client starts under, so its logs are
+    /// tagged with the connection until the next `GatewayConnected` event
+    pub fn set_connection(&mut self, connection: Option<(SequenceId, KeyedUri)>) {
+        self.connection = connection;
+    }
+
+    fn router_logger(&self, logger: &Logger) -> Logger {
+        let logger = match &self.connection {
+            Some((connection_id, gateway)) => {
+                dispatcher::connection_logger(logger, *connection_id, gateway)
+            }
+            None => logger.clone(),
+        };
+        logger.new(o!(
             "module" => "router",
             "pubkey" => self.router.uri.pubkey.to_string(),
             "uri" => self.router.uri.uri.to_string(),
             "oui" => self.oui,
-        ));
+        ))
+    }
+
+    pub async fn run(
+        &mut self,
+        mut messages: MessageReceiver,
+        mut events: dispatcher::Subscription,
+        shutdown: triggered::Listener,
+        base_logger: &Logger,
+    ) -> Result<Vec<Packet>> {
+        let mut logger = self.router_logger(base_logger);
         info!(logger, "starting");
 
+        match self.store.restore_waiting_packets(STORE_GC_INTERVAL) {
+            Ok(0) => (),
+            Ok(restored) => info!(logger, "restored {} queued packets", restored),
+            Err(err) => warn!(logger, "failed to restore queued packets {:?}", err),
+        }
+
         let mut store_gc_timer = time::interval(STORE_GC_INTERVAL);
         store_gc_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
 
@@ -101,7 +158,7 @@ impl RouterClient {
             tokio::select! {
                 _ = shutdown.clone() => {
                     info!(logger, "shutting down");
-                    return Ok(())
+                    return Ok(self.drain(&mut messages, &logger).await)
                 },
                 message = messages.recv() => match message {
                     Some(Message::Uplink{packet, received}) => {
@@ -109,17 +166,25 @@ impl RouterClient {
                             .unwrap_or_else(|err| warn!(logger, "ignoring failed uplink {:?}", err))
                             .await;
                     },
-                    Some(Message::RegionChanged(region)) => {
-                        self.region = region;
-                        info!(logger, "updated region";
-                            "region" => region);
-                    },
                     Some(Message::Stop) => {
                         info!(logger, "stop requested, shutting down");
-                        return Ok(())
+                        self.store.take_waiting_packets();
+                        return Ok(vec![])
                     },
                     None => warn!(logger, "ignoring closed uplinks channel"),
                 },
+                Some(event) = events.recv() => match event {
+                    dispatcher::Event::RegionParamsChanged(region_params, _height) => {
+                        self.region = region_params.region;
+                        info!(logger, "updated region";
+                            "region" => self.region);
+                    },
+                    dispatcher::Event::RoutingChanged(_) => (),
+                    dispatcher::Event::GatewayConnected(connection_id, gateway) => {
+                        self.connection = Some((connection_id, gateway));
+                        logger = self.router_logger(base_logger);
+                    },
+                },
                 _ = store_gc_timer.tick() => {
                     let removed = self.store.gc_waiting_packets(STORE_GC_INTERVAL);
                     if removed > 0 {
@@ -130,37 +195,119 @@ impl RouterClient {
         }
     }
 
+    /// Sends the uplinks still queued for the router before the client
+    /// stops. The shutdown stage timeout bounds how long this can take.
+    /// Returns the uplinks that could not be sent, starting with the one
+    /// whose send failed.
+    async fn drain(&mut self, messages: &mut MessageReceiver, logger: &Logger) -> Vec<Packet> {
+        while let Ok(message) = messages.try_recv() {
+            if let Message::Uplink { packet, received } = message {
+                if let Err(err) = self.store.store_waiting_packet(packet, received) {
+                    warn!(logger, "failed to store queued uplink {:?}", err);
+                }
+            }
+        }
+        while let Some(packet) = self.store.pop_waiting_packet() {
+            if let Err(err) = self.send_waiting_packet(logger, &packet).await {
+                warn!(logger, "failed to drain queued uplinks {:?}", err);
+                self.store.requeue_waiting_packet(packet);
+                break;
+            }
+        }
+        self.store.take_waiting_packets()
+    }
+
     async fn handle_uplink(
         &mut self,
         logger: &Logger,
         uplink: Packet,
         received: Instant,
     ) -> Result {
-        self.store.store_waiting_packet(uplink, received)?;
+        if let Err(err) = self.store.store_waiting_packet(uplink, received) {
+            warn!(logger, "failed to store queued uplink {:?}", err);
+        }
         self.send_waiting_packets(logger).await
     }
 
-    async fn handle_downlink(&mut self, logger: &Logger, packet: Packet) {
+    async fn handle_downlink(&mut self, logger: &Logger, packet: Packet, received: Instant) {
+        if !self.downlink_limit.allow(received) {
+            self.qos.record_throttled(&self.router.uri);
+            warn!(logger, "dropping downlink over router downlink limit";
+                "throttled" => self.qos.stats(&self.router.uri).throttled());
+            return;
+        }
         let _ = self
             .downlinks
-            .downlink(packet)
+            .downlink(packet, received)
             .inspect_err(|_| warn!(logger, "failed to push downlink"))
             .await;
     }
 
     async fn send_waiting_packets(&mut self, logger: &Logger) -> Result {
         while let Some(packet) = self.store.pop_waiting_packet() {
-            if let Some(message) = self.send_packet(logger, &packet).await? {
-                match message.to_downlink() {
-                    Ok(Some(packet)) => self.handle_downlink(logger, packet).await,
-                    Ok(None) => (),
-                    Err(err) => warn!(logger, "ignoring router response: {err:?}"),
+            self.send_waiting_packet(logger, &packet).await?;
+        }
+        Ok(())
+    }
+
+    async fn send_waiting_packet(&mut self, logger: &Logger, packet: &QuePacket) -> Result {
+        if let Some(message) = self.send_packet(logger, packet).await? {
+            match message.to_downlink() {
+                Ok(Some(downlink)) => {
+                    let received = Instant::now();
+                    let join = JoinAssist::device(packet.packet());
+                    let (downlink, in_rx2) = match join {
+                        Some(device) => self.assist_join(logger, device, downlink, received),
+                        None => (downlink, false),
+                    };
+                    let scheduled = downlink.timestamp;
+                    // Leave room for the measured time to get a downlink
+                    // to the concentrator, falling back to rx2 if rx1 is
+                    // too close
+                    let path_latency = self.downlinks.downlink_latency().typical();
+                    match downlink.into_downlink_window(
+                        packet.timestamp,
+                        packet.hold_time(),
+                        path_latency,
+                    ) {
+                        Ok(downlink) => {
+                            if let Some(device) = join {
+                                let rx2 = in_rx2 || downlink.timestamp != scheduled;
+                                self.joins.record(device, &downlink, rx2, received);
+                            }
+                            self.handle_downlink(logger, downlink, received).await
+                        }
+                        Err(err) => warn!(logger, "ignoring router downlink: {err}"),
+                    }
                 }
+                Ok(None) => (),
+                Err(err) => warn!(logger, "ignoring router response: {err:?}"),
             }
         }
         Ok(())
     }
 
+    /// Schedules a join accept for a device that is still sending join
+    /// requests with the cached parameters of its previous join accept.
+    /// Returns the join accept and whether it is in rx2.
+    fn assist_join(
+        &mut self,
+        logger: &Logger,
+        device: (u64, u64),
+        accept: Packet,
+        now: Instant,
+    ) -> (Packet, bool) {
+        let previous = match self.joins.retried(device, now) {
+            Some(previous) => previous,
+            None => return (accept, false),
+        };
+        info!(logger, "join retried, reusing join accept window";
+            "freq" => previous.frequency,
+            "datr" => &previous.datarate,
+            "rx2" => previous.rx2);
+        previous.reuse(accept)
+    }
+
     async fn send_packet(
         &mut self,
         logger: &Logger,
@@ -168,14 +315,57 @@ impl RouterClient {
     ) -> Result<Option<StateChannelMessage>> {
         debug!(logger, "sending packet";
             "packet_hash" => packet.hash().to_b64());
-        StateChannelMessage::packet(
+        let message = StateChannelMessage::packet(
             packet.packet().clone(),
-            self.keypair.clone(),
+            &self.signer,
             &self.region,
             packet.hold_time().as_millis() as u64,
         )
-        .and_then(|message| self.router.route(message.to_message()))
-        .map_ok(StateChannelMessage::from_message)
-        .await
+        .await?;
+        let started = Instant::now();
+        let response = self.router.route(message.to_message()).await;
+        // A router that can not be resolved is backed off by the resolver,
+        // not held against the router's quality of service
+        if !matches!(&response, Err(err) if err.is_resolve()) {
+            self.qos
+                .record(&self.router.uri, started.elapsed(), is_accepted(&response));
+        }
+        if is_purchased(&response) {
+            let packet = packet.packet();
+            self.usage.record(
+                self.oui,
+                packet.payload().len(),
+                packet.dc_payload(),
+                SystemTime::now(),
+            );
+        }
+        response.map(StateChannelMessage::from_message)
+    }
+}
+
+/// Whether the router accepted the routed packet. Routing errors and
+/// explicitly unaccepted responses count as rejections.
+fn is_accepted(response: &Result<BlockchainStateChannelMessageV1>) -> bool {
+    match response {
+        Ok(BlockchainStateChannelMessageV1 {
+            msg: Some(Msg::Response(BlockchainStateChannelResponseV1 { accepted, .. })),
+        }) => *accepted,
+        Ok(_) => true,
+        Err(_) => false,
+    }
+}
+
+/// Whether the router purchased the routed packet, either with a state
+/// channel purchase or by accepting it. Only purchased packets count towards
+/// the usage of the router's oui.
+fn is_purchased(response: &Result<BlockchainStateChannelMessageV1>) -> bool {
+    match response {
+        Ok(BlockchainStateChannelMessageV1 {
+            msg: Some(Msg::Purchase(_)),
+        }) => true,
+        Ok(BlockchainStateChannelMessageV1 {
+            msg: Some(Msg::Response(BlockchainStateChannelResponseV1 { accepted, .. })),
+        }) => *accepted,
+        _ => false,
     }
 }
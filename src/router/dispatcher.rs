@@ -1,25 +1,66 @@
 use crate::{
-    gateway,
-    router::{self, RouterClient, Routing},
-    service::{self, gateway::GatewayService},
-    sync, CacheSettings, Error, KeyedUri, Keypair, Packet, Region, Result, Settings,
+    clock::SharedClock,
+    crash, gateway,
+    router::{
+        self,
+        handoff::{RoutingHandoff, ROUTING_HANDOFF_MAX_AGE},
+        mirror::PacketMirror,
+        qos::Admission,
+        routing::RoutingDiff,
+        usage::{OuiUsage, UsageBucket},
+        Decision, PacketTrace, PacketTraces, RouterClient, RouterQos, Routing,
+    },
+    sequence::{Sequence, SequenceId},
+    service::{
+        self,
+        breaker::CircuitBreaker,
+        dns,
+        gateway::{
+            BlockInfo, GatewayService, GatewayVersion, HealthScore, HealthTracker,
+            GATEWAY_MAX_BLOCK_AGE,
+        },
+        last_gateway::{LastGateway, LAST_GATEWAY_MAX_AGE},
+        latency::LatencyHistogram,
+        resolver::Resolver,
+        validator_pool::{ValidatorPool, VALIDATOR_POOL_MAX_AGE},
+    },
+    settings::{
+        self, BuildInfo, QuarantineSettings, RouterClientSettings, SeedUri, TimeoutSettings,
+        TlsSettings, ValidatorSettings, VerifyPolicy,
+    },
+    storage::{self, Store},
+    sync, Base64, CacheSettings, Error, KeyedUri, Keypair, Packet, Region, RegionParams,
+    RequestSigner, Result, Settings, SigningRecord,
 };
 use exponential_backoff::Backoff;
 use futures::{
+    future::{self, BoxFuture},
+    stream::{self, FuturesOrdered, FuturesUnordered},
     task::{Context, Poll},
     TryFutureExt,
 };
-use helium_proto::BlockchainVarV1;
+use helium_proto::{BlockchainVarV1, GatewayRespV1};
+use rand::Rng;
+use serde::Serialize;
 use slog::{debug, info, o, warn, Logger};
 use slog_scope;
 use std::{
-    collections::HashMap,
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
     pin::Pin,
-    sync::Arc,
-    time::{Duration, Instant},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant, SystemTime},
 };
-use tokio::{task::JoinHandle, time};
-use tokio_stream::{self, StreamExt, StreamMap};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    task::JoinHandle,
+    time,
+};
+use tokio_stream::{self, Stream, StreamExt, StreamMap};
 
 #[derive(Debug)]
 pub enum Message {
@@ -37,19 +78,118 @@ pub enum Message {
     Region {
         response: sync::ResponseSender<Result<Region>>,
     },
+    Status {
+        response: sync::ResponseSender<DispatcherStatus>,
+    },
+    Snapshot {
+        response: sync::ResponseSender<DispatcherSnapshot>,
+    },
 }
 
 #[derive(Debug)]
 pub struct HeightResponse {
     pub gateway: KeyedUri,
-    pub height: u64,
-    pub block_age: u64,
+    pub block: BlockInfo,
     pub gateway_version: Option<u64>,
 }
 
+/// A snapshot of dispatcher internals to help diagnose a slow gateway
+#[derive(Debug, Default)]
+pub struct DispatcherStatus {
+    /// Number of messages waiting in the dispatcher message channel
+    pub queue_depth: usize,
+    pub routers: Vec<RouterStatus>,
+    /// Time since the last message on each gateway stream. A stream that has
+    /// not produced a message on the current gateway is not listed.
+    pub streams: Vec<(String, Duration)>,
+}
+
+#[derive(Debug)]
+pub struct RouterStatus {
+    pub oui: u32,
+    pub uri: KeyedUri,
+    /// Number of messages waiting in the router's dispatch channel
+    pub queue_depth: usize,
+}
+
+/// A serializable dump of the dispatcher state to attach to bug reports, and
+/// for the info command to read
+#[derive(Debug, Serialize)]
+pub struct DispatcherSnapshot {
+    /// Unix time in seconds the snapshot was taken at
+    pub taken: u64,
+    /// Number of messages waiting in the dispatcher message channel
+    pub queue_depth: usize,
+    pub region: String,
+    pub routing_height: u64,
+    pub region_height: u64,
+    pub gateway_retry: u32,
+    pub connection_id: Option<SequenceId>,
+    pub default_routers: Vec<String>,
+    pub routers: Vec<RouterSnapshot>,
+    /// Seconds since the last message on each gateway stream
+    pub streams: HashMap<String, u64>,
+    /// Counts of gateway stream messages by handling time bucket
+    pub stream_handling: HashMap<String, Vec<(String, u64)>>,
+    /// Health scores of the validators used so far
+    pub validators: Vec<HealthScore>,
+    /// Hourly traffic purchased by the routers of each oui
+    pub usage: BTreeMap<u32, Vec<UsageBucket>>,
+    /// Most recent signing operations with the gateway keypair
+    pub signatures: Vec<SigningRecord>,
+    /// The build of the running binary
+    pub build: BuildInfo,
+    /// Routing decisions of the most recent uplinks, oldest first. Empty
+    /// unless packet tracing is enabled in the settings.
+    pub traces: Vec<TraceSnapshot>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RouterSnapshot {
+    pub oui: u32,
+    pub uri: String,
+    pub pubkey: String,
+    pub admission: String,
+    /// Whether the router client is running or parked
+    pub running: bool,
+    pub queue_depth: usize,
+    /// Number of downlinks from the router dropped for exceeding the
+    /// downlink limit
+    pub throttled_downlinks: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TraceSnapshot {
+    pub id: SequenceId,
+    pub hash: String,
+    /// Seconds since the uplink was received
+    pub age: u64,
+    pub decisions: Vec<String>,
+}
+
 pub type MessageSender = sync::MessageSender<Message>;
 pub type MessageReceiver = sync::MessageReceiver<Message>;
 
+/// Events published by the dispatcher to the gateway and router clients
+#[derive(Debug, Clone)]
+pub enum Event {
+    /// Updated region parameters and the chain height they became effective at
+    RegionParamsChanged(RegionParams, u64),
+    /// A routing update changed the routers or filters of an oui
+    RoutingChanged(RoutingDiff),
+    /// A new gateway connection with its id and the gateway it is attached to
+    GatewayConnected(SequenceId, KeyedUri),
+}
+
+pub type EventBus = sync::EventBus<Event>;
+pub type Subscription = sync::Subscription<Event>;
+
+/// Number of events a subscriber can fall behind before it skips events
+const EVENT_BUS_SIZE: usize = 10;
+
+/// Size of the dispatcher message channel
+pub const MESSAGE_QUEUE_SIZE: usize = 20;
+
 pub fn message_channel(size: usize) -> (MessageSender, MessageReceiver) {
     sync::message_channel(size)
 }
@@ -88,23 +228,151 @@ impl MessageSender {
         let _ = self.0.send(Message::Region { response: tx }).await;
         rx.recv().await?
     }
+
+    pub async fn status(&self) -> Result<DispatcherStatus> {
+        // Measure the queue before the status request itself is queued
+        let queue_depth = MESSAGE_QUEUE_SIZE.saturating_sub(self.0.capacity());
+        let (tx, rx) = sync::response_channel();
+        let _ = self.0.send(Message::Status { response: tx }).await;
+        let status = rx.recv().await?;
+        Ok(DispatcherStatus {
+            queue_depth,
+            ..status
+        })
+    }
+
+    pub async fn snapshot(&self) -> Result<DispatcherSnapshot> {
+        let queue_depth = MESSAGE_QUEUE_SIZE.saturating_sub(self.0.capacity());
+        let (tx, rx) = sync::response_channel();
+        let _ = self.0.send(Message::Snapshot { response: tx }).await;
+        let snapshot = rx.recv().await?;
+        Ok(DispatcherSnapshot {
+            queue_depth,
+            ..snapshot
+        })
+    }
+}
+
+/// File in the runtime dir that the dispatcher snapshot is written to
+pub const SNAPSHOT_FILE: &str = "dispatcher_state.json";
+/// Interval at which the dispatcher snapshot is refreshed
+pub const SNAPSHOT_INTERVAL: Duration = Duration::from_secs(15);
+
+/// Returns the path of the dispatcher snapshot for the given cache settings
+pub fn snapshot_path(settings: &CacheSettings) -> PathBuf {
+    settings.runtime_dir().join(SNAPSHOT_FILE)
+}
+
+/// Writes a snapshot of the dispatcher state as JSON to the given path every
+/// `SNAPSHOT_INTERVAL`, where the info command reads it, and every time the
+/// process receives SIGUSR1.
+pub async fn dump_snapshots(
+    messages: MessageSender,
+    path: PathBuf,
+    shutdown: triggered::Listener,
+    logger: &Logger,
+) -> Result {
+    let logger =
+        logger.new(o!("module" => "dispatcher", "path" => path.to_string_lossy().into_owned()));
+    // Without the signal the snapshot is still refreshed periodically
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => Some(signals),
+        Err(err) => {
+            warn!(logger, "failed to register snapshot signal: {err:?}");
+            None
+        }
+    };
+    let mut interval = time::interval(SNAPSHOT_INTERVAL);
+    loop {
+        let requested = tokio::select! {
+            _ = shutdown.clone() => return Ok(()),
+            _ = interval.tick() => false,
+            Some(_) = async { signals.as_mut()?.recv().await }, if signals.is_some() => true,
+        };
+        let written = messages
+            .snapshot()
+            .await
+            .and_then(|snapshot| write_snapshot(&path, &snapshot));
+        match written {
+            Ok(()) if requested => info!(logger, "wrote dispatcher snapshot"),
+            Ok(()) => (),
+            Err(err) => warn!(logger, "dispatcher snapshot error: {err:?}"),
+        }
+    }
+}
+
+fn write_snapshot(path: &Path, snapshot: &DispatcherSnapshot) -> Result {
+    fs::write(path, serde_json::to_vec_pretty(snapshot)?)?;
+    Ok(())
 }
 
 pub struct Dispatcher {
     keypair: Arc<Keypair>,
+    signer: RequestSigner,
     region: Region,
     messages: MessageReceiver,
     downlinks: gateway::MessageSender,
+    seeds: Vec<SeedUri>,
+    // Seed validators as last discovered from the seed settings
     seed_gateways: Vec<KeyedUri>,
     routing_height: u64,
     region_height: u64,
     cache_settings: CacheSettings,
+    store: Arc<dyn Store>,
     gateway_retry: u32,
+    // Gateway retried after its streams failed, which is not retried again
+    // until it passes a gateway check
+    retried_gateway: Option<KeyedUri>,
+    // Ids of gateway connections, used to tag connection logs
+    connections: Sequence,
+    connection: Option<(SequenceId, KeyedUri)>,
+    // Ids of uplinks, used in packet traces and mirrored packet metadata
+    packets: Sequence,
     routers: HashMap<RouterKey, RouterEntry>,
+    // Last applied routing proto of every oui, handed off to the next run of
+    // the gateway on shutdown
+    routing_protos: HashMap<u32, helium_proto::Routing>,
+    // Routing updates being parsed off the dispatcher loop, applied in the
+    // order they were received. Kept across gateway sessions so updates
+    // received before a session ends are still applied.
+    routing_builds: FuturesOrdered<RoutingBuild>,
+    // Uplinks router clients could not deliver before they stopped
+    undelivered: HashMap<RouterKey, Vec<Packet>>,
+    // Set when the gateway is restarting in place, in which case the routing
+    // and undelivered uplinks are handed off to the restarted gateway
+    restart: Arc<AtomicBool>,
     default_routers: Option<Vec<KeyedUri>>,
+    health: HealthTracker,
+    validators: ValidatorSettings,
+    min_validator_version: Option<GatewayVersion>,
+    // Consecutive response verification failures of each gateway
+    verify_failures: HashMap<KeyedUri, u32>,
+    router_qos: RouterQos,
+    // Host lookups shared by all router clients
+    resolver: Resolver,
+    timeouts: TimeoutSettings,
+    tls: TlsSettings,
+    quarantine: QuarantineSettings,
+    router_clients: RouterClientSettings,
+    verify_policy: VerifyPolicy,
+    // Last block info fetched from the current gateway
+    last_block: Option<BlockInfo>,
+    // Guards the request/response rpcs made to the current gateway
+    rpc_breaker: CircuitBreaker,
+    // Time of the last message received on each stream of the current gateway
+    stream_updates: HashMap<GatewayStream, Instant>,
+    // Time spent handling the messages of each gateway stream
+    stream_handling: HashMap<GatewayStream, LatencyHistogram>,
+    // Stream idle time after which the gateway connection is probed
+    stream_probe: Option<Duration>,
+    events: EventBus,
+    traces: PacketTraces,
+    mirror: Option<PacketMirror>,
+    usage: OuiUsage,
+    clock: SharedClock,
 }
 
-#[derive(PartialEq, Eq, Hash)]
+#[derive(Clone, PartialEq, Eq, Hash)]
 struct RouterKey {
     oui: u32,
     uri: KeyedUri,
@@ -113,16 +381,90 @@ struct RouterKey {
 #[derive(Debug)]
 struct RouterEntry {
     routing: Routing,
+    // The running client for the router, None while the router is parked
+    client: Option<RunningRouter>,
+    last_uplink: Instant,
+}
+
+#[derive(Debug)]
+struct RunningRouter {
     dispatch: router::client::MessageSender,
-    join_handle: JoinHandle<Result>,
+    join_handle: JoinHandle<Result<Vec<Packet>>>,
+}
+
+impl RouterEntry {
+    async fn uplink(&self, packet: Packet, received: Instant) -> Result {
+        match &self.client {
+            Some(client) => client.dispatch.uplink(packet, received).await,
+            None => Err(Error::custom("router not running")),
+        }
+    }
+
+    fn queue_depth(&self) -> usize {
+        self.client
+            .as_ref()
+            .map_or(0, |client| client.dispatch.queue_depth())
+    }
 }
 
 const GATEWAY_BACKOFF_RETRIES: u32 = 10;
 const GATEWAY_BACKOFF_MIN_WAIT: Duration = Duration::from_secs(5);
 const GATEWAY_BACKOFF_MAX_WAIT: Duration = Duration::from_secs(1800); // 30 minutes
 
+// Wait before retrying a validator whose streams failed once, before falling
+// back to reselection
+const GATEWAY_RETRY_WAIT: Duration = Duration::from_secs(2);
+// Number of times a single failed gateway stream is subscribed to again
+// before the validator is given up on
+const STREAM_RESUBSCRIBE_RETRIES: u32 = 3;
+// Wait before subscribing to a failed gateway stream again, multiplied by
+// the attempt
+const STREAM_RESUBSCRIBE_WAIT: Duration = Duration::from_secs(1);
+
 const GATEWAY_CHECK_INTERVAL: Duration = Duration::from_secs(900); // 15 minutes
-const GATEWAY_MAX_BLOCK_AGE: Duration = Duration::from_secs(1800); // 30 minutes
+const ROUTER_IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+// Number of validators to request from a seed when looking up the current
+// key of a gateway that failed response verification
+const GATEWAY_LOOKUP_COUNT: u32 = 50;
+// Consecutive response verification failures of a gateway after which it is
+// looked up in case it rotated its key
+const GATEWAY_ROTATION_FAILURES: u32 = 2;
+// Maximum number of routers an uplink is dispatched to concurrently
+const UPLINK_FANOUT_LIMIT: usize = 8;
+// Number of validators to fetch from a seed for validator selection
+const GATEWAY_FETCH_COUNT: u32 = 5;
+// Number of selected validators whose streams are set up concurrently, the
+// first to succeed being used
+const GATEWAY_RACE_COUNT: usize = 3;
+// Time spent handling a single gateway stream message above which a warning
+// is logged
+const STREAM_HANDLING_WARN: Duration = Duration::from_secs(1);
+// Consecutive failed rpcs to a gateway after which further rpcs fail fast
+const RPC_BREAKER_THRESHOLD: u32 = 3;
+const RPC_BREAKER_OPEN_PERIOD: Duration = Duration::from_secs(30);
+// Number of gateway pubkey characters included in connection logs
+const CONNECTION_PUBKEY_PREFIX: usize = 12;
+
+/// How to continue once running with a gateway ended
+#[derive(Debug)]
+enum Reconnect {
+    /// Select a new gateway after the gateway backoff
+    Reselect,
+    /// Reconnect right away to the gateway, which rotated its key
+    Rotated(KeyedUri),
+    /// Retry the gateway after a short wait, since its streams failed
+    /// without it being quarantined. A gateway is retried only once until it
+    /// passes a gateway check, and selection falls back to other validators
+    /// if the retry fails.
+    Retry(KeyedUri),
+}
+
+impl From<Option<KeyedUri>> for Reconnect {
+    fn from(rotated: Option<KeyedUri>) -> Self {
+        rotated.map_or(Self::Reselect, Self::Rotated)
+    }
+}
 
 #[derive(Debug, Hash, PartialEq, Eq, Clone)]
 enum GatewayStream {
@@ -130,40 +472,370 @@ enum GatewayStream {
     RegionParams,
 }
 
+impl std::fmt::Display for GatewayStream {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Routing => f.write_str("routing"),
+            Self::RegionParams => f.write_str("region_params"),
+        }
+    }
+}
+
 type GatewayStreams = StreamMap<GatewayStream, service::gateway::Streaming>;
 
+/// A new subscription to a single failed gateway stream
+type Resubscribe<S = service::gateway::Streaming> = BoxFuture<'static, (GatewayStream, Result<S>)>;
+
+/// What happened on the streams of a gateway
+#[derive(Debug)]
+enum StreamEvent {
+    /// A message was received on the given stream
+    Message(GatewayStream, GatewayRespV1),
+    /// The given stream failed and is to be subscribed to again with the
+    /// given attempt
+    Resubscribe(GatewayStream, Error, u32),
+    /// The given stream was subscribed to again
+    Resubscribed(GatewayStream),
+    /// The given stream failed for good
+    Failed(GatewayStream, Error),
+    /// All streams closed without any of them being subscribed to again
+    Closed,
+}
+
+/// The streams of a gateway. A failed stream is subscribed to again on its
+/// own a few times, while the other stream keeps running. The attempts of a
+/// stream are reset once it delivers a message again.
+struct GatewayStreamSet<S = service::gateway::Streaming> {
+    streams: StreamMap<GatewayStream, S>,
+    resubscribes: FuturesUnordered<Resubscribe<S>>,
+    attempts: HashMap<GatewayStream, u32>,
+}
+
+impl<S> GatewayStreamSet<S>
+where
+    S: Stream<Item = Result<GatewayRespV1>> + Unpin + Send + 'static,
+{
+    fn new(streams: StreamMap<GatewayStream, S>) -> Self {
+        Self {
+            streams,
+            resubscribes: FuturesUnordered::new(),
+            attempts: HashMap::new(),
+        }
+    }
+
+    /// Adds a new subscription to a stream, which was failed with a
+    /// `StreamEvent::Resubscribe`
+    fn resubscribe(&mut self, resubscribe: Resubscribe<S>) {
+        self.resubscribes.push(resubscribe);
+    }
+
+    /// Waits for the next event on the streams. Cancel safe, so it can be
+    /// used as a branch of `tokio::select!`.
+    async fn next(&mut self) -> StreamEvent {
+        loop {
+            tokio::select! {
+                message = self.streams.next(), if !self.streams.is_empty() => match message {
+                    Some((gateway_stream, Ok(message))) => {
+                        self.attempts.remove(&gateway_stream);
+                        return StreamEvent::Message(gateway_stream, message);
+                    }
+                    Some((gateway_stream, Err(err))) => {
+                        self.streams.remove(&gateway_stream);
+                        return self.failed(gateway_stream, err);
+                    }
+                    // Streams that are being subscribed to again are not
+                    // closed
+                    None if !self.resubscribes.is_empty() => (),
+                    None => return StreamEvent::Closed,
+                },
+                Some((gateway_stream, resubscribed)) = self.resubscribes.next(), if !self.resubscribes.is_empty() => match resubscribed {
+                    Ok(resubscribed) => {
+                        self.streams.insert(gateway_stream.clone(), resubscribed);
+                        return StreamEvent::Resubscribed(gateway_stream);
+                    }
+                    Err(err) => return self.failed(gateway_stream, err),
+                },
+                else => return StreamEvent::Closed,
+            }
+        }
+    }
+
+    fn failed(&mut self, gateway_stream: GatewayStream, err: Error) -> StreamEvent {
+        let attempts = self.attempts.entry(gateway_stream.clone()).or_default();
+        if !resubscribes_stream(&err, *attempts) {
+            return StreamEvent::Failed(gateway_stream, err);
+        }
+        *attempts += 1;
+        StreamEvent::Resubscribe(gateway_stream, err, *attempts)
+    }
+}
+
+/// Runs the gateway sessions the dispatcher reconnects between, so the
+/// reconnect sequence can be driven without connecting to validators
+#[async_trait::async_trait(?Send)]
+trait GatewaySessions {
+    /// Runs a single session with a gateway, trying the given last gateway
+    /// first. Returns how to reconnect once the session ended, or None on
+    /// shutdown.
+    async fn run(
+        &mut self,
+        dispatcher: &mut Dispatcher,
+        last_gateway: Option<KeyedUri>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<Reconnect>>;
+
+    /// Runs a single session with the given gateway, connecting to it
+    /// directly without going through the seeds. Returns how to reconnect
+    /// once the session ended, or None on shutdown.
+    async fn retry(
+        &mut self,
+        dispatcher: &mut Dispatcher,
+        gateway: KeyedUri,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<Reconnect>>;
+}
+
+/// Sessions with validators selected through the seeds
+struct SeedSessions;
+
+#[async_trait::async_trait(?Send)]
+impl GatewaySessions for SeedSessions {
+    async fn run(
+        &mut self,
+        dispatcher: &mut Dispatcher,
+        last_gateway: Option<KeyedUri>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<Reconnect>> {
+        dispatcher.run_session(last_gateway, shutdown, logger).await
+    }
+
+    async fn retry(
+        &mut self,
+        dispatcher: &mut Dispatcher,
+        gateway: KeyedUri,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<Reconnect>> {
+        dispatcher.retry_session(&gateway, shutdown, logger).await
+    }
+}
+
+/// What is needed to set up the streams of a gateway, so candidate gateways
+/// can be raced without borrowing the dispatcher
+#[derive(Clone)]
+struct StreamSetup {
+    routing_height: u64,
+    signer: RequestSigner,
+    verify_policy: VerifyPolicy,
+}
+
+/// A routing update being parsed in the background, resolving to the update
+/// height and the parsed routing of each oui along with the proto it was
+/// parsed from
+type RoutingBuild = JoinHandle<(u64, Vec<(helium_proto::Routing, Routing)>)>;
+
 impl Dispatcher {
     // Allow mutable key type for HashMap with Uri in the key
     #[allow(clippy::mutable_key_type)]
     pub fn new(
         messages: MessageReceiver,
         downlinks: gateway::MessageSender,
+        clock: SharedClock,
+        restart: Arc<AtomicBool>,
         settings: &Settings,
     ) -> Result<Self> {
-        let seed_gateways = settings.gateways.clone();
+        let seeds = settings.gateways.clone();
         let routers = HashMap::with_capacity(5);
         let default_routers = settings.routers.clone();
         let cache_settings = settings.cache.clone();
+        let store = storage::open(&cache_settings);
+        let connections = Sequence::new(store.clone(), "connection");
+        let packets = Sequence::new(store.clone(), "packet");
+        let usage = OuiUsage::new(store.clone());
+        let health = HealthTracker::new(clock.clone(), store.clone());
+        // Mirroring is a debugging aid, so a mirror that can not be set up
+        // is disabled rather than keeping the dispatcher from starting
+        let mirror = settings
+            .mirror
+            .as_ref()
+            .and_then(|mirror| match PacketMirror::new(mirror) {
+                Ok(mirror) => Some(mirror),
+                Err(err) => {
+                    warn!(slog_scope::logger(), "packet mirroring disabled: {err:?}";
+                        "endpoint" => &mirror.endpoint);
+                    None
+                }
+            });
         Ok(Self {
             keypair: settings.keypair.clone(),
+            signer: RequestSigner::from(settings.keypair.clone()),
             region: settings.region,
             messages,
             downlinks,
-            seed_gateways,
+            seeds,
+            seed_gateways: vec![],
             routers,
+            routing_protos: HashMap::new(),
+            routing_builds: FuturesOrdered::new(),
+            undelivered: HashMap::new(),
+            restart,
             routing_height: 0,
             region_height: 0,
             default_routers,
             cache_settings,
+            store,
             gateway_retry: 0,
+            retried_gateway: None,
+            connections,
+            connection: None,
+            packets,
+            health,
+            validators: settings.validators.clone(),
+            min_validator_version: settings.min_validator_version.clone(),
+            verify_failures: HashMap::new(),
+            router_qos: RouterQos::default(),
+            resolver: Resolver::new(settings.timeouts.connect()),
+            timeouts: settings.timeouts,
+            tls: settings.tls.clone(),
+            quarantine: settings.quarantine.clone(),
+            router_clients: settings.router_clients.clone(),
+            verify_policy: settings.verify_policy,
+            last_block: None,
+            rpc_breaker: CircuitBreaker::new(RPC_BREAKER_THRESHOLD, RPC_BREAKER_OPEN_PERIOD),
+            stream_updates: HashMap::new(),
+            stream_handling: HashMap::new(),
+            stream_probe: match settings.stream_probe {
+                0 => None,
+                secs => Some(Duration::from_secs(secs)),
+            },
+            events: EventBus::new(EVENT_BUS_SIZE),
+            traces: PacketTraces::new(settings.trace_packets),
+            mirror,
+            usage,
+            clock,
         })
     }
 
+    /// Subscribes to events published by the dispatcher
+    pub fn subscribe(&self) -> Subscription {
+        self.events.subscribe()
+    }
+
     pub async fn run(&mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
         let logger = logger.new(o!("module" => "dispatcher"));
         info!(logger, "starting"; 
             "region" => self.region);
+        self.restore_routing(&shutdown, &logger).await;
+        let result = self
+            .run_gateways(&mut SeedSessions, shutdown, &logger)
+            .await;
+        self.drain_routers(&logger).await;
+        self.save_usage(&logger);
+        if self.restart.load(Ordering::SeqCst) {
+            self.hand_off(&logger);
+        }
+        result
+    }
+
+    /// Starts the routers of the routing handed off by a previous run of the
+    /// gateway, if it stopped recently enough for the routing to be current.
+    async fn restore_routing(&mut self, shutdown: &triggered::Listener, logger: &Logger) {
+        let handoff = match RoutingHandoff::load(self.store.as_ref(), ROUTING_HANDOFF_MAX_AGE) {
+            Some(handoff) => handoff,
+            None => return,
+        };
+        let protos = match handoff.routings() {
+            Ok(protos) => protos,
+            Err(err) => {
+                warn!(logger, "failed to decode routing handoff: {err:?}");
+                return;
+            }
+        };
+        // The routing height is left alone so the first gateway is still
+        // asked for the full routing, which replaces the restored one
+        for proto in protos {
+            match Routing::from_proto(logger, &proto) {
+                Ok(routing) => {
+                    self.handle_oui_routing_update(&routing, shutdown, logger)
+                        .await;
+                    self.routing_protos.insert(routing.oui, proto);
+                }
+                Err(err) => warn!(logger, "failed to parse routing: {err:?}"),
+            }
+        }
+        info!(logger, "restored routing handoff";
+            "height" => handoff.height,
+            "ouis" => self.routing_protos.len());
+        self.restore_uplinks(&handoff, logger).await;
+    }
+
+    /// Queues the uplinks handed off by a previous run of the gateway to the
+    /// restored routers they were queued for. They are treated as received
+    /// when the handoff was written so their hold time includes the restart.
+    async fn restore_uplinks(&self, handoff: &RoutingHandoff, logger: &Logger) {
+        let uplinks = match handoff.uplinks() {
+            Ok(uplinks) => uplinks,
+            Err(err) => {
+                warn!(logger, "failed to decode handed off uplinks: {err:?}");
+                return;
+            }
+        };
+        if uplinks.is_empty() {
+            return;
+        }
+        let now = self.clock.now();
+        let received = now.checked_sub(handoff.age()).unwrap_or(now);
+        let mut restored = 0;
+        let mut dropped = 0;
+        for (oui, uri, packet) in uplinks {
+            let queued = match self.routers.get(&RouterKey { oui, uri }) {
+                Some(router_entry) => router_entry.uplink(packet, received).await,
+                None => Err(Error::custom("router not restored")),
+            };
+            match queued {
+                Ok(()) => restored += 1,
+                Err(_) => dropped += 1,
+            }
+        }
+        info!(logger, "restored handed off uplinks";
+            "restored" => restored,
+            "dropped" => dropped);
+    }
+
+    /// Stores the uplink usage of the routers for reporting after a restart
+    fn save_usage(&self, logger: &Logger) {
+        if let Err(err) = self.usage.save() {
+            warn!(logger, "failed to store oui usage: {err:?}");
+        }
+    }
+
+    /// Stores the current routing and the uplinks the routers could not
+    /// deliver for the restarted gateway
+    fn hand_off(&self, logger: &Logger) {
+        if self.routing_protos.is_empty() {
+            return;
+        }
+        let saved = RoutingHandoff::new(self.routing_height, self.routing_protos.values())
+            .and_then(|mut handoff| {
+                for (router_key, packets) in &self.undelivered {
+                    handoff.add_uplinks(router_key.oui, &router_key.uri, packets);
+                }
+                handoff.save(self.store.as_ref())
+            });
+        if let Err(err) = saved {
+            warn!(logger, "failed to store routing handoff: {err:?}");
+        }
+    }
 
+    async fn run_gateways<S: GatewaySessions>(
+        &mut self,
+        sessions: &mut S,
+        shutdown: triggered::Listener,
+        logger: &Logger,
+    ) -> Result {
         if let Some(default_routers) = &self.default_routers {
             for default_router in default_routers {
                 info!(logger, "default router";
@@ -177,47 +849,296 @@ impl Dispatcher {
             GATEWAY_BACKOFF_MIN_WAIT,
             GATEWAY_BACKOFF_MAX_WAIT,
         );
+        // Try the last successfully used gateway first (if still fresh) to
+        // avoid going through seed selection on startup
+        let mut last_gateway = LastGateway::load(self.store.as_ref(), LAST_GATEWAY_MAX_AGE)
+            .filter(|uri| self.validators.permits(&uri.pubkey));
+        // Gateway to connect to directly, skipping seed discovery
+        let mut retry_gateway: Option<KeyedUri> = None;
         loop {
             if shutdown.is_triggered() {
                 // Prevent unneeded seed reselection
                 return Ok(());
             }
-            // Select seed
-            let seed_gateway = GatewayService::select_seed(&self.seed_gateways)?;
-            info!(logger, "seed gateway";
-                "pubkey" => seed_gateway.uri.pubkey.to_string(),
-                "uri" => seed_gateway.uri.uri.to_string());
+            let session = match retry_gateway.take() {
+                Some(gateway) => sessions.retry(self, gateway, &shutdown, logger).await?,
+                None => {
+                    crash::set_subsystem_state("dispatcher", "selecting validator");
+                    sessions
+                        .run(self, last_gateway.take(), &shutdown, logger)
+                        .await?
+                }
+            };
+            let reconnect = match session {
+                Some(reconnect) => reconnect,
+                None => return Ok(()),
+            };
+            match reconnect {
+                Reconnect::Rotated(gateway) => last_gateway = Some(gateway),
+                Reconnect::Retry(gateway) if self.retried_gateway.as_ref() != Some(&gateway) => {
+                    crash::set_subsystem_state(
+                        "dispatcher",
+                        format!("retrying validator {}", gateway.pubkey),
+                    );
+                    let sleep = jitter(GATEWAY_RETRY_WAIT, self.validators.backoff_jitter);
+                    info!(logger, "retrying gateway in {}s", sleep.as_secs();
+                        "pubkey" => gateway.pubkey.to_string(),
+                        "uri" => gateway.uri.to_string());
+                    self.wait_gateway_change(sleep, shutdown.clone(), logger)
+                        .await;
+                    self.retried_gateway = Some(gateway.clone());
+                    retry_gateway = Some(gateway);
+                }
+                // The gateway was already retried and failed again
+                Reconnect::Retry(gateway) => {
+                    info!(logger, "gateway failed after retry";
+                        "pubkey" => gateway.pubkey.to_string(),
+                        "uri" => gateway.uri.to_string());
+                    self.retried_gateway = None;
+                    self.prepare_gateway_change(&gateway_backoff, shutdown.clone(), logger)
+                        .await
+                }
+                Reconnect::Reselect => {
+                    self.prepare_gateway_change(&gateway_backoff, shutdown.clone(), logger)
+                        .await
+                }
+            }
+        }
+    }
 
-            tokio::select! {
-                    _ = shutdown.clone() => {
-                        info!(logger, "shutting down");
-                        return Ok(())
-                    },
-                // Try to select a random validator from the seed and fetch the needed streams
-                gateway = Self::select_gateway(seed_gateway, &shutdown, &logger)
-                    .and_then(|service | self.setup_gateway_streams(service, &logger))
-                     => match gateway {
-                        Ok(Some((service, gateway_streams))) =>
-                            self.run_with_gateway(service, gateway_streams, shutdown.clone(), &logger)
-                                .await?,
-                        Ok(None) =>
-                            return Ok(()),
-                        Err(_err) => ()
-                    }
+    /// Selects a seed and a validator from it, trying the given last gateway
+    /// first, and runs with the selected validator. Returns how to reconnect
+    /// once running with the validator ended, or None on shutdown.
+    async fn run_session(
+        &mut self,
+        last_gateway: Option<KeyedUri>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<Reconnect>> {
+        if let Some(pin) = self.validators.pin.clone() {
+            self.run_pinned(&pin, shutdown.clone(), logger).await?;
+            return Ok(Some(Reconnect::Reselect));
+        }
+        // Discover seeds again on every selection, keeping the previous
+        // seeds if none could be discovered
+        let seed_gateways = dns::seed_uris(&self.seeds, self.timeouts.connect(), logger).await;
+        if !seed_gateways.is_empty() {
+            self.seed_gateways = seed_gateways;
+        } else if self.seed_gateways.is_empty() {
+            warn!(logger, "no seed validators found");
+            return Ok(Some(Reconnect::Reselect));
+        }
+        // Select seed
+        let seed_gateway = match GatewayService::select_seed(
+            &self.seed_gateways,
+            &self.resolver,
+            &self.health,
+            self.timeouts,
+            &self.tls,
+        )
+        .await
+        {
+            Ok(seed_gateway) => seed_gateway,
+            Err(err) if err.is_resolve() => {
+                warn!(logger, "seed selection error: {err}");
+                return Ok(Some(Reconnect::Reselect));
             }
+            Err(err) => return Err(err),
+        };
+        info!(logger, "seed gateway";
+            "pubkey" => seed_gateway.uri.pubkey.to_string(),
+            "uri" => seed_gateway.uri.uri.to_string());
 
-            self.prepare_gateway_change(&gateway_backoff, shutdown.clone(), &logger)
-                .await;
+        let validators = self.validators.clone();
+        let min_version = self.min_validator_version.clone();
+        let setup = StreamSetup {
+            routing_height: self.routing_height,
+            signer: self.signer.clone(),
+            verify_policy: self.verify_policy,
+        };
+        tokio::select! {
+            _ = shutdown.clone() => {
+                info!(logger, "shutting down");
+                Ok(None)
+            },
+            // Try to select a random validator from the seed and fetch the needed streams
+            gateway = Self::select_gateway(
+                    seed_gateway, last_gateway, &validators,
+                    min_version.as_ref(), &setup, self.store.clone(), shutdown, logger
+                ) => match gateway {
+                    Ok(Some((service, gateway_streams))) => self
+                        .run_with_gateway(service, gateway_streams, shutdown.clone(), logger)
+                        .await
+                        .map(Some),
+                    Ok(None) => Ok(None),
+                    Err(_err) => Ok(Some(Reconnect::Reselect)),
+                }
+        }
+    }
+
+    /// Connects directly to the given gateway that is retried and runs with
+    /// it, skipping seed discovery and validator selection. Returns how to
+    /// reconnect once running with the gateway ended, or None on shutdown.
+    async fn retry_session(
+        &mut self,
+        gateway: &KeyedUri,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Option<Reconnect>> {
+        info!(logger, "retrying gateway";
+            "pubkey" => gateway.pubkey.to_string(),
+            "uri" => gateway.uri.to_string());
+        let setup = StreamSetup {
+            routing_height: self.routing_height,
+            signer: self.signer.clone(),
+            verify_policy: self.verify_policy,
+        };
+        let min_version = self.min_validator_version.clone();
+        let health = self.health.clone();
+        let timeouts = self.timeouts;
+        let tls = self.tls.clone();
+        let resolver = self.resolver.clone();
+        let connect = async {
+            let service =
+                GatewayService::resolve(gateway, &resolver, health, timeouts, &tls).await?;
+            Self::connect_gateway(service, min_version.as_ref(), &setup, logger).await
+        };
+        let connected = tokio::select! {
+            connected = connect => connected,
+            _ = shutdown.clone() => return Ok(None),
+        };
+        match connected {
+            Ok((service, gateway_streams)) => self
+                .run_with_gateway(service, gateway_streams, shutdown.clone(), logger)
+                .await
+                .map(Some),
+            Err(err) => {
+                warn!(logger, "gateway retry error: {err:?}";
+                    "pubkey" => gateway.pubkey.to_string(),
+                    "uri" => gateway.uri.to_string());
+                Ok(Some(Reconnect::Reselect))
+            }
+        }
+    }
+
+    /// Connects to the pinned validator and runs with it until its streams
+    /// end, bypassing seed and validator selection
+    async fn run_pinned(
+        &mut self,
+        pin: &KeyedUri,
+        shutdown: triggered::Listener,
+        logger: &Logger,
+    ) -> Result {
+        info!(logger, "pinned gateway";
+            "pubkey" => pin.pubkey.to_string(),
+            "uri" => pin.uri.to_string());
+        let setup = StreamSetup {
+            routing_height: self.routing_height,
+            signer: self.signer.clone(),
+            verify_policy: self.verify_policy,
+        };
+        let health = self.health.clone();
+        let timeouts = self.timeouts;
+        let tls = self.tls.clone();
+        let resolver = self.resolver.clone();
+        let connect = async {
+            let gateway = GatewayService::resolve(pin, &resolver, health, timeouts, &tls).await?;
+            Self::setup_gateway_streams(gateway, &setup, logger).await
+        };
+        let connected = tokio::select! {
+            connected = connect => connected,
+            _ = shutdown.clone() => return Ok(()),
+        };
+        match connected {
+            Ok((service, gateway_streams)) => {
+                // The pinned validator is reconnected to however running with
+                // it ended, so a key rotation is not followed either
+                self.run_with_gateway(service, gateway_streams, shutdown, logger)
+                    .await?;
+            }
+            Err(err) => warn!(logger, "pinned gateway error: {err:?}";
+                "pubkey" => pin.pubkey.to_string(),
+                "uri" => pin.uri.to_string()),
         }
+        Ok(())
     }
 
+    #[allow(clippy::too_many_arguments)]
     async fn select_gateway(
         mut seed_gateway: GatewayService,
+        last_gateway: Option<KeyedUri>,
+        validators: &ValidatorSettings,
+        min_version: Option<&GatewayVersion>,
+        setup: &StreamSetup,
+        store: Arc<dyn Store>,
         shutdown: &triggered::Listener,
         logger: &Logger,
-    ) -> Result<Option<GatewayService>> {
-        match seed_gateway.random_new(5, shutdown.clone()).await {
-            Ok(result) => Ok(result),
+    ) -> Result<Option<(GatewayService, GatewayStreams)>> {
+        if let Some(last_gateway) = last_gateway {
+            info!(logger, "trying last gateway";
+                "pubkey" => last_gateway.pubkey.to_string(),
+                "uri" => last_gateway.uri.to_string());
+            let gateway = seed_gateway.with_uri(&last_gateway)?;
+            match Self::connect_gateway(gateway, min_version, setup, logger).await {
+                Ok(connected) => return Ok(Some(connected)),
+                Err(err) => info!(logger, "last gateway did not connect: {err:?}";
+                    "pubkey" => last_gateway.pubkey.to_string(),
+                    "uri" => last_gateway.uri.to_string()),
+            }
+        }
+        // Reselect from the recently fetched validators to avoid a seed round
+        // trip, which also keeps reselection working while seeds are down
+        if let Some(pool) = ValidatorPool::load(store.as_ref(), VALIDATOR_POOL_MAX_AGE) {
+            match Self::select_versioned(
+                &seed_gateway,
+                &pool.validators,
+                validators,
+                min_version,
+                setup,
+                logger,
+            )
+            .await
+            {
+                Ok(gateway) => {
+                    info!(logger, "selected cached validator";
+                        "height" => pool.height,
+                        "count" => pool.validators.len());
+                    return Ok(Some(gateway));
+                }
+                Err(err) => {
+                    // None of the cached validators connected, fall through
+                    // to fetching validators from the seed and keep the
+                    // failed pool from being tried again
+                    warn!(logger, "cached validator selection error: {err:?}");
+                    if let Err(err) = ValidatorPool::invalidate(store.as_ref()) {
+                        warn!(logger, "failed to invalidate validator pool: {err:?}");
+                    }
+                }
+            }
+        }
+        let pool = tokio::select! {
+            pool = seed_gateway.validator_pool(GATEWAY_FETCH_COUNT) => pool,
+            _ = shutdown.clone() => return Ok(None),
+        };
+        let gateway = match pool {
+            Ok(pool) => {
+                if let Err(err) = pool.save(store.as_ref()) {
+                    warn!(logger, "failed to store validator pool: {err:?}");
+                }
+                Self::select_versioned(
+                    &seed_gateway,
+                    &pool.validators,
+                    validators,
+                    min_version,
+                    setup,
+                    logger,
+                )
+                .await
+            }
+            Err(err) => Err(err),
+        };
+        match gateway {
+            Ok(gateway) => Ok(Some(gateway)),
             Err(err) => {
                 warn!(logger, "gateway selection error: {err:?}";
                     "pubkey" => seed_gateway.uri.pubkey.to_string(),
@@ -227,105 +1148,503 @@ impl Dispatcher {
         }
     }
 
-    async fn setup_gateway_streams(
-        &mut self,
-        gateway: Option<GatewayService>,
+    /// Selects permitted validators from the given list and races setting up
+    /// their streams, using the first that succeeds. Validators below the
+    /// given minimum version are skipped. Further rounds are run with the
+    /// remaining validators until one connects or none are left. With probing
+    /// enabled the validators are tried in probed order instead.
+    async fn select_versioned(
+        seed_gateway: &GatewayService,
+        pool: &[KeyedUri],
+        validators: &ValidatorSettings,
+        min_version: Option<&GatewayVersion>,
+        setup: &StreamSetup,
         logger: &Logger,
-    ) -> Result<Option<(GatewayService, GatewayStreams)>> {
-        if gateway.is_none() {
-            return Ok(None);
+    ) -> Result<(GatewayService, GatewayStreams)> {
+        if validators.probe {
+            return Self::select_probed(seed_gateway, pool, validators, min_version, setup, logger)
+                .await;
         }
-        let mut gateway = gateway.unwrap();
-        let mut routing_gateway = gateway.clone();
-        let routing = routing_gateway.routing(self.routing_height);
-        let region_params = gateway.region_params(self.keypair.clone());
-        match tokio::try_join!(routing, region_params) {
-            Ok((routing, region_params)) => {
-                let stream_map = StreamMap::from_iter([
-                    (GatewayStream::Routing, routing),
-                    (GatewayStream::RegionParams, region_params),
-                ]);
-                Ok(Some((gateway, stream_map)))
+        race_candidates(
+            pool.to_vec(),
+            GATEWAY_RACE_COUNT,
+            |candidates| {
+                seed_gateway
+                    .select_from(candidates, validators)
+                    .map(|gateway| (gateway.uri.clone(), gateway))
+            },
+            |gateway| Self::connect_gateway(gateway, min_version, setup, logger),
+            logger,
+        )
+        .await
+    }
+
+    /// Probes the permitted validators from the given list once and sets up
+    /// the streams of the fastest one that meets the given minimum version,
+    /// falling back to the next fastest when that fails. The version
+    /// reported by the probe is used for the minimum version check.
+    async fn select_probed(
+        seed_gateway: &GatewayService,
+        pool: &[KeyedUri],
+        validators: &ValidatorSettings,
+        min_version: Option<&GatewayVersion>,
+        setup: &StreamSetup,
+        logger: &Logger,
+    ) -> Result<(GatewayService, GatewayStreams)> {
+        let probed = seed_gateway.probe_fastest(pool, validators).await?;
+        for (gateway, version) in probed {
+            if !Self::accepts_version(&gateway, Ok(version), min_version, logger) {
+                continue;
             }
-            Err(err) => {
-                warn!(logger, "gateway stream setup error: {err:?} "; 
-                    "pubkey" => gateway.uri.pubkey.to_string(),
-                    "uri" => gateway.uri.uri.to_string());
-                Err(err)
+            let pubkey = gateway.uri.pubkey.clone();
+            let health = gateway.health().clone();
+            match Self::setup_gateway_streams(gateway, setup, logger).await {
+                Ok(connected) => return Ok(connected),
+                Err(err) => {
+                    info!(logger, "probed validator did not connect: {err:?}");
+                    health.record_stream_failure(&pubkey);
+                }
             }
         }
+        Err(Error::custom("no probed validator connected"))
     }
 
-    async fn run_with_gateway(
-        &mut self,
+    /// Checks the version of the given gateway and sets up its streams. A
+    /// gateway whose streams could not be set up is recorded as failed in its
+    /// health tracker.
+    async fn connect_gateway(
         mut gateway: GatewayService,
-        mut streams: GatewayStreams,
-        shutdown: triggered::Listener,
+        min_version: Option<&GatewayVersion>,
+        setup: &StreamSetup,
         logger: &Logger,
-    ) -> Result {
-        info!(logger, "using gateway";
-            "pubkey" => gateway.uri.pubkey.to_string(),
-            "uri" => gateway.uri.uri.to_string());
+    ) -> Result<(GatewayService, GatewayStreams)> {
+        if !Self::meets_min_version(&mut gateway, min_version, logger).await {
+            return Err(Error::version_rejected());
+        }
+        let pubkey = gateway.uri.pubkey.clone();
+        let health = gateway.health().clone();
+        let connected = Self::setup_gateway_streams(gateway, setup, logger).await;
+        if connected.is_err() {
+            health.record_stream_failure(&pubkey);
+        }
+        connected
+    }
 
-        // Initialize liveness check for gateway
-        let mut gateway_check = time::interval(GATEWAY_CHECK_INTERVAL);
-        loop {
-            tokio::select! {
-                _ = shutdown.clone() => {
+    async fn meets_min_version(
+        gateway: &mut GatewayService,
+        min_version: Option<&GatewayVersion>,
+        logger: &Logger,
+    ) -> bool {
+        if min_version.is_none() {
+            return true;
+        }
+        let version = gateway.version().await;
+        Self::accepts_version(gateway, version, min_version, logger)
+    }
+
+    /// Whether the given version result of a gateway meets the given minimum
+    /// version
+    fn accepts_version(
+        gateway: &GatewayService,
+        version: Result<Option<u64>>,
+        min_version: Option<&GatewayVersion>,
+        logger: &Logger,
+    ) -> bool {
+        let min_version = match min_version {
+            Some(min_version) => min_version,
+            None => return true,
+        };
+        let version = match version {
+            Ok(Some(version)) => GatewayVersion::from(version),
+            Ok(None) => {
+                info!(logger, "skipping validator with unknown version";
+                    "pubkey" => gateway.uri.pubkey.to_string(),
+                    "uri" => gateway.uri.uri.to_string());
+                return false;
+            }
+            Err(err) => {
+                warn!(logger, "skipping validator, version error: {err:?}";
+                    "pubkey" => gateway.uri.pubkey.to_string(),
+                    "uri" => gateway.uri.uri.to_string());
+                return false;
+            }
+        };
+        if version < *min_version {
+            info!(logger, "skipping validator below minimum version";
+                "pubkey" => gateway.uri.pubkey.to_string(),
+                "uri" => gateway.uri.uri.to_string(),
+                "version" => version.to_string(),
+                "min_version" => min_version.to_string());
+            return false;
+        }
+        true
+    }
+
+    async fn setup_gateway_streams(
+        mut gateway: GatewayService,
+        setup: &StreamSetup,
+        logger: &Logger,
+    ) -> Result<(GatewayService, GatewayStreams)> {
+        let mut routing_gateway = gateway.clone();
+        let routing = routing_gateway.routing(setup.routing_height);
+        let region_params = gateway.region_params(setup.signer.clone());
+        match tokio::try_join!(routing, region_params) {
+            Ok((routing, region_params)) => {
+                let stream_map = StreamMap::from_iter([
+                    (
+                        GatewayStream::Routing,
+                        routing.with_verify_policy(setup.verify_policy),
+                    ),
+                    (
+                        GatewayStream::RegionParams,
+                        region_params.with_verify_policy(setup.verify_policy),
+                    ),
+                ]);
+                Ok((gateway, stream_map))
+            }
+            Err(err) => {
+                warn!(logger, "gateway stream setup error: {err:?} "; 
+                    "pubkey" => gateway.uri.pubkey.to_string(),
+                    "uri" => gateway.uri.uri.to_string());
+                Err(err)
+            }
+        }
+    }
+
+    async fn run_with_gateway(
+        &mut self,
+        mut gateway: GatewayService,
+        mut streams: GatewayStreams,
+        shutdown: triggered::Listener,
+        logger: &Logger,
+    ) -> Result<Reconnect> {
+        let connection_id = self.connections.next_id();
+        self.connection = Some((connection_id, gateway.uri.clone()));
+        let logger = &connection_logger(logger, connection_id, &gateway.uri);
+        for streaming in streams.values_mut() {
+            streaming.set_logger(logger.clone());
+        }
+        self.events
+            .publish(Event::GatewayConnected(connection_id, gateway.uri.clone()));
+        info!(logger, "using gateway";
+            "pubkey" => gateway.uri.pubkey.to_string(),
+            "uri" => gateway.uri.uri.to_string());
+        crash::set_subsystem_state(
+            "dispatcher",
+            format!(
+                "connection {connection_id} to validator {}",
+                gateway.uri.pubkey
+            ),
+        );
+        self.save_last_gateway(&gateway.uri, logger);
+
+        self.stream_updates.clear();
+        self.rpc_breaker.reset();
+        self.last_block = None;
+        // Initialize liveness check for gateway
+        let mut gateway_check = time::interval(GATEWAY_CHECK_INTERVAL);
+        let mut idle_check = time::interval(ROUTER_IDLE_CHECK_INTERVAL);
+        let park_idle = self.router_clients.park_idle;
+        let connected = self.clock.now();
+        let mut streams = GatewayStreamSet::new(streams);
+        let mut stream_probe = time::interval(self.stream_probe.unwrap_or(GATEWAY_CHECK_INTERVAL));
+        loop {
+            tokio::select! {
+                _ = shutdown.clone() => {
                     info!(logger, "shutting down");
-                    return Ok(())
+                    self.save_last_gateway(&gateway.uri, logger);
+                    // Let routers drain their queued uplinks while the
+                    // gateway streams are still open
+                    self.drain_routers(logger).await;
+                    return Ok(Reconnect::Reselect)
                 },
-                gateway_message = streams.next() => match gateway_message {
-                    Some((gateway_stream, Ok(gateway_message))) => match gateway_stream {
-                        GatewayStream::Routing => self.handle_routing_update(&gateway_message, &shutdown, logger).await,
-                        GatewayStream::RegionParams => self.handle_region_params_update(&gateway_message, logger).await,
-                    },
-                    Some((gateway_stream, Err(err))) =>  {
+                event = streams.next() => match event {
+                    StreamEvent::Message(gateway_stream, gateway_message) => {
+                        self.verify_failures.remove(&gateway.uri);
+                        let started = self.clock.now();
+                        self.stream_updates.insert(gateway_stream.clone(), started);
                         match gateway_stream {
-                            GatewayStream::Routing =>  warn!(logger, "gateway routing stream error: {err:?}"),
-                            GatewayStream::RegionParams =>  warn!(logger, "gateway region_params stream error: {err:?}"),
+                            GatewayStream::Routing => if let Some(build) = self.build_routing(&gateway_message, logger) {
+                                self.routing_builds.push(build)
+                            },
+                            GatewayStream::RegionParams => self.handle_region_params_update(&gateway_message, logger).await,
                         }
-                        return Ok(())
+                        self.record_stream_handling(gateway_stream, started, logger);
                     },
-                    None => {
+                    StreamEvent::Resubscribe(gateway_stream, err, attempt) => {
+                        warn!(logger, "gateway {gateway_stream} stream error: {err:?}");
+                        info!(logger, "resubscribing gateway stream";
+                            "stream" => gateway_stream.to_string(),
+                            "attempt" => attempt);
+                        streams.resubscribe(self.resubscribe(&gateway, gateway_stream, attempt, logger));
+                    },
+                    StreamEvent::Resubscribed(gateway_stream) => {
+                        info!(logger, "resubscribed gateway stream"; "stream" => gateway_stream.to_string());
+                    },
+                    StreamEvent::Failed(gateway_stream, err) => {
+                        warn!(logger, "gateway {gateway_stream} stream error: {err:?}");
+                        return Ok(self.stream_failed(&gateway.uri, &err, logger).await)
+                    },
+                    StreamEvent::Closed => {
                         warn!(logger, "gateway streams closed");
-                        return Ok(());
-                }
+                        if self.quarantine_validator(&gateway.uri, logger) {
+                            return Ok(Reconnect::Reselect);
+                        }
+                        return Ok(Reconnect::Retry(gateway.uri.clone()));
+                    },
                 },
                 _ = gateway_check.tick() => match self.check_gateway(&mut gateway, logger).await {
                     Ok(()) => {
-                        self.gateway_retry = 0
+                        self.gateway_retry = 0;
+                        self.retried_gateway = None;
+                        self.save_last_gateway(&gateway.uri, logger);
+                        self.log_status(logger)
                     },
                     Err(err) => {
                         warn!(logger, "gateway check error: {err}");
-                        return Ok(())
+                        return Ok(self.failed_gateway(&gateway.uri, &err, logger).await)
+                    }
+                },
+                Some(built) = self.routing_builds.next(), if !self.routing_builds.is_empty() => match built {
+                    Ok((height, routings)) => self.apply_routing(height, routings, &shutdown, logger).await,
+                    Err(err) => warn!(logger, "routing build error: {err:?}"),
+                },
+                _ = idle_check.tick(), if park_idle => self.park_idle_routers(logger).await,
+                _ = stream_probe.tick(), if self.stream_probe.is_some() => {
+                    if let Err(err) = self.probe_gateway(&mut gateway, connected, logger).await {
+                        warn!(logger, "gateway probe error: {err}");
+                        return Ok(self.failed_gateway(&gateway.uri, &err, logger).await)
                     }
                 },
                 message = self.messages.recv() => match message {
-                    Some(message) => self.handle_message(message, Some(&mut gateway.clone()), logger).await,
+                    Some(message) => self.handle_message(message, Some(&mut gateway.clone()), &shutdown, logger).await,
                     None => {
                         warn!(logger, "messages channel closed");
-                        return Ok(())
+                        return Ok(Reconnect::Reselect)
                     }
                 }
             }
         }
     }
 
+    /// Stores the given gateway as the last used one. Refreshed while the
+    /// gateway stays in use so a long running connection is still tried
+    /// first after a restart.
+    fn save_last_gateway(&self, gateway: &KeyedUri, logger: &Logger) {
+        if let Err(err) = LastGateway::save(self.store.as_ref(), gateway) {
+            warn!(logger, "failed to store last gateway: {err:?}");
+        }
+    }
+
+    /// Subscribes to the given gateway stream again after a wait that grows
+    /// with the given attempt. Routing is requested from the current routing
+    /// height.
+    fn resubscribe(
+        &self,
+        gateway: &GatewayService,
+        gateway_stream: GatewayStream,
+        attempt: u32,
+        logger: &Logger,
+    ) -> Resubscribe {
+        let mut gateway = gateway.clone();
+        let routing_height = self.routing_height;
+        let signer = self.signer.clone();
+        let verify_policy = self.verify_policy;
+        let logger = logger.clone();
+        Box::pin(async move {
+            time::sleep(STREAM_RESUBSCRIBE_WAIT * attempt).await;
+            let streaming = match gateway_stream {
+                GatewayStream::Routing => gateway.routing(routing_height).await,
+                GatewayStream::RegionParams => gateway.region_params(signer).await,
+            };
+            let streaming = streaming.map(|mut streaming| {
+                streaming.set_logger(logger);
+                streaming.with_verify_policy(verify_policy)
+            });
+            (gateway_stream, streaming)
+        })
+    }
+
+    /// Gives up on a gateway after one of its streams failed for good.
+    /// Verification failures are handled as by `failed_gateway`. Otherwise
+    /// the gateway is retried unless it was quarantined for its stream
+    /// failures.
+    async fn stream_failed(
+        &mut self,
+        gateway: &KeyedUri,
+        err: &Error,
+        logger: &Logger,
+    ) -> Reconnect {
+        let quarantined = self.quarantine_validator(gateway, logger);
+        if err.is_verification() {
+            return self.failed_gateway(gateway, err, logger).await;
+        }
+        if quarantined {
+            Reconnect::Reselect
+        } else {
+            Reconnect::Retry(gateway.clone())
+        }
+    }
+
+    /// Decides how to reconnect after the given gateway failed with the given
+    /// error. A gateway that repeatedly fails response verification is looked
+    /// up in case it rotated its key, and is retried until then.
+    async fn failed_gateway(
+        &mut self,
+        gateway: &KeyedUri,
+        err: &Error,
+        logger: &Logger,
+    ) -> Reconnect {
+        if !err.is_verification() {
+            return Reconnect::Reselect;
+        }
+        let failures = self.verify_failures.entry(gateway.clone()).or_default();
+        *failures += 1;
+        if *failures < GATEWAY_ROTATION_FAILURES {
+            info!(logger, "gateway failed verification";
+                "pubkey" => gateway.pubkey.to_string(),
+                "uri" => gateway.uri.to_string(),
+                "failures" => *failures);
+            return Reconnect::Retry(gateway.clone());
+        }
+        self.verify_failures.remove(gateway);
+        self.rotated_gateway(gateway, logger).await.into()
+    }
+
+    /// Records a stream failure of the given validator, quarantining it from
+    /// selection when it keeps dropping its streams. Returns whether the
+    /// validator was quarantined.
+    fn quarantine_validator(&self, gateway: &KeyedUri, logger: &Logger) -> bool {
+        let now = self.clock.now();
+        match self.health.record_stream_failure(&gateway.pubkey) {
+            Some(until) => {
+                warn!(logger, "quarantining validator for {}s", (until - now).as_secs();
+                    "pubkey" => gateway.pubkey.to_string(),
+                    "uri" => gateway.uri.to_string());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Looks up a gateway that failed response verification in a seed
+    /// validator listing. Returns the gateway with its new key if it rotated
+    /// its key while keeping the same uri.
+    async fn rotated_gateway(&self, gateway: &KeyedUri, logger: &Logger) -> Option<KeyedUri> {
+        let mut seed_gateway = GatewayService::select_seed(
+            &self.seed_gateways,
+            &self.resolver,
+            &self.health,
+            self.timeouts,
+            &self.tls,
+        )
+        .await
+        .ok()?;
+        let listing = match seed_gateway.validators(GATEWAY_LOOKUP_COUNT).await {
+            Ok(listing) => listing,
+            Err(err) => {
+                warn!(logger, "gateway key lookup error: {err:?}");
+                return None;
+            }
+        };
+        let rotated = listing.into_iter().find(|listed| {
+            listed.uri == gateway.uri
+                && listed.pubkey != gateway.pubkey
+                && self.validators.permits(&listed.pubkey)
+        })?;
+        info!(logger, "gateway key rotated";
+            "uri" => gateway.uri.to_string(),
+            "pubkey" => gateway.pubkey.to_string(),
+            "new_pubkey" => rotated.pubkey.to_string());
+        Some(rotated)
+    }
+
     async fn check_gateway(&mut self, gateway: &mut GatewayService, logger: &Logger) -> Result {
-        let (_, block_age) = gateway.height().await?;
-        info!(logger, "checking gateway"; 
+        // Not guarded by the rpc breaker, an open breaker would otherwise
+        // fail the check and drop a gateway whose streams may be fine
+        let block = gateway.height().await?;
+        let block = self.track_block(block);
+        info!(logger, "checking gateway";
             "pubkey" => gateway.uri.pubkey.to_string(),
-            "block_age" => block_age);
-        if block_age > GATEWAY_MAX_BLOCK_AGE.as_secs() {
+            "block_age" => block.block_age,
+            "blocks_per_sec" => block.blocks_per_sec);
+        for (rpc, p50, p95) in gateway.latencies().summary(&gateway.uri.pubkey) {
+            debug!(logger, "gateway rpc latency";
+                "rpc" => rpc.to_string(),
+                "p50_ms" => p50.as_millis() as u64,
+                "p95_ms" => p95.as_millis() as u64);
+        }
+        if block.block_age > GATEWAY_MAX_BLOCK_AGE.as_secs() {
             return Err(Error::gateway_service_check(
-                block_age,
+                block.block_age,
                 GATEWAY_MAX_BLOCK_AGE.as_secs(),
             ));
         }
         Ok(())
     }
 
+    /// Records the time spent handling a gateway stream message. Slow
+    /// handling holds up uplinks waiting in the dispatcher message channel.
+    fn record_stream_handling(
+        &mut self,
+        gateway_stream: GatewayStream,
+        started: Instant,
+        logger: &Logger,
+    ) {
+        let elapsed = self.clock.now().saturating_duration_since(started);
+        if elapsed > STREAM_HANDLING_WARN {
+            warn!(logger, "slow gateway stream message";
+                "stream" => gateway_stream.to_string(),
+                "elapsed_ms" => elapsed.as_millis() as u64);
+        }
+        self.stream_handling
+            .entry(gateway_stream)
+            .or_default()
+            .record(elapsed);
+    }
+
+    /// Sends a version request to the gateway if none of its streams has
+    /// received a message within the probe interval. Idle streams over a
+    /// connection that was dropped by a NAT are otherwise only noticed by the
+    /// next gateway check.
+    async fn probe_gateway(
+        &mut self,
+        gateway: &mut GatewayService,
+        connected: Instant,
+        logger: &Logger,
+    ) -> Result {
+        let probe_interval = match self.stream_probe {
+            Some(probe_interval) => probe_interval,
+            None => return Ok(()),
+        };
+        let last_update = self
+            .stream_updates
+            .values()
+            .max()
+            .copied()
+            .unwrap_or(connected);
+        if self.stream_lag(&last_update) < probe_interval {
+            return Ok(());
+        }
+        debug!(logger, "probing idle gateway connection");
+        gateway.version().await.map(|_| ())
+    }
+
+    /// Records the given block info from the current gateway, estimating the
+    /// block rate from the previously recorded one
+    fn track_block(&mut self, block: BlockInfo) -> BlockInfo {
+        let block = BlockInfo {
+            fetched_at: self.clock.now(),
+            ..block
+        };
+        let block = match &self.last_block {
+            Some(previous) => block.with_rate(previous),
+            None => block,
+        };
+        self.last_block = Some(block);
+        block
+    }
+
     async fn prepare_gateway_change(
         &mut self,
         backoff: &Backoff,
@@ -337,43 +1656,63 @@ impl Dispatcher {
             return;
         }
 
-        // Reset routing and region heigth for the next gateway
-        self.routing_height = 0;
-        self.region_height = 0;
+        // Routing and region heights are kept so the next gateway only sends
+        // newer updates, and a validator that is behind can not roll back
+        // the routing or region params
 
-        // Use backof to sleep exponentially longer
+        // Use backof to sleep exponentially longer, jittered so gateways that
+        // lost the same validator spread out their reconnects
         self.gateway_retry += 1;
         let sleep = backoff
             .next(self.gateway_retry)
             .unwrap_or(GATEWAY_BACKOFF_MAX_WAIT);
+        let sleep = jitter(sleep, self.validators.backoff_jitter).min(GATEWAY_BACKOFF_MAX_WAIT);
+
+        info!(logger, "selecting new gateway in {}s", sleep.as_secs());
+        crash::set_subsystem_state(
+            "dispatcher",
+            format!("backing off for {}s", sleep.as_secs()),
+        );
+        self.wait_gateway_change(sleep, shutdown, logger).await
+    }
 
+    /// Waits for the given time before connecting to a gateway
+    async fn wait_gateway_change(
+        &mut self,
+        sleep: Duration,
+        shutdown: triggered::Listener,
+        logger: &Logger,
+    ) {
         // Select over either shutdown or sleep, and handle messages that don't
         // require a gateway
-        info!(logger, "selecting new gateway in {}s", sleep.as_secs());
         tokio::select! {
-            _ = shutdown => {},
-            _ = time::sleep(sleep) => {}
+            _ = shutdown.clone() => {},
+            _ = self.clock.sleep(sleep) => {}
             message = self.messages.recv() => match message {
-                Some(message) => self.handle_message(message, None, logger).await,
+                Some(message) => self.handle_message(message, None, &shutdown, logger).await,
                 None => warn!(logger, "ignoring closed messages channel"),
             }
         }
     }
 
     async fn handle_message(
-        &self,
+        &mut self,
         message: Message,
         gateway: Option<&mut GatewayService>,
+        shutdown: &triggered::Listener,
         logger: &Logger,
     ) {
         match message {
             Message::Uplink {
                 packet,
                 received_time,
-            } => self.handle_uplink(&packet, received_time, logger).await,
+            } => {
+                self.handle_uplink(&packet, received_time, shutdown, logger)
+                    .await
+            }
             Message::Config { keys, response } => {
                 let reply = if let Some(gateway) = gateway {
-                    gateway.config(keys).await
+                    self.guarded(gateway.config(keys), logger).await
                 } else {
                     Err(Error::no_service())
                 };
@@ -381,14 +1720,16 @@ impl Dispatcher {
             }
             Message::Height { response } => {
                 let reply = if let Some(gateway) = gateway {
-                    let gateway_version = gateway.version().await.unwrap_or(None);
-                    gateway
-                        .height()
+                    let height = async {
+                        let gateway_version = gateway.version().await.unwrap_or(None);
+                        let block = gateway.height().await?;
+                        Ok::<_, Error>((gateway.uri.clone(), block, gateway_version))
+                    };
+                    self.guarded(height, logger)
                         .await
-                        .map(|(height, block_age)| HeightResponse {
-                            gateway: gateway.uri.clone(),
-                            height,
-                            block_age,
+                        .map(|(gateway, block, gateway_version)| HeightResponse {
+                            gateway,
+                            block: self.track_block(block),
                             gateway_version,
                         })
                 } else {
@@ -397,30 +1738,455 @@ impl Dispatcher {
                 response.send(reply, logger)
             }
             Message::Region { response } => response.send(Ok(self.region), logger),
+            Message::Status { response } => response.send(self.status(), logger),
+            Message::Snapshot { response } => {
+                // Snapshots are taken periodically, which bounds the usage
+                // lost when the gateway stops without draining the routers
+                self.save_usage(logger);
+                response.send(self.snapshot(), logger)
+            }
         }
     }
 
-    async fn handle_uplink(&self, packet: &Packet, received: Instant, logger: &Logger) {
-        let mut handled = false;
-        for router_entry in self.routers.values() {
-            if router_entry.routing.matches_routing_info(packet.routing()) {
-                match router_entry.dispatch.uplink(packet.clone(), received).await {
-                    Ok(()) => (),
-                    Err(err) => warn!(logger, "ignoring router dispatch error: {err:?}"),
-                }
-                handled = true;
+    /// Runs a gateway rpc unless the rpc circuit breaker is open, so a
+    /// failing gateway does not hold up the dispatcher with rpcs that time
+    /// out.
+    async fn guarded<T>(
+        &mut self,
+        rpc: impl std::future::Future<Output = Result<T>>,
+        logger: &Logger,
+    ) -> Result<T> {
+        self.rpc_breaker.check(self.clock.now())?;
+        let result = rpc.await;
+        if self.rpc_breaker.record(&result, self.clock.now()) {
+            warn!(
+                logger,
+                "gateway rpcs failing, pausing for {}s",
+                RPC_BREAKER_OPEN_PERIOD.as_secs()
+            );
+        }
+        result
+    }
+
+    fn status(&self) -> DispatcherStatus {
+        let routers = self
+            .routers
+            .iter()
+            .map(|(router_key, router_entry)| RouterStatus {
+                oui: router_key.oui,
+                uri: router_key.uri.clone(),
+                queue_depth: router_entry.queue_depth(),
+            })
+            .collect();
+        let streams = self
+            .stream_updates
+            .iter()
+            .map(|(stream, updated)| (stream.to_string(), self.stream_lag(updated)))
+            .collect();
+        DispatcherStatus {
+            queue_depth: 0,
+            routers,
+            streams,
+        }
+    }
+
+    fn snapshot(&self) -> DispatcherSnapshot {
+        let routers = self
+            .routers
+            .iter()
+            .map(|(router_key, router_entry)| RouterSnapshot {
+                oui: router_key.oui,
+                uri: router_key.uri.uri.to_string(),
+                pubkey: router_key.uri.pubkey.to_string(),
+                admission: self
+                    .router_qos
+                    .admission(&router_key.uri)
+                    .describe(self.clock.now()),
+                running: router_entry.client.is_some(),
+                queue_depth: router_entry.queue_depth(),
+                throttled_downlinks: self.router_qos.stats(&router_key.uri).throttled(),
+            })
+            .collect();
+        let streams = self
+            .stream_updates
+            .iter()
+            .map(|(stream, updated)| (stream.to_string(), self.stream_lag(updated).as_secs()))
+            .collect();
+        DispatcherSnapshot {
+            taken: self
+                .clock
+                .system_time()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |taken| taken.as_secs()),
+            queue_depth: 0,
+            region: self.region.to_string(),
+            routing_height: self.routing_height,
+            region_height: self.region_height,
+            gateway_retry: self.gateway_retry,
+            connection_id: self.connection.as_ref().map(|(id, _)| *id),
+            default_routers: self
+                .default_routers
+                .iter()
+                .flatten()
+                .map(|uri| uri.uri.to_string())
+                .collect(),
+            routers,
+            streams,
+            stream_handling: self
+                .stream_handling
+                .iter()
+                .map(|(stream, histogram)| (stream.to_string(), histogram.buckets()))
+                .collect(),
+            validators: self.health.scores(),
+            usage: self.usage.buckets(self.clock.system_time()),
+            signatures: self.keypair.signing_audit(),
+            build: settings::build_info(),
+            traces: self
+                .traces
+                .iter()
+                .map(|trace| TraceSnapshot {
+                    id: trace.id,
+                    hash: trace.hash.to_b64(),
+                    age: self.stream_lag(&trace.received).as_secs(),
+                    decisions: trace.decisions.iter().map(|d| d.to_string()).collect(),
+                })
+                .collect(),
+        }
+    }
+
+    fn stream_lag(&self, updated: &Instant) -> Duration {
+        self.clock.now().saturating_duration_since(*updated)
+    }
+
+    fn log_status(&self, logger: &Logger) {
+        let status = self.status();
+        for router in &status.routers {
+            debug!(logger, "router status";
+                "oui" => router.oui,
+                "uri" => router.uri.uri.to_string(),
+                "queue_depth" => router.queue_depth);
+        }
+        for (stream, lag) in &status.streams {
+            debug!(logger, "stream status";
+                "stream" => stream,
+                "lag_secs" => lag.as_secs());
+        }
+        for (stream, histogram) in &self.stream_handling {
+            let buckets: Vec<String> = histogram
+                .buckets()
+                .iter()
+                .map(|(bucket, count)| format!("{bucket}={count}"))
+                .collect();
+            debug!(logger, "stream handling";
+                "stream" => stream.to_string(),
+                "count" => histogram.count(),
+                "max_ms" => histogram.max().as_millis() as u64,
+                "buckets" => buckets.join(","));
+        }
+    }
+
+    async fn handle_uplink(
+        &mut self,
+        packet: &Packet,
+        received: Instant,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) {
+        self.update_admissions(logger);
+        let now = self.clock.now();
+        // When an oui has multiple matching router uris only the best
+        // performing one receives the packet
+        let id = self.packets.next_id();
+        // Packets are only hashed and their decisions recorded when tracing
+        // is enabled
+        let mut trace = self
+            .traces
+            .is_enabled()
+            .then(|| PacketTrace::new(id, packet, received));
+        let mut selected: HashMap<u32, &RouterKey> = HashMap::new();
+        for (router_key, router_entry) in &self.routers {
+            if !router_entry.routing.matches_routing_info(packet.routing()) {
+                continue;
+            }
+            if !self.router_qos.is_admitted(&router_key.uri, now) {
+                PacketTrace::record_to(&mut trace, || Decision::Quarantined {
+                    oui: router_key.oui,
+                    uri: router_key.uri.clone(),
+                });
+                continue;
             }
+            let outranked = match selected.get_mut(&router_key.oui) {
+                Some(best) if self.router_qos.is_better(&router_key.uri, &best.uri) => {
+                    std::mem::replace(best, router_key)
+                }
+                Some(_) => router_key,
+                None => {
+                    selected.insert(router_key.oui, router_key);
+                    continue;
+                }
+            };
+            PacketTrace::record_to(&mut trace, || Decision::Outranked {
+                oui: outranked.oui,
+                uri: outranked.uri.clone(),
+            });
         }
+        let mut selected: Vec<RouterKey> = selected.into_values().cloned().collect();
+        let handled = !selected.is_empty();
         if !handled {
             if let Some(default_routers) = &self.default_routers {
-                for (router_key, router_entry) in &self.routers {
-                    if default_routers.contains(&router_key.uri) {
-                        debug!(logger, "sending to default router");
-                        let _ = router_entry.dispatch.uplink(packet.clone(), received).await;
-                    }
+                selected = self
+                    .routers
+                    .iter()
+                    .filter(|(router_key, _)| {
+                        default_routers.contains(&router_key.uri)
+                            && self.router_qos.is_admitted(&router_key.uri, now)
+                    })
+                    .map(|(router_key, _)| router_key.clone())
+                    .collect();
+            }
+            for router_key in &selected {
+                debug!(logger, "sending to default router");
+                PacketTrace::record_to(&mut trace, || Decision::DefaultRouter {
+                    uri: router_key.uri.clone(),
+                });
+            }
+            if let Some(trace) = trace.as_mut().filter(|trace| trace.decisions.is_empty()) {
+                trace.record(Decision::Unrouted);
+            }
+        }
+        for router_key in &selected {
+            self.wake_router(router_key, shutdown, logger).await;
+        }
+        let mut delivered = vec![];
+        // Dispatch concurrently so a full router channel does not delay the
+        // packet for routers of other ouis
+        let dispatches = selected
+            .iter()
+            .filter_map(|router_key| self.routers.get_key_value(router_key))
+            .map(|(router_key, router_entry)| async move {
+                let result = router_entry.uplink(packet.clone(), received).await;
+                (router_key, result)
+            });
+        let results: Vec<_> =
+            futures::StreamExt::buffer_unordered(stream::iter(dispatches), UPLINK_FANOUT_LIMIT)
+                .collect()
+                .await;
+        for (router_key, result) in results {
+            if result.is_ok() {
+                self.router_qos.packet_sent(&router_key.uri, now);
+                delivered.push(router_key.oui);
+            }
+            match result {
+                Ok(()) if !handled => (),
+                Ok(()) => PacketTrace::record_to(&mut trace, || Decision::Matched {
+                    oui: router_key.oui,
+                    uri: router_key.uri.clone(),
+                }),
+                Err(err) => {
+                    warn!(logger, "ignoring router dispatch error: {err:?}");
+                    PacketTrace::record_to(&mut trace, || Decision::DispatchError {
+                        oui: router_key.oui,
+                        uri: router_key.uri.clone(),
+                        error: err.to_string(),
+                    })
                 }
             }
         }
+        if let Some(trace) = trace {
+            let decisions: Vec<String> = trace.decisions.iter().map(|d| d.to_string()).collect();
+            debug!(logger, "packet trace";
+                "id" => trace.id.to_string(),
+                "hash" => trace.hash.to_b64(),
+                "decisions" => decisions.join(", "));
+            self.traces.push(trace);
+        }
+        self.mirror_uplink(id, packet, &delivered, logger);
+    }
+
+    /// Mirrors the metadata of the given uplink if it matches the routing of
+    /// the mirrored oui. The mirror is removed once it has expired.
+    fn mirror_uplink(
+        &mut self,
+        id: SequenceId,
+        packet: &Packet,
+        delivered: &[u32],
+        logger: &Logger,
+    ) {
+        let mirror = match &self.mirror {
+            Some(mirror) if mirror.is_expired(self.clock.system_time()) => {
+                info!(logger, "packet mirror expired"; "oui" => mirror.oui);
+                self.mirror = None;
+                return;
+            }
+            Some(mirror) => mirror,
+            None => return,
+        };
+        let matched = self.routers.iter().any(|(router_key, router_entry)| {
+            router_key.oui == mirror.oui
+                && router_entry.routing.matches_routing_info(packet.routing())
+        });
+        if !matched {
+            return;
+        }
+        if let Err(err) = mirror.mirror(id, packet, delivered.contains(&mirror.oui)) {
+            debug!(logger, "failed to mirror packet: {err:?}"; "oui" => mirror.oui);
+        }
+    }
+
+    /// Records an uplink for the given router, starting its client if the
+    /// router is parked
+    async fn wake_router(
+        &mut self,
+        router_key: &RouterKey,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) {
+        let now = self.clock.now();
+        match self.routers.get_mut(router_key) {
+            Some(router_entry) => {
+                router_entry.last_uplink = now;
+                if router_entry.client.is_some() {
+                    return;
+                }
+            }
+            None => return,
+        }
+        // Uplinks always start the routers they need, even when no idle
+        // router can be stopped to stay within the limit
+        if !self.make_room(logger).await {
+            debug!(logger, "running router limit exceeded";
+                "max_running" => self.router_clients.max_running);
+        }
+        info!(logger, "starting parked router";
+            "oui" => router_key.oui,
+            "uri" => router_key.uri.uri.to_string());
+        match self
+            .start_client(shutdown.clone(), router_key.oui, router_key.uri.clone())
+            .await
+        {
+            Ok(client) => {
+                if let Some(router_entry) = self.routers.get_mut(router_key) {
+                    router_entry.client = Some(client);
+                }
+            }
+            Err(err) => warn!(logger, "failed to start router: {err:?}"),
+        }
+    }
+
+    /// Parks the least recently used idle router if the running router limit
+    /// is reached. Returns whether another router can be started within the
+    /// limit.
+    async fn make_room(&mut self, logger: &Logger) -> bool {
+        let max_running = self.router_clients.max_running;
+        let running = self
+            .routers
+            .values()
+            .filter(|router_entry| router_entry.client.is_some())
+            .count();
+        if max_running == 0 || running < max_running {
+            return true;
+        }
+        match self.idle_router() {
+            Some(router_key) => {
+                self.park_router(&router_key, logger).await;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the least recently used running router that has not had an
+    /// uplink for at least the idle timeout
+    fn idle_router(&self) -> Option<RouterKey> {
+        let now = self.clock.now();
+        let idle_timeout = Duration::from_secs(self.router_clients.idle_timeout);
+        self.routers
+            .iter()
+            .filter(|(_, router_entry)| {
+                router_entry.client.is_some()
+                    && now.saturating_duration_since(router_entry.last_uplink) >= idle_timeout
+            })
+            .min_by_key(|(_, router_entry)| router_entry.last_uplink)
+            .map(|(router_key, _)| router_key.clone())
+    }
+
+    /// Parks all routers that have not had an uplink for the idle timeout
+    async fn park_idle_routers(&mut self, logger: &Logger) {
+        while let Some(router_key) = self.idle_router() {
+            self.park_router(&router_key, logger).await;
+        }
+    }
+
+    /// Stops the client of the given router while keeping its routing entry
+    async fn park_router(&mut self, router_key: &RouterKey, logger: &Logger) {
+        let client = self
+            .routers
+            .get_mut(router_key)
+            .and_then(|router_entry| router_entry.client.take());
+        if let Some(client) = client {
+            info!(logger, "parking idle router";
+                "oui" => router_key.oui,
+                "uri" => router_key.uri.uri.to_string());
+            client.dispatch.stop().await;
+        }
+    }
+
+    /// Waits for the running router clients to stop. Router clients drain
+    /// their queued uplinks once shutdown is triggered.
+    async fn drain_routers(&mut self, logger: &Logger) {
+        let (router_keys, clients): (Vec<RouterKey>, Vec<RunningRouter>) = self
+            .routers
+            .iter_mut()
+            .filter_map(|(router_key, router_entry)| {
+                router_entry
+                    .client
+                    .take()
+                    .map(|client| (router_key.clone(), client))
+            })
+            .unzip();
+        if clients.is_empty() {
+            return;
+        }
+        info!(logger, "draining routers"; "count" => clients.len());
+        crash::set_subsystem_state("dispatcher", format!("draining {} routers", clients.len()));
+        let results = future::join_all(clients).await;
+        for (router_key, result) in router_keys.into_iter().zip(results) {
+            match result {
+                Ok(Ok(packets)) if packets.is_empty() => (),
+                Ok(Ok(packets)) => self
+                    .undelivered
+                    .entry(router_key)
+                    .or_default()
+                    .extend(packets),
+                Ok(Err(err)) => warn!(logger, "router stopped with error: {err:?}"),
+                Err(err) => warn!(logger, "router task failed: {err:?}"),
+            }
+        }
+    }
+
+    /// Quarantines routers that reject most of the packets they are sent, and
+    /// puts routers whose quarantine period has passed on probation
+    fn update_admissions(&mut self, logger: &Logger) {
+        let now = self.clock.now();
+        for router_key in self.routers.keys() {
+            let uri = &router_key.uri;
+            match self.router_qos.update_admission(uri, now, &self.quarantine) {
+                Some(Admission::Probation { remaining }) => {
+                    info!(logger, "router on probation";
+                        "oui" => router_key.oui,
+                        "uri" => uri.uri.to_string(),
+                        "packets" => remaining,
+                        "limit" => self.quarantine.probation_limit)
+                }
+                Some(Admission::Quarantined { .. }) => {
+                    warn!(logger, "quarantining router";
+                        "oui" => router_key.oui,
+                        "uri" => uri.uri.to_string(),
+                        "period" => self.quarantine.period)
+                }
+                _ => (),
+            }
+        }
     }
 
     async fn handle_region_params_update<R: service::gateway::Response>(
@@ -430,7 +2196,13 @@ impl Dispatcher {
     ) {
         let update_height = response.height();
         let current_height = self.region_height;
-        if update_height <= self.region_height {
+        // The region params stream starts with the current params, which a
+        // reconnect to a validator at the same height repeats
+        if update_height == self.region_height {
+            debug!(logger, "region_params unchanged at height {update_height}");
+            return;
+        }
+        if update_height < self.region_height {
             warn!(
                 logger,
                 "region_params returned invalid height {update_height} while at {current_height}"
@@ -446,14 +2218,9 @@ impl Dispatcher {
                     "region" => self.region,
                     "height" => update_height
                 );
-                // Tell downlink handler
-                self.downlinks
-                    .region_params_changed(region_params.clone())
-                    .await;
-                // Tell routers about it
-                for router_entry in self.routers.values() {
-                    router_entry.dispatch.region_changed(self.region).await;
-                }
+                // Tell the downlink handler and routers
+                self.events
+                    .publish(Event::RegionParamsChanged(region_params, update_height));
             }
             Err(err) => {
                 warn!(logger, "error decoding region: {err:?}");
@@ -461,12 +2228,15 @@ impl Dispatcher {
         }
     }
 
-    async fn handle_routing_update<R: service::gateway::Response>(
-        &mut self,
+    /// Starts parsing the given routing update on a blocking thread, since
+    /// compiling the filters of a large routing table would otherwise hold up
+    /// uplinks. Uplinks keep using the current routing until the parsed
+    /// update is applied.
+    fn build_routing<R: service::gateway::Response>(
+        &self,
         response: &R,
-        shutdown: &triggered::Listener,
         logger: &Logger,
-    ) {
+    ) -> Option<RoutingBuild> {
         let update_height = response.height();
         let current_height = self.routing_height;
         if update_height <= self.routing_height {
@@ -474,109 +2244,208 @@ impl Dispatcher {
                 logger,
                 "routing returned invalid height {update_height} while at {current_height}",
             );
-            return;
+            return None;
         }
         let routing_protos = match response.routings() {
-            Ok(v) => v,
+            Ok(v) => v.to_vec(),
             Err(err) => {
                 warn!(logger, "error decoding routing {err:?}");
-                return;
+                return None;
             }
         };
-        let mut proto_stream = tokio_stream::iter(routing_protos.iter());
-        while let Some(proto) = proto_stream.next().await {
-            match Routing::from_proto(logger, proto) {
-                Ok(routing) => {
-                    self.handle_oui_routing_update(&routing, shutdown, logger)
-                        .await
-                }
-                Err(err) => warn!(logger, "failed to parse routing: {err:?}"),
-            }
+        let logger = logger.clone();
+        Some(tokio::task::spawn_blocking(move || {
+            let routings = routing_protos
+                .into_iter()
+                .filter_map(|proto| {
+                    Routing::from_proto(&logger, &proto)
+                        .map_err(|err| warn!(logger, "failed to parse routing: {err:?}"))
+                        .map(|routing| (proto, routing))
+                        .ok()
+                })
+                .collect();
+            (update_height, routings)
+        }))
+    }
+
+    /// Applies a parsed routing update. The routers of all ouis in the
+    /// update are swapped in one step, with new routers added parked, before
+    /// anything is awaited. The new routers are then started within the
+    /// running router limit, and the removed ones stopped.
+    async fn apply_routing(
+        &mut self,
+        update_height: u64,
+        routings: Vec<(helium_proto::Routing, Routing)>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) {
+        // Checked again since updates may have been queued before an earlier
+        // one was applied
+        if update_height <= self.routing_height {
+            return;
+        }
+        let mut added = vec![];
+        let mut removed = vec![];
+        for (proto, routing) in routings {
+            let (oui_added, oui_removed) = self.swap_oui_routing(&routing, logger);
+            added.extend(oui_added);
+            removed.extend(oui_removed);
+            self.routing_protos.insert(routing.oui, proto);
         }
         self.routing_height = update_height;
-        info!(logger, "updated routing to height {:?}", update_height)
+        info!(logger, "updated routing to height {:?}", update_height);
+        let saved = RoutingHandoff::new(self.routing_height, self.routing_protos.values())
+            .and_then(|table| table.save_table(self.store.as_ref()));
+        if let Err(err) = saved {
+            warn!(logger, "failed to store routing table: {err:?}");
+        }
+        self.start_stop_routers(added, removed, shutdown, logger)
+            .await;
     }
 
-    #[allow(clippy::map_entry)]
     async fn handle_oui_routing_update(
         &mut self,
         routing: &Routing,
         shutdown: &triggered::Listener,
         logger: &Logger,
     ) {
-        let mut uris = tokio_stream::iter(routing.uris.iter());
-        while let Some(uri) = uris.next().await {
+        let (added, removed) = self.swap_oui_routing(routing, logger);
+        self.start_stop_routers(added, removed, shutdown, logger)
+            .await;
+    }
+
+    /// Replaces the routers of the oui of the given routing without awaiting
+    /// anything. Routers new to the oui are added parked. Returns the keys of
+    /// the added routers and the clients of the removed ones, which are
+    /// expected to be started and stopped by the caller.
+    #[allow(clippy::map_entry)]
+    fn swap_oui_routing(
+        &mut self,
+        routing: &Routing,
+        logger: &Logger,
+    ) -> (Vec<RouterKey>, Vec<router::client::MessageSender>) {
+        let previous = self
+            .routers
+            .iter()
+            .find(|(key, _)| key.oui == routing.oui)
+            .map(|(_, entry)| &entry.routing);
+        let diff = routing.diff(previous);
+        if !diff.is_empty() {
+            log_routing_diff(&diff, logger);
+            self.events.publish(Event::RoutingChanged(diff));
+        }
+        let now = self.clock.now();
+        let mut added = vec![];
+        for uri in &routing.uris {
             let key = RouterKey {
                 oui: routing.oui,
                 uri: uri.to_owned(),
             };
             // We have to allow clippy::map_entry above since we need to borrow
             // immutable before borrowing as mutable to insert
-            if !self.routers.contains_key(&key) {
-                match self
-                    .start_router(shutdown.clone(), routing.clone(), uri.clone())
-                    .await
-                {
-                    Ok(router_entry) => {
-                        self.routers.insert(key, router_entry);
-                    }
-                    Err(err) => {
-                        warn!(logger, "faild to construct router: {err:?}");
-                    }
-                }
+            if let Some(router_entry) = self.routers.get_mut(&key) {
+                router_entry.routing = routing.clone();
+            } else {
+                let router_entry = RouterEntry {
+                    routing: routing.clone(),
+                    client: None,
+                    last_uplink: now,
+                };
+                self.routers.insert(key.clone(), router_entry);
+                added.push(key);
             }
         }
         // Remove any routers that are not in the new oui uri list
-        let mut removables = Vec::with_capacity(self.routers.len());
+        let mut removed = Vec::with_capacity(self.routers.len());
         self.routers.retain(|key, entry| {
-            if key.oui == routing.oui && !entry.routing.contains_uri(&key.uri) {
+            if key.oui == routing.oui && !routing.contains_uri(&key.uri) {
                 // Router will be removed from the map. The router is expected
                 // to stop itself when it receives the stop message
                 info!(logger, "removing router";
                     "oui" => key.oui,
                     "uri" => key.uri.uri.to_string()
                 );
-                removables.push(entry.dispatch.clone());
+                if let Some(client) = &entry.client {
+                    removed.push(client.dispatch.clone());
+                }
                 return false;
             }
             true
         });
-        for removable in removables {
+        (added, removed)
+    }
+
+    /// Stops the given removed router clients and starts the given added
+    /// routers. Added routers that do not fit within the running router
+    /// limit, or fail to start, stay parked and are started on their first
+    /// uplink.
+    async fn start_stop_routers(
+        &mut self,
+        added: Vec<RouterKey>,
+        removed: Vec<router::client::MessageSender>,
+        shutdown: &triggered::Listener,
+        logger: &Logger,
+    ) {
+        for removable in removed {
             removable.stop().await;
         }
+        for router_key in added {
+            if !self.make_room(logger).await {
+                continue;
+            }
+            match self
+                .start_client(shutdown.clone(), router_key.oui, router_key.uri.clone())
+                .await
+            {
+                Ok(client) => {
+                    if let Some(router_entry) = self.routers.get_mut(&router_key) {
+                        router_entry.client = Some(client);
+                    }
+                }
+                Err(err) => warn!(logger, "failed to start router: {err:?}"),
+            }
+        }
     }
 
-    async fn start_router(
+    async fn start_client(
         &self,
         shutdown: triggered::Listener,
-        routing: Routing,
+        oui: u32,
         uri: KeyedUri,
-    ) -> Result<RouterEntry> {
+    ) -> Result<RunningRouter> {
         // We start the router scope at the root logger to avoid picking up the
         // previously set KV pairs (which causes dupes)
         let logger = slog_scope::logger();
-        let (client_tx, client_rx) = router::client::message_channel(10);
+        let (client_tx, client_rx) =
+            router::client::message_channel(router::client::MESSAGE_QUEUE_SIZE);
         let mut client = RouterClient::new(
-            routing.oui,
+            oui,
             self.region,
             uri,
             self.downlinks.clone(),
-            self.keypair.clone(),
+            self.signer.clone(),
             self.cache_settings.clone(),
+            self.store.clone(),
+            self.router_qos.clone(),
+            self.usage.clone(),
+            self.resolver.clone(),
+            self.timeouts,
+            self.router_clients.downlink_limit,
         )
         .await?;
+        client.set_connection(self.connection.clone());
+        let events = self.events.subscribe();
         let join_handle =
-            tokio::spawn(async move { client.run(client_rx, shutdown, &logger).await });
-        Ok(RouterEntry {
-            routing,
+            tokio::spawn(async move { client.run(client_rx, events, shutdown, &logger).await });
+        Ok(RunningRouter {
             dispatch: client_tx,
             join_handle,
         })
     }
 }
 
-impl std::future::Future for RouterEntry {
-    type Output = std::result::Result<Result, tokio::task::JoinError>;
+impl std::future::Future for RunningRouter {
+    type Output = std::result::Result<Result<Vec<Packet>>, tokio::task::JoinError>;
 
     fn poll(
         mut self: Pin<&mut Self>,
@@ -585,3 +2454,942 @@ impl std::future::Future for RouterEntry {
         Pin::new(&mut self.join_handle).poll(cxt)
     }
 }
+
+fn log_routing_diff(diff: &RoutingDiff, logger: &Logger) {
+    let uris = |uris: &[KeyedUri]| {
+        uris.iter()
+            .map(|uri| uri.uri.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
+    };
+    info!(logger, "routing changed";
+        "oui" => diff.oui,
+        "added" => uris(&diff.added),
+        "removed" => uris(&diff.removed),
+        "filters" => format!("{} -> {}", diff.filters.0, diff.filters.1),
+        "subnets" => format!("{} -> {}", diff.subnets.0, diff.subnets.1));
+}
+
+/// Tags log lines emitted while attached to a gateway with a short connection
+/// id and the gateway pubkey prefix, so interleaved logs around a gateway
+/// change can be attributed to the right connection.
+pub fn connection_logger(logger: &Logger, connection_id: SequenceId, uri: &KeyedUri) -> Logger {
+    let pubkey = uri.pubkey.to_string();
+    let prefix: String = pubkey.chars().take(CONNECTION_PUBKEY_PREFIX).collect();
+    logger.new(o!(
+        "conn" => connection_id.to_string(),
+        "validator" => prefix,
+    ))
+}
+
+/// Whether a gateway stream that failed with the given error is subscribed
+/// to again after the given number of attempts. Verification failures are
+/// not retried since the gateway may have rotated its key, and validator
+/// errors that describe the request would fail the same way again.
+fn resubscribes_stream(err: &Error, attempts: u32) -> bool {
+    !err.is_verification() && err.is_retryable() && attempts < STREAM_RESUBSCRIBE_RETRIES
+}
+
+/// Races connecting to candidates picked from the given list with the given
+/// selection, up to the given number at a time, and returns the first that
+/// connects. Further rounds are run with the remaining candidates until one
+/// connects. Returns the selection error once no candidate can be picked.
+async fn race_candidates<G, T, F>(
+    mut candidates: Vec<KeyedUri>,
+    race_count: usize,
+    mut select: impl FnMut(&[KeyedUri]) -> Result<(KeyedUri, G)>,
+    connect: impl Fn(G) -> F,
+    logger: &Logger,
+) -> Result<T>
+where
+    F: std::future::Future<Output = Result<T>>,
+{
+    loop {
+        let mut racers = Vec::with_capacity(race_count);
+        while racers.len() < race_count {
+            match select(&candidates) {
+                Ok((uri, racer)) => {
+                    candidates.retain(|candidate| candidate != &uri);
+                    racers.push(Box::pin(connect(racer)));
+                }
+                Err(err) if racers.is_empty() => return Err(err),
+                Err(_) => break,
+            }
+        }
+        match future::select_ok(racers).await {
+            Ok((connected, _)) => return Ok(connected),
+            Err(err) => info!(logger, "no candidate validator connected: {err:?}"),
+        }
+    }
+}
+
+/// Randomly lengthens or shortens the given wait by up to the given
+/// percentage of it
+fn jitter(wait: Duration, percent: u8) -> Duration {
+    if percent == 0 {
+        return wait;
+    }
+    let percent = f64::from(percent.min(100)) / 100.0;
+    wait.mul_f64(1.0 + rand::thread_rng().gen_range(-percent..=percent))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        clock::{self, MockClock},
+        error::RegionError,
+        keypair::test_pubkey,
+    };
+    use helium_crypto::{KeyTag, KeyType, Network};
+    use rand::rngs::OsRng;
+    use rust_decimal::Decimal;
+    use std::{cell::RefCell, collections::VecDeque};
+
+    /// A scripted gateway stream response
+    struct MockResponse {
+        height: u64,
+        region_params: Option<RegionParams>,
+    }
+
+    impl service::gateway::Response for MockResponse {
+        fn height(&self) -> u64 {
+            self.height
+        }
+
+        fn routings(&self) -> Result<&[helium_proto::Routing]> {
+            Ok(&[])
+        }
+
+        fn region_params(&self) -> Result<RegionParams> {
+            self.region_params
+                .clone()
+                .ok_or_else(RegionError::no_region_params)
+        }
+    }
+
+    fn logger() -> Logger {
+        Logger::root(slog::Discard, o!())
+    }
+
+    fn region(region: helium_proto::Region) -> Region {
+        Region::from_i32(region.into()).expect("region")
+    }
+
+    /// Constructs a dispatcher without any gateway or router connections. The
+    /// returned senders and receivers need to be kept alive for the duration
+    /// of a test since the dispatcher treats closed channels as shutdown
+    /// conditions.
+    fn dispatcher() -> (Dispatcher, MessageSender, gateway::MessageReceiver) {
+        let keypair = helium_crypto::Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let (messages_tx, messages) = message_channel(10);
+        let (downlinks, downlinks_rx) = gateway::message_channel(10);
+        let store: Arc<dyn Store> = Arc::new(storage::MemoryStore::default());
+        let keypair: Arc<Keypair> = Arc::new(keypair.into());
+        let dispatcher = Dispatcher {
+            keypair: keypair.clone(),
+            signer: RequestSigner::from(keypair),
+            region: region(helium_proto::Region::Us915),
+            messages,
+            downlinks,
+            seeds: vec![],
+            seed_gateways: vec![],
+            routing_height: 0,
+            region_height: 0,
+            cache_settings: CacheSettings {
+                max_packets: 10,
+                store: std::env::temp_dir().join("dispatcher_test"),
+                read_only: false,
+                runtime_dir: None,
+            },
+            store: store.clone(),
+            gateway_retry: 0,
+            retried_gateway: None,
+            connections: Sequence::new(store.clone(), "connection"),
+            connection: None,
+            packets: Sequence::new(store.clone(), "packet"),
+            routers: HashMap::new(),
+            routing_protos: HashMap::new(),
+            routing_builds: FuturesOrdered::new(),
+            undelivered: HashMap::new(),
+            restart: Arc::default(),
+            default_routers: None,
+            health: HealthTracker::default(),
+            validators: ValidatorSettings::default(),
+            min_validator_version: None,
+            verify_failures: HashMap::new(),
+            router_qos: RouterQos::default(),
+            resolver: Resolver::default(),
+            timeouts: TimeoutSettings::default(),
+            tls: TlsSettings::default(),
+            quarantine: QuarantineSettings {
+                period: 3600,
+                probation: 10,
+                probation_limit: 60,
+            },
+            router_clients: RouterClientSettings::default(),
+            verify_policy: VerifyPolicy::Strict,
+            last_block: None,
+            rpc_breaker: CircuitBreaker::new(RPC_BREAKER_THRESHOLD, RPC_BREAKER_OPEN_PERIOD),
+            stream_updates: HashMap::new(),
+            stream_handling: HashMap::new(),
+            stream_probe: None,
+            events: EventBus::new(EVENT_BUS_SIZE),
+            traces: PacketTraces::default(),
+            mirror: None,
+            usage: OuiUsage::new(store),
+            clock: clock::system(),
+        };
+        (dispatcher, messages_tx, downlinks_rx)
+    }
+
+    #[tokio::test]
+    async fn routing_height_regression() {
+        let logger = logger();
+        let (_trigger, shutdown) = triggered::trigger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        for (height, expected) in [(10, 10), (5, 10), (10, 10), (11, 11)] {
+            let response = MockResponse {
+                height,
+                region_params: None,
+            };
+            if let Some(build) = dispatcher.build_routing(&response, &logger) {
+                let (height, routings) = build.await.expect("routing build");
+                dispatcher
+                    .apply_routing(height, routings, &shutdown, &logger)
+                    .await;
+            }
+            assert_eq!(expected, dispatcher.routing_height);
+        }
+    }
+
+    /// Constructs a running router entry for an oui 1 router on the given
+    /// local port
+    fn test_router(dispatcher: &Dispatcher, port: u16) -> (RouterKey, RouterEntry) {
+        let routing = Routing::from_proto(
+            &logger(),
+            &helium_proto::Routing {
+                oui: 1,
+                ..Default::default()
+            },
+        )
+        .expect("routing");
+        let uri = KeyedUri {
+            uri: format!("http://127.0.0.1:{port}").parse().expect("uri"),
+            pubkey: Arc::new(dispatcher.keypair.public_key().clone()),
+        };
+        let (dispatch, _dispatch_rx) = router::client::message_channel(1);
+        let router_entry = RouterEntry {
+            routing,
+            client: Some(RunningRouter {
+                dispatch,
+                join_handle: tokio::spawn(async { Ok(vec![]) }),
+            }),
+            last_uplink: dispatcher.clock.now(),
+        };
+        (RouterKey { oui: 1, uri }, router_entry)
+    }
+
+    #[tokio::test]
+    async fn idle_router_eviction() {
+        let logger = logger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let clock = Arc::new(MockClock::default());
+        dispatcher.clock = clock.clone();
+        dispatcher.router_clients.max_running = 2;
+        let idle_timeout = Duration::from_secs(dispatcher.router_clients.idle_timeout);
+
+        let (oldest_key, oldest) = test_router(&dispatcher, 8080);
+        dispatcher.routers.insert(oldest_key.clone(), oldest);
+        clock.advance(Duration::from_secs(1));
+        let (newest_key, newest) = test_router(&dispatcher, 8081);
+        dispatcher.routers.insert(newest_key.clone(), newest);
+
+        // No router has been idle long enough to make room
+        assert!(dispatcher.idle_router().is_none());
+        assert!(!dispatcher.make_room(&logger).await);
+
+        // The least recently used router is parked first
+        clock.advance(idle_timeout);
+        assert!(dispatcher.make_room(&logger).await);
+        assert!(dispatcher.routers[&oldest_key].client.is_none());
+        assert!(dispatcher.routers[&newest_key].client.is_some());
+        assert_eq!(0, dispatcher.routers[&oldest_key].queue_depth());
+
+        // Below the limit no router is parked
+        assert!(dispatcher.make_room(&logger).await);
+        assert!(dispatcher.routers[&newest_key].client.is_some());
+    }
+
+    #[tokio::test]
+    async fn idle_router_parking() {
+        let logger = logger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let clock = Arc::new(MockClock::default());
+        dispatcher.clock = clock.clone();
+        let idle_timeout = Duration::from_secs(dispatcher.router_clients.idle_timeout);
+
+        let (idle_key, idle) = test_router(&dispatcher, 8080);
+        dispatcher.routers.insert(idle_key.clone(), idle);
+        clock.advance(idle_timeout);
+        let (active_key, active) = test_router(&dispatcher, 8081);
+        dispatcher.routers.insert(active_key.clone(), active);
+
+        dispatcher.park_idle_routers(&logger).await;
+        assert!(dispatcher.routers[&idle_key].client.is_none());
+        assert!(dispatcher.routers[&active_key].client.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn router_drain() {
+        let logger = logger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let (router_key, router_entry) = test_router(&dispatcher, 8080);
+        let drain_time = Duration::from_secs(1);
+        let (dispatch, _dispatch_rx) = router::client::message_channel(1);
+        dispatcher.routers.insert(
+            router_key.clone(),
+            RouterEntry {
+                client: Some(RunningRouter {
+                    dispatch,
+                    join_handle: tokio::spawn(async move {
+                        time::sleep(drain_time).await;
+                        Ok(vec![Packet::from(helium_proto::Packet::default())])
+                    }),
+                }),
+                ..router_entry
+            },
+        );
+
+        let started = time::Instant::now();
+        dispatcher.drain_routers(&logger).await;
+        assert!(started.elapsed() >= drain_time);
+        assert!(dispatcher.routers[&router_key].client.is_none());
+        // Uplinks the router could not deliver are kept for a handoff
+        assert_eq!(1, dispatcher.undelivered[&router_key].len());
+    }
+
+    #[tokio::test]
+    async fn quarantine_expiry() {
+        let logger = logger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let clock = Arc::new(MockClock::default());
+        dispatcher.clock = clock.clone();
+        let period = Duration::from_secs(dispatcher.quarantine.period);
+        let (router_key, router_entry) = test_router(&dispatcher, 8080);
+        for _ in 0..router::qos::QUARANTINE_MIN_SAMPLES {
+            dispatcher
+                .router_qos
+                .record(&router_key.uri, Duration::from_millis(10), false);
+        }
+        dispatcher.routers.insert(router_key.clone(), router_entry);
+        let admission = |dispatcher: &Dispatcher| dispatcher.router_qos.admission(&router_key.uri);
+
+        dispatcher.update_admissions(&logger);
+        assert!(admission(&dispatcher).is_quarantined());
+
+        // The quarantine survives the router leaving and re-entering the
+        // routing
+        let router_entry = dispatcher.routers.remove(&router_key).expect("router");
+        dispatcher.routers.insert(router_key.clone(), router_entry);
+        dispatcher.update_admissions(&logger);
+        assert!(admission(&dispatcher).is_quarantined());
+
+        clock.advance(period);
+        dispatcher.update_admissions(&logger);
+        assert_eq!(
+            Admission::Probation { remaining: 10 },
+            admission(&dispatcher)
+        );
+    }
+
+    #[tokio::test]
+    async fn oui_routing_update() {
+        let logger = logger();
+        let (_trigger, shutdown) = triggered::trigger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let (dropped_key, dropped) = test_router(&dispatcher, 8080);
+        dispatcher.routers.insert(dropped_key.clone(), dropped);
+        let (kept_key, kept) = test_router(&dispatcher, 8081);
+        dispatcher.routers.insert(kept_key.clone(), kept);
+
+        let routing = Routing::from_proto(
+            &logger,
+            &helium_proto::Routing {
+                oui: 1,
+                addresses: vec![helium_proto::RoutingAddress {
+                    uri: b"http://127.0.0.1:8081".to_vec(),
+                    pub_key: dispatcher.keypair.public_key().to_vec(),
+                }],
+                ..Default::default()
+            },
+        )
+        .expect("routing");
+        dispatcher
+            .handle_oui_routing_update(&routing, &shutdown, &logger)
+            .await;
+
+        // Existing entries take the new routing, and routers it no longer
+        // lists are removed
+        assert!(!dispatcher.routers.contains_key(&dropped_key));
+        assert!(dispatcher.routers[&kept_key]
+            .routing
+            .contains_uri(&kept_key.uri));
+    }
+
+    #[tokio::test]
+    async fn region_params_height_regression() {
+        let logger = logger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let mut events = dispatcher.subscribe();
+        let region_params = |region| RegionParams {
+            gain: Decimal::new(12, 1),
+            region,
+            params: vec![],
+        };
+
+        let response = MockResponse {
+            height: 10,
+            region_params: Some(region_params(region(helium_proto::Region::Eu868))),
+        };
+        dispatcher
+            .handle_region_params_update(&response, &logger)
+            .await;
+        assert_eq!(10, dispatcher.region_height);
+        assert_eq!("EU868", dispatcher.region.to_string());
+        assert!(matches!(
+            events.recv().await,
+            Some(Event::RegionParamsChanged(_, 10))
+        ));
+
+        // An older update must not roll back the region
+        let response = MockResponse {
+            height: 9,
+            region_params: Some(region_params(region(helium_proto::Region::Au915))),
+        };
+        dispatcher
+            .handle_region_params_update(&response, &logger)
+            .await;
+        assert_eq!(10, dispatcher.region_height);
+        assert_eq!("EU868", dispatcher.region.to_string());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gateway_change_backoff() {
+        let logger = logger();
+        let (_trigger, shutdown) = triggered::trigger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        dispatcher.routing_height = 10;
+        dispatcher.region_height = 10;
+        let backoff = Backoff::new(
+            GATEWAY_BACKOFF_RETRIES,
+            GATEWAY_BACKOFF_MIN_WAIT,
+            GATEWAY_BACKOFF_MAX_WAIT,
+        );
+
+        for retry in 1..=3 {
+            let start = time::Instant::now();
+            dispatcher
+                .prepare_gateway_change(&backoff, shutdown.clone(), &logger)
+                .await;
+            let slept = start.elapsed();
+            assert_eq!(retry, dispatcher.gateway_retry);
+            assert!(slept > Duration::ZERO);
+            assert!(slept <= GATEWAY_BACKOFF_MAX_WAIT);
+        }
+        // Heights are kept so the next gateway resumes from them
+        assert_eq!(10, dispatcher.routing_height);
+        assert_eq!(10, dispatcher.region_height);
+    }
+
+    #[test]
+    fn stream_resubscribe_errors() {
+        let (dispatcher, _messages, _downlinks) = dispatcher();
+        let unverified = Error::unverified(dispatcher.keypair.public_key());
+        assert!(resubscribes_stream(&Error::custom("stream reset"), 0));
+        assert!(!resubscribes_stream(
+            &Error::custom("stream reset"),
+            STREAM_RESUBSCRIBE_RETRIES
+        ));
+        assert!(!resubscribes_stream(&unverified, 0));
+        assert!(!resubscribes_stream(&Error::validator_error(b"too_old"), 0));
+        assert!(resubscribes_stream(&Error::validator_error(b"busy"), 0));
+    }
+
+    #[tokio::test]
+    async fn gateway_verification_failures() {
+        let logger = logger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let gateway = KeyedUri {
+            uri: "http://127.0.0.1:8080".parse().expect("uri"),
+            pubkey: Arc::new(dispatcher.keypair.public_key().clone()),
+        };
+        let unverified = Error::unverified(&gateway.pubkey);
+
+        // Other errors reselect right away
+        assert!(matches!(
+            dispatcher
+                .failed_gateway(&gateway, &Error::custom("stream reset"), &logger)
+                .await,
+            Reconnect::Reselect
+        ));
+        // A single verification failure is retried, and repeated failures
+        // look up the gateway key, which reselects without any seeds
+        assert!(matches!(
+            dispatcher.failed_gateway(&gateway, &unverified, &logger).await,
+            Reconnect::Retry(uri) if uri == gateway
+        ));
+        assert!(matches!(
+            dispatcher
+                .failed_gateway(&gateway, &unverified, &logger)
+                .await,
+            Reconnect::Reselect
+        ));
+        assert!(dispatcher.verify_failures.is_empty());
+    }
+
+    #[tokio::test]
+    async fn gateway_stream_failed() {
+        let logger = logger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let gateway = KeyedUri {
+            uri: "http://127.0.0.1:8080".parse().expect("uri"),
+            pubkey: Arc::new(dispatcher.keypair.public_key().clone()),
+        };
+        let reset = Error::custom("stream reset");
+
+        // A failed stream retries the gateway until the gateway is
+        // quarantined for its stream failures
+        assert!(matches!(
+            dispatcher.stream_failed(&gateway, &reset, &logger).await,
+            Reconnect::Retry(uri) if uri == gateway
+        ));
+        assert!(matches!(
+            dispatcher.stream_failed(&gateway, &reset, &logger).await,
+            Reconnect::Reselect
+        ));
+    }
+
+    type TestStream = stream::BoxStream<'static, Result<GatewayRespV1>>;
+
+    fn test_stream<S>(stream: S) -> TestStream
+    where
+        S: Stream<Item = Result<GatewayRespV1>> + Send + 'static,
+    {
+        Box::pin(stream)
+    }
+
+    fn test_response(height: u64) -> Result<GatewayRespV1> {
+        Ok(GatewayRespV1 {
+            height,
+            ..Default::default()
+        })
+    }
+
+    /// A resubscribe of the given stream that completes after the given wait
+    fn test_resubscribe(
+        gateway_stream: GatewayStream,
+        wait: Duration,
+        resubscribed: Result<TestStream>,
+    ) -> Resubscribe<TestStream> {
+        Box::pin(async move {
+            time::sleep(wait).await;
+            (gateway_stream, resubscribed)
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_resubscribe() {
+        let mut streams = StreamMap::new();
+        streams.insert(
+            GatewayStream::Routing,
+            test_stream(stream::iter(vec![
+                test_response(1),
+                Err(Error::custom("stream reset")),
+            ])),
+        );
+        streams.insert(GatewayStream::RegionParams, test_stream(stream::pending()));
+        let mut streams = GatewayStreamSet::new(streams);
+
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Message(GatewayStream::Routing, response) if response.height == 1
+        ));
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Resubscribe(GatewayStream::Routing, _, 1)
+        ));
+        // A failed resubscribe is another attempt
+        streams.resubscribe(test_resubscribe(
+            GatewayStream::Routing,
+            STREAM_RESUBSCRIBE_WAIT,
+            Err(Error::custom("refused")),
+        ));
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Resubscribe(GatewayStream::Routing, _, 2)
+        ));
+        let resubscribed = test_stream(stream::iter(vec![
+            test_response(2),
+            Err(Error::custom("stream reset")),
+        ]));
+        streams.resubscribe(test_resubscribe(
+            GatewayStream::Routing,
+            STREAM_RESUBSCRIBE_WAIT * 2,
+            Ok(resubscribed),
+        ));
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Resubscribed(GatewayStream::Routing)
+        ));
+        // A message resets the attempts of its stream
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Message(GatewayStream::Routing, response) if response.height == 2
+        ));
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Resubscribe(GatewayStream::Routing, _, 1)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_resubscribe_attempts() {
+        let mut streams = StreamMap::new();
+        streams.insert(
+            GatewayStream::Routing,
+            test_stream(stream::iter(vec![Err(Error::custom("stream reset"))])),
+        );
+        streams.insert(GatewayStream::RegionParams, test_stream(stream::pending()));
+        let mut streams = GatewayStreamSet::new(streams);
+
+        for attempt in 1..=STREAM_RESUBSCRIBE_RETRIES {
+            assert!(matches!(
+                streams.next().await,
+                StreamEvent::Resubscribe(GatewayStream::Routing, _, resubscribe) if resubscribe == attempt
+            ));
+            streams.resubscribe(test_resubscribe(
+                GatewayStream::Routing,
+                STREAM_RESUBSCRIBE_WAIT * attempt,
+                Err(Error::custom("refused")),
+            ));
+        }
+        // The stream fails for good once it ran out of attempts
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Failed(GatewayStream::Routing, _)
+        ));
+
+        // Errors that can not be retried fail the stream right away
+        let mut streams = StreamMap::new();
+        streams.insert(
+            GatewayStream::RegionParams,
+            test_stream(stream::iter(vec![Err(Error::validator_error(b"too_old"))])),
+        );
+        let mut streams = GatewayStreamSet::new(streams);
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Failed(GatewayStream::RegionParams, _)
+        ));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stream_closed_while_resubscribing() {
+        let mut streams = StreamMap::new();
+        streams.insert(
+            GatewayStream::Routing,
+            test_stream(stream::iter(vec![Err(Error::custom("stream reset"))])),
+        );
+        streams.insert(
+            GatewayStream::RegionParams,
+            test_stream(stream::once(async {
+                time::sleep(Duration::from_secs(1)).await;
+                test_response(1)
+            })),
+        );
+        let mut streams = GatewayStreamSet::new(streams);
+        let started = time::Instant::now();
+
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Resubscribe(GatewayStream::Routing, _, 1)
+        ));
+        streams.resubscribe(test_resubscribe(
+            GatewayStream::Routing,
+            Duration::from_secs(5),
+            Ok(test_stream(stream::empty())),
+        ));
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Message(GatewayStream::RegionParams, _)
+        ));
+        // The region params stream ends while the routing stream is being
+        // subscribed to again, which does not close the streams
+        assert!(matches!(
+            streams.next().await,
+            StreamEvent::Resubscribed(GatewayStream::Routing)
+        ));
+        assert_eq!(Duration::from_secs(5), started.elapsed());
+        assert!(matches!(streams.next().await, StreamEvent::Closed));
+    }
+
+    #[test]
+    fn backoff_jitter() {
+        let wait = Duration::from_secs(10);
+        assert_eq!(wait, jitter(wait, 0));
+        for _ in 0..100 {
+            let jittered = jitter(wait, 50);
+            assert!(jittered >= Duration::from_secs(5));
+            assert!(jittered <= Duration::from_secs(15));
+        }
+        // Jitter is capped at the full wait
+        assert!(jitter(wait, 200) <= Duration::from_secs(20));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gateway_change_shutdown() {
+        let logger = logger();
+        let (trigger, shutdown) = triggered::trigger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        let backoff = Backoff::new(
+            GATEWAY_BACKOFF_RETRIES,
+            GATEWAY_BACKOFF_MIN_WAIT,
+            GATEWAY_BACKOFF_MAX_WAIT,
+        );
+        trigger.trigger();
+        let start = time::Instant::now();
+        dispatcher
+            .prepare_gateway_change(&backoff, shutdown, &logger)
+            .await;
+        assert_eq!(Duration::ZERO, start.elapsed());
+        assert_eq!(0, dispatcher.gateway_retry);
+    }
+
+    /// How a scripted gateway session was started
+    #[derive(Debug, PartialEq)]
+    enum Session {
+        /// Selected through the seeds, trying the given last gateway first
+        Selected(Option<KeyedUri>),
+        /// Connected directly to the retried gateway
+        Retried(KeyedUri),
+    }
+
+    /// A gateway double whose sessions end with the scripted reconnects in
+    /// order. Records when and how each session started.
+    struct ScriptedGateway {
+        reconnects: VecDeque<Reconnect>,
+        sessions: Vec<(time::Instant, Session)>,
+    }
+
+    impl ScriptedGateway {
+        fn new(reconnects: Vec<Reconnect>) -> Self {
+            Self {
+                reconnects: VecDeque::from(reconnects),
+                sessions: vec![],
+            }
+        }
+
+        fn waits(&self) -> Vec<Duration> {
+            self.sessions
+                .windows(2)
+                .map(|sessions| sessions[1].0 - sessions[0].0)
+                .collect()
+        }
+    }
+
+    #[async_trait::async_trait(?Send)]
+    impl GatewaySessions for ScriptedGateway {
+        async fn run(
+            &mut self,
+            _dispatcher: &mut Dispatcher,
+            last_gateway: Option<KeyedUri>,
+            _shutdown: &triggered::Listener,
+            _logger: &Logger,
+        ) -> Result<Option<Reconnect>> {
+            self.sessions
+                .push((time::Instant::now(), Session::Selected(last_gateway)));
+            Ok(self.reconnects.pop_front())
+        }
+
+        async fn retry(
+            &mut self,
+            _dispatcher: &mut Dispatcher,
+            gateway: KeyedUri,
+            _shutdown: &triggered::Listener,
+            _logger: &Logger,
+        ) -> Result<Option<Reconnect>> {
+            self.sessions
+                .push((time::Instant::now(), Session::Retried(gateway)));
+            Ok(self.reconnects.pop_front())
+        }
+    }
+
+    fn race_uri(port: u16) -> KeyedUri {
+        KeyedUri {
+            uri: format!("http://127.0.0.1:{port}").parse().expect("uri"),
+            pubkey: Arc::new(test_pubkey()),
+        }
+    }
+
+    /// Picks the first remaining candidate
+    fn select_first(candidates: &[KeyedUri]) -> Result<(KeyedUri, KeyedUri)> {
+        candidates
+            .first()
+            .map(|uri| (uri.clone(), uri.clone()))
+            .ok_or_else(|| Error::custom("empty gateway list"))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn race_candidate_rounds() {
+        let logger = logger();
+        let candidates: Vec<KeyedUri> = (8080..8085).map(race_uri).collect();
+        let attempts = RefCell::new(vec![]);
+        let connect = |connects: u16| {
+            let attempts = &attempts;
+            move |uri: KeyedUri| {
+                attempts.borrow_mut().push(uri.clone());
+                async move {
+                    if uri.uri.port_u16() == Some(connects) {
+                        Ok(uri)
+                    } else {
+                        Err(Error::custom("refused"))
+                    }
+                }
+            }
+        };
+
+        // Rounds continue with the remaining candidates until one connects
+        let connected =
+            race_candidates(candidates.clone(), 2, select_first, connect(8084), &logger)
+                .await
+                .expect("connected");
+        assert_eq!(candidates[4], connected);
+        assert_eq!(candidates, *attempts.borrow());
+
+        // Every candidate is tried once before giving up
+        attempts.borrow_mut().clear();
+        let connected =
+            race_candidates(candidates.clone(), 2, select_first, connect(0), &logger).await;
+        assert!(connected.is_err());
+        assert_eq!(candidates, *attempts.borrow());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn race_fastest_candidate() {
+        let logger = logger();
+        let candidates: Vec<KeyedUri> = (8080..8083).map(race_uri).collect();
+        // Later candidates answer faster, and the first one fails after the
+        // others connected
+        let connect = |uri: KeyedUri| async move {
+            let port = uri.uri.port_u16().unwrap_or_default();
+            time::sleep(Duration::from_secs(u64::from(8090 - port))).await;
+            if port == 8080 {
+                return Err(Error::custom("refused"));
+            }
+            Ok(uri)
+        };
+        let started = time::Instant::now();
+        let connected = race_candidates(candidates.clone(), 3, select_first, connect, &logger)
+            .await
+            .expect("connected");
+        assert_eq!(candidates[2], connected);
+        assert_eq!(Duration::from_secs(8), started.elapsed());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gateway_reconnect_sequence() {
+        let logger = logger();
+        let (_trigger, shutdown) = triggered::trigger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        dispatcher.validators.backoff_jitter = 0;
+        let uri = |port| KeyedUri {
+            uri: format!("http://127.0.0.1:{port}").parse().expect("uri"),
+            pubkey: Arc::new(dispatcher.keypair.public_key().clone()),
+        };
+        let (retried, rotated) = (uri(8080), uri(8081));
+        let mut gateway = ScriptedGateway::new(vec![
+            Reconnect::Retry(retried.clone()),
+            Reconnect::Rotated(rotated.clone()),
+            Reconnect::Reselect,
+            Reconnect::Reselect,
+        ]);
+        dispatcher
+            .run_gateways(&mut gateway, shutdown, &logger)
+            .await
+            .expect("run gateways");
+
+        let tried: Vec<&Session> = gateway
+            .sessions
+            .iter()
+            .map(|(_, session)| session)
+            .collect();
+        assert_eq!(
+            vec![
+                &Session::Selected(None),
+                &Session::Retried(retried),
+                &Session::Selected(Some(rotated)),
+                &Session::Selected(None),
+                &Session::Selected(None)
+            ],
+            tried
+        );
+        let waits = gateway.waits();
+        // A failed gateway is retried after a short wait, a rotated one right
+        // away, and reselection backs off
+        assert_eq!(GATEWAY_RETRY_WAIT, waits[0]);
+        assert_eq!(Duration::ZERO, waits[1]);
+        for wait in &waits[2..] {
+            assert!(*wait > Duration::ZERO);
+            assert!(*wait <= GATEWAY_BACKOFF_MAX_WAIT);
+        }
+        assert_eq!(2, dispatcher.gateway_retry);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn gateway_retried_once() {
+        let logger = logger();
+        let (_trigger, shutdown) = triggered::trigger();
+        let (mut dispatcher, _messages, _downlinks) = dispatcher();
+        dispatcher.validators.backoff_jitter = 50;
+        let retried = KeyedUri {
+            uri: "http://127.0.0.1:8080".parse().expect("uri"),
+            pubkey: Arc::new(dispatcher.keypair.public_key().clone()),
+        };
+        let mut gateway = ScriptedGateway::new(vec![
+            Reconnect::Retry(retried.clone()),
+            Reconnect::Retry(retried.clone()),
+        ]);
+        dispatcher
+            .run_gateways(&mut gateway, shutdown, &logger)
+            .await
+            .expect("run gateways");
+
+        // A gateway that fails again after its retry is not retried a second
+        // time, and selection backs off instead
+        let tried: Vec<&Session> = gateway
+            .sessions
+            .iter()
+            .map(|(_, session)| session)
+            .collect();
+        assert_eq!(
+            vec![
+                &Session::Selected(None),
+                &Session::Retried(retried),
+                &Session::Selected(None)
+            ],
+            tried
+        );
+        assert_eq!(1, dispatcher.gateway_retry);
+        assert_eq!(None, dispatcher.retried_gateway);
+        // The retry wait is jittered like the backoff
+        let waits = gateway.waits();
+        assert!(waits[0] >= GATEWAY_RETRY_WAIT / 2);
+        assert!(waits[0] < GATEWAY_RETRY_WAIT * 2);
+    }
+}
@@ -0,0 +1,193 @@
+use crate::{clock::unix_now, storage::Store, KeyedUri, Packet, Result};
+use helium_proto::Message;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const ROUTING_HANDOFF_KEY: &str = "routing_handoff.json";
+const ROUTING_TABLE_KEY: &str = "routing_table.json";
+
+/// How long a stored routing handoff is restored from after it was written.
+/// The handoff is meant to bridge a restart of the gateway, not to replace
+/// the routing fetched from a validator.
+pub const ROUTING_HANDOFF_MAX_AGE: Duration = Duration::from_secs(120); // 2 minutes
+
+/// The routing the dispatcher was using when it stopped, persisted in the
+/// cache store so that a restarted gateway can start its routers right away
+/// instead of waiting for a validator routing stream.
+///
+/// The routing last applied by the running gateway is kept in the same form
+/// as its routing table, so commands can inspect the routing the gateway
+/// actually uses.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RoutingHandoff {
+    /// Height of the routing
+    pub height: u64,
+    /// Base64 encoded routing protos, one per oui
+    routings: Vec<String>,
+    /// Uplinks router clients could not deliver before the gateway stopped
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    uplinks: Vec<HandoffUplink>,
+    /// Unix timestamp (in seconds) of when the handoff was written
+    pub timestamp: u64,
+}
+
+/// An uplink handed off to the router it was queued for
+#[derive(Debug, Serialize, Deserialize)]
+struct HandoffUplink {
+    oui: u32,
+    router: KeyedUri,
+    /// Base64 encoded packet proto
+    packet: String,
+}
+
+impl RoutingHandoff {
+    pub fn new<'a, I>(height: u64, routings: I) -> Result<Self>
+    where
+        I: IntoIterator<Item = &'a helium_proto::Routing>,
+    {
+        Ok(Self {
+            height,
+            routings: routings
+                .into_iter()
+                .map(|routing| base64::encode(routing.encode_to_vec()))
+                .collect(),
+            uplinks: vec![],
+            timestamp: unix_now()?,
+        })
+    }
+
+    /// Adds the given undelivered uplinks of the router of an oui to this
+    /// handoff
+    pub fn add_uplinks<'a, I>(&mut self, oui: u32, router: &KeyedUri, packets: I)
+    where
+        I: IntoIterator<Item = &'a Packet>,
+    {
+        self.uplinks
+            .extend(packets.into_iter().map(|packet| HandoffUplink {
+                oui,
+                router: router.clone(),
+                packet: base64::encode(packet.encode_to_vec()),
+            }))
+    }
+
+    /// Returns how long ago this handoff was written
+    pub fn age(&self) -> Duration {
+        let now = unix_now().unwrap_or(self.timestamp);
+        Duration::from_secs(now.saturating_sub(self.timestamp))
+    }
+
+    /// Loads the routing handoff from the given store. Returns None if there
+    /// is no stored handoff, it could not be read or it is older than the
+    /// given maximum age.
+    pub fn load(store: &dyn Store, max_age: Duration) -> Option<Self> {
+        let handoff = Self::load_key(store, ROUTING_HANDOFF_KEY)?;
+        let age = unix_now().ok()?.saturating_sub(handoff.timestamp);
+        if age <= max_age.as_secs() {
+            Some(handoff)
+        } else {
+            None
+        }
+    }
+
+    /// Stores this handoff in the given store
+    pub fn save(&self, store: &dyn Store) -> Result {
+        store.put(ROUTING_HANDOFF_KEY, &serde_json::to_vec(self)?)
+    }
+
+    /// Loads the routing table of the gateway from the given store. Returns
+    /// None if there is no stored routing table or it could not be read.
+    pub fn load_table(store: &dyn Store) -> Option<Self> {
+        Self::load_key(store, ROUTING_TABLE_KEY)
+    }
+
+    /// Stores this routing as the routing table of the gateway
+    pub fn save_table(&self, store: &dyn Store) -> Result {
+        store.put(ROUTING_TABLE_KEY, &serde_json::to_vec(self)?)
+    }
+
+    fn load_key(store: &dyn Store, key: &str) -> Option<Self> {
+        let data = store.get(key).ok()??;
+        serde_json::from_slice(&data).ok()
+    }
+
+    /// Decodes the routing protos of this handoff
+    pub fn routings(&self) -> Result<Vec<helium_proto::Routing>> {
+        self.routings
+            .iter()
+            .map(|encoded| {
+                Ok(helium_proto::Routing::decode(
+                    &base64::decode(encoded)?[..],
+                )?)
+            })
+            .collect()
+    }
+
+    /// Decodes the handed off uplinks with the oui and router they were
+    /// queued for, oldest first
+    pub fn uplinks(&self) -> Result<Vec<(u32, KeyedUri, Packet)>> {
+        self.uplinks
+            .iter()
+            .map(|uplink| {
+                let packet = helium_proto::Packet::decode(&base64::decode(&uplink.packet)?[..])?;
+                Ok((uplink.oui, uplink.router.clone(), Packet::from(packet)))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn roundtrip() {
+        let store = MemoryStore::default();
+        assert!(RoutingHandoff::load(&store, ROUTING_HANDOFF_MAX_AGE).is_none());
+
+        let routing = helium_proto::Routing {
+            oui: 1,
+            ..Default::default()
+        };
+        let mut handoff = RoutingHandoff::new(100, [&routing]).expect("handoff");
+        handoff.save(&store).expect("save");
+        let loaded = RoutingHandoff::load(&store, ROUTING_HANDOFF_MAX_AGE).expect("handoff");
+        assert_eq!(100, loaded.height);
+        assert_eq!(vec![routing.clone()], loaded.routings().expect("routings"));
+        assert!(loaded.uplinks().expect("uplinks").is_empty());
+
+        handoff.timestamp -= ROUTING_HANDOFF_MAX_AGE.as_secs() + 1;
+        handoff.save(&store).expect("save");
+        assert!(RoutingHandoff::load(&store, ROUTING_HANDOFF_MAX_AGE).is_none());
+
+        // The routing table does not expire and is kept apart from the handoff
+        assert!(RoutingHandoff::load_table(&store).is_none());
+        handoff.save_table(&store).expect("save table");
+        let table = RoutingHandoff::load_table(&store).expect("table");
+        assert_eq!(vec![routing], table.routings().expect("routings"));
+    }
+
+    #[test]
+    fn uplinks() {
+        let store = MemoryStore::default();
+        let router = KeyedUri {
+            uri: "http://127.0.0.1:8080".parse().expect("uri"),
+            pubkey: std::sync::Arc::new(crate::keypair::test_pubkey()),
+        };
+        let packet = Packet::from(helium_proto::Packet {
+            payload: vec![1, 2, 3],
+            ..Default::default()
+        });
+        let mut handoff = RoutingHandoff::new(100, []).expect("handoff");
+        handoff.add_uplinks(1, &router, [&packet]);
+        handoff.save(&store).expect("save");
+
+        let loaded = RoutingHandoff::load(&store, ROUTING_HANDOFF_MAX_AGE).expect("handoff");
+        let uplinks = loaded.uplinks().expect("uplinks");
+        assert_eq!(1, uplinks.len());
+        let (oui, uplink_router, uplink) = &uplinks[0];
+        assert_eq!(1, *oui);
+        assert_eq!(router, *uplink_router);
+        assert_eq!(packet.payload(), uplink.payload());
+    }
+}
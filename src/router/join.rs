@@ -0,0 +1,144 @@
+use crate::Packet;
+use helium_proto::{routing_information::Data as RoutingData, Eui, RoutingInformation};
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Time after a join accept within which another join request from the same
+/// device is taken as a retry, meaning the join accept was not received
+pub const JOIN_RETRY_WINDOW: Duration = Duration::from_secs(60);
+
+/// The downlink parameters a join accept was scheduled with
+#[derive(Debug, Clone, PartialEq)]
+pub struct JoinWindow {
+    pub frequency: f32,
+    pub datarate: String,
+    pub rx2: bool,
+}
+
+impl JoinWindow {
+    /// Schedules the given join accept with these parameters. An accept is
+    /// moved to its rx2 window if the previous one was sent in rx2. In rx1 the
+    /// data rate is only reused when the accept answers on the same
+    /// frequency, since the rx1 frequency follows the uplink channel. Returns
+    /// the join accept and whether it is in rx2.
+    pub fn reuse(&self, accept: Packet) -> (Packet, bool) {
+        if self.rx2 && accept.rx2_window.is_some() {
+            let accept = accept.into_rx2_window();
+            (accept.with_radio(self.frequency, &self.datarate), true)
+        } else if !self.rx2 && accept.frequency == self.frequency {
+            (accept.with_radio(self.frequency, &self.datarate), false)
+        } else {
+            (accept, false)
+        }
+    }
+}
+
+/// Remembers the downlink parameters of recent join accepts per device, so
+/// the join accept for a retried join request can be scheduled with the same
+/// parameters right away.
+#[derive(Debug, Default)]
+pub struct JoinAssist {
+    accepts: HashMap<(u64, u64), (JoinWindow, Instant)>,
+}
+
+impl JoinAssist {
+    /// Returns the (deveui, appeui) of the device that sent the given uplink
+    /// if it is a join request
+    pub fn device(uplink: &Packet) -> Option<(u64, u64)> {
+        match uplink.routing() {
+            Some(RoutingInformation {
+                data: Some(RoutingData::Eui(Eui { deveui, appeui })),
+            }) => Some((*deveui, *appeui)),
+            _ => None,
+        }
+    }
+
+    /// Returns the window of the last join accept sent to the given device if
+    /// it was sent within the join retry window
+    pub fn retried(&mut self, device: (u64, u64), now: Instant) -> Option<JoinWindow> {
+        self.accepts
+            .retain(|_, (_, sent)| now.saturating_duration_since(*sent) < JOIN_RETRY_WINDOW);
+        self.accepts.get(&device).map(|(window, _)| window.clone())
+    }
+
+    /// Records the window the given join accept for the given device was
+    /// scheduled in
+    pub fn record(&mut self, device: (u64, u64), accept: &Packet, rx2: bool, now: Instant) {
+        let window = JoinWindow {
+            frequency: accept.frequency,
+            datarate: accept.datarate.clone(),
+            rx2,
+        };
+        self.accepts.insert(device, (window, now));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_window() {
+        let join = Packet::from(helium_proto::Packet {
+            routing: Some(RoutingInformation {
+                data: Some(RoutingData::Eui(Eui {
+                    deveui: 1,
+                    appeui: 2,
+                })),
+            }),
+            ..Default::default()
+        });
+        let device = JoinAssist::device(&join).expect("join request");
+        assert_eq!((1, 2), device);
+
+        let mut joins = JoinAssist::default();
+        let now = Instant::now();
+        assert_eq!(None, joins.retried(device, now));
+        let accept = Packet::from(helium_proto::Packet {
+            frequency: 869.525,
+            datarate: "SF12BW125".to_string(),
+            ..Default::default()
+        });
+        joins.record(device, &accept, true, now);
+        let window = joins
+            .retried(device, now + Duration::from_secs(10))
+            .expect("retried");
+        assert!(window.rx2);
+        assert_eq!("SF12BW125", window.datarate);
+        assert_eq!(None, joins.retried(device, now + JOIN_RETRY_WINDOW));
+
+        // A retried accept is sent in the same window with the same radio
+        let retry = Packet::from(helium_proto::Packet {
+            timestamp: 1_000_000,
+            frequency: 868.1,
+            datarate: "SF7BW125".to_string(),
+            rx2_window: Some(helium_proto::Window {
+                timestamp: 2_000_000,
+                frequency: 869.525,
+                datarate: "SF9BW125".to_string(),
+            }),
+            ..Default::default()
+        });
+        let (reused, rx2) = window.reuse(retry.clone());
+        assert!(rx2);
+        assert_eq!(2_000_000, reused.timestamp);
+        assert_eq!("SF12BW125", reused.datarate);
+        let rx1 = JoinWindow {
+            frequency: 868.1,
+            datarate: "SF8BW125".to_string(),
+            rx2: false,
+        };
+        let (reused, rx2) = rx1.reuse(retry.clone());
+        assert!(!rx2);
+        assert_eq!(1_000_000, reused.timestamp);
+        assert_eq!("SF8BW125", reused.datarate);
+        // An rx1 accept on another channel is left alone
+        let other = JoinWindow {
+            frequency: 868.3,
+            ..rx1
+        };
+        assert_eq!("SF7BW125", other.reuse(retry).0.datarate);
+    }
+}
@@ -0,0 +1,109 @@
+use crate::{
+    gateway::unspecified_for, sequence::SequenceId, settings::MirrorSettings, Base64, Error,
+    Packet, Result,
+};
+use helium_proto::{routing_information::Data as RoutingData, RoutingInformation};
+use serde_json::json;
+use std::{
+    net::{ToSocketAddrs, UdpSocket},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Mirrors the metadata of uplinks for a single oui as JSON datagrams to a
+/// debug endpoint until the configured expiry time. Payloads are not
+/// mirrored.
+#[derive(Debug)]
+pub struct PacketMirror {
+    pub oui: u32,
+    until: SystemTime,
+    socket: UdpSocket,
+}
+
+impl PacketMirror {
+    pub fn new(settings: &MirrorSettings) -> Result<Self> {
+        let endpoint = settings.endpoint.to_socket_addrs()?.next().ok_or_else(|| {
+            Error::custom(format!(
+                "no address for mirror endpoint {}",
+                settings.endpoint
+            ))
+        })?;
+        let socket = UdpSocket::bind(unspecified_for(&endpoint))?;
+        socket.connect(endpoint)?;
+        // Mirroring is best effort and must not block the dispatcher
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            oui: settings.oui,
+            until: UNIX_EPOCH + Duration::from_secs(settings.until),
+            socket,
+        })
+    }
+
+    pub fn is_expired(&self, now: SystemTime) -> bool {
+        now >= self.until
+    }
+
+    /// Sends the metadata of the given uplink and whether it was delivered to
+    /// a router of the mirrored oui
+    pub fn mirror(&self, id: SequenceId, packet: &Packet, delivered: bool) -> Result {
+        let routing = match packet.routing() {
+            Some(RoutingInformation {
+                data: Some(RoutingData::Devaddr(devaddr)),
+            }) => json!({ "devaddr": devaddr }),
+            Some(RoutingInformation {
+                data: Some(RoutingData::Eui(eui)),
+            }) => json!({ "deveui": eui.deveui, "appeui": eui.appeui }),
+            _ => json!(null),
+        };
+        let metadata = json!({
+            "oui": self.oui,
+            "id": id.to_string(),
+            "hash": packet.hash().to_b64(),
+            "tmst": packet.timestamp,
+            "freq": packet.frequency,
+            "datr": packet.datarate,
+            "rssi": packet.signal_strength,
+            "lsnr": packet.snr,
+            "size": packet.payload().len(),
+            "routing": routing,
+            "delivered": delivered,
+        });
+        self.socket.send(metadata.to_string().as_bytes())?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn mirror() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").expect("receiver");
+        let mirror = PacketMirror::new(&MirrorSettings {
+            oui: 7,
+            endpoint: receiver.local_addr().expect("address").to_string(),
+            until: 100,
+        })
+        .expect("mirror");
+        assert!(!mirror.is_expired(UNIX_EPOCH + Duration::from_secs(99)));
+        assert!(mirror.is_expired(UNIX_EPOCH + Duration::from_secs(100)));
+
+        let packet = Packet::from(helium_proto::Packet {
+            payload: vec![0x40, 1, 2, 3, 4],
+            routing: Some(RoutingInformation {
+                data: Some(RoutingData::Devaddr(0x48000001)),
+            }),
+            ..Default::default()
+        });
+        let id = SequenceId { boot: 1, seq: 2 };
+        mirror.mirror(id, &packet, true).expect("mirror packet");
+        let mut buf = [0u8; 1024];
+        let len = receiver.recv(&mut buf).expect("datagram");
+        let metadata: serde_json::Value = serde_json::from_slice(&buf[..len]).expect("json");
+        assert_eq!(7, metadata["oui"]);
+        assert_eq!("00000001-2", metadata["id"]);
+        assert_eq!(0x48000001, metadata["routing"]["devaddr"]);
+        assert_eq!(5, metadata["size"]);
+        assert_eq!(Some(true), metadata["delivered"].as_bool());
+    }
+}
@@ -1,11 +1,21 @@
 pub mod client;
 pub mod dispatcher;
 pub mod filter;
+pub mod handoff;
+pub mod join;
+pub mod mirror;
+pub mod qos;
 pub mod routing;
 pub mod store;
+pub mod trace;
+pub mod usage;
 
 pub use client::RouterClient;
 pub use dispatcher::Dispatcher;
 pub use filter::{DevAddrFilter, EuiFilter};
-pub use routing::Routing;
+pub use join::{JoinAssist, JoinWindow};
+pub use qos::{PacketLimit, RouterQos};
+pub use routing::{Routing, RoutingDiff};
 pub use store::{QuePacket, RouterStore};
+pub use trace::{Decision, PacketTrace, PacketTraces};
+pub use usage::OuiUsage;
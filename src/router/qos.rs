@@ -0,0 +1,416 @@
+use crate::{
+    service::latency::{LatencyWindow, LATENCY_WINDOW_SIZE},
+    settings::QuarantineSettings,
+    KeyedUri,
+};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Minimum number of recent samples before a router can be quarantined
+pub const QUARANTINE_MIN_SAMPLES: usize = 10;
+/// Rejection rate at or above which a router is quarantined
+pub const QUARANTINE_REJECTION_RATE: f64 = 0.9;
+/// Period over which the downlinks of a router are limited
+pub const DOWNLINK_LIMIT_PERIOD: Duration = Duration::from_secs(60);
+/// Period over which the packets sent to a router on probation are limited
+pub const PROBATION_LIMIT_PERIOD: Duration = Duration::from_secs(60);
+
+/// Whether a router is admitted to receive packets
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    #[default]
+    Admitted,
+    /// The router receives no packets until the given time
+    Quarantined { until: Instant },
+    /// The router is re-admitted after quarantine, and is fully admitted
+    /// again after the given number of packets without being quarantined
+    Probation { remaining: u32 },
+}
+
+impl Admission {
+    pub fn is_quarantined(&self) -> bool {
+        matches!(self, Self::Quarantined { .. })
+    }
+
+    /// Describes the admission as of the given time
+    pub fn describe(&self, now: Instant) -> String {
+        match self {
+            Self::Admitted => "admitted".to_string(),
+            Self::Quarantined { until } => format!(
+                "quarantined for {}s",
+                until.saturating_duration_since(now).as_secs()
+            ),
+            Self::Probation { remaining } => format!("probation for {remaining} packets"),
+        }
+    }
+
+    /// Returns the admission after a packet was sent to the router
+    pub fn packet_sent(self) -> Self {
+        match self {
+            Self::Probation { remaining } if remaining > 1 => Self::Probation {
+                remaining: remaining - 1,
+            },
+            Self::Probation { .. } => Self::Admitted,
+            other => other,
+        }
+    }
+}
+
+/// Recent delivery outcomes and route latencies for a single router
+#[derive(Debug, Default, Clone)]
+pub struct RouterStats {
+    // Whether each of the most recent packets was accepted by the router
+    outcomes: VecDeque<bool>,
+    latencies: LatencyWindow,
+    throttled: u64,
+    admission: Admission,
+    // Limits the packets sent to the router while it is on probation
+    probation_limit: PacketLimit,
+}
+
+impl RouterStats {
+    fn record(&mut self, latency: Duration, accepted: bool) {
+        if self.outcomes.len() >= LATENCY_WINDOW_SIZE {
+            self.outcomes.pop_front();
+        }
+        self.outcomes.push_back(accepted);
+        self.latencies.record(latency);
+    }
+
+    /// The fraction of recent packets that the router rejected or failed to
+    /// route
+    pub fn rejection_rate(&self) -> f64 {
+        if self.outcomes.is_empty() {
+            return 0.0;
+        }
+        let rejected = self.outcomes.iter().filter(|accepted| !**accepted).count();
+        rejected as f64 / self.outcomes.len() as f64
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.latencies.p50()
+    }
+
+    /// The number of downlinks from the router that were dropped for
+    /// exceeding the downlink limit
+    pub fn throttled(&self) -> u64 {
+        self.throttled
+    }
+
+    pub fn admission(&self) -> Admission {
+        self.admission
+    }
+
+    /// Whether the router rejects so many packets that it should be
+    /// quarantined
+    pub fn is_abusive(&self) -> bool {
+        self.outcomes.len() >= QUARANTINE_MIN_SAMPLES
+            && self.rejection_rate() >= QUARANTINE_REJECTION_RATE
+    }
+
+    /// Compares the quality of two routers. Lower rejection rates rank better,
+    /// with ties broken by the lower median latency. Routers without samples
+    /// rank best so they get a chance to be measured.
+    fn cmp_quality(&self, other: &Self) -> Ordering {
+        match (self.outcomes.is_empty(), other.outcomes.is_empty()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => self
+                .rejection_rate()
+                .partial_cmp(&other.rejection_rate())
+                .unwrap_or(Ordering::Equal)
+                .then_with(|| self.p50().cmp(&other.p50())),
+        }
+    }
+}
+
+/// Tracks route quality per router uri and key, since an operator may run
+/// several routers with the same key. Clones share the same underlying stats
+/// so a tracker can be shared between the dispatcher and the router clients
+/// it starts.
+#[derive(Debug, Default, Clone)]
+pub struct RouterQos(Arc<Mutex<HashMap<KeyedUri, RouterStats>>>);
+
+impl RouterQos {
+    pub fn record(&self, router: &KeyedUri, latency: Duration, accepted: bool) {
+        let mut routers = self.0.lock().expect("router qos lock");
+        routers
+            .entry(router.clone())
+            .or_default()
+            .record(latency, accepted);
+    }
+
+    pub fn record_throttled(&self, router: &KeyedUri) {
+        let mut routers = self.0.lock().expect("router qos lock");
+        routers.entry(router.clone()).or_default().throttled += 1;
+    }
+
+    pub fn stats(&self, router: &KeyedUri) -> RouterStats {
+        let routers = self.0.lock().expect("router qos lock");
+        routers.get(router).cloned().unwrap_or_default()
+    }
+
+    pub fn is_abusive(&self, router: &KeyedUri) -> bool {
+        let routers = self.0.lock().expect("router qos lock");
+        routers.get(router).map_or(false, RouterStats::is_abusive)
+    }
+
+    pub fn admission(&self, router: &KeyedUri) -> Admission {
+        let routers = self.0.lock().expect("router qos lock");
+        routers
+            .get(router)
+            .map_or(Admission::Admitted, RouterStats::admission)
+    }
+
+    /// Quarantines the router if it rejects most of the packets it is sent,
+    /// and puts it on probation once its quarantine period has passed. The
+    /// admission is kept here rather than with the routing so it survives
+    /// the router being removed from and re-added to the routing. Returns
+    /// the new admission if it changed.
+    pub fn update_admission(
+        &self,
+        router: &KeyedUri,
+        now: Instant,
+        settings: &QuarantineSettings,
+    ) -> Option<Admission> {
+        let mut routers = self.0.lock().expect("router qos lock");
+        let stats = routers.get_mut(router)?;
+        let admission = match stats.admission {
+            Admission::Quarantined { until } if now >= until => {
+                // Start measuring the router afresh on probation
+                *stats = RouterStats {
+                    throttled: stats.throttled,
+                    probation_limit: PacketLimit::new(
+                        settings.probation_limit,
+                        PROBATION_LIMIT_PERIOD,
+                    ),
+                    ..Default::default()
+                };
+                Admission::Probation {
+                    remaining: settings.probation,
+                }
+            }
+            Admission::Admitted | Admission::Probation { .. } if stats.is_abusive() => {
+                Admission::Quarantined {
+                    until: now + Duration::from_secs(settings.period),
+                }
+            }
+            _ => return None,
+        };
+        stats.admission = admission;
+        Some(admission)
+    }
+
+    /// Whether the router can be sent a packet at the given time. Routers
+    /// are not sent packets while quarantined or while on probation and
+    /// over the probation packet limit.
+    pub fn is_admitted(&self, router: &KeyedUri, now: Instant) -> bool {
+        let routers = self.0.lock().expect("router qos lock");
+        match routers.get(router) {
+            Some(stats) => match stats.admission {
+                Admission::Admitted => true,
+                Admission::Quarantined { .. } => false,
+                Admission::Probation { .. } => !stats.probation_limit.is_full(now),
+            },
+            None => true,
+        }
+    }
+
+    /// Counts a packet sent to the router at the given time towards its
+    /// probation
+    pub fn packet_sent(&self, router: &KeyedUri, now: Instant) {
+        let mut routers = self.0.lock().expect("router qos lock");
+        if let Some(stats) = routers.get_mut(router) {
+            if let Admission::Probation { .. } = stats.admission {
+                stats.probation_limit.record(now);
+                stats.admission = stats.admission.packet_sent();
+            }
+        }
+    }
+
+    /// Whether the router with the given key performs strictly better than
+    /// the other router
+    pub fn is_better(&self, router: &KeyedUri, other: &KeyedUri) -> bool {
+        let routers = self.0.lock().expect("router qos lock");
+        let default = RouterStats::default();
+        let stats = routers.get(router).unwrap_or(&default);
+        let other_stats = routers.get(other).unwrap_or(&default);
+        stats.cmp_quality(other_stats) == Ordering::Less
+    }
+}
+
+/// Limits the number of packets to the given limit per period. It limits
+/// the downlinks a single router can send to the concentrator per
+/// `DOWNLINK_LIMIT_PERIOD`, so one router can not take up the transmit path
+/// or the regional duty cycle, and the packets sent to a router on probation.
+/// A limit of zero is unlimited.
+#[derive(Debug, Default, Clone)]
+pub struct PacketLimit {
+    limit: usize,
+    period: Duration,
+    // Times of the packets allowed in the current period, oldest first
+    sent: VecDeque<Instant>,
+}
+
+impl PacketLimit {
+    pub fn new(limit: u32, period: Duration) -> Self {
+        Self {
+            limit: limit as usize,
+            period,
+            sent: VecDeque::new(),
+        }
+    }
+
+    /// Returns whether a packet at the given time is within the limit, and
+    /// counts it if it is
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if self.is_full(now) {
+            return false;
+        }
+        self.record(now);
+        true
+    }
+
+    /// Whether a packet at the given time would exceed the limit
+    fn is_full(&self, now: Instant) -> bool {
+        self.limit != 0 && self.in_period(now) >= self.limit
+    }
+
+    fn record(&mut self, now: Instant) {
+        if self.limit == 0 {
+            return;
+        }
+        while let Some(sent) = self.sent.front() {
+            if now.saturating_duration_since(*sent) < self.period {
+                break;
+            }
+            self.sent.pop_front();
+        }
+        self.sent.push_back(now);
+    }
+
+    fn in_period(&self, now: Instant) -> usize {
+        self.sent
+            .iter()
+            .filter(|sent| now.saturating_duration_since(**sent) < self.period)
+            .count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Keypair, Network};
+    use rand::rngs::OsRng;
+
+    /// Returns a router uri on the given local port with a new key
+    fn router(port: u16) -> KeyedUri {
+        let keypair = Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        KeyedUri {
+            uri: format!("http://127.0.0.1:{port}").parse().expect("uri"),
+            pubkey: Arc::new(keypair.public_key().to_owned()),
+        }
+    }
+
+    #[test]
+    fn preference() {
+        let qos = RouterQos::default();
+        let (fast, slow, rejecting) = (router(8080), router(8081), router(8082));
+        for _ in 0..10 {
+            qos.record(&fast, Duration::from_millis(50), true);
+            qos.record(&slow, Duration::from_millis(500), true);
+            qos.record(&rejecting, Duration::from_millis(10), false);
+        }
+        assert!(qos.is_better(&fast, &slow));
+        assert!(qos.is_better(&slow, &rejecting));
+        assert!(!qos.is_better(&rejecting, &fast));
+        assert_eq!(1.0, qos.stats(&rejecting).rejection_rate());
+        // Unmeasured routers are preferred until they have samples
+        assert!(qos.is_better(&router(8083), &fast));
+
+        // Routers sharing a key are tracked by their uri
+        let shared = KeyedUri {
+            uri: "http://127.0.0.1:8084".parse().expect("uri"),
+            ..fast.clone()
+        };
+        assert!(qos.is_better(&shared, &fast));
+    }
+
+    #[test]
+    fn quarantine() {
+        let qos = RouterQos::default();
+        let settings = QuarantineSettings {
+            period: 60,
+            probation: 3,
+            probation_limit: 2,
+        };
+        let now = Instant::now();
+        let rejecting = router(8080);
+        for _ in 0..QUARANTINE_MIN_SAMPLES - 1 {
+            qos.record(&rejecting, Duration::from_millis(10), false);
+        }
+        assert!(!qos.is_abusive(&rejecting));
+        assert_eq!(None, qos.update_admission(&rejecting, now, &settings));
+        qos.record(&rejecting, Duration::from_millis(10), false);
+        assert!(qos.is_abusive(&rejecting));
+        let until = now + Duration::from_secs(settings.period);
+        assert_eq!(
+            Some(Admission::Quarantined { until }),
+            qos.update_admission(&rejecting, now, &settings)
+        );
+        assert!(!qos.is_admitted(&rejecting, now));
+
+        // On probation the stats are reset and the packet volume is limited
+        assert_eq!(
+            Some(Admission::Probation { remaining: 3 }),
+            qos.update_admission(&rejecting, until, &settings)
+        );
+        assert!(!qos.is_abusive(&rejecting));
+        for _ in 0..2 {
+            assert!(qos.is_admitted(&rejecting, until));
+            qos.packet_sent(&rejecting, until);
+        }
+        assert!(!qos.is_admitted(&rejecting, until));
+        let later = until + PROBATION_LIMIT_PERIOD;
+        assert!(qos.is_admitted(&rejecting, later));
+        qos.packet_sent(&rejecting, later);
+        assert_eq!(Admission::Admitted, qos.admission(&rejecting));
+        assert!(qos.is_admitted(&rejecting, later));
+
+        let admission = Admission::Probation { remaining: 2 };
+        let admission = admission.packet_sent();
+        assert_eq!(Admission::Probation { remaining: 1 }, admission);
+        assert_eq!(Admission::Admitted, admission.packet_sent());
+    }
+
+    #[test]
+    fn downlink_limit() {
+        let start = Instant::now();
+        let mut limit = PacketLimit::new(2, DOWNLINK_LIMIT_PERIOD);
+        assert!(limit.allow(start));
+        assert!(limit.allow(start + Duration::from_secs(30)));
+        assert!(!limit.allow(start + Duration::from_secs(59)));
+        // The first downlink has left the period
+        assert!(limit.allow(start + DOWNLINK_LIMIT_PERIOD));
+        assert!(!limit.allow(start + DOWNLINK_LIMIT_PERIOD));
+
+        let mut unlimited = PacketLimit::new(0, DOWNLINK_LIMIT_PERIOD);
+        assert!((0..10).all(|_| unlimited.allow(start)));
+
+        let qos = RouterQos::default();
+        let throttled = router(8080);
+        qos.record_throttled(&throttled);
+        assert_eq!(1, qos.stats(&throttled).throttled());
+    }
+}
@@ -12,7 +12,56 @@ pub struct Routing {
     subnets: Vec<DevAddrFilter>,
 }
 
+/// The changes to the routing of an oui made by a routing update
+#[derive(Debug, Clone)]
+pub struct RoutingDiff {
+    pub oui: u32,
+    pub added: Vec<KeyedUri>,
+    pub removed: Vec<KeyedUri>,
+    /// Number of eui filters before and after the update
+    pub filters: (usize, usize),
+    /// Number of devaddr subnets before and after the update
+    pub subnets: (usize, usize),
+}
+
+impl RoutingDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.filters.0 == self.filters.1
+            && self.subnets.0 == self.subnets.1
+    }
+}
+
 impl Routing {
+    /// Returns the changes from the given previous routing of the same oui to
+    /// this one. No previous routing is treated as an empty routing.
+    pub fn diff(&self, previous: Option<&Routing>) -> RoutingDiff {
+        let (uris, filters, subnets) = previous.map_or((&[][..], 0, 0), |previous| {
+            (
+                &previous.uris[..],
+                previous.filters.len(),
+                previous.subnets.len(),
+            )
+        });
+        RoutingDiff {
+            oui: self.oui,
+            added: self
+                .uris
+                .iter()
+                .filter(|uri| !uris.contains(uri))
+                .cloned()
+                .collect(),
+            removed: uris
+                .iter()
+                .filter(|uri| !self.contains_uri(uri))
+                .cloned()
+                .collect(),
+            filters: (filters, self.filters.len()),
+            subnets: (subnets, self.subnets.len()),
+        }
+    }
+
     pub fn contains_uri(&self, uri: &KeyedUri) -> bool {
         self.uris.iter().any(|keyed_uri| keyed_uri == uri)
     }
@@ -24,7 +73,7 @@ impl Routing {
         }
     }
 
-    fn matches_routing_data(&self, routing_data: &Option<RoutingData>) -> bool {
+    pub fn matches_routing_data(&self, routing_data: &Option<RoutingData>) -> bool {
         match routing_data {
             None => false,
             Some(RoutingData::Eui(eui)) => self.filters.iter().any(|filter| filter.contains(eui)),
@@ -35,8 +84,16 @@ impl Routing {
     }
 
     pub fn from_proto(logger: &Logger, r: &helium_proto::Routing) -> Result<Self> {
-        let filters = r.filters.iter().map(EuiFilter::from_bin).collect();
-        let subnets = r.subnets.iter().map(DevAddrFilter::from_bin).collect();
+        let filters = r
+            .filters
+            .iter()
+            .map(EuiFilter::from_bin)
+            .collect::<Result<Vec<EuiFilter>>>()?;
+        let subnets = r
+            .subnets
+            .iter()
+            .map(DevAddrFilter::from_bin)
+            .collect::<Result<Vec<DevAddrFilter>>>()?;
         let oui = r.oui;
         let uris = r
             .addresses
@@ -80,3 +137,50 @@ impl Routing {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network};
+    use helium_proto::RoutingAddress;
+    use rand::rngs::OsRng;
+
+    fn routing(ports: &[u16], pub_key: &[u8]) -> Routing {
+        let addresses = ports
+            .iter()
+            .map(|port| RoutingAddress {
+                uri: format!("http://127.0.0.1:{port}").into_bytes(),
+                pub_key: pub_key.to_vec(),
+            })
+            .collect();
+        let proto = helium_proto::Routing {
+            oui: 1,
+            addresses,
+            ..Default::default()
+        };
+        Routing::from_proto(&slog::Logger::root(slog::Discard, slog::o!()), &proto)
+            .expect("routing")
+    }
+
+    #[test]
+    fn diff() {
+        let keypair = helium_crypto::Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let pub_key = keypair.public_key().to_vec();
+        let previous = routing(&[8080, 8081], &pub_key);
+        let current = routing(&[8081, 8082], &pub_key);
+
+        let diff = current.diff(Some(&previous));
+        assert_eq!(vec![current.uris[1].clone()], diff.added);
+        assert_eq!(vec![previous.uris[0].clone()], diff.removed);
+        assert!(!diff.is_empty());
+
+        assert!(current.diff(Some(&current)).is_empty());
+        assert_eq!(2, current.diff(None).added.len());
+    }
+}
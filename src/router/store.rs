@@ -1,19 +1,34 @@
-use crate::{CacheSettings, Packet, Result};
+use crate::{clock::unix_now, storage::Store, CacheSettings, KeyedUri, Packet, Result};
+use helium_proto::Message;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::VecDeque,
     ops::Deref,
+    sync::Arc,
     time::{Duration, Instant},
 };
+use xxhash_rust::xxh64::Xxh64;
 
+/// Prefix of the store keys of the uplinks queued for all routers
+const QUEUE_KEY_PREFIX: &str = "router_queue_";
+const QUEUE_KEY_SUFFIX: &str = ".json";
+
+/// The uplinks waiting to be sent to a router. Every queued uplink is also
+/// kept in the cache store until it is sent or discarded, so uplinks queued
+/// when the gateway is killed are picked up by the next run.
 pub struct RouterStore {
     waiting_packets: VecDeque<QuePacket>,
     max_packets: u16,
+    store: Arc<dyn Store>,
+    key_prefix: String,
+    next_seq: u64,
 }
 
 #[derive(Debug)]
 pub struct QuePacket {
     received: Instant,
     packet: Packet,
+    seq: u64,
 }
 
 impl QuePacket {
@@ -34,27 +49,130 @@ impl Deref for QuePacket {
     }
 }
 
+/// A queued uplink as kept in the cache store
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredPacket {
+    /// Unix timestamp (in seconds) of when the uplink was received
+    received: u64,
+    /// Base64 encoded packet proto
+    packet: String,
+}
+
 impl RouterStore {
-    pub fn new(settings: &CacheSettings) -> Self {
+    pub fn new(settings: &CacheSettings, store: Arc<dyn Store>, oui: u32, uri: &KeyedUri) -> Self {
         let max_packets = settings.max_packets;
         let waiting_packets = VecDeque::new();
+        let mut hasher = Xxh64::new(0);
+        hasher.update(uri.pubkey.to_string().as_bytes());
+        hasher.update(uri.uri.to_string().as_bytes());
+        let key_prefix = format!("{QUEUE_KEY_PREFIX}{oui}_{:016x}_", hasher.digest());
         Self {
             waiting_packets,
             max_packets,
+            store,
+            key_prefix,
+            next_seq: 0,
         }
     }
 
-    pub fn store_waiting_packet(&mut self, packet: Packet, received: Instant) -> Result {
-        self.waiting_packets
-            .push_back(QuePacket { packet, received });
+    fn key(&self, seq: u64) -> String {
+        format!("{}{seq:020}{QUEUE_KEY_SUFFIX}", self.key_prefix)
+    }
+
+    fn save(&self, packet: &QuePacket) -> Result {
+        let stored = StoredPacket {
+            received: unix_now()?.saturating_sub(packet.hold_time().as_secs()),
+            packet: base64::encode(packet.encode_to_vec()),
+        };
+        self.store
+            .put(&self.key(packet.seq), &serde_json::to_vec(&stored)?)
+    }
+
+    /// Removes the stored copy of a packet that left the queue. A copy that
+    /// fails to be removed is pruned once it is too old to be restored.
+    fn forget(&self, packet: &QuePacket) {
+        let _ = self.store.remove(&self.key(packet.seq));
+    }
+
+    /// Restores the uplinks that a previous run of the gateway left queued
+    /// for this router in the store. Stored uplinks older than the given age
+    /// are discarded for all routers. Returns the number of restored uplinks.
+    pub fn restore_waiting_packets(&mut self, max_age: Duration) -> Result<usize> {
+        self.store.prune(QUEUE_KEY_PREFIX, max_age)?;
+        let now = unix_now()?;
+        let mut restored = 0;
+        for (key, data) in self.store.scan(&self.key_prefix)? {
+            let seq = key[self.key_prefix.len()..]
+                .trim_end_matches(QUEUE_KEY_SUFFIX)
+                .parse::<u64>();
+            let stored = serde_json::from_slice::<StoredPacket>(&data);
+            let (seq, stored) = match (seq, stored) {
+                (Ok(seq), Ok(stored)) => (seq, stored),
+                _ => {
+                    self.store.remove(&key)?;
+                    continue;
+                }
+            };
+            let packet = helium_proto::Packet::decode(&base64::decode(&stored.packet)?[..])?;
+            let age = Duration::from_secs(now.saturating_sub(stored.received));
+            let received = Instant::now().checked_sub(age).unwrap_or_else(Instant::now);
+            self.next_seq = self.next_seq.max(seq + 1);
+            self.push_waiting_packet(QuePacket {
+                received,
+                packet: Packet::from(packet),
+                seq,
+            });
+            restored += 1;
+        }
+        Ok(restored)
+    }
+
+    fn push_waiting_packet(&mut self, packet: QuePacket) {
+        self.waiting_packets.push_back(packet);
         if self.waiting_packets_len() > self.max_packets as usize {
-            self.waiting_packets.pop_front();
+            if let Some(dropped) = self.waiting_packets.pop_front() {
+                self.forget(&dropped);
+            }
         }
-        Ok(())
+    }
+
+    /// Queues an uplink. The uplink is queued even if storing it fails.
+    pub fn store_waiting_packet(&mut self, packet: Packet, received: Instant) -> Result {
+        let packet = QuePacket {
+            packet,
+            received,
+            seq: self.next_seq,
+        };
+        self.next_seq += 1;
+        let saved = self.save(&packet);
+        self.push_waiting_packet(packet);
+        saved
     }
 
     pub fn pop_waiting_packet(&mut self) -> Option<QuePacket> {
-        self.waiting_packets.pop_front()
+        let packet = self.waiting_packets.pop_front()?;
+        self.forget(&packet);
+        Some(packet)
+    }
+
+    /// Puts a popped packet back at the front of the waiting packets
+    pub fn requeue_waiting_packet(&mut self, packet: QuePacket) {
+        // A packet that fails to be stored again is still sent or handed off
+        // by this run
+        let _ = self.save(&packet);
+        self.waiting_packets.push_front(packet);
+    }
+
+    /// Removes and returns all waiting packets, oldest first
+    pub fn take_waiting_packets(&mut self) -> Vec<Packet> {
+        let packets: Vec<QuePacket> = self.waiting_packets.drain(..).collect();
+        packets
+            .into_iter()
+            .map(|packet| {
+                self.forget(&packet);
+                packet.packet
+            })
+            .collect()
     }
 
     pub fn waiting_packets_len(&self) -> usize {
@@ -65,8 +183,82 @@ impl RouterStore {
     /// of packets that were removed.
     pub fn gc_waiting_packets(&mut self, duration: Duration) -> usize {
         let before_len = self.waiting_packets.len();
-        self.waiting_packets
-            .retain(|packet| packet.received.elapsed() <= duration);
+        let (kept, removed): (VecDeque<QuePacket>, VecDeque<QuePacket>) = self
+            .waiting_packets
+            .drain(..)
+            .partition(|packet| packet.received.elapsed() <= duration);
+        self.waiting_packets = kept;
+        removed.iter().for_each(|packet| self.forget(packet));
         before_len - self.waiting_packets.len()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    fn packet(payload: &[u8]) -> Packet {
+        Packet::from(helium_proto::Packet {
+            payload: payload.to_vec(),
+            ..Default::default()
+        })
+    }
+
+    fn router_store(store: Arc<dyn Store>, oui: u32) -> RouterStore {
+        let settings = CacheSettings {
+            max_packets: 2,
+            store: std::path::PathBuf::new(),
+            read_only: true,
+            runtime_dir: None,
+        };
+        let uri = KeyedUri {
+            uri: "http://127.0.0.1:8080".parse().expect("uri"),
+            pubkey: Arc::new(crate::keypair::test_pubkey()),
+        };
+        RouterStore::new(&settings, store, oui, &uri)
+    }
+
+    #[test]
+    fn restore() {
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        let mut queue = router_store(store.clone(), 1);
+        for payload in [b"a", b"b", b"c"] {
+            queue
+                .store_waiting_packet(packet(payload), Instant::now())
+                .expect("store");
+        }
+        // The oldest packet was dropped from the full queue
+        let popped = queue.pop_waiting_packet().expect("pop");
+        assert_eq!(b"b".to_vec(), popped.payload);
+        queue.requeue_waiting_packet(popped);
+
+        let mut restored = router_store(store.clone(), 1);
+        assert_eq!(
+            2,
+            restored
+                .restore_waiting_packets(Duration::from_secs(60))
+                .expect("restore")
+        );
+        restored
+            .store_waiting_packet(packet(b"d"), Instant::now())
+            .expect("store");
+        let payloads: Vec<Vec<u8>> = restored
+            .take_waiting_packets()
+            .into_iter()
+            .map(|packet| packet.payload.clone())
+            .collect();
+        assert_eq!(vec![b"c".to_vec(), b"d".to_vec()], payloads);
+
+        // Queues of other routers are kept apart
+        let mut other = router_store(store.clone(), 2);
+        assert_eq!(
+            0,
+            other
+                .restore_waiting_packets(Duration::from_secs(60))
+                .expect("restore")
+        );
+        // Taken packets are no longer stored
+        assert!(store.scan(QUEUE_KEY_PREFIX).expect("scan").is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+use crate::{sequence::SequenceId, KeyedUri, Packet};
+use std::{collections::VecDeque, fmt, time::Instant};
+
+/// A routing decision made by the dispatcher for a single uplink
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Decision {
+    /// The router's routing filters match the packet and it was sent the packet
+    Matched { oui: u32, uri: KeyedUri },
+    /// The router matches but is quarantined, or on probation and over its
+    /// packet limit, and was skipped
+    Quarantined { oui: u32, uri: KeyedUri },
+    /// The router matches but a better performing router for the same oui
+    /// was sent the packet instead
+    Outranked { oui: u32, uri: KeyedUri },
+    /// No router matched and the packet was sent to a default router
+    DefaultRouter { uri: KeyedUri },
+    /// Sending the packet to the router failed
+    DispatchError {
+        oui: u32,
+        uri: KeyedUri,
+        error: String,
+    },
+    /// No router matched and there was no default router to send it to
+    Unrouted,
+}
+
+impl fmt::Display for Decision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Matched { oui, uri } => write!(f, "matched oui {oui} {}", uri.uri),
+            Self::Quarantined { oui, uri } => write!(f, "quarantined oui {oui} {}", uri.uri),
+            Self::Outranked { oui, uri } => write!(f, "outranked oui {oui} {}", uri.uri),
+            Self::DefaultRouter { uri } => write!(f, "default router {}", uri.uri),
+            Self::DispatchError { oui, uri, error } => {
+                write!(f, "dispatch error oui {oui} {}: {error}", uri.uri)
+            }
+            Self::Unrouted => f.write_str("unrouted"),
+        }
+    }
+}
+
+/// The routing decisions made for a single uplink
+#[derive(Debug, Clone)]
+pub struct PacketTrace {
+    pub id: SequenceId,
+    /// Hash of the packet payload
+    pub hash: Vec<u8>,
+    pub received: Instant,
+    pub decisions: Vec<Decision>,
+}
+
+impl PacketTrace {
+    pub fn new(id: SequenceId, packet: &Packet, received: Instant) -> Self {
+        Self {
+            id,
+            hash: packet.hash(),
+            received,
+            decisions: vec![],
+        }
+    }
+
+    pub fn record(&mut self, decision: Decision) {
+        self.decisions.push(decision)
+    }
+
+    /// Records the given decision to an optional trace. The decision is only
+    /// built when there is a trace to record it to.
+    pub fn record_to(trace: &mut Option<Self>, decision: impl FnOnce() -> Decision) {
+        if let Some(trace) = trace {
+            trace.record(decision())
+        }
+    }
+}
+
+/// Keeps the traces of the most recent uplinks. A trace buffer with a zero
+/// capacity keeps no traces.
+#[derive(Debug, Default)]
+pub struct PacketTraces {
+    capacity: usize,
+    traces: VecDeque<PacketTrace>,
+}
+
+impl PacketTraces {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            traces: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.capacity > 0
+    }
+
+    pub fn push(&mut self, trace: PacketTrace) {
+        if !self.is_enabled() {
+            return;
+        }
+        if self.traces.len() >= self.capacity {
+            self.traces.pop_front();
+        }
+        self.traces.push_back(trace);
+    }
+
+    /// Returns the retained traces, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = &PacketTrace> {
+        self.traces.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn trace(hash: u8) -> PacketTrace {
+        PacketTrace {
+            id: SequenceId {
+                boot: 0,
+                seq: hash as u64,
+            },
+            hash: vec![hash],
+            received: Instant::now(),
+            decisions: vec![Decision::Unrouted],
+        }
+    }
+
+    #[test]
+    fn retains_latest() {
+        let mut traces = PacketTraces::new(2);
+        for hash in 0..3 {
+            traces.push(trace(hash));
+        }
+        let hashes: Vec<Vec<u8>> = traces.iter().map(|t| t.hash.clone()).collect();
+        assert_eq!(vec![vec![1], vec![2]], hashes);
+
+        let mut disabled = PacketTraces::new(0);
+        disabled.push(trace(0));
+        assert_eq!(0, disabled.iter().count());
+    }
+}
@@ -0,0 +1,166 @@
+use crate::{storage::Store, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Length of a usage bucket
+pub const USAGE_BUCKET: Duration = Duration::from_secs(3600); // 1 hour
+/// Number of most recent buckets kept per oui, whether or not the oui had
+/// traffic in them
+pub const USAGE_BUCKETS: u64 = 48;
+
+const USAGE_KEY: &str = "oui_usage.json";
+
+type Buckets = HashMap<u32, BTreeMap<u64, Usage>>;
+
+/// Traffic purchased by the routers of an oui within one bucket
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Usage {
+    pub packets: u64,
+    pub bytes: u64,
+    /// Data credits the purchased packets cost
+    pub dc: u64,
+}
+
+/// The usage of an oui in a single bucket
+#[derive(Debug, Clone, Serialize)]
+pub struct UsageBucket {
+    /// Unix timestamp (in seconds) of the start of the bucket
+    pub start: u64,
+    #[serde(flatten)]
+    pub usage: Usage,
+}
+
+/// Aggregates the uplink traffic routers purchased per oui in fixed time
+/// buckets, so the usage of every tenant oui served by the gateway can be
+/// reported. The usage is persisted in the cache store so it survives
+/// restarts. Clones share the same usage so it can be shared between the
+/// dispatcher and the router clients it starts.
+#[derive(Debug, Clone)]
+pub struct OuiUsage {
+    // Bucket start to usage, per oui
+    ouis: Arc<Mutex<Buckets>>,
+    store: Arc<dyn Store>,
+}
+
+impl OuiUsage {
+    /// Loads the usage stored by a previous run from the given store
+    pub fn new(store: Arc<dyn Store>) -> Self {
+        let ouis = store
+            .get(USAGE_KEY)
+            .ok()
+            .flatten()
+            .and_then(|data| serde_json::from_slice::<Buckets>(&data).ok())
+            .unwrap_or_default();
+        Self {
+            ouis: Arc::new(Mutex::new(ouis)),
+            store,
+        }
+    }
+
+    /// Records an uplink of the given payload size and data credit cost as
+    /// purchased by the router of the given oui at the given time
+    pub fn record(&self, oui: u32, bytes: usize, dc: u64, now: SystemTime) {
+        let mut ouis = self.ouis.lock().expect("oui usage lock");
+        let usage = ouis
+            .entry(oui)
+            .or_default()
+            .entry(bucket_start(now))
+            .or_default();
+        usage.packets += 1;
+        usage.bytes += bytes as u64;
+        usage.dc += dc;
+        expire(&mut ouis, now);
+    }
+
+    /// Returns the buckets of every oui within the last `USAGE_BUCKETS`
+    /// buckets before the given time, oldest first
+    pub fn buckets(&self, now: SystemTime) -> BTreeMap<u32, Vec<UsageBucket>> {
+        let mut ouis = self.ouis.lock().expect("oui usage lock");
+        expire(&mut ouis, now);
+        ouis.iter()
+            .map(|(oui, buckets)| {
+                let buckets = buckets
+                    .iter()
+                    .map(|(start, usage)| UsageBucket {
+                        start: *start,
+                        usage: *usage,
+                    })
+                    .collect();
+                (*oui, buckets)
+            })
+            .collect()
+    }
+
+    /// Stores the usage for the next run of the gateway
+    pub fn save(&self) -> Result {
+        let data = serde_json::to_vec(&*self.ouis.lock().expect("oui usage lock"))?;
+        self.store.put(USAGE_KEY, &data)
+    }
+}
+
+/// Removes the buckets that started `USAGE_BUCKETS` or more buckets before
+/// the bucket of the given time, and the ouis left without buckets
+fn expire(ouis: &mut Buckets, now: SystemTime) {
+    let oldest = bucket_start(now).saturating_sub(USAGE_BUCKET.as_secs() * (USAGE_BUCKETS - 1));
+    ouis.retain(|_, buckets| {
+        buckets.retain(|start, _| *start >= oldest);
+        !buckets.is_empty()
+    });
+}
+
+fn bucket_start(time: SystemTime) -> u64 {
+    let secs = time
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    secs - secs % USAGE_BUCKET.as_secs()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn buckets() {
+        let store = Arc::new(MemoryStore::new(10));
+        let usage = OuiUsage::new(store.clone());
+        let start = UNIX_EPOCH + USAGE_BUCKET * 10;
+        usage.record(1, 30, 2, start);
+        usage.record(1, 10, 1, start + USAGE_BUCKET - Duration::from_secs(1));
+        usage.record(2, 10, 1, start + USAGE_BUCKET);
+
+        let buckets = usage.buckets(start + USAGE_BUCKET);
+        assert_eq!(1, buckets[&1].len());
+        assert_eq!(USAGE_BUCKET.as_secs() * 10, buckets[&1][0].start);
+        assert_eq!(
+            Usage {
+                packets: 2,
+                bytes: 40,
+                dc: 3
+            },
+            buckets[&1][0].usage
+        );
+        assert_eq!(USAGE_BUCKET.as_secs() * 11, buckets[&2][0].start);
+
+        // The usage survives a restart
+        usage.save().expect("save");
+        let usage = OuiUsage::new(store);
+        assert_eq!(2, usage.buckets(start + USAGE_BUCKET).len());
+
+        for hour in 0..USAGE_BUCKETS as u32 + 2 {
+            usage.record(3, 1, 1, start + USAGE_BUCKET * hour);
+        }
+        let end = start + USAGE_BUCKET * (USAGE_BUCKETS as u32 + 1);
+        let buckets = usage.buckets(end);
+        assert_eq!(USAGE_BUCKETS as usize, buckets[&3].len());
+        assert_eq!(USAGE_BUCKET.as_secs() * 12, buckets[&3][0].start);
+        // Buckets expire by time, not only when newer buckets are recorded
+        assert!(!buckets.contains_key(&1));
+        let buckets = usage.buckets(end + USAGE_BUCKET * USAGE_BUCKETS as u32);
+        assert!(buckets.is_empty());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::storage::Store;
+use serde::Serialize;
+use std::{fmt, fs, sync::Arc};
+
+/// Number of ids reserved in the store at a time. The store is written once
+/// per block, and the unused ids of a block are skipped after a restart.
+pub const SEQUENCE_BLOCK_SIZE: u64 = 1000;
+
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+
+/// An id from a `Sequence`. The boot id distinguishes ids issued in different
+/// boots in case the persisted counter was lost, for example when the cache
+/// is kept in memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize)]
+pub struct SequenceId {
+    pub boot: u32,
+    pub seq: u64,
+}
+
+impl fmt::Display for SequenceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:08x}-{}", self.boot, self.seq)
+    }
+}
+
+/// A monotonic id sequence persisted in the cache store, so ids keep
+/// increasing across restarts of the gateway.
+#[derive(Debug)]
+pub struct Sequence {
+    key: String,
+    store: Arc<dyn Store>,
+    boot: u32,
+    next: u64,
+    // First id that has not been reserved in the store
+    reserved: u64,
+}
+
+impl Sequence {
+    /// Loads the sequence with the given name from the store, continuing
+    /// after the last block reserved by a previous run.
+    pub fn new(store: Arc<dyn Store>, name: &str) -> Self {
+        let key = format!("sequence_{name}.json");
+        let next = store
+            .get(&key)
+            .ok()
+            .flatten()
+            .and_then(|data| serde_json::from_slice::<u64>(&data).ok())
+            .unwrap_or(0);
+        Self {
+            key,
+            store,
+            boot: boot_id(),
+            next,
+            reserved: next,
+        }
+    }
+
+    pub fn next_id(&mut self) -> SequenceId {
+        if self.next >= self.reserved {
+            self.reserved = self.next.saturating_add(SEQUENCE_BLOCK_SIZE);
+            // A failed write only risks reusing ids after a restart, which
+            // the boot id still tells apart
+            let _ = serde_json::to_vec(&self.reserved)
+                .map_err(Into::into)
+                .and_then(|data| self.store.put(&self.key, &data));
+        }
+        let seq = self.next;
+        self.next += 1;
+        SequenceId {
+            boot: self.boot,
+            seq,
+        }
+    }
+}
+
+/// Returns the first 32 bits of the kernel boot id, or a random value where
+/// the boot id is not available
+fn boot_id() -> u32 {
+    fs::read_to_string(BOOT_ID_PATH)
+        .ok()
+        .and_then(|boot_id| u32::from_str_radix(boot_id.get(..8)?, 16).ok())
+        .unwrap_or_else(rand::random)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::storage::MemoryStore;
+
+    #[test]
+    fn survives_restart() {
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        let mut sequence = Sequence::new(store.clone(), "test");
+        assert_eq!(0, sequence.next_id().seq);
+        assert_eq!(1, sequence.next_id().seq);
+
+        let mut restarted = Sequence::new(store, "test");
+        let id = restarted.next_id();
+        assert_eq!(SEQUENCE_BLOCK_SIZE, id.seq);
+        assert_eq!(sequence.boot, id.boot);
+    }
+}
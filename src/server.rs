@@ -1,27 +1,97 @@
 use crate::*;
 use api::LocalServer;
+use futures::FutureExt;
 use gateway;
 use router::{dispatcher, Dispatcher};
-use slog::{info, Logger};
+use shutdown::Coordinator;
+use slog::{info, warn, Logger};
+use std::sync::{atomic::AtomicBool, Arc};
 use updater::Updater;
 
-pub async fn run(shutdown: &triggered::Listener, settings: &Settings, logger: &Logger) -> Result {
+/// Runs the gateway service. Servers take their listeners from the given
+/// socket activation descriptors where passed.
+/// The dispatcher hands its routing off to the next run of the gateway if
+/// restart is set by the time it shuts down.
+pub async fn run(
+    shutdown: &triggered::Listener,
+    settings: &Settings,
+    mut activated: activation::ListenFds,
+    restart: Arc<AtomicBool>,
+    logger: &Logger,
+) -> Result {
     let (gateway_tx, gateway_rx) = gateway::message_channel(10);
-    let (dispatcher_tx, dispatcher_rx) = dispatcher::message_channel(20);
-    let mut dispatcher = Dispatcher::new(dispatcher_rx, gateway_tx, settings)?;
-    let mut gateway = gateway::Gateway::new(dispatcher_tx.clone(), gateway_rx, settings).await?;
+    let downlink_latency = gateway_tx.downlink_latency();
+    let (dispatcher_tx, dispatcher_rx) =
+        dispatcher::message_channel(dispatcher::MESSAGE_QUEUE_SIZE);
+    // Wall clock time is disciplined by the GPS time of received uplinks on
+    // concentrators that report it
+    let clock = Arc::new(clock::GpsClock::default());
+    let mut dispatcher =
+        Dispatcher::new(dispatcher_rx, gateway_tx, clock.clone(), restart, settings)?;
+    let mut gateway = gateway::Gateway::new(
+        dispatcher_tx.clone(),
+        gateway_rx,
+        downlink_latency,
+        dispatcher.subscribe(),
+        clock,
+        settings,
+    )
+    .await?;
     let updater = Updater::new(settings)?;
-    let api = LocalServer::new(dispatcher_tx, settings)?;
+    let snapshots = dispatcher_tx.clone();
+    let api = LocalServer::new(dispatcher_tx, settings, &mut activated)?;
+    activation::close_unused(activated, logger);
     info!(logger,
         "starting server";
         "version" => settings::version().to_string(),
+        "features" => settings::features().join(","),
+        "commit" => env!("GATEWAY_GIT_COMMIT"),
+        "target" => env!("GATEWAY_TARGET"),
         "key" => settings.keypair.public_key().to_string(),
     );
+    if let Some(url) = settings.crash.upload.clone() {
+        let dir = settings.cache.crash_dir();
+        let logger = logger.clone();
+        tokio::spawn(async move {
+            if let Err(err) = crash::upload_pending(&dir, &url, &logger).await {
+                warn!(logger, "crash report upload error: {err:?}");
+            }
+        });
+    }
+    if settings.cache.read_only {
+        warn!(logger,
+            "read only mode, gateway state is kept in memory and lost on restart";
+            "lost" => "last used gateway, validator pool, routing table and handoff, oui usage, queued uplinks, validator health, connection and packet ids",
+            "kept" => "settings bundle serial",
+            "runtime_dir" => settings.cache.runtime_dir().to_string_lossy().into_owned(),
+        );
+    }
+    // Stop the packet forwarder side first so no new uplinks arrive while
+    // the dispatcher drains its routers and then closes its gateway streams
+    let mut coordinator = Coordinator::new(&settings.shutdown);
+    let (gateway_shutdown, gateway_stopped) = coordinator.stage("gateway");
+    let (dispatcher_shutdown, dispatcher_stopped) = coordinator.stage("dispatcher");
+    let (api_shutdown, api_stopped) = coordinator.stage("api");
+    let (updater_shutdown, updater_stopped) = coordinator.stage("updater");
     tokio::try_join!(
-        gateway.run(shutdown.clone(), logger),
-        dispatcher.run(shutdown.clone(), logger),
-        updater.run(shutdown.clone(), logger),
-        api.run(shutdown.clone(), logger),
+        coordinator.run(shutdown.clone(), logger),
+        gateway
+            .run(gateway_shutdown, logger)
+            .inspect(|_| gateway_stopped.trigger()),
+        dispatcher
+            .run(dispatcher_shutdown.clone(), logger)
+            .inspect(|_| dispatcher_stopped.trigger()),
+        dispatcher::dump_snapshots(
+            snapshots,
+            dispatcher::snapshot_path(&settings.cache),
+            dispatcher_shutdown,
+            logger
+        ),
+        updater
+            .run(updater_shutdown, logger)
+            .inspect(|_| updater_stopped.trigger()),
+        api.run(api_shutdown, logger)
+            .inspect(|_| api_stopped.trigger()),
     )
     .map(|_| ())
 }
@@ -0,0 +1,91 @@
+use crate::{Error, Result};
+use std::time::{Duration, Instant};
+
+/// Short-circuits calls to a failing validator. After `threshold` consecutive
+/// transport failures or timeouts the breaker opens and calls fail immediately for `open_period`.
+/// The first call after the open period is let through, and a failure of that
+/// call opens the breaker again.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    threshold: u32,
+    open_period: Duration,
+    failures: u32,
+    open_until: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, open_period: Duration) -> Self {
+        Self {
+            threshold,
+            open_period,
+            failures: 0,
+            open_until: None,
+        }
+    }
+
+    /// Returns an error if the breaker is open at the given time
+    pub fn check(&self, now: Instant) -> Result {
+        match self.open_until {
+            Some(open_until) if open_until > now => Err(Error::circuit_open(open_until - now)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Records the outcome of a call made at the given time. Returns true if
+    /// the failure opened the breaker. Only transport failures and timeouts
+    /// count as failures, an error response shows the validator is reachable.
+    pub fn record<T>(&mut self, result: &Result<T>, now: Instant) -> bool {
+        if !matches!(result, Err(err) if err.is_transport()) {
+            self.reset();
+            return false;
+        }
+        self.failures = self.failures.saturating_add(1);
+        if self.failures < self.threshold {
+            return false;
+        }
+        self.open_until = Some(now + self.open_period);
+        true
+    }
+
+    pub fn reset(&mut self) {
+        self.failures = 0;
+        self.open_until = None;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn opens_after_threshold() {
+        let period = Duration::from_secs(30);
+        let mut breaker = CircuitBreaker::new(2, period);
+        let now = Instant::now();
+        let failure: Result = Err(Error::timeout("test"));
+
+        assert!(!breaker.record(&failure, now));
+        assert!(breaker.check(now).is_ok());
+        assert!(breaker.record(&failure, now));
+        assert!(breaker.check(now).is_err());
+
+        // Half open after the period, a single failure opens it again
+        let later = now + period;
+        assert!(breaker.check(later).is_ok());
+        breaker.record(&failure, later);
+        assert!(breaker.check(later).is_err());
+
+        breaker.record(&Ok(()), later + period);
+        breaker.record(&failure, later + period);
+        assert!(breaker.check(later + period).is_ok());
+    }
+
+    #[test]
+    fn ignores_error_responses() {
+        let mut breaker = CircuitBreaker::new(1, Duration::from_secs(30));
+        let now = Instant::now();
+        let failure: Result = Err(Error::validator_error(b"not_found"));
+        assert!(!breaker.record(&failure, now));
+        assert!(breaker.check(now).is_ok());
+    }
+}
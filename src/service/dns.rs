@@ -0,0 +1,135 @@
+use crate::{
+    settings::{SeedUri, SrvSeed},
+    Error, KeyedUri, Result,
+};
+use rand::{rngs::OsRng, seq::SliceRandom};
+use slog::{warn, Logger};
+use std::{sync::Arc, time::Duration};
+use tokio::time;
+use trust_dns_resolver::TokioAsyncResolver;
+
+/// A service location from an SRV record
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SrvRecord {
+    pub priority: u16,
+    pub weight: u16,
+    pub port: u16,
+    pub target: String,
+}
+
+/// Returns the seed validators for the given seed settings. Keyed seeds are
+/// returned as is. SRV seeds are looked up and contribute one seed, picked
+/// from the SRV targets with a locally configured public key. SRV seeds that
+/// fail to resolve are logged and skipped.
+pub async fn seed_uris(seeds: &[SeedUri], timeout: Duration, logger: &Logger) -> Vec<KeyedUri> {
+    let mut uris = vec![];
+    for seed in seeds {
+        match seed {
+            SeedUri::Keyed(uri) => uris.push(uri.clone()),
+            SeedUri::Srv(srv) => match srv_seed(srv, timeout).await {
+                Ok(uri) => uris.push(uri),
+                Err(err) => warn!(logger, "seed srv lookup error: {err}"; "srv" => &srv.srv),
+            },
+        }
+    }
+    uris
+}
+
+async fn srv_seed(seed: &SrvSeed, timeout: Duration) -> Result<KeyedUri> {
+    let records = lookup_srv(&seed.srv, timeout).await?;
+    let record = select_srv(&records, |target| seed.pubkeys.contains_key(target))
+        .ok_or_else(|| Error::resolve(&seed.srv, "no srv target with a configured pubkey"))?;
+    Ok(KeyedUri {
+        uri: format!("{}://{}:{}", seed.scheme, record.target, record.port).parse()?,
+        pubkey: Arc::new(seed.pubkeys[&record.target].clone()),
+    })
+}
+
+/// Picks a record from the given SRV records as described in RFC 2782,
+/// considering only the records whose target is permitted. Of the permitted
+/// records with the lowest priority one is picked at random, weighted by the
+/// record weights. Records with a zero weight are only picked if all of them
+/// have a zero weight.
+fn select_srv<F>(records: &[SrvRecord], permitted: F) -> Option<&SrvRecord>
+where
+    F: Fn(&str) -> bool,
+{
+    let permitted: Vec<&SrvRecord> = records
+        .iter()
+        .filter(|record| permitted(&record.target))
+        .collect();
+    let priority = permitted.iter().map(|record| record.priority).min()?;
+    let candidates: Vec<&SrvRecord> = permitted
+        .into_iter()
+        .filter(|record| record.priority == priority)
+        .collect();
+    let weighted = candidates.iter().any(|record| record.weight > 0);
+    candidates
+        .choose_weighted(&mut OsRng, |record| {
+            if weighted {
+                u32::from(record.weight)
+            } else {
+                1
+            }
+        })
+        .ok()
+        .copied()
+}
+
+/// Looks up the SRV records of the given name with the system resolver
+/// configuration. Targets are returned in lower case without their trailing
+/// dot.
+pub async fn lookup_srv(name: &str, timeout: Duration) -> Result<Vec<SrvRecord>> {
+    let resolver =
+        TokioAsyncResolver::tokio_from_system_conf().map_err(|err| Error::resolve(name, err))?;
+    let lookup = match time::timeout(timeout, resolver.srv_lookup(name)).await {
+        Ok(Ok(lookup)) => lookup,
+        Ok(Err(err)) => return Err(Error::resolve(name, err)),
+        Err(_) => return Err(Error::resolve(name, "timeout")),
+    };
+    Ok(lookup
+        .iter()
+        .map(|srv| SrvRecord {
+            priority: srv.priority(),
+            weight: srv.weight(),
+            port: srv.port(),
+            target: srv.target().to_utf8().trim_end_matches('.').to_lowercase(),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn record(priority: u16, weight: u16, target: &str) -> SrvRecord {
+        SrvRecord {
+            priority,
+            weight,
+            port: 8080,
+            target: target.to_string(),
+        }
+    }
+
+    #[test]
+    fn select() {
+        let records = vec![
+            record(10, 0, "a.example.com"),
+            record(10, 5, "b.example.com"),
+            record(20, 100, "c.example.com"),
+        ];
+        // The zero weight record loses to the weighted one of the same
+        // priority
+        for _ in 0..10 {
+            let selected = select_srv(&records, |_| true).expect("selected");
+            assert_eq!("b.example.com", selected.target);
+        }
+        // Targets without a configured key are never picked, even if they
+        // have the lowest priority
+        let selected = select_srv(&records, |target| target != "b.example.com").expect("selected");
+        assert_eq!("a.example.com", selected.target);
+        let selected = select_srv(&records, |target| target == "c.example.com").expect("selected");
+        assert_eq!("c.example.com", selected.target);
+        assert_eq!(None, select_srv(&records, |_| false));
+    }
+}
@@ -1,44 +1,90 @@
 use crate::{
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
-    Error, KeyedUri, Keypair, MsgSign, MsgVerify, PublicKey, RegionParams, Result,
+    service::{
+        latency::{LatencyTracker, Rpc, LATENCY_SLO},
+        resolver::{ResolvedConnector, Resolver},
+        validator_pool::ValidatorPool,
+    },
+    settings::{self, TimeoutSettings, TlsSettings, ValidatorSettings, VerifyPolicy},
+    Error, KeyedUri, MsgVerify, PublicKey, RegionParams, RequestSigner, Result,
 };
+use futures::{future, ready};
 use helium_proto::{
     gateway_resp_v1,
     services::{self, Channel, Endpoint},
-    BlockchainVarV1, GatewayConfigReqV1, GatewayConfigRespV1, GatewayRegionParamsUpdateReqV1,
-    GatewayRespV1, GatewayRoutingReqV1, GatewayScIsActiveReqV1, GatewayScIsActiveRespV1,
-    GatewayValidatorsReqV1, GatewayValidatorsRespV1, GatewayVersionReqV1, GatewayVersionRespV1,
-    Routing,
+    BlockchainVarV1, GatewayConfigReqV1, GatewayConfigRespV1, GatewayErrorRespV1,
+    GatewayRegionParamsUpdateReqV1, GatewayRespV1, GatewayRoutingReqV1, GatewayScIsActiveReqV1,
+    GatewayScIsActiveRespV1, GatewayValidatorsReqV1, GatewayValidatorsRespV1, GatewayVersionReqV1,
+    GatewayVersionRespV1, Routing,
 };
+use http::Uri;
 use rand::{rngs::OsRng, seq::SliceRandom};
+use slog::{warn, Logger};
 use std::{
+    net::SocketAddr,
     pin::Pin,
     sync::Arc,
     task::{Context, Poll},
+    time::{Duration, Instant},
 };
 use tokio_stream::Stream;
 
 type GatewayClient = services::gateway::Client<Channel>;
+
+/// Block age at which a validator is considered to have fallen behind the
+/// chain. The dispatcher leaves a validator reporting an older block.
+pub const GATEWAY_MAX_BLOCK_AGE: Duration = Duration::from_secs(1800); // 30 minutes
 pub use crate::service::version::GatewayVersion;
+pub use health::{HealthScore, HealthTracker};
+
+pub mod health;
 
 #[derive(Debug)]
 pub struct Streaming {
     streaming: tonic::Streaming<GatewayRespV1>,
     verifier: Arc<PublicKey>,
+    verify_policy: VerifyPolicy,
+    verify_failures: u32,
+    logger: Logger,
+}
+
+impl Streaming {
+    pub fn with_verify_policy(self, verify_policy: VerifyPolicy) -> Self {
+        Self {
+            verify_policy,
+            ..self
+        }
+    }
+
+    /// Sets the logger for tolerated verification failures, for example to
+    /// tag them with the connection the stream belongs to
+    pub fn set_logger(&mut self, logger: Logger) {
+        self.logger = logger;
+    }
 }
 
 impl Stream for Streaming {
     type Item = Result<GatewayRespV1>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        Pin::new(&mut self.streaming)
-            .poll_next(cx)
-            .map_err(Error::from)
-            .map(|msg| match msg {
-                Some(Ok(response)) => Some(response.verify(&self.verifier).map(|_| response)),
-                Some(Err(err)) => Some(Err(err)),
-                None => None,
-            })
+        loop {
+            let response = match ready!(Pin::new(&mut self.streaming).poll_next(cx)) {
+                Some(Ok(response)) => response,
+                Some(Err(err)) => return Poll::Ready(Some(Err(err.into()))),
+                None => return Poll::Ready(None),
+            };
+            match response.verify(&self.verifier) {
+                Ok(()) => return Poll::Ready(Some(Ok(response))),
+                Err(err) => {
+                    self.verify_failures += 1;
+                    if !self.verify_policy.tolerates(self.verify_failures) {
+                        return Poll::Ready(Some(Err(err)));
+                    }
+                    warn!(self.logger, "ignoring unverified gateway response: {err:?}";
+                        "pubkey" => self.verifier.to_string(),
+                        "failures" => self.verify_failures);
+                }
+            }
+        }
     }
 }
 
@@ -56,6 +102,9 @@ impl Response for GatewayRespV1 {
     fn routings(&self) -> Result<&[Routing]> {
         match &self.msg {
             Some(gateway_resp_v1::Msg::RoutingStreamedResp(routings)) => Ok(&routings.routings),
+            Some(gateway_resp_v1::Msg::ErrorResp(GatewayErrorRespV1 { error, .. })) => {
+                Err(Error::validator_error(error))
+            }
             msg => Err(Error::custom(
                 format!("Unexpected gateway message {msg:?}",),
             )),
@@ -67,6 +116,9 @@ impl Response for GatewayRespV1 {
             Some(gateway_resp_v1::Msg::RegionParamsStreamedResp(params)) => {
                 RegionParams::try_from(params.to_owned())
             }
+            Some(gateway_resp_v1::Msg::ErrorResp(GatewayErrorRespV1 { error, .. })) => {
+                Err(Error::validator_error(error))
+            }
             msg => Err(Error::custom(
                 format!("Unexpected gateway message {msg:?}",),
             )),
@@ -74,68 +126,289 @@ impl Response for GatewayRespV1 {
     }
 }
 
+/// The chain height reported by a gateway
+#[derive(Debug, Clone, Copy)]
+pub struct BlockInfo {
+    pub height: u64,
+    /// Age in seconds of the block at `height` when it was fetched
+    pub block_age: u64,
+    pub fetched_at: Instant,
+    /// Estimated blocks per second, when a previous block info from the same
+    /// gateway is available to estimate it from
+    pub blocks_per_sec: Option<f64>,
+}
+
+impl BlockInfo {
+    pub fn new(height: u64, block_age: u64) -> Self {
+        Self {
+            height,
+            block_age,
+            fetched_at: Instant::now(),
+            blocks_per_sec: None,
+        }
+    }
+
+    /// Estimates the block rate from the height progress since the given
+    /// earlier block info. The previous estimate is kept if the chain has not
+    /// progressed.
+    pub fn with_rate(self, previous: &BlockInfo) -> Self {
+        let elapsed = self
+            .fetched_at
+            .saturating_duration_since(previous.fetched_at)
+            .as_secs_f64();
+        let blocks_per_sec = match self.height.checked_sub(previous.height) {
+            Some(blocks) if blocks > 0 && elapsed > 0.0 => Some(blocks as f64 / elapsed),
+            _ => previous.blocks_per_sec,
+        };
+        Self {
+            blocks_per_sec,
+            ..self
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct GatewayService {
     pub uri: KeyedUri,
     client: GatewayClient,
+    health: HealthTracker,
+    timeouts: TimeoutSettings,
+    tls: TlsSettings,
 }
 
 impl GatewayService {
-    pub fn new(keyed_uri: &KeyedUri) -> Result<Self> {
-        let channel = Endpoint::from(keyed_uri.uri.clone())
-            .connect_timeout(CONNECT_TIMEOUT)
-            .timeout(RPC_TIMEOUT)
-            .connect_lazy();
+    pub fn new(
+        keyed_uri: &KeyedUri,
+        health: HealthTracker,
+        timeouts: TimeoutSettings,
+        tls: &TlsSettings,
+    ) -> Result<Self> {
+        Self::with_addr(keyed_uri, None, health, timeouts, tls)
+    }
+
+    /// Returns a service for the given uri that connects to the address the
+    /// given resolver looked the uri host up at. The lazy channel of a
+    /// service created with `new` resolves the host once, so a seed behind
+    /// round robin DNS would keep being reached at the same address. The
+    /// channel keeps the original uri, so the authority and host sent to the
+    /// validator and the name checked by TLS are unchanged.
+    pub async fn resolve(
+        keyed_uri: &KeyedUri,
+        resolver: &Resolver,
+        health: HealthTracker,
+        timeouts: TimeoutSettings,
+        tls: &TlsSettings,
+    ) -> Result<Self> {
+        let addr = resolver.resolve(&keyed_uri.uri).await?;
+        Self::with_addr(keyed_uri, Some(addr), health, timeouts, tls)
+    }
+
+    fn with_addr(
+        keyed_uri: &KeyedUri,
+        addr: Option<SocketAddr>,
+        health: HealthTracker,
+        timeouts: TimeoutSettings,
+        tls: &TlsSettings,
+    ) -> Result<Self> {
+        let mut endpoint = Self::endpoint(&keyed_uri.uri, tls)?
+            .connect_timeout(timeouts.connect())
+            .timeout(timeouts.rpc())
+            .user_agent(settings::user_agent())?;
+        if let Some(interval) = timeouts.keepalive_interval() {
+            endpoint = endpoint
+                .http2_keep_alive_interval(interval)
+                .keep_alive_timeout(timeouts.keepalive_timeout())
+                .keep_alive_while_idle(true);
+        }
+        let channel = match addr {
+            Some(addr) => endpoint
+                .connect_with_connector_lazy(ResolvedConnector::new(addr, timeouts.connect())),
+            None => endpoint.connect_lazy(),
+        };
         Ok(Self {
             uri: keyed_uri.clone(),
             client: GatewayClient::new(channel),
+            health,
+            timeouts,
+            tls: tls.clone(),
         })
     }
 
-    pub fn select_seed(seed_uris: &[KeyedUri]) -> Result<Self> {
-        seed_uris
+    /// Returns the endpoint for the given uri. Validators with https or grpcs
+    /// uris are connected to with TLS.
+    fn endpoint(uri: &Uri, tls: &TlsSettings) -> Result<Endpoint> {
+        match uri.scheme_str() {
+            Some("https") | Some("grpcs") => Self::tls_endpoint(uri, tls),
+            _ => Ok(Endpoint::from(uri.clone())),
+        }
+    }
+
+    #[cfg(feature = "tls")]
+    fn tls_endpoint(uri: &Uri, tls: &TlsSettings) -> Result<Endpoint> {
+        use tonic::transport::{Certificate, ClientTlsConfig};
+        // The transport only knows grpcs uris by their https scheme
+        let mut parts = uri.clone().into_parts();
+        parts.scheme = Some(http::uri::Scheme::HTTPS);
+        let uri = Uri::from_parts(parts)
+            .map_err(|err| Error::custom(format!("invalid validator uri: {err}")))?;
+        let mut config = ClientTlsConfig::new();
+        if let Some(ca) = tls.ca_pem() {
+            config = config.ca_certificate(Certificate::from_pem(ca));
+        }
+        if let Some(domain) = &tls.domain {
+            config = config.domain_name(domain);
+        }
+        Ok(Endpoint::from(uri).tls_config(config)?)
+    }
+
+    #[cfg(not(feature = "tls"))]
+    fn tls_endpoint(uri: &Uri, _tls: &TlsSettings) -> Result<Endpoint> {
+        Err(Error::custom(format!(
+            "tls support not enabled for validator {uri}"
+        )))
+    }
+
+    /// Selects a random seed from the given list, looking its host up with
+    /// the given resolver on every selection
+    pub async fn select_seed(
+        seed_uris: &[KeyedUri],
+        resolver: &Resolver,
+        health: &HealthTracker,
+        timeouts: TimeoutSettings,
+        tls: &TlsSettings,
+    ) -> Result<Self> {
+        let uri = seed_uris
             .choose(&mut OsRng)
-            .ok_or_else(|| Error::custom("empty uri list"))
-            .and_then(Self::new)
+            .ok_or_else(|| Error::custom("empty uri list"))?;
+        Self::resolve(uri, resolver, health.clone(), timeouts, tls).await
     }
 
-    pub async fn random_new(
-        &mut self,
-        fetch_count: u8,
-        cancel: triggered::Listener,
-    ) -> Result<Option<Self>> {
-        tokio::select! {
-            gateways = self.validators(fetch_count.into()) => match gateways {
-                Ok(gateways) => gateways
-                    .choose(&mut OsRng)
-                    .ok_or_else(|| Error::custom("empty gateway list"))
-                    .and_then(Self::new)
-                    .map(Some),
-                Err(err) => Err(err)
-            },
-            _ = cancel.clone() => Ok(None)
+    /// Returns a service for the given validator that shares the latency
+    /// tracker, timeouts and TLS settings of this one
+    pub fn with_uri(&self, keyed_uri: &KeyedUri) -> Result<Self> {
+        Self::new(keyed_uri, self.health.clone(), self.timeouts, &self.tls)
+    }
+
+    pub fn health(&self) -> &HealthTracker {
+        &self.health
+    }
+
+    pub fn latencies(&self) -> &LatencyTracker {
+        self.health.latencies()
+    }
+
+    fn record_latency(&self, rpc: Rpc, started: Instant) {
+        self.latencies()
+            .record(&self.uri.pubkey, rpc, started.elapsed())
+    }
+
+    /// Selects a random validator from the given list that is permitted by
+    /// the given validator settings, weighted by validator health. Validators
+    /// that are quarantined or do not meet the latency SLO are skipped unless
+    /// that leaves none.
+    pub fn select_from(
+        &self,
+        validators: &[KeyedUri],
+        permitted: &ValidatorSettings,
+    ) -> Result<Self> {
+        let candidates = self.health.candidates(
+            validators
+                .iter()
+                .filter(|uri| permitted.permits(&uri.pubkey))
+                .cloned()
+                .collect(),
+        );
+        candidates
+            .choose_weighted(&mut OsRng, |uri| self.health.weight(&uri.pubkey))
+            .map_err(|_| Error::custom("empty gateway list"))
+            .and_then(|uri| self.with_uri(uri))
+    }
+
+    /// Probes the validators in the given list that are permitted by the
+    /// given validator settings with a version request. Returns the
+    /// validators that responded within the latency objective with their
+    /// reported version, fastest first. The probed latency of a validator is
+    /// scaled by its health weight, so an unhealthy validator ranks as
+    /// slower. Quarantined validators are skipped unless all are.
+    pub async fn probe_fastest(
+        &self,
+        validators: &[KeyedUri],
+        permitted: &ValidatorSettings,
+    ) -> Result<Vec<(Self, Option<u64>)>> {
+        let candidates = self.health.unquarantined(
+            validators
+                .iter()
+                .filter(|uri| permitted.permits(&uri.pubkey))
+                .cloned()
+                .collect(),
+        );
+        let probes = candidates
+            .iter()
+            .map(|uri| self.with_uri(uri))
+            .collect::<Result<Vec<Self>>>()?
+            .into_iter()
+            .map(|mut gateway| async move {
+                let started = Instant::now();
+                let probed = gateway.version().await;
+                (gateway, probed.map(|version| (version, started.elapsed())))
+            });
+        let mut responded: Vec<(Self, Option<u64>, f64)> = future::join_all(probes)
+            .await
+            .into_iter()
+            .filter_map(|(gateway, probed)| match probed {
+                Ok((version, latency)) if latency <= LATENCY_SLO => {
+                    let rank = latency.as_secs_f64() / self.health.weight(&gateway.uri.pubkey);
+                    Some((gateway, version, rank))
+                }
+                _ => None,
+            })
+            .collect();
+        if responded.is_empty() {
+            return Err(Error::custom("no validator responded to probe"));
         }
+        responded.sort_by(|(_, _, a), (_, _, b)| a.total_cmp(b));
+        Ok(responded
+            .into_iter()
+            .map(|(gateway, version, _)| (gateway, version))
+            .collect())
+    }
+
+    /// Fetches validators from this one along with the chain height they were
+    /// fetched at.
+    pub async fn validator_pool(&mut self, quantity: u32) -> Result<ValidatorPool> {
+        let (validators, height) = self.fetch_validators(quantity).await?;
+        ValidatorPool::new(validators, height)
     }
 
     pub async fn routing(&mut self, height: u64) -> Result<Streaming> {
-        let stream = self.client.routing(GatewayRoutingReqV1 { height }).await?;
+        let started = Instant::now();
+        let stream = self.client.routing(GatewayRoutingReqV1 { height }).await;
+        self.record_latency(Rpc::Routing, started);
+        let stream = stream?;
         Ok(Streaming {
             streaming: stream.into_inner(),
             verifier: self.uri.pubkey.clone(),
+            verify_policy: VerifyPolicy::default(),
+            verify_failures: 0,
+            logger: slog_scope::logger(),
         })
     }
 
-    pub async fn region_params(&mut self, keypair: Arc<Keypair>) -> Result<Streaming> {
-        let mut req = GatewayRegionParamsUpdateReqV1 {
-            address: keypair.public_key().to_vec(),
-            signature: vec![],
-        };
-        req.signature = req.sign(keypair).await?;
+    pub async fn region_params(&mut self, signer: RequestSigner) -> Result<Streaming> {
+        let req = signer
+            .sign(GatewayRegionParamsUpdateReqV1::default())
+            .await?;
 
-        let stream = self.client.region_params_update(req).await?;
+        let started = Instant::now();
+        let stream = self.client.region_params_update(req).await;
+        self.record_latency(Rpc::RegionParams, started);
+        let stream = stream?;
         Ok(Streaming {
             streaming: stream.into_inner(),
             verifier: self.uri.pubkey.clone(),
+            verify_policy: VerifyPolicy::default(),
+            verify_failures: 0,
+            logger: slog_scope::logger(),
         })
     }
 
@@ -144,14 +417,16 @@ impl GatewayService {
         id: &[u8],
         owner: &[u8],
     ) -> Result<GatewayScIsActiveRespV1> {
+        let started = Instant::now();
         let resp = self
             .client
             .is_active_sc(GatewayScIsActiveReqV1 {
                 sc_owner: owner.into(),
                 sc_id: id.into(),
             })
-            .await?
-            .into_inner();
+            .await;
+        self.record_latency(Rpc::IsActiveSc, started);
+        let resp = resp?.into_inner();
         resp.verify(&self.uri.pubkey)?;
         match resp.msg {
             Some(gateway_resp_v1::Msg::IsActiveResp(resp)) => {
@@ -164,6 +439,9 @@ impl GatewayService {
                     Err(Error::custom("mismatched state channel id and owner"))
                 }
             }
+            Some(gateway_resp_v1::Msg::ErrorResp(GatewayErrorRespV1 { error, .. })) => {
+                Err(Error::validator_error(&error))
+            }
             Some(other) => Err(Error::custom(format!(
                 "invalid is_active response {other:?}",
             ))),
@@ -172,11 +450,10 @@ impl GatewayService {
     }
 
     async fn get_config(&mut self, keys: Vec<String>) -> Result<GatewayRespV1> {
-        let resp = self
-            .client
-            .config(GatewayConfigReqV1 { keys })
-            .await?
-            .into_inner();
+        let started = Instant::now();
+        let resp = self.client.config(GatewayConfigReqV1 { keys }).await;
+        self.record_latency(Rpc::Config, started);
+        let resp = resp?.into_inner();
         resp.verify(&self.uri.pubkey)?;
         Ok(resp)
     }
@@ -184,26 +461,46 @@ impl GatewayService {
     pub async fn config(&mut self, keys: Vec<String>) -> Result<Vec<BlockchainVarV1>> {
         match self.get_config(keys).await?.msg {
             Some(gateway_resp_v1::Msg::ConfigResp(GatewayConfigRespV1 { result })) => Ok(result),
+            Some(gateway_resp_v1::Msg::ErrorResp(GatewayErrorRespV1 { error, .. })) => {
+                Err(Error::validator_error(&error))
+            }
             Some(other) => Err(Error::custom(format!("invalid config response {other:?}"))),
             None => Err(Error::custom("empty config response")),
         }
     }
 
-    pub async fn height(&mut self) -> Result<(u64, u64)> {
+    pub async fn height(&mut self) -> Result<BlockInfo> {
         let resp = self.get_config(vec![]).await?;
-        Ok((resp.height, resp.block_age))
+        self.health
+            .record_block_age(&self.uri.pubkey, resp.block_age);
+        Ok(BlockInfo::new(resp.height, resp.block_age))
     }
 
     pub async fn validators(&mut self, quantity: u32) -> Result<Vec<KeyedUri>> {
+        self.fetch_validators(quantity)
+            .await
+            .map(|(validators, _height)| validators)
+    }
+
+    async fn fetch_validators(&mut self, quantity: u32) -> Result<(Vec<KeyedUri>, u64)> {
+        let started = Instant::now();
         let resp = self
             .client
             .validators(GatewayValidatorsReqV1 { quantity })
-            .await?
-            .into_inner();
+            .await;
+        self.record_latency(Rpc::Validators, started);
+        let resp = resp?.into_inner();
         resp.verify(&self.uri.pubkey)?;
         match resp.msg {
             Some(gateway_resp_v1::Msg::ValidatorsResp(GatewayValidatorsRespV1 { result })) => {
-                result.into_iter().map(KeyedUri::try_from).collect()
+                let validators = result
+                    .into_iter()
+                    .map(KeyedUri::try_from)
+                    .collect::<Result<_>>()?;
+                Ok((validators, resp.height))
+            }
+            Some(gateway_resp_v1::Msg::ErrorResp(GatewayErrorRespV1 { error, .. })) => {
+                Err(Error::validator_error(&error))
             }
             Some(other) => Err(Error::custom(format!(
                 "invalid validator response {other:?}"
@@ -213,16 +510,18 @@ impl GatewayService {
     }
 
     pub async fn version(&mut self) -> Result<Option<u64>> {
-        let resp = self
-            .client
-            .version(GatewayVersionReqV1 {})
-            .await?
-            .into_inner();
+        let started = Instant::now();
+        let resp = self.client.version(GatewayVersionReqV1 {}).await;
+        self.record_latency(Rpc::Version, started);
+        let resp = resp?.into_inner();
         resp.verify(&self.uri.pubkey)?;
         match resp.msg {
             Some(gateway_resp_v1::Msg::Version(GatewayVersionRespV1 { version })) => {
                 Ok(Some(version))
             }
+            Some(gateway_resp_v1::Msg::ErrorResp(GatewayErrorRespV1 { error, .. })) => {
+                Err(Error::validator_error(&error))
+            }
             Some(other) => Err(Error::custom(format!(
                 "invalid validator response {other:?}"
             ))),
@@ -230,3 +529,26 @@ impl GatewayService {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn block_rate() {
+        let previous = BlockInfo::new(100, 10);
+        let current = BlockInfo {
+            fetched_at: previous.fetched_at + Duration::from_secs(120),
+            ..BlockInfo::new(102, 5)
+        }
+        .with_rate(&previous);
+        assert_eq!(Some(2.0 / 120.0), current.blocks_per_sec);
+
+        let stalled = BlockInfo {
+            fetched_at: current.fetched_at + Duration::from_secs(60),
+            ..BlockInfo::new(102, 65)
+        }
+        .with_rate(&current);
+        assert_eq!(current.blocks_per_sec, stalled.blocks_per_sec);
+    }
+}
@@ -0,0 +1,362 @@
+use super::GATEWAY_MAX_BLOCK_AGE;
+use crate::{
+    clock::{self, SharedClock},
+    service::latency::{LatencyTracker, LATENCY_SLO},
+    storage::{MemoryStore, Store},
+    KeyedUri, PublicKey,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, UNIX_EPOCH},
+};
+
+/// Prefix of the store keys of the stream failures of validators
+const HEALTH_KEY_PREFIX: &str = "validator_health_";
+const HEALTH_KEY_SUFFIX: &str = ".json";
+
+/// Number of stream failures after which a validator is quarantined
+pub const VALIDATOR_QUARANTINE_THRESHOLD: u32 = 2;
+/// Quarantine period of a validator that reached the failure threshold. The
+/// period doubles with each further failure.
+pub const VALIDATOR_QUARANTINE_PENALTY: Duration = Duration::from_secs(300); // 5 minutes
+/// Time without failures after which the failures of a validator are
+/// forgotten. Also the longest a validator is quarantined for.
+pub const VALIDATOR_QUARANTINE_DECAY: Duration = Duration::from_secs(3600); // 1 hour
+/// Block age up to which a validator is considered in sync with the chain
+pub const HEALTH_BLOCK_AGE_OK: Duration = Duration::from_secs(300); // 5 minutes
+/// Lowest selection weight of a validator, so that even unhealthy validators
+/// are eventually tried again
+pub const HEALTH_MIN_WEIGHT: f64 = 0.01;
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ValidatorHealth {
+    stream_failures: u32,
+    last_failure: Option<Instant>,
+    block_age: Option<u64>,
+}
+
+impl ValidatorHealth {
+    fn stream_failures(&self, now: Instant) -> u32 {
+        match self.last_failure {
+            Some(last) if now < last + VALIDATOR_QUARANTINE_DECAY => self.stream_failures,
+            _ => 0,
+        }
+    }
+
+    fn quarantined_until(&self, now: Instant) -> Option<Instant> {
+        let excess = self
+            .stream_failures(now)
+            .checked_sub(VALIDATOR_QUARANTINE_THRESHOLD)?;
+        let penalty = VALIDATOR_QUARANTINE_PENALTY
+            .saturating_mul(2u32.saturating_pow(excess.min(16)))
+            .min(VALIDATOR_QUARANTINE_DECAY);
+        Some(self.last_failure? + penalty)
+    }
+}
+
+/// The stream failures of a validator as kept in the cache store
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredHealth {
+    stream_failures: u32,
+    /// Unix timestamp (in seconds) of the last stream failure
+    last_failure: u64,
+}
+
+/// The health of a single validator as used for selection
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthScore {
+    pub pubkey: String,
+    /// Score between 0 (unhealthy) and 1 (healthy)
+    pub score: f64,
+    /// Recent stream failures, forgotten after a quiet period
+    pub stream_failures: u32,
+    /// Whether the validator is quarantined for its stream failures
+    pub quarantined: bool,
+    /// Block age last reported by the validator in seconds
+    pub block_age: Option<u64>,
+    /// p95 rpc latency in milliseconds
+    pub p95_ms: Option<u64>,
+}
+
+/// Tracks the stream failures, reported block ages and rpc latencies of
+/// validators. Validators that repeatedly fail their streams are quarantined
+/// from selection, and the remaining ones are scored by their health.
+/// Validators without any history score as healthy so they get a chance to
+/// be measured. Failures and quarantines are timed with the given clock.
+/// Clones share the same history so a tracker can be handed to every
+/// `GatewayService` derived from the same seed. Stream failures are kept in
+/// the given store so quarantines survive a restart of the gateway.
+#[derive(Clone)]
+pub struct HealthTracker {
+    latencies: LatencyTracker,
+    validators: Arc<Mutex<HashMap<PublicKey, ValidatorHealth>>>,
+    clock: SharedClock,
+    store: Arc<dyn Store>,
+}
+
+impl Default for HealthTracker {
+    fn default() -> Self {
+        Self::new(clock::system(), Arc::new(MemoryStore::default()))
+    }
+}
+
+impl std::fmt::Debug for HealthTracker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HealthTracker")
+            .field("latencies", &self.latencies)
+            .field("validators", &self.validators)
+            .finish()
+    }
+}
+
+impl HealthTracker {
+    /// Creates a tracker with the stream failures restored from the given
+    /// store. Failures that could not be restored are ignored.
+    pub fn new(clock: SharedClock, store: Arc<dyn Store>) -> Self {
+        let validators = Self::load(clock.as_ref(), store.as_ref()).unwrap_or_default();
+        Self {
+            latencies: LatencyTracker::default(),
+            validators: Arc::new(Mutex::new(validators)),
+            clock,
+            store,
+        }
+    }
+
+    fn load(
+        clock: &dyn clock::Clock,
+        store: &dyn Store,
+    ) -> crate::Result<HashMap<PublicKey, ValidatorHealth>> {
+        store.prune(HEALTH_KEY_PREFIX, VALIDATOR_QUARANTINE_DECAY)?;
+        let now = clock.now();
+        let unix_now = clock.system_time().duration_since(UNIX_EPOCH)?.as_secs();
+        let mut validators = HashMap::new();
+        for (key, data) in store.scan(HEALTH_KEY_PREFIX)? {
+            let pubkey = key[HEALTH_KEY_PREFIX.len()..].trim_end_matches(HEALTH_KEY_SUFFIX);
+            let (pubkey, stored) = match (
+                PublicKey::from_str(pubkey),
+                serde_json::from_slice::<StoredHealth>(&data),
+            ) {
+                (Ok(pubkey), Ok(stored)) => (pubkey, stored),
+                _ => continue,
+            };
+            let age = Duration::from_secs(unix_now.saturating_sub(stored.last_failure));
+            let last_failure = match now.checked_sub(age) {
+                Some(last_failure) => last_failure,
+                None => continue,
+            };
+            let health = ValidatorHealth {
+                stream_failures: stored.stream_failures,
+                last_failure: Some(last_failure),
+                block_age: None,
+            };
+            validators.insert(pubkey, health);
+        }
+        Ok(validators)
+    }
+
+    fn save(&self, validator: &PublicKey, health: &ValidatorHealth) -> crate::Result {
+        let stored = StoredHealth {
+            stream_failures: health.stream_failures,
+            last_failure: self
+                .clock
+                .system_time()
+                .duration_since(UNIX_EPOCH)?
+                .as_secs(),
+        };
+        self.store.put(
+            &format!("{HEALTH_KEY_PREFIX}{validator}{HEALTH_KEY_SUFFIX}"),
+            &serde_json::to_vec(&stored)?,
+        )
+    }
+
+    pub fn latencies(&self) -> &LatencyTracker {
+        &self.latencies
+    }
+
+    /// Records a stream failure of the given validator, either a dropped
+    /// stream or streams that could not be set up. Returns the end of its
+    /// quarantine if the validator is quarantined after this failure.
+    pub fn record_stream_failure(&self, validator: &PublicKey) -> Option<Instant> {
+        let now = self.clock.now();
+        let mut validators = self.validators.lock().expect("health tracker lock");
+        let health = validators.entry(validator.clone()).or_default();
+        health.stream_failures = health.stream_failures(now) + 1;
+        health.last_failure = Some(now);
+        // The failure still counts for this run if it fails to be stored
+        let _ = self.save(validator, health);
+        health.quarantined_until(now)
+    }
+
+    pub fn record_block_age(&self, validator: &PublicKey, block_age: u64) {
+        let mut validators = self.validators.lock().expect("health tracker lock");
+        validators.entry(validator.clone()).or_default().block_age = Some(block_age);
+    }
+
+    pub fn is_quarantined(&self, validator: &PublicKey) -> bool {
+        let now = self.clock.now();
+        self.health(validator)
+            .quarantined_until(now)
+            .map_or(false, |until| now < until)
+    }
+
+    /// Returns the validators in the given list that are not quarantined, or
+    /// the full list if all of them are.
+    pub fn unquarantined(&self, validators: Vec<KeyedUri>) -> Vec<KeyedUri> {
+        let (quarantined, candidates): (Vec<KeyedUri>, Vec<KeyedUri>) = validators
+            .into_iter()
+            .partition(|uri| self.is_quarantined(&uri.pubkey));
+        if candidates.is_empty() {
+            quarantined
+        } else {
+            candidates
+        }
+    }
+
+    /// Returns the validators in the given list that are not quarantined and
+    /// meet the latency SLO. Each filter is skipped if it would leave no
+    /// validators.
+    pub fn candidates(&self, validators: Vec<KeyedUri>) -> Vec<KeyedUri> {
+        let (candidates, slow): (Vec<KeyedUri>, Vec<KeyedUri>) = self
+            .unquarantined(validators)
+            .into_iter()
+            .partition(|uri| self.latencies.meets_slo(&uri.pubkey));
+        if candidates.is_empty() {
+            slow
+        } else {
+            candidates
+        }
+    }
+
+    /// Returns the health score of the given validator
+    pub fn score(&self, validator: &PublicKey) -> f64 {
+        self.health_score(validator).score
+    }
+
+    /// Returns the health scores of all validators with a recorded history
+    pub fn scores(&self) -> Vec<HealthScore> {
+        let validators: Vec<PublicKey> = self
+            .validators
+            .lock()
+            .expect("health tracker lock")
+            .keys()
+            .cloned()
+            .collect();
+        validators
+            .iter()
+            .map(|validator| self.health_score(validator))
+            .collect()
+    }
+
+    /// Returns the selection weight of the given validator
+    pub fn weight(&self, validator: &PublicKey) -> f64 {
+        self.score(validator).max(HEALTH_MIN_WEIGHT)
+    }
+
+    fn health(&self, validator: &PublicKey) -> ValidatorHealth {
+        self.validators
+            .lock()
+            .expect("health tracker lock")
+            .get(validator)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    fn health_score(&self, validator: &PublicKey) -> HealthScore {
+        let now = self.clock.now();
+        let health = self.health(validator);
+        let p95 = self.latencies.window(validator).p95();
+        let stream_failures = health.stream_failures(now);
+        // Each recent stream failure halves the score
+        let failures_score = 0.5f64.powi(stream_failures.min(16) as i32);
+        // Falls linearly from the ok block age to the age at which the
+        // dispatcher leaves a validator
+        let block_age_score = health.block_age.map_or(1.0, |age| {
+            let ok = HEALTH_BLOCK_AGE_OK.as_secs();
+            let max = GATEWAY_MAX_BLOCK_AGE.as_secs();
+            1.0 - (age.clamp(ok, max) - ok) as f64 / (max - ok) as f64
+        });
+        let latency_score = p95.map_or(1.0, |p95| {
+            (LATENCY_SLO.as_secs_f64() / p95.as_secs_f64().max(f64::EPSILON)).min(1.0)
+        });
+        HealthScore {
+            pubkey: validator.to_string(),
+            score: failures_score * block_age_score * latency_score,
+            stream_failures,
+            quarantined: health
+                .quarantined_until(now)
+                .map_or(false, |until| now < until),
+            block_age: health.block_age,
+            p95_ms: p95.map(|p95| p95.as_millis() as u64),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        clock::{Clock, MockClock},
+        keypair::test_pubkey,
+        service::latency::Rpc,
+    };
+
+    #[test]
+    fn score() {
+        let pubkey = test_pubkey();
+        let clock = Arc::new(MockClock::default());
+        let health = HealthTracker::new(clock.clone(), Arc::new(MemoryStore::default()));
+        assert_eq!(1.0, health.score(&pubkey));
+
+        health.record_stream_failure(&pubkey);
+        assert_eq!(0.5, health.score(&pubkey));
+        // Stream failures are forgotten after a quiet period
+        clock.advance(VALIDATOR_QUARANTINE_DECAY);
+        assert_eq!(1.0, health.score(&pubkey));
+
+        health.record_block_age(&pubkey, GATEWAY_MAX_BLOCK_AGE.as_secs());
+        assert_eq!(0.0, health.score(&pubkey));
+        assert_eq!(HEALTH_MIN_WEIGHT, health.weight(&pubkey));
+        health.record_block_age(&pubkey, 10);
+
+        health
+            .latencies()
+            .record(&pubkey, Rpc::Config, LATENCY_SLO * 2);
+        assert_eq!(0.5, health.score(&pubkey));
+        assert_eq!(1, health.scores().len());
+    }
+
+    #[test]
+    fn quarantine() {
+        let pubkey = test_pubkey();
+        let clock = Arc::new(MockClock::default());
+        let store: Arc<dyn Store> = Arc::new(MemoryStore::default());
+        let health = HealthTracker::new(clock.clone(), store.clone());
+        let now = clock.now();
+        assert_eq!(None, health.record_stream_failure(&pubkey));
+        assert!(!health.is_quarantined(&pubkey));
+
+        let until = health.record_stream_failure(&pubkey).expect("quarantined");
+        assert_eq!(now + VALIDATOR_QUARANTINE_PENALTY, until);
+        assert!(health.is_quarantined(&pubkey));
+        assert!(health.scores()[0].quarantined);
+        clock.advance(VALIDATOR_QUARANTINE_PENALTY);
+        assert!(!health.is_quarantined(&pubkey));
+
+        // Repeated failures double the penalty
+        let now = clock.now();
+        let until = health.record_stream_failure(&pubkey).expect("quarantined");
+        assert_eq!(now + VALIDATOR_QUARANTINE_PENALTY * 2, until);
+
+        // Quarantines are restored from the store
+        let restored = HealthTracker::new(clock.clone(), store);
+        assert!(restored.is_quarantined(&pubkey));
+        assert_eq!(3, restored.scores()[0].stream_failures);
+
+        // Failures decay after a quiet period
+        clock.advance(VALIDATOR_QUARANTINE_DECAY);
+        assert_eq!(None, health.record_stream_failure(&pubkey));
+    }
+}
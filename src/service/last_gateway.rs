@@ -0,0 +1,45 @@
+use crate::{clock::unix_now, storage::Store, KeyedUri, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const LAST_GATEWAY_KEY: &str = "last_gateway.json";
+
+/// How long a stored last gateway is considered usable after it was last
+/// successfully used.
+pub const LAST_GATEWAY_MAX_AGE: Duration = Duration::from_secs(86400); // 24 hours
+
+/// The last validator that the gateway successfully established its streams
+/// with. This is persisted in the cache store so that it can be tried first
+/// after a restart instead of going through seed selection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LastGateway {
+    pub uri: KeyedUri,
+    /// Unix timestamp (in seconds) of when the gateway was last used
+    pub timestamp: u64,
+}
+
+impl LastGateway {
+    /// Loads the last gateway from the given store. Returns None if there is
+    /// no stored gateway, it could not be read or it is older than the given
+    /// maximum age.
+    pub fn load(store: &dyn Store, max_age: Duration) -> Option<KeyedUri> {
+        let data = store.get(LAST_GATEWAY_KEY).ok()??;
+        let last_gateway: Self = serde_json::from_slice(&data).ok()?;
+        let age = unix_now().ok()?.saturating_sub(last_gateway.timestamp);
+        if age <= max_age.as_secs() {
+            Some(last_gateway.uri)
+        } else {
+            None
+        }
+    }
+
+    /// Stores the given gateway uri as the last used gateway in the given
+    /// store.
+    pub fn save(store: &dyn Store, uri: &KeyedUri) -> Result {
+        let last_gateway = Self {
+            uri: uri.clone(),
+            timestamp: unix_now()?,
+        };
+        store.put(LAST_GATEWAY_KEY, &serde_json::to_vec(&last_gateway)?)
+    }
+}
@@ -0,0 +1,216 @@
+use crate::PublicKey;
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+/// Number of most recent samples kept per rpc for each validator
+pub const LATENCY_WINDOW_SIZE: usize = 50;
+
+/// The p95 latency a validator is expected to stay under. Validators breaching
+/// this are skipped during selection when better candidates are available.
+pub const LATENCY_SLO: Duration = Duration::from_millis(1500);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rpc {
+    Routing,
+    RegionParams,
+    IsActiveSc,
+    Config,
+    Validators,
+    Version,
+}
+
+impl fmt::Display for Rpc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let s = match self {
+            Self::Routing => "routing",
+            Self::RegionParams => "region_params",
+            Self::IsActiveSc => "is_active_sc",
+            Self::Config => "config",
+            Self::Validators => "validators",
+            Self::Version => "version",
+        };
+        f.write_str(s)
+    }
+}
+
+/// A rolling window of latency samples
+#[derive(Debug, Default, Clone)]
+pub struct LatencyWindow(VecDeque<Duration>);
+
+impl LatencyWindow {
+    pub fn record(&mut self, latency: Duration) {
+        if self.0.len() >= LATENCY_WINDOW_SIZE {
+            self.0.pop_front();
+        }
+        self.0.push_back(latency);
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// Returns the nearest rank percentile of the samples in the window, or
+    /// None if no samples have been recorded.
+    pub fn percentile(&self, percentile: u8) -> Option<Duration> {
+        if self.0.is_empty() {
+            return None;
+        }
+        let mut sorted: Vec<Duration> = self.0.iter().copied().collect();
+        sorted.sort_unstable();
+        let rank = (percentile.min(100) as usize * (sorted.len() - 1) + 50) / 100;
+        Some(sorted[rank])
+    }
+
+    pub fn p50(&self) -> Option<Duration> {
+        self.percentile(50)
+    }
+
+    pub fn p95(&self) -> Option<Duration> {
+        self.percentile(95)
+    }
+}
+
+/// Upper bounds of the buckets of a `LatencyHistogram`. Samples above the
+/// last bound are counted in an overflow bucket.
+pub const HISTOGRAM_BOUNDS: [Duration; 5] = [
+    Duration::from_millis(1),
+    Duration::from_millis(10),
+    Duration::from_millis(100),
+    Duration::from_secs(1),
+    Duration::from_secs(10),
+];
+
+/// Counts latency samples in fixed buckets over the lifetime of the process
+#[derive(Debug, Default, Clone)]
+pub struct LatencyHistogram {
+    counts: [u64; HISTOGRAM_BOUNDS.len() + 1],
+    max: Duration,
+}
+
+impl LatencyHistogram {
+    pub fn record(&mut self, latency: Duration) {
+        let bucket = HISTOGRAM_BOUNDS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(HISTOGRAM_BOUNDS.len());
+        self.counts[bucket] += 1;
+        self.max = self.max.max(latency);
+    }
+
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    /// Returns the sample count of each bucket labeled by its upper bound
+    pub fn buckets(&self) -> Vec<(String, u64)> {
+        HISTOGRAM_BOUNDS
+            .iter()
+            .map(|bound| format!("le_{}ms", bound.as_millis()))
+            .chain(std::iter::once("inf".to_string()))
+            .zip(self.counts.iter().copied())
+            .collect()
+    }
+}
+
+type ValidatorLatencies = HashMap<Rpc, LatencyWindow>;
+
+/// Tracks rpc latencies per validator public key. Clones share the same
+/// underlying samples so a tracker can be handed to every `GatewayService`
+/// derived from the same seed.
+#[derive(Debug, Default, Clone)]
+pub struct LatencyTracker(Arc<Mutex<HashMap<PublicKey, ValidatorLatencies>>>);
+
+impl LatencyTracker {
+    pub fn record(&self, validator: &PublicKey, rpc: Rpc, latency: Duration) {
+        let mut validators = self.0.lock().expect("latency tracker lock");
+        validators
+            .entry(validator.clone())
+            .or_default()
+            .entry(rpc)
+            .or_default()
+            .record(latency);
+    }
+
+    /// Returns the combined latency window over all rpcs for the given
+    /// validator
+    pub fn window(&self, validator: &PublicKey) -> LatencyWindow {
+        let validators = self.0.lock().expect("latency tracker lock");
+        let mut combined = LatencyWindow::default();
+        if let Some(latencies) = validators.get(validator) {
+            latencies
+                .values()
+                .flat_map(|window| window.0.iter())
+                .for_each(|latency| combined.0.push_back(*latency));
+        }
+        combined
+    }
+
+    /// Returns the (p50, p95) latencies per rpc for the given validator
+    pub fn summary(&self, validator: &PublicKey) -> Vec<(Rpc, Duration, Duration)> {
+        let validators = self.0.lock().expect("latency tracker lock");
+        validators
+            .get(validator)
+            .map(|latencies| {
+                latencies
+                    .iter()
+                    .filter_map(|(rpc, window)| {
+                        window
+                            .p50()
+                            .zip(window.p95())
+                            .map(|(p50, p95)| (*rpc, p50, p95))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Whether the given validator meets the latency SLO. Validators without
+    /// any recorded samples are considered to meet it.
+    pub fn meets_slo(&self, validator: &PublicKey) -> bool {
+        self.window(validator)
+            .p95()
+            .map_or(true, |p95| p95 <= LATENCY_SLO)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn percentiles() {
+        let mut window = LatencyWindow::default();
+        assert_eq!(None, window.p50());
+        for ms in 1..=100 {
+            window.record(Duration::from_millis(ms));
+        }
+        assert_eq!(LATENCY_WINDOW_SIZE, window.len());
+        assert_eq!(Some(Duration::from_millis(76)), window.p50());
+        assert_eq!(Some(Duration::from_millis(98)), window.p95());
+    }
+
+    #[test]
+    fn histogram() {
+        let mut histogram = LatencyHistogram::default();
+        for ms in [0, 1, 5, 250, 20_000] {
+            histogram.record(Duration::from_millis(ms));
+        }
+        assert_eq!(5, histogram.count());
+        assert_eq!(Duration::from_secs(20), histogram.max());
+        let counts: Vec<u64> = histogram.buckets().into_iter().map(|(_, n)| n).collect();
+        assert_eq!(vec![2, 1, 0, 1, 0, 1], counts);
+        assert_eq!("le_1ms", histogram.buckets()[0].0);
+    }
+}
@@ -1,8 +1,15 @@
 use std::time::Duration;
 
+/// Default connect and rpc timeouts, see `TimeoutSettings`
 pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 pub const RPC_TIMEOUT: Duration = Duration::from_secs(5);
 
+pub mod breaker;
+pub mod dns;
 pub mod gateway;
+pub mod last_gateway;
+pub mod latency;
+pub mod resolver;
 pub mod router;
+pub mod validator_pool;
 mod version;
@@ -0,0 +1,226 @@
+use crate::{service::CONNECT_TIMEOUT, Error, Result};
+use futures::future::BoxFuture;
+use http::Uri;
+use rand::{rngs::OsRng, seq::SliceRandom};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    net::{IpAddr, SocketAddr},
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use tokio::{net::TcpStream, time};
+use trust_dns_resolver::{error::ResolveError, TokioAsyncResolver};
+
+/// How long a successful host lookup is trusted before the host is looked up
+/// again
+pub const RESOLVE_CACHE_TTL: Duration = Duration::from_secs(300);
+/// Initial wait before retrying a host that failed to resolve. The wait
+/// doubles with every further failure up to `RESOLVE_BACKOFF_MAX`.
+pub const RESOLVE_BACKOFF_MIN: Duration = Duration::from_secs(5);
+pub const RESOLVE_BACKOFF_MAX: Duration = Duration::from_secs(600); // 10 minutes
+
+#[derive(Debug, Clone, Copy)]
+enum Resolution {
+    Resolved { addr: SocketAddr, until: Instant },
+    Failed { retry_at: Instant, failures: u32 },
+}
+
+/// Resolves and caches the hosts of service uris with negative caching. A
+/// service whose hostname does not resolve is failed immediately until its
+/// backoff expires, instead of stalling every request on a lookup that will
+/// fail again. Hosts are looked up with a DNS resolver using the system
+/// resolver configuration, so lookups don't block a runtime thread. Clones
+/// share the same cache and DNS resolver.
+#[derive(Clone)]
+pub struct Resolver {
+    cache: Arc<Mutex<HashMap<String, Resolution>>>,
+    dns: std::result::Result<TokioAsyncResolver, ResolveError>,
+    lookup_timeout: Duration,
+}
+
+impl fmt::Debug for Resolver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Resolver")
+            .field("cache", &self.cache)
+            .field("lookup_timeout", &self.lookup_timeout)
+            .finish()
+    }
+}
+
+impl Default for Resolver {
+    fn default() -> Self {
+        Self::new(CONNECT_TIMEOUT)
+    }
+}
+
+impl Resolver {
+    pub fn new(lookup_timeout: Duration) -> Self {
+        Self {
+            cache: Arc::default(),
+            dns: TokioAsyncResolver::tokio_from_system_conf(),
+            lookup_timeout,
+        }
+    }
+
+    /// Returns the address to connect to for the given uri. A host that
+    /// resolves to several addresses is reached at one picked at random,
+    /// which is kept until the lookup expires. IP address hosts are returned
+    /// as is.
+    pub async fn resolve(&self, uri: &Uri) -> Result<SocketAddr> {
+        let host = match uri.host() {
+            Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+            None => return Err(Error::resolve(&uri.to_string(), "missing host")),
+        };
+        let port = uri.port_u16().unwrap_or(match uri.scheme_str() {
+            Some("https") | Some("grpcs") => 443,
+            _ => 80,
+        });
+        if let Ok(ip) = host.parse::<IpAddr>() {
+            return Ok(SocketAddr::new(ip, port));
+        }
+        let now = Instant::now();
+        let failures = match self.cached(host) {
+            Some(Resolution::Resolved { addr, until }) if until > now => return Ok(addr),
+            Some(Resolution::Failed { retry_at, .. }) if retry_at > now => {
+                return Err(Error::resolve(
+                    host,
+                    format!("retrying in {}s", (retry_at - now).as_secs()),
+                ))
+            }
+            Some(Resolution::Failed { failures, .. }) => failures,
+            _ => 0,
+        };
+        let resolved = self.lookup_ip(host).await.and_then(|ips| {
+            ips.choose(&mut OsRng)
+                .map(|ip| SocketAddr::new(*ip, port))
+                .ok_or_else(|| Error::resolve(host, "no addresses"))
+        });
+        let now = Instant::now();
+        let resolution = match resolved {
+            Ok(addr) => Resolution::Resolved {
+                addr,
+                until: now + RESOLVE_CACHE_TTL,
+            },
+            Err(_) => Resolution::Failed {
+                retry_at: now + backoff(failures),
+                failures: failures.saturating_add(1),
+            },
+        };
+        self.insert(host, resolution);
+        resolved
+    }
+
+    /// Looks up the A and AAAA records of the given host
+    async fn lookup_ip(&self, host: &str) -> Result<Vec<IpAddr>> {
+        let dns = self.dns.as_ref().map_err(|err| Error::resolve(host, err))?;
+        match time::timeout(self.lookup_timeout, dns.lookup_ip(host)).await {
+            Ok(Ok(lookup)) => Ok(lookup.iter().collect()),
+            Ok(Err(err)) => Err(Error::resolve(host, err)),
+            Err(_) => Err(Error::resolve(host, "timeout")),
+        }
+    }
+
+    fn cached(&self, host: &str) -> Option<Resolution> {
+        self.cache.lock().expect("resolver lock").get(host).copied()
+    }
+
+    fn insert(&self, host: &str, resolution: Resolution) {
+        self.cache
+            .lock()
+            .expect("resolver lock")
+            .insert(host.to_string(), resolution);
+    }
+}
+
+/// A channel connector that dials an address resolved by the resolver. A
+/// channel connected through it keeps its original uri, so the authority and
+/// host sent to the service, and the name checked by TLS, are unchanged.
+#[derive(Debug, Clone, Copy)]
+pub struct ResolvedConnector {
+    addr: SocketAddr,
+    connect_timeout: Duration,
+}
+
+impl ResolvedConnector {
+    pub fn new(addr: SocketAddr, connect_timeout: Duration) -> Self {
+        Self {
+            addr,
+            connect_timeout,
+        }
+    }
+}
+
+impl tower::Service<Uri> for ResolvedConnector {
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = BoxFuture<'static, io::Result<TcpStream>>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _uri: Uri) -> Self::Future {
+        let Self {
+            addr,
+            connect_timeout,
+        } = *self;
+        Box::pin(async move {
+            let stream = time::timeout(connect_timeout, TcpStream::connect(addr))
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connect timed out"))??;
+            stream.set_nodelay(true)?;
+            Ok(stream)
+        })
+    }
+}
+
+/// The wait before retrying a host after the given number of earlier failed
+/// lookups
+fn backoff(failures: u32) -> Duration {
+    RESOLVE_BACKOFF_MIN
+        .saturating_mul(2u32.saturating_pow(failures.min(16)))
+        .min(RESOLVE_BACKOFF_MAX)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backoff_doubles() {
+        assert_eq!(RESOLVE_BACKOFF_MIN, backoff(0));
+        assert_eq!(RESOLVE_BACKOFF_MIN * 4, backoff(2));
+        assert_eq!(RESOLVE_BACKOFF_MAX, backoff(u32::MAX));
+    }
+
+    #[tokio::test]
+    async fn resolve() {
+        let resolver = Resolver::default();
+        let ip: Uri = "http://127.0.0.1:8080".parse().expect("uri");
+        let addr = resolver.resolve(&ip).await.expect("ip addr");
+        assert_eq!("127.0.0.1:8080".parse::<SocketAddr>().expect("addr"), addr);
+
+        let host: Uri = "http://localhost:8080/path".parse().expect("uri");
+        let addr = resolver.resolve(&host).await.expect("resolved addr");
+        assert_eq!(8080, addr.port());
+        assert!(addr.ip().is_loopback());
+    }
+
+    #[tokio::test]
+    async fn negative_cache() {
+        let resolver = Resolver::default();
+
+        let broken: Uri = "http://router.example:8080".parse().expect("uri");
+        resolver.insert(
+            "router.example",
+            Resolution::Failed {
+                retry_at: Instant::now() + RESOLVE_BACKOFF_MIN,
+                failures: 1,
+            },
+        );
+        let err = resolver.resolve(&broken).await.expect_err("cached failure");
+        assert!(err.is_resolve());
+    }
+}
@@ -1,36 +1,61 @@
 use crate::{
-    service::{CONNECT_TIMEOUT, RPC_TIMEOUT},
+    service::resolver::{ResolvedConnector, Resolver},
+    settings::{self, TimeoutSettings},
     KeyedUri, Result,
 };
 use helium_proto::{
     services::{self, Channel, Endpoint},
     BlockchainStateChannelMessageV1,
 };
+use std::net::SocketAddr;
 
 type RouterClient = services::router::RouterClient<Channel>;
 
+/// A router service connected through the shared resolver. The router host
+/// is looked up by the resolver only, and the channel is rebuilt for the
+/// resolved address when the lookup changes.
 #[derive(Debug)]
 pub struct RouterService {
     pub uri: KeyedUri,
-    router_client: RouterClient,
+    resolver: Resolver,
+    timeouts: TimeoutSettings,
+    router_client: Option<(SocketAddr, RouterClient)>,
 }
 
 impl RouterService {
-    pub fn new(keyed_uri: KeyedUri) -> Result<Self> {
-        let router_channel = Endpoint::from(keyed_uri.uri.clone())
-            .timeout(RPC_TIMEOUT)
-            .connect_timeout(CONNECT_TIMEOUT)
-            .connect_lazy();
-        Ok(Self {
+    pub fn new(keyed_uri: KeyedUri, resolver: Resolver, timeouts: TimeoutSettings) -> Self {
+        Self {
             uri: keyed_uri,
-            router_client: RouterClient::new(router_channel),
-        })
+            resolver,
+            timeouts,
+            router_client: None,
+        }
     }
 
     pub async fn route(
         &mut self,
         msg: BlockchainStateChannelMessageV1,
     ) -> Result<BlockchainStateChannelMessageV1> {
-        Ok(self.router_client.route(msg).await?.into_inner())
+        let mut router_client = self.router_client().await?;
+        Ok(router_client.route(msg).await?.into_inner())
+    }
+
+    async fn router_client(&mut self) -> Result<RouterClient> {
+        let addr = self.resolver.resolve(&self.uri.uri).await?;
+        match &self.router_client {
+            Some((current, router_client)) if *current == addr => Ok(router_client.clone()),
+            _ => {
+                let router_channel = Endpoint::from(self.uri.uri.clone())
+                    .timeout(self.timeouts.rpc())
+                    .user_agent(settings::user_agent())?
+                    .connect_with_connector_lazy(ResolvedConnector::new(
+                        addr,
+                        self.timeouts.connect(),
+                    ));
+                let router_client = RouterClient::new(router_channel);
+                self.router_client = Some((addr, router_client.clone()));
+                Ok(router_client)
+            }
+        }
     }
 }
@@ -0,0 +1,94 @@
+use crate::{clock::unix_now, storage::Store, KeyedUri, Result};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+const VALIDATOR_POOL_KEY: &str = "validator_pool.json";
+
+/// How long a fetched validator list is reused for validator selection
+/// before it is fetched from a seed again.
+pub const VALIDATOR_POOL_MAX_AGE: Duration = Duration::from_secs(3600); // 1 hour
+
+/// The validators most recently fetched from a seed, persisted in the cache
+/// store so that a new validator can be selected without a seed round trip,
+/// including while the seeds are unreachable.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ValidatorPool {
+    pub validators: Vec<KeyedUri>,
+    /// Chain height reported by the seed the validators were fetched from
+    pub height: u64,
+    /// Unix timestamp (in seconds) of when the validators were fetched
+    pub timestamp: u64,
+}
+
+impl ValidatorPool {
+    pub fn new(validators: Vec<KeyedUri>, height: u64) -> Result<Self> {
+        Ok(Self {
+            validators,
+            height,
+            timestamp: unix_now()?,
+        })
+    }
+
+    /// Loads the validator pool from the given store. Returns None if there
+    /// is no stored pool, it could not be read, it is empty or it is older
+    /// than the given maximum age.
+    pub fn load(store: &dyn Store, max_age: Duration) -> Option<Self> {
+        let data = store.get(VALIDATOR_POOL_KEY).ok()??;
+        let pool: Self = serde_json::from_slice(&data).ok()?;
+        let age = unix_now().ok()?.saturating_sub(pool.timestamp);
+        if age <= max_age.as_secs() && !pool.validators.is_empty() {
+            Some(pool)
+        } else {
+            None
+        }
+    }
+
+    /// Stores this pool in the given store
+    pub fn save(&self, store: &dyn Store) -> Result {
+        store.put(VALIDATOR_POOL_KEY, &serde_json::to_vec(self)?)
+    }
+
+    /// Replaces the stored pool with an empty one, so the next selection
+    /// fetches validators from a seed again. Used when none of the pooled
+    /// validators could be connected to.
+    pub fn invalidate(store: &dyn Store) -> Result {
+        Self {
+            validators: vec![],
+            height: 0,
+            timestamp: 0,
+        }
+        .save(store)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{keypair::test_pubkey, storage::MemoryStore};
+    use std::sync::Arc;
+
+    #[test]
+    fn freshness() {
+        let store = MemoryStore::default();
+        assert!(ValidatorPool::load(&store, VALIDATOR_POOL_MAX_AGE).is_none());
+
+        let uri = KeyedUri {
+            uri: "http://44.238.156.97:8080".parse().expect("uri"),
+            pubkey: Arc::new(test_pubkey()),
+        };
+        let mut pool = ValidatorPool::new(vec![uri], 100).expect("pool");
+        pool.save(&store).expect("save");
+        let loaded = ValidatorPool::load(&store, VALIDATOR_POOL_MAX_AGE).expect("fresh pool");
+        assert_eq!(100, loaded.height);
+        assert_eq!(1, loaded.validators.len());
+
+        pool.timestamp -= VALIDATOR_POOL_MAX_AGE.as_secs() + 1;
+        pool.save(&store).expect("save");
+        assert!(ValidatorPool::load(&store, VALIDATOR_POOL_MAX_AGE).is_none());
+
+        pool.timestamp = unix_now().expect("now");
+        pool.save(&store).expect("save");
+        ValidatorPool::invalidate(&store).expect("invalidate");
+        assert!(ValidatorPool::load(&store, VALIDATOR_POOL_MAX_AGE).is_none());
+    }
+}
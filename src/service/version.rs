@@ -1,7 +1,9 @@
-use serde::Serialize;
-use std::fmt;
+use crate::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(try_from = "String")]
 pub struct GatewayVersion {
     major: u16,
     minor: u16,
@@ -21,6 +23,34 @@ impl From<u64> for GatewayVersion {
     }
 }
 
+impl FromStr for GatewayVersion {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = s
+            .split('.')
+            .map(u16::from_str)
+            .collect::<std::result::Result<Vec<u16>, _>>()
+            .map_err(|_| Error::custom(format!("invalid version {s}")))?;
+        match parts[..] {
+            [major, minor, patch] => Ok(Self {
+                major,
+                minor,
+                patch,
+            }),
+            _ => Err(Error::custom(format!("invalid version {s}"))),
+        }
+    }
+}
+
+impl TryFrom<String> for GatewayVersion {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
 impl fmt::Display for GatewayVersion {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.write_fmt(format_args!("{}.{}.{}", self.major, self.minor, self.patch))
@@ -35,5 +65,8 @@ mod test {
     fn version() {
         let version = GatewayVersion::from(10110000u64);
         assert_eq!("1.11.0", version.to_string());
+        assert_eq!(version, "1.11.0".parse().expect("version"));
+        assert!(GatewayVersion::from(10100001u64) < version);
+        assert!("1.11".parse::<GatewayVersion>().is_err());
     }
 }
@@ -1,16 +1,90 @@
 use crate::{
-    api::GatewayStakingMode, releases, Error, KeyedUri, Keypair, PublicKey, Region, Result,
+    api::GatewayStakingMode,
+    logger::LogFile,
+    releases,
+    service::{gateway::GatewayVersion, CONNECT_TIMEOUT, RPC_TIMEOUT},
+    storage::{self, Store},
+    Error, KeyedUri, Keypair, PublicKey, Region, Result,
 };
-use config::{Config, Environment, File};
+use config::{
+    builder::DefaultState, Config, ConfigBuilder, ConfigError, Environment, File, FileFormat,
+};
+use helium_crypto::Verify;
 use http::uri::Uri;
 pub use log_method::LogMethod;
-use serde::Deserialize;
-use std::{fmt, path::Path, str::FromStr, sync::Arc};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 pub fn version() -> semver::Version {
     semver::Version::parse(env!("CARGO_PKG_VERSION")).expect("unable to parse version")
 }
 
+/// Returns the optional cargo features this binary was built with
+pub fn features() -> Vec<&'static str> {
+    env!("GATEWAY_FEATURES")
+        .split(',')
+        .filter(|feature| !feature.is_empty())
+        .collect()
+}
+
+/// Describes the exact build of this binary, so that the variant of a
+/// gateway firmware can be identified
+#[derive(Debug, Clone, Serialize)]
+pub struct BuildInfo {
+    pub version: String,
+    /// Short git commit the binary was built from, or "unknown"
+    pub git_commit: &'static str,
+    /// Unix timestamp (in seconds) of the build
+    pub build_timestamp: u64,
+    pub features: Vec<&'static str>,
+    /// Target triple the binary was built for
+    pub target: &'static str,
+}
+
+/// The version of this binary along with its build information, as printed
+/// by `--version`
+pub const LONG_VERSION: &str = concat!(
+    env!("CARGO_PKG_VERSION"),
+    "\ncommit: ",
+    env!("GATEWAY_GIT_COMMIT"),
+    "\nbuilt: ",
+    env!("GATEWAY_BUILD_TIMESTAMP"),
+    "\nfeatures: ",
+    env!("GATEWAY_FEATURES"),
+    "\ntarget: ",
+    env!("GATEWAY_TARGET"),
+);
+
+/// Returns the build information of this binary
+pub fn build_info() -> BuildInfo {
+    BuildInfo {
+        version: version().to_string(),
+        git_commit: env!("GATEWAY_GIT_COMMIT"),
+        build_timestamp: env!("GATEWAY_BUILD_TIMESTAMP").parse().unwrap_or(0),
+        features: features(),
+        target: env!("GATEWAY_TARGET"),
+    }
+}
+
+/// Returns the user agent used for outgoing grpc connections, which includes
+/// the version and enabled features of this binary. For example
+/// `gateway-rs/1.0.0 (ecc608)`.
+pub fn user_agent() -> String {
+    format!(
+        "{}/{} ({})",
+        env!("CARGO_PKG_NAME"),
+        version(),
+        features().join(",")
+    )
+}
+
 /// Settings are all the configuration parameters the service needs to operate.
 #[derive(Debug, Deserialize)]
 pub struct Settings {
@@ -22,6 +96,17 @@ pub struct Settings {
     /// Default 4467
     #[serde(default = "default_api")]
     pub api: u16,
+    /// Optional unix domain socket path to also serve the local API on. Only
+    /// peers running as the same user as the gateway (or root) are accepted.
+    /// Default none
+    #[serde(default)]
+    pub api_socket: Option<PathBuf>,
+    /// Optional UDP address ("host:port") to forward received frames with the
+    /// proprietary lorawan MHDR type to, as semtech style rxpk json. Frames of
+    /// private protocols sharing the concentrator are dropped when not set.
+    /// Default none
+    #[serde(default)]
+    pub proprietary_sink: Option<String>,
     /// The location of the keypair binary file for the gateway. If the keyfile
     /// is not found there a new one is generated and saved in that location.
     pub keypair: Arc<Keypair>,
@@ -32,6 +117,11 @@ pub struct Settings {
     /// The lorawan region to use. This value should line up with the configured
     /// region of the semtech packet forwarder. Defaults to "US915"
     pub region: Region,
+    /// The uplink sub-band (1 to 8) the concentrator is configured for in
+    /// regions with sub-bands (US915 and AU915). Uplinks on channels outside
+    /// the sub-band are dropped. Default none, accepting all channels
+    #[serde(default)]
+    pub subband: Option<u8>,
     /// Log settings
     pub log: LogSettings,
     /// Update settings
@@ -39,11 +129,74 @@ pub struct Settings {
     /// The routers to deliver packets to when no routers are found while
     /// processing a packet.
     pub routers: Option<Vec<KeyedUri>>,
+    /// Limits on the router clients run for the routers in the routing table
+    #[serde(default)]
+    pub router_clients: RouterClientSettings,
     /// The validator(s) to query for chain related state. Defaults to a Helium
     /// validator.
-    pub gateways: Vec<KeyedUri>,
+    pub gateways: Vec<SeedUri>,
+    /// Allow and deny lists for validators selected from the seed validators
+    #[serde(default)]
+    pub validators: ValidatorSettings,
+    /// Minimum version of validators to select, for example "1.11.0". Older
+    /// validators are skipped (default none)
+    #[serde(default)]
+    pub min_validator_version: Option<GatewayVersion>,
     /// Cache settings
     pub cache: CacheSettings,
+    /// Router quarantine settings
+    pub quarantine: QuarantineSettings,
+    /// Shutdown ordering settings
+    #[serde(default)]
+    pub shutdown: ShutdownSettings,
+    /// How to handle streamed validator responses that fail signature
+    /// verification (default strict)
+    #[serde(default)]
+    pub verify_policy: VerifyPolicy,
+    /// Number of recent uplinks to keep a routing decision trace for
+    /// (default 0, disabled)
+    #[serde(default)]
+    pub trace_packets: usize,
+    /// Optional mirroring of the uplink metadata of one oui to a debug
+    /// endpoint (default none)
+    #[serde(default)]
+    pub mirror: Option<MirrorSettings>,
+    /// Seconds without messages on the gateway streams after which the
+    /// gateway connection is probed with a lightweight rpc, to keep NAT
+    /// mappings alive and detect half-open connections (default 0, disabled)
+    #[serde(default)]
+    pub stream_probe: u64,
+    /// Connect and rpc timeouts for validator and router connections
+    #[serde(default)]
+    pub timeouts: TimeoutSettings,
+    /// TLS settings for validators with https or grpcs uris
+    #[serde(default)]
+    pub tls: TlsSettings,
+    /// Public keys of the operators allowed to sign settings bundles. A
+    /// bundle.toml in the settings folder is only applied when its
+    /// bundle.toml.sig verifies against one of these keys, its serial is not
+    /// older than the last applied one and it has not expired (default none)
+    #[serde(default)]
+    pub operator_keys: Vec<PublicKey>,
+    /// Crash report settings
+    #[serde(default)]
+    pub crash: CrashSettings,
+    /// Whether a settings bundle was applied or rejected while loading
+    #[serde(skip)]
+    pub bundle: BundleStatus,
+}
+
+/// The outcome of loading the settings bundle from the settings folder
+#[derive(Debug, Clone, Default)]
+pub enum BundleStatus {
+    /// There is no settings bundle
+    #[default]
+    Absent,
+    /// The bundle with the given serial was signed by the given operator key
+    /// and applied
+    Applied { signer: PublicKey, serial: u64 },
+    /// The bundle was not applied for the given reason
+    Rejected { reason: String },
 }
 
 /// Settings for log method and level to be used by the running service.
@@ -52,11 +205,101 @@ pub struct LogSettings {
     /// Log level to show (default info)
     pub level: log_level::Level,
 
-    ///  Which log method to use (stdio or syslog, default stdio)
+    ///  Which log method to use (stdio, stderr or syslog, default stdio)
     pub method: log_method::LogMethod,
 
     /// Whehter to show timestamps in the stdio output stream (default false)
     pub timestamp: bool,
+
+    /// Minimum interval in seconds between repeats of the same warning. Repeats
+    /// within the interval are dropped and counted (default 60, 0 disables)
+    pub rate_limit: u64,
+
+    /// Debug log sample rates keyed by module name, for example
+    /// { dispatcher = 10 } to only log 1 in 10 debug lines of each debug log
+    /// statement in the dispatcher. Re-read on SIGHUP (default none)
+    #[serde(default)]
+    pub sample: HashMap<String, u64>,
+
+    /// Log sinks to write to at the same time, each with its own method and
+    /// level. When set these replace the single sink configured by method,
+    /// level and timestamp above (default none)
+    #[serde(default)]
+    pub sinks: Vec<LogSinkSettings>,
+}
+
+impl LogSettings {
+    /// Returns the configured log sinks, or the single sink described by the
+    /// top level method, level and timestamp settings if none are configured.
+    pub fn sinks(&self) -> Vec<LogSinkSettings> {
+        if !self.sinks.is_empty() {
+            return self.sinks.clone();
+        }
+        vec![LogSinkSettings {
+            method: self.method,
+            level: Some(self.level),
+            timestamp: Some(self.timestamp),
+            path: None,
+        }]
+    }
+
+    /// Reads only the debug log sample rates from the settings in the given
+    /// folder, with a verified settings bundle and the environment overrides
+    /// merged in. The rest of the settings, like the keypair, is not loaded,
+    /// so this is cheap enough to run on every SIGHUP.
+    pub fn sample_rates(path: &Path) -> Result<HashMap<String, u64>> {
+        let local = local_config(path).build()?;
+        let operator_keys: Vec<PublicKey> = local.get("operator_keys").unwrap_or_default();
+        let store = bundle_store(&local.get::<CacheSettings>("cache")?);
+        let mut builder = local_config(path);
+        if let Ok(Some(bundle)) =
+            verify_bundle(path, &operator_keys, store.as_ref(), SystemTime::now())
+        {
+            builder = builder.add_source(File::from_str(&bundle.contents, FileFormat::Toml));
+        }
+        let config = builder.add_source(environment()).build()?;
+        match config.get("log.sample") {
+            Ok(rates) => Ok(rates),
+            Err(ConfigError::NotFound(_)) => Ok(HashMap::new()),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Checks that every file sink has a path that can be opened for
+    /// appending, so a misconfigured sink fails loading the settings rather
+    /// than setting up the logger.
+    fn validate(&self) -> std::result::Result<(), ConfigError> {
+        for sink in self.sinks() {
+            if !matches!(sink.method, LogMethod::File) {
+                continue;
+            }
+            let path = sink
+                .path
+                .as_ref()
+                .ok_or_else(|| ConfigError::Message("file log sink without path".to_string()))?;
+            LogFile::open(path).map_err(|err| {
+                ConfigError::Message(format!("log file {}: {err}", path.display()))
+            })?;
+        }
+        Ok(())
+    }
+}
+
+/// Settings for a single log sink
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogSinkSettings {
+    /// Which log method to use (stdio, stderr, syslog or file)
+    pub method: log_method::LogMethod,
+    /// Log level to show (default the top level log level)
+    #[serde(default)]
+    pub level: Option<log_level::Level>,
+    /// Whether to show timestamps in stdio and stderr output (default the top
+    /// level timestamp setting)
+    #[serde(default)]
+    pub timestamp: Option<bool>,
+    /// The file to append JSON formatted log lines to for the file method
+    #[serde(default)]
+    pub path: Option<PathBuf>,
 }
 
 /// Settings for log method and level to be used by the running service.
@@ -85,6 +328,339 @@ pub struct UpdateSettings {
 pub struct CacheSettings {
     // Maximum number of packets to queue up per router client
     pub max_packets: u16,
+    /// The folder to store persisted gateway state in (default
+    /// /etc/helium_gateway/cache)
+    pub store: PathBuf,
+    /// Keep persisted gateway state in memory only and never write outside
+    /// the runtime folder, for read only root file systems. The serial of the
+    /// last applied settings bundle is kept in the runtime folder (default
+    /// false)
+    #[serde(default)]
+    pub read_only: bool,
+    /// The folder for runtime files like update downloads (default the
+    /// system temporary folder)
+    #[serde(default)]
+    pub runtime_dir: Option<PathBuf>,
+}
+
+impl CacheSettings {
+    pub fn runtime_dir(&self) -> PathBuf {
+        self.runtime_dir.clone().unwrap_or_else(std::env::temp_dir)
+    }
+
+    /// Returns the folder crash reports are written to, which is the store
+    /// folder unless it is not to be written to in read only mode
+    pub fn crash_dir(&self) -> PathBuf {
+        if self.read_only {
+            self.runtime_dir()
+        } else {
+            self.store.clone()
+        }
+    }
+}
+
+/// Settings for quarantining routers that reject most packets
+#[derive(Debug, Deserialize, Clone)]
+pub struct QuarantineSettings {
+    /// How long a router is quarantined for (in seconds, default 3600)
+    pub period: u64,
+    /// Number of packets a router receives on probation after quarantine
+    /// before it is fully admitted again (default 10)
+    pub probation: u32,
+    /// Maximum number of packets per minute a router on probation receives,
+    /// or 0 for no limit (default 60)
+    pub probation_limit: u32,
+}
+
+/// How to handle streamed validator responses that fail signature
+/// verification
+#[derive(Debug, Default, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum VerifyPolicy {
+    /// End the stream on the first unverified response, which causes another
+    /// validator to be selected
+    #[default]
+    Strict,
+    /// Log and drop unverified responses and keep the stream
+    Log,
+    /// Log and drop up to the given number of unverified responses before
+    /// ending the stream
+    Threshold(u32),
+}
+
+impl VerifyPolicy {
+    /// Whether a stream with the given number of verification failures should
+    /// be kept
+    pub fn tolerates(&self, failures: u32) -> bool {
+        match self {
+            Self::Strict => false,
+            Self::Log => true,
+            Self::Threshold(max) => failures <= *max,
+        }
+    }
+}
+
+/// Settings for crash reports. A report is written to the cache store
+/// folder (or the runtime folder in read only mode) when the gateway panics.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct CrashSettings {
+    /// Url to post pending crash reports to as JSON on the next start. Reports
+    /// are only kept locally when not set (default none)
+    #[serde(default)]
+    pub upload: Option<String>,
+}
+
+/// Settings for the order in which service components are stopped
+#[derive(Debug, Deserialize, Clone)]
+pub struct ShutdownSettings {
+    /// Components to stop first, in order. Components that are not listed
+    /// are stopped afterwards (default "gateway", "dispatcher", "api",
+    /// "updater")
+    #[serde(
+        default = "default_shutdown_order",
+        deserialize_with = "deserialize_shutdown_order"
+    )]
+    pub order: Vec<String>,
+    /// Seconds to wait for the components of a stage to stop before moving
+    /// on to the next stage (default 5)
+    #[serde(default = "default_shutdown_timeout")]
+    pub timeout: u64,
+}
+
+/// Names of the components that can be stopped in order, in their default
+/// order
+pub const SHUTDOWN_STAGES: [&str; 4] = ["gateway", "dispatcher", "api", "updater"];
+
+fn deserialize_shutdown_order<'de, D>(d: D) -> std::result::Result<Vec<String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let order = Vec::<String>::deserialize(d)?;
+    if let Some(unknown) = order
+        .iter()
+        .find(|name| !SHUTDOWN_STAGES.contains(&name.as_str()))
+    {
+        return Err(serde::de::Error::custom(format!(
+            "unknown shutdown stage \"{unknown}\", expected one of {}",
+            SHUTDOWN_STAGES.join(", ")
+        )));
+    }
+    Ok(order)
+}
+
+impl Default for ShutdownSettings {
+    fn default() -> Self {
+        Self {
+            order: default_shutdown_order(),
+            timeout: default_shutdown_timeout(),
+        }
+    }
+}
+
+/// Settings for the router clients started for routers in the routing table
+#[derive(Debug, Deserialize, Clone)]
+pub struct RouterClientSettings {
+    /// Maximum number of router clients to run at once. Idle routers are
+    /// stopped to make room and started again on their next uplink (default
+    /// 0, unlimited)
+    #[serde(default)]
+    pub max_running: usize,
+    /// Seconds without uplinks after which a router is considered idle
+    /// (default 600)
+    #[serde(default = "default_router_idle_timeout")]
+    pub idle_timeout: u64,
+    /// Whether to stop idle routers even below the running router limit.
+    /// Parked routers keep their routing and are started again on their next
+    /// uplink (default false)
+    #[serde(default)]
+    pub park_idle: bool,
+    /// Maximum number of downlinks a single router may send per minute.
+    /// Further downlinks from the router are dropped (default 0, unlimited)
+    #[serde(default)]
+    pub downlink_limit: u32,
+}
+
+impl Default for RouterClientSettings {
+    fn default() -> Self {
+        Self {
+            max_running: 0,
+            idle_timeout: default_router_idle_timeout(),
+            park_idle: false,
+            downlink_limit: 0,
+        }
+    }
+}
+
+/// Settings to mirror the uplink metadata of a single oui, to debug packet
+/// delivery for a customer. Payloads are not mirrored.
+#[derive(Debug, Deserialize, Clone)]
+pub struct MirrorSettings {
+    /// The oui whose uplinks are mirrored
+    pub oui: u32,
+    /// UDP address ("host:port") to send the uplink metadata to as json
+    pub endpoint: String,
+    /// Unix timestamp (in seconds) after which mirroring stops
+    pub until: u64,
+}
+
+/// Timeouts for the connections to validators and routers. Raise these on
+/// high latency backhaul like satellite or cellular links.
+#[derive(Debug, Deserialize, Clone, Copy)]
+pub struct TimeoutSettings {
+    /// Seconds to wait for a connection to be established. Also bounds router
+    /// host lookups (default 10)
+    #[serde(default = "default_connect_timeout")]
+    pub connect: u64,
+    /// Seconds to wait for the response to an rpc (default 5)
+    #[serde(default = "default_rpc_timeout")]
+    pub rpc: u64,
+    /// Seconds between HTTP/2 keepalive pings on validator connections, even
+    /// while idle, to detect connections silently dropped by NAT routers
+    /// (default 0, disabled)
+    #[serde(default)]
+    pub keepalive_interval: u64,
+    /// Seconds to wait for a keepalive ping to be acknowledged before the
+    /// connection is closed (default 20)
+    #[serde(default = "default_keepalive_timeout")]
+    pub keepalive_timeout: u64,
+}
+
+impl Default for TimeoutSettings {
+    fn default() -> Self {
+        Self {
+            connect: default_connect_timeout(),
+            rpc: default_rpc_timeout(),
+            keepalive_interval: 0,
+            keepalive_timeout: default_keepalive_timeout(),
+        }
+    }
+}
+
+impl TimeoutSettings {
+    pub fn connect(&self) -> Duration {
+        Duration::from_secs(self.connect)
+    }
+
+    pub fn rpc(&self) -> Duration {
+        Duration::from_secs(self.rpc)
+    }
+
+    /// Returns the keepalive ping interval, or None if keepalive pings are
+    /// disabled
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        (self.keepalive_interval > 0).then(|| Duration::from_secs(self.keepalive_interval))
+    }
+
+    pub fn keepalive_timeout(&self) -> Duration {
+        Duration::from_secs(self.keepalive_timeout)
+    }
+}
+
+/// A seed validator, either given by its uri and public key or discovered
+/// through the SRV records of a DNS name.
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum SeedUri {
+    Keyed(KeyedUri),
+    Srv(SrvSeed),
+}
+
+/// Seed validators discovered through the SRV records of a DNS name. DNS
+/// only supplies the host and port of a seed. Its public key, which
+/// validator responses are verified against, must be configured for the SRV
+/// target, and targets without a configured key are never used.
+#[derive(Debug, Deserialize, Clone)]
+pub struct SrvSeed {
+    pub srv: String,
+    /// Public keys of the seeds by lower case SRV target host name
+    pub pubkeys: HashMap<String, PublicKey>,
+    /// Uri scheme of the discovered seeds. Seeds are connected to with TLS
+    /// for https or grpcs (default http)
+    #[serde(default = "default_srv_scheme")]
+    pub scheme: String,
+}
+
+/// TLS settings for validator connections. Validators with https or grpcs
+/// uris are connected to with TLS, which requires the tls feature.
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct TlsSettings {
+    /// PEM file with the certificate authority to verify validators against
+    /// (default the system roots)
+    #[serde(default)]
+    pub ca: Option<PathBuf>,
+    /// Domain name to verify validator certificates against, for validators
+    /// behind TLS terminating proxies (default the uri host)
+    #[serde(default)]
+    pub domain: Option<String>,
+    /// The contents of the certificate authority file, read once when the
+    /// settings are loaded
+    #[serde(skip)]
+    ca_pem: Option<Arc<[u8]>>,
+}
+
+impl TlsSettings {
+    /// Returns the PEM contents of the configured certificate authority
+    pub fn ca_pem(&self) -> Option<&[u8]> {
+        self.ca_pem.as_deref()
+    }
+
+    /// Reads the configured certificate authority so an unreadable file fails
+    /// loading the settings and connections do not re-read it.
+    fn load(&mut self) -> std::result::Result<(), ConfigError> {
+        if let Some(ca) = &self.ca {
+            let pem = std::fs::read(ca)
+                .map_err(|err| ConfigError::Message(format!("tls ca {}: {err}", ca.display())))?;
+            self.ca_pem = Some(pem.into());
+        }
+        Ok(())
+    }
+}
+
+/// Settings to restrict which validators the gateway will select
+#[derive(Debug, Deserialize, Clone)]
+pub struct ValidatorSettings {
+    /// Validator public keys to exclusively select from. An empty list allows
+    /// any validator (default empty)
+    #[serde(default)]
+    pub allow: Vec<PublicKey>,
+    /// Validator public keys to never select (default empty)
+    #[serde(default)]
+    pub deny: Vec<PublicKey>,
+    /// Whether to probe the candidate validators with a version request and
+    /// select the fastest one responding within the validator latency
+    /// objective instead of a random one (default false)
+    #[serde(default)]
+    pub probe: bool,
+    /// A validator to always use. Seed and validator selection are skipped
+    /// entirely when set, as are the allow and deny lists and the minimum
+    /// validator version (default none)
+    #[serde(default)]
+    pub pin: Option<KeyedUri>,
+    /// Percentage by which the wait before selecting a new validator is
+    /// randomly lengthened or shortened, so that gateways that lose the same
+    /// validator do not all reconnect at once. Capped at 100 (default 50)
+    #[serde(default = "default_backoff_jitter")]
+    pub backoff_jitter: u8,
+}
+
+impl Default for ValidatorSettings {
+    fn default() -> Self {
+        Self {
+            allow: vec![],
+            deny: vec![],
+            probe: false,
+            pin: None,
+            backoff_jitter: default_backoff_jitter(),
+        }
+    }
+}
+
+impl ValidatorSettings {
+    /// Whether the validator with the given public key may be selected. The
+    /// deny list takes precedence over the allow list.
+    pub fn permits(&self, pubkey: &PublicKey) -> bool {
+        !self.deny.contains(pubkey) && (self.allow.is_empty() || self.allow.contains(pubkey))
+    }
 }
 
 impl Settings {
@@ -95,20 +671,72 @@ impl Settings {
     /// Environemnt overrides have the same name as the entries in the settings
     /// file in uppercase and prefixed with "GW_". For example "GW_KEY" will
     /// override the key file location.
+    ///
+    /// A bundle.toml pushed to the same folder is merged in after the
+    /// settings file, but only if its signature verifies against one of the
+    /// operator keys of the local settings, its serial is at least the serial
+    /// of the last applied bundle and it has not expired. A bundle that is
+    /// rejected, or whose merged settings fail to load, is ignored and
+    /// reported in the `bundle` status, and the local settings are loaded
+    /// without it.
+    ///
+    /// The serial of the last applied bundle is kept in the cache store of
+    /// the local settings, and only updated once the merged settings have
+    /// loaded. In read only mode it is kept in the runtime folder instead, so
+    /// replay protection lasts as long as the runtime folder keeps its files.
     pub fn new(path: &Path) -> Result<Self> {
-        let default_file = path.join("default.toml");
-        let settings_file = path.join("settings.toml");
-        Config::builder()
-            // Source default config
-            .add_source(File::with_name(default_file.to_str().expect("file name")))
-            // Add optional settings file
-            .add_source(File::with_name(settings_file.to_str().expect("file name")).required(false))
-            // Add in settings from the environment (with a prefix of APP)
-            // Eg.. `GW_DEBUG=1 ./target/app` would set the `debug` key
-            .add_source(Environment::with_prefix("gw").separator("_"))
+        let local = local_config(path).build()?;
+        let operator_keys: Vec<PublicKey> = local.get("operator_keys").unwrap_or_default();
+        let store = bundle_store(&local.get::<CacheSettings>("cache")?);
+        let applied = verify_bundle(path, &operator_keys, store.as_ref(), SystemTime::now())
+            .and_then(|bundle| match bundle {
+                Some(bundle) => Self::load_bundle(path, &bundle, store.as_ref())
+                    .map(|settings| Some((settings, bundle))),
+                None => Ok(None),
+            });
+        let (mut settings, bundle) = match applied {
+            Ok(Some((settings, bundle))) => (
+                settings,
+                BundleStatus::Applied {
+                    signer: bundle.signer,
+                    serial: bundle.serial,
+                },
+            ),
+            Ok(None) => (Self::load(local_config(path))?, BundleStatus::Absent),
+            Err(reason) => (
+                Self::load(local_config(path))?,
+                BundleStatus::Rejected { reason },
+            ),
+        };
+        settings.bundle = bundle;
+        Ok(settings)
+    }
+
+    /// Loads the settings with the given verified bundle merged in, and
+    /// records the bundle as the last applied one once they have loaded
+    fn load_bundle(
+        path: &Path,
+        bundle: &Bundle,
+        store: &dyn Store,
+    ) -> std::result::Result<Self, String> {
+        let merged =
+            local_config(path).add_source(File::from_str(&bundle.contents, FileFormat::Toml));
+        let settings =
+            Self::load(merged).map_err(|err| format!("bundle {}: {err}", bundle.serial))?;
+        bundle.commit(store)?;
+        Ok(settings)
+    }
+
+    /// Loads and validates the settings from the given config files, with
+    /// the environment overrides merged in
+    fn load(builder: ConfigBuilder<DefaultState>) -> std::result::Result<Self, ConfigError> {
+        let mut settings: Self = builder
+            .add_source(environment())
             .build()
-            .and_then(|config| config.try_deserialize())
-            .map_err(|e| e.into())
+            .and_then(|config| config.try_deserialize())?;
+        settings.log.validate()?;
+        settings.tls.load()?;
+        Ok(settings)
     }
 
     /// Returns the onboarding key for this gateway. The onboarding key is
@@ -127,6 +755,137 @@ impl Settings {
     }
 }
 
+/// Returns the config builder for the default and settings files in the given
+/// folder
+fn local_config(path: &Path) -> ConfigBuilder<DefaultState> {
+    let default_file = path.join("default.toml");
+    let settings_file = path.join("settings.toml");
+    Config::builder()
+        // Source default config
+        .add_source(File::with_name(default_file.to_str().expect("file name")))
+        // Add optional settings file
+        .add_source(File::with_name(settings_file.to_str().expect("file name")).required(false))
+}
+
+/// Returns the source for settings from the environment (with a prefix of
+/// GW). Eg.. `GW_DEBUG=1 ./target/app` would set the `debug` key
+fn environment() -> Environment {
+    Environment::with_prefix("gw").separator("_")
+}
+
+const BUNDLE_FILE: &str = "bundle.toml";
+const BUNDLE_SIGNATURE_FILE: &str = "bundle.toml.sig";
+/// Store key of the serial of the last applied settings bundle
+const BUNDLE_SERIAL_KEY: &str = "bundle_serial";
+
+/// A verified settings bundle
+#[derive(Debug)]
+struct Bundle {
+    contents: String,
+    signer: PublicKey,
+    serial: u64,
+}
+
+/// Returns the store the serial of the last applied settings bundle is kept
+/// in. Read only mode keeps the cache store in memory, which would allow an
+/// older bundle to be replayed after a restart, so the serial is kept in the
+/// runtime folder instead.
+fn bundle_store(settings: &CacheSettings) -> Arc<dyn Store> {
+    if settings.read_only {
+        Arc::new(storage::FileStore::new(settings.runtime_dir()))
+    } else {
+        storage::open(settings)
+    }
+}
+
+impl Bundle {
+    /// Records the serial of this bundle as the last applied one
+    fn commit(&self, store: &dyn Store) -> std::result::Result<(), String> {
+        store
+            .put(BUNDLE_SERIAL_KEY, self.serial.to_string().as_bytes())
+            .map_err(|err| format!("bundle serial: {err}"))
+    }
+}
+
+/// Reads the settings bundle in the given folder and verifies its base64
+/// encoded signature against the given operator keys. Returns the verified
+/// bundle, or None if there is no bundle.
+///
+/// The signed bundle must contain a `serial`, and may contain an `expires`
+/// unix timestamp after which it is no longer applied. A bundle with a
+/// serial lower than the last applied one, which is kept in the given store,
+/// is rejected so that an older signed bundle can not be replayed. Bundles
+/// may not change the operator keys themselves, so that the keys trusted by
+/// a gateway can only be changed locally.
+fn verify_bundle(
+    path: &Path,
+    operator_keys: &[PublicKey],
+    store: &dyn Store,
+    now: SystemTime,
+) -> std::result::Result<Option<Bundle>, String> {
+    let bundle_file = path.join(BUNDLE_FILE);
+    if !bundle_file.exists() {
+        return Ok(None);
+    }
+    if operator_keys.is_empty() {
+        return Err("no operator keys configured".to_string());
+    }
+    let contents = fs::read_to_string(&bundle_file).map_err(|err| format!("bundle: {err}"))?;
+    let signature = fs::read_to_string(path.join(BUNDLE_SIGNATURE_FILE))
+        .map_err(|err| format!("bundle signature: {err}"))
+        .and_then(|encoded| {
+            base64::decode(encoded.trim()).map_err(|err| format!("bundle signature: {err}"))
+        })?;
+    let signer = operator_keys
+        .iter()
+        .find(|key| key.verify(contents.as_bytes(), &signature).is_ok())
+        .ok_or_else(|| "not signed by an operator key".to_string())?;
+    let bundle = Config::builder()
+        .add_source(File::from_str(&contents, FileFormat::Toml))
+        .build()
+        .map_err(|err| format!("bundle: {err}"))?;
+    if bundle.get::<config::Value>("operator_keys").is_ok() {
+        return Err("bundle may not change operator keys".to_string());
+    }
+    let serial: u64 = bundle
+        .get("serial")
+        .map_err(|err| format!("bundle serial: {err}"))?;
+    // An expiry that is present but can not be read would otherwise keep the
+    // bundle valid forever
+    let expires = match bundle.get::<u64>("expires") {
+        Ok(expires) => Some(expires),
+        Err(ConfigError::NotFound(_)) => None,
+        Err(err) => return Err(format!("bundle expires: {err}")),
+    };
+    if let Some(expires) = expires {
+        let now = now
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        if now >= expires {
+            return Err(format!("bundle {serial} expired at {expires}"));
+        }
+    }
+    let applied = store
+        .get(BUNDLE_SERIAL_KEY)
+        .map_err(|err| format!("bundle serial: {err}"))?
+        .and_then(|data| String::from_utf8(data).ok())
+        .and_then(|applied| applied.parse::<u64>().ok());
+    if let Some(applied) = applied.filter(|applied| serial < *applied) {
+        return Err(format!(
+            "bundle {serial} is older than the applied bundle {applied}"
+        ));
+    }
+    Ok(Some(Bundle {
+        contents,
+        signer: signer.clone(),
+        serial,
+    }))
+}
+
+fn default_srv_scheme() -> String {
+    "http".to_string()
+}
+
 fn default_listen() -> String {
     "127.0.0.1:1680".to_string()
 }
@@ -135,6 +894,37 @@ fn default_api() -> u16 {
     4467
 }
 
+fn default_shutdown_order() -> Vec<String> {
+    SHUTDOWN_STAGES
+        .iter()
+        .map(|name| name.to_string())
+        .collect()
+}
+
+fn default_shutdown_timeout() -> u64 {
+    5
+}
+
+fn default_router_idle_timeout() -> u64 {
+    600
+}
+
+fn default_backoff_jitter() -> u8 {
+    50
+}
+
+fn default_connect_timeout() -> u64 {
+    CONNECT_TIMEOUT.as_secs()
+}
+
+fn default_rpc_timeout() -> u64 {
+    RPC_TIMEOUT.as_secs()
+}
+
+fn default_keepalive_timeout() -> u64 {
+    20
+}
+
 #[derive(Debug)]
 #[repr(u8)]
 pub enum StakingMode {
@@ -246,12 +1036,16 @@ pub mod log_method {
     use std::fmt;
 
     /// The method to use for logging.
-    #[derive(Debug)]
+    #[derive(Debug, Clone, Copy)]
     pub enum LogMethod {
         /// Display logging information on stdout
         Stdio,
+        /// Display logging information on stderr
+        Stderr,
         /// Send logging information to syslog
         Syslog,
+        /// Append logging information as JSON lines to a file
+        File,
     }
 
     impl<'de> Deserialize<'de> for LogMethod {
@@ -272,7 +1066,9 @@ pub mod log_method {
                 {
                     let method = match value.to_lowercase().as_str() {
                         "stdio" => LogMethod::Stdio,
+                        "stderr" => LogMethod::Stderr,
                         "syslog" => LogMethod::Syslog,
+                        "file" => LogMethod::File,
                         unsupported => {
                             return Err(de::Error::custom(format!(
                                 "unsupported log method: \"{unsupported}\""
@@ -287,3 +1083,117 @@ pub mod log_method {
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use helium_crypto::{KeyTag, KeyType, Network, Sign};
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn bundle_signature() {
+        let path =
+            std::env::temp_dir().join(format!("settings_bundle_test_{}", std::process::id()));
+        fs::create_dir_all(&path).expect("bundle dir");
+        let store = storage::MemoryStore::default();
+        let now = UNIX_EPOCH + Duration::from_secs(1000);
+        let verify = |operator_keys: &[PublicKey]| verify_bundle(&path, operator_keys, &store, now);
+        let operator = helium_crypto::Keypair::generate(
+            KeyTag {
+                network: Network::MainNet,
+                key_type: KeyType::Ed25519,
+            },
+            &mut OsRng,
+        );
+        let operator_keys = vec![operator.public_key().to_owned()];
+        assert!(verify(&operator_keys).expect("no bundle").is_none());
+
+        let write_bundle = |contents: &str| {
+            let signature = operator.sign(contents.as_bytes()).expect("signature");
+            fs::write(path.join(BUNDLE_FILE), contents).expect("bundle");
+            fs::write(path.join(BUNDLE_SIGNATURE_FILE), base64::encode(signature))
+                .expect("bundle signature");
+        };
+        write_bundle("serial = 2\nlisten = \"0.0.0.0:1680\"\n");
+        let bundle = verify(&operator_keys)
+            .expect("verified bundle")
+            .expect("bundle");
+        assert_eq!("serial = 2\nlisten = \"0.0.0.0:1680\"\n", bundle.contents);
+        assert_eq!(operator_keys[0], bundle.signer);
+        assert_eq!(2, bundle.serial);
+        assert!(verify(&[]).is_err());
+        bundle.commit(&store).expect("commit");
+        // The applied bundle is applied again on the next load
+        assert!(verify(&operator_keys).expect("reapplied bundle").is_some());
+
+        // A modified bundle no longer verifies
+        fs::write(path.join(BUNDLE_FILE), "serial = 3\n").expect("bundle");
+        assert!(verify(&operator_keys).is_err());
+
+        write_bundle("serial = 3\noperator_keys = []\n");
+        assert!(verify(&operator_keys).is_err());
+
+        // Bundles need a serial, may not be replayed and expire
+        write_bundle("listen = \"0.0.0.0:1680\"\n");
+        assert!(verify(&operator_keys).is_err());
+        write_bundle("serial = 1\n");
+        assert!(verify(&operator_keys).is_err());
+        write_bundle("serial = 4\nexpires = 1000\n");
+        assert!(verify(&operator_keys).is_err());
+        write_bundle("serial = 4\nexpires = \"never\"\n");
+        assert!(verify(&operator_keys).is_err());
+        write_bundle("serial = 4\nexpires = 1001\n");
+        let bundle = verify(&operator_keys).expect("bundle").expect("bundle");
+        assert_eq!(4, bundle.serial);
+        // Only a committed bundle raises the serial older bundles are
+        // checked against
+        write_bundle("serial = 3\n");
+        assert!(verify(&operator_keys).expect("bundle").is_some());
+        bundle.commit(&store).expect("commit");
+        assert!(verify(&operator_keys).is_err());
+
+        fs::remove_dir_all(&path).expect("remove bundle dir");
+    }
+
+    #[test]
+    fn log_sample_rates() {
+        let path =
+            std::env::temp_dir().join(format!("settings_sample_test_{}", std::process::id()));
+        fs::create_dir_all(&path).expect("settings dir");
+        // Only the cache settings are needed besides the sample rates
+        fs::write(
+            path.join("default.toml"),
+            "[cache]\nmax_packets = 20\nstore = \"/nonexistent\"\n",
+        )
+        .expect("default settings");
+        assert!(LogSettings::sample_rates(&path)
+            .expect("sample rates")
+            .is_empty());
+        fs::write(
+            path.join("settings.toml"),
+            "[log.sample]\ndispatcher = 10\n",
+        )
+        .expect("settings");
+        assert_eq!(
+            Some(&10),
+            LogSettings::sample_rates(&path)
+                .expect("sample rates")
+                .get("dispatcher")
+        );
+        fs::remove_dir_all(&path).expect("remove settings dir");
+    }
+
+    #[test]
+    fn shutdown_order() {
+        let parse = |contents: &str| {
+            Config::builder()
+                .add_source(File::from_str(contents, FileFormat::Toml))
+                .build()
+                .and_then(|config| config.try_deserialize::<ShutdownSettings>())
+        };
+        let settings = parse("order = [\"dispatcher\", \"gateway\"]").expect("settings");
+        assert_eq!(vec!["dispatcher", "gateway"], settings.order);
+        assert_eq!(SHUTDOWN_STAGES.to_vec(), parse("").expect("settings").order);
+        assert!(parse("order = [\"gateway\", \"routers\"]").is_err());
+    }
+}
@@ -0,0 +1,102 @@
+use crate::{crash, settings::ShutdownSettings, Result};
+use slog::{info, warn, Logger};
+use std::time::Duration;
+use tokio::time;
+
+/// Stops the components of the service in stages. Once the service shutdown
+/// is triggered each stage is told to stop in turn, and the next stage is
+/// only told to stop once all components of the previous stage have stopped
+/// or the stage timeout passed.
+pub struct Coordinator {
+    order: Vec<String>,
+    timeout: Duration,
+    stages: Vec<Stage>,
+}
+
+struct Stage {
+    name: &'static str,
+    stop: triggered::Trigger,
+    stopped: triggered::Listener,
+}
+
+impl Coordinator {
+    pub fn new(settings: &ShutdownSettings) -> Self {
+        Self {
+            order: settings.order.clone(),
+            timeout: Duration::from_secs(settings.timeout),
+            stages: vec![],
+        }
+    }
+
+    /// Adds a named stage. Returns the listener that components of the stage
+    /// shut down on and the trigger they fire once they have stopped.
+    pub fn stage(&mut self, name: &'static str) -> (triggered::Listener, triggered::Trigger) {
+        let (stop, stop_listener) = triggered::trigger();
+        let (stopped_trigger, stopped) = triggered::trigger();
+        crash::set_subsystem_state(name, "running");
+        self.stages.push(Stage {
+            name,
+            stop,
+            stopped,
+        });
+        (stop_listener, stopped_trigger)
+    }
+
+    /// Waits for the given shutdown to trigger, then stops all stages. Stages
+    /// are stopped in the configured order, followed by stages not named in
+    /// the configured order in the order they were added.
+    pub async fn run(mut self, shutdown: triggered::Listener, logger: &Logger) -> Result {
+        shutdown.await;
+        let order = self.order;
+        self.stages.sort_by_key(|stage| {
+            order
+                .iter()
+                .position(|name| name == stage.name)
+                .unwrap_or(order.len())
+        });
+        for stage in self.stages {
+            info!(logger, "stopping"; "stage" => stage.name);
+            crash::set_subsystem_state(stage.name, "stopping");
+            stage.stop.trigger();
+            if time::timeout(self.timeout, stage.stopped).await.is_err() {
+                warn!(logger, "stage did not stop in time";
+                    "stage" => stage.name,
+                    "timeout_secs" => self.timeout.as_secs());
+            } else {
+                crash::set_subsystem_state(stage.name, "stopped");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use slog::o;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    async fn ordered_stop() {
+        let logger = Logger::root(slog::Discard, o!());
+        let mut coordinator = Coordinator::new(&ShutdownSettings {
+            order: vec!["second".to_string(), "first".to_string()],
+            timeout: 5,
+        });
+        let stopped = Arc::new(Mutex::new(vec![]));
+        let mut components = vec![];
+        for name in ["first", "last", "second"] {
+            let (listener, done) = coordinator.stage(name);
+            let stopped = stopped.clone();
+            components.push(tokio::spawn(async move {
+                listener.await;
+                stopped.lock().unwrap().push(name);
+                done.trigger();
+            }));
+        }
+        let (trigger, shutdown) = triggered::trigger();
+        trigger.trigger();
+        coordinator.run(shutdown, &logger).await.expect("run");
+        assert_eq!(vec!["second", "first", "last"], *stopped.lock().unwrap());
+    }
+}
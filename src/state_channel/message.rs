@@ -1,9 +1,8 @@
-use crate::{Error, Keypair, MsgSign, Packet, Region, Result};
+use crate::{Error, Packet, Region, RequestSigner, Result};
 use helium_proto::{
     blockchain_state_channel_message_v1::Msg, BlockchainStateChannelMessageV1,
     BlockchainStateChannelPacketV1,
 };
-use std::sync::Arc;
 
 #[derive(Debug)]
 pub struct StateChannelMessage(pub(crate) Msg);
@@ -11,18 +10,17 @@ pub struct StateChannelMessage(pub(crate) Msg);
 impl StateChannelMessage {
     pub async fn packet(
         packet: Packet,
-        keypair: Arc<Keypair>,
+        signer: &RequestSigner,
         region: &Region,
         hold_time: u64,
     ) -> Result<Self> {
-        let mut packet = BlockchainStateChannelPacketV1 {
+        let packet = BlockchainStateChannelPacketV1 {
             packet: Some(packet.to_packet()),
-            signature: vec![],
-            hotspot: keypair.public_key().into(),
             region: region.into(),
             hold_time,
+            ..Default::default()
         };
-        packet.signature = packet.sign(keypair).await?;
+        let packet = signer.sign(packet).await?;
         Ok(Self::from(packet))
     }
 
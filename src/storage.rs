@@ -0,0 +1,271 @@
+use crate::{CacheSettings, Error, Result};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A key value store for state that the gateway persists between runs.
+/// Keys are simple names without path separators.
+pub trait Store: fmt::Debug + Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+    fn put(&self, key: &str, value: &[u8]) -> Result;
+    fn remove(&self, key: &str) -> Result;
+    /// Returns all entries with a key that starts with the given prefix,
+    /// ordered by key
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>>;
+    /// Removes the entries with a key that starts with the given prefix and
+    /// that were last written longer than the given age ago. Returns the
+    /// number of removed entries.
+    fn prune(&self, prefix: &str, max_age: Duration) -> Result<usize>;
+}
+
+/// Maximum number of entries kept by a memory store before the oldest
+/// entries are evicted
+pub const MEMORY_STORE_MAX_ENTRIES: usize = 1024;
+
+/// Opens the store configured in the given cache settings. In read only mode
+/// all state is kept in memory.
+pub fn open(settings: &CacheSettings) -> Arc<dyn Store> {
+    if settings.read_only {
+        Arc::new(MemoryStore::new(MEMORY_STORE_MAX_ENTRIES))
+    } else {
+        Arc::new(FileStore::new(settings.store.clone()))
+    }
+}
+
+fn check_key(key: &str) -> Result {
+    if key.is_empty() || key.contains('/') || key.ends_with(TMP_SUFFIX) {
+        return Err(Error::custom(format!("invalid store key: {key:?}")));
+    }
+    Ok(())
+}
+
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Stores every entry as a file in a single folder
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+}
+
+impl FileStore {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+}
+
+impl Store for FileStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        match fs::read(self.dir.join(key)) {
+            Ok(data) => Ok(Some(data)),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result {
+        check_key(key)?;
+        fs::create_dir_all(&self.dir)?;
+        // Write to a temporary file first to avoid leaving a truncated file
+        // behind on power loss
+        let tmp_path = self.dir.join(format!("{key}{TMP_SUFFIX}"));
+        fs::write(&tmp_path, value)?;
+        fs::rename(tmp_path, self.dir.join(key))?;
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result {
+        check_key(key)?;
+        match fs::remove_file(self.dir.join(key)) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(err.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let mut result = vec![];
+        for (key, path) in self.entries(prefix)? {
+            match fs::read(path) {
+                Ok(data) => result.push((key, data)),
+                // Removed since the folder was read
+                Err(err) if err.kind() == std::io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(result)
+    }
+
+    fn prune(&self, prefix: &str, max_age: Duration) -> Result<usize> {
+        let now = SystemTime::now();
+        let mut removed = 0;
+        for (_, path) in self.entries(prefix)? {
+            let modified = fs::metadata(&path).and_then(|metadata| metadata.modified());
+            if let Ok(modified) = modified {
+                if now.duration_since(modified).unwrap_or_default() > max_age {
+                    fs::remove_file(path)?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+impl FileStore {
+    /// Returns the keys and paths of the entries with a key that starts with
+    /// the given prefix, ordered by key. Temporary files of interrupted
+    /// writes are skipped.
+    fn entries(&self, prefix: &str) -> Result<Vec<(String, PathBuf)>> {
+        let dir = match fs::read_dir(&self.dir) {
+            Ok(dir) => dir,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err.into()),
+        };
+        let mut entries = vec![];
+        for entry in dir {
+            let entry = entry?;
+            let key = entry.file_name().to_string_lossy().into_owned();
+            if key.starts_with(prefix) && !key.ends_with(TMP_SUFFIX) {
+                entries.push((key, entry.path()));
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+}
+
+/// Keeps a bounded number of entries in memory. Entries are lost when the
+/// process exits.
+#[derive(Debug)]
+pub struct MemoryStore {
+    entries: Mutex<HashMap<String, (Instant, Vec<u8>)>>,
+    max_entries: usize,
+}
+
+impl MemoryStore {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+}
+
+impl Default for MemoryStore {
+    fn default() -> Self {
+        Self::new(MEMORY_STORE_MAX_ENTRIES)
+    }
+}
+
+impl Store for MemoryStore {
+    fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        check_key(key)?;
+        let entries = self.entries.lock().expect("memory store lock");
+        Ok(entries.get(key).map(|(_, value)| value.clone()))
+    }
+
+    fn put(&self, key: &str, value: &[u8]) -> Result {
+        check_key(key)?;
+        let mut entries = self.entries.lock().expect("memory store lock");
+        if !entries.contains_key(key) && entries.len() >= self.max_entries {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (written, _))| *written)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key.to_string(), (Instant::now(), value.to_vec()));
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result {
+        check_key(key)?;
+        let mut entries = self.entries.lock().expect("memory store lock");
+        entries.remove(key);
+        Ok(())
+    }
+
+    fn scan(&self, prefix: &str) -> Result<Vec<(String, Vec<u8>)>> {
+        let entries = self.entries.lock().expect("memory store lock");
+        let mut result: Vec<(String, Vec<u8>)> = entries
+            .iter()
+            .filter(|(key, _)| key.starts_with(prefix))
+            .map(|(key, (_, value))| (key.clone(), value.clone()))
+            .collect();
+        result.sort();
+        Ok(result)
+    }
+
+    fn prune(&self, prefix: &str, max_age: Duration) -> Result<usize> {
+        let mut entries = self.entries.lock().expect("memory store lock");
+        let before = entries.len();
+        entries
+            .retain(|key, (written, _)| !key.starts_with(prefix) || written.elapsed() <= max_age);
+        Ok(before - entries.len())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn check_store(store: &dyn Store) {
+        assert_eq!(None, store.get("missing").expect("get"));
+        store.put("a_1", b"one").expect("put");
+        store.put("a_2", b"two").expect("put");
+        store.put("b_1", b"three").expect("put");
+        assert_eq!(Some(b"one".to_vec()), store.get("a_1").expect("get"));
+        store.put("a_1", b"four").expect("put");
+        assert_eq!(Some(b"four".to_vec()), store.get("a_1").expect("get"));
+        assert_eq!(Some(b"three".to_vec()), store.get("b_1").expect("get"));
+        assert!(store.put("../escape", b"").is_err());
+
+        assert_eq!(
+            vec![
+                ("a_1".to_string(), b"four".to_vec()),
+                ("a_2".to_string(), b"two".to_vec())
+            ],
+            store.scan("a_").expect("scan")
+        );
+        store.remove("a_2").expect("remove");
+        store.remove("a_2").expect("remove missing");
+        assert_eq!(1, store.scan("a_").expect("scan").len());
+        // Entries younger than the age and outside the prefix are kept
+        assert_eq!(
+            0,
+            store.prune("a_", Duration::from_secs(60)).expect("prune")
+        );
+        std::thread::sleep(Duration::from_millis(10));
+        assert_eq!(1, store.prune("a_", Duration::ZERO).expect("prune"));
+        assert!(store.scan("a_").expect("scan").is_empty());
+        assert_eq!(Some(b"three".to_vec()), store.get("b_1").expect("get"));
+    }
+
+    #[test]
+    fn file_store() {
+        let dir = std::env::temp_dir().join("file_store_test");
+        let _ = fs::remove_dir_all(&dir);
+        check_store(&FileStore::new(dir.clone()));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn memory_store() {
+        check_store(&MemoryStore::default());
+
+        let store = MemoryStore::new(2);
+        for key in ["a", "b", "c"] {
+            store.put(key, key.as_bytes()).expect("put");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(None, store.get("a").expect("get"));
+        assert_eq!(Some(b"b".to_vec()), store.get("b").expect("get"));
+        assert_eq!(Some(b"c".to_vec()), store.get("c").expect("get"));
+    }
+}
@@ -1,6 +1,6 @@
 use crate::{Error, Result};
 use slog::{warn, Logger};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot};
 
 #[derive(Debug)]
 pub struct MessageSender<T>(pub(crate) mpsc::Sender<T>);
@@ -23,6 +23,49 @@ impl<T> Clone for MessageSender<T> {
     }
 }
 
+/// A typed broadcast bus. Every subscription receives each event published
+/// after it was created. Subscriptions are bounded by the bus capacity and a
+/// subscriber that falls further behind skips the oldest events.
+#[derive(Debug)]
+pub struct EventBus<T>(broadcast::Sender<T>);
+pub struct Subscription<T>(broadcast::Receiver<T>);
+
+impl<T: Clone> EventBus<T> {
+    pub fn new(capacity: usize) -> Self {
+        let (tx, _) = broadcast::channel(capacity);
+        Self(tx)
+    }
+
+    /// Publishes an event to all current subscriptions. Events published
+    /// without any subscriptions are dropped.
+    pub fn publish(&self, event: T) {
+        let _ = self.0.send(event);
+    }
+
+    pub fn subscribe(&self) -> Subscription<T> {
+        Subscription(self.0.subscribe())
+    }
+}
+
+impl<T> Clone for EventBus<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T: Clone> Subscription<T> {
+    /// Returns the next event, or None once the bus is dropped
+    pub async fn recv(&mut self) -> Option<T> {
+        loop {
+            match self.0.recv().await {
+                Ok(event) => return Some(event),
+                Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                Err(broadcast::error::RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct ResponseSender<T>(oneshot::Sender<T>);
 pub struct ResponseReceiver<T>(oneshot::Receiver<T>);
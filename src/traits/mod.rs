@@ -5,7 +5,7 @@ mod txn_envelope;
 mod txn_fee;
 
 pub use self::base64::Base64;
-pub use msg_sign::MsgSign;
+pub use msg_sign::{MsgSign, RequestSigner, SignedRequest};
 pub use msg_verify::MsgVerify;
 pub use txn_envelope::TxnEnvelope;
 pub use txn_fee::{TxnFee, TxnFeeConfig, CONFIG_FEE_KEYS};
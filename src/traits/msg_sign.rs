@@ -1,10 +1,10 @@
-use crate::{Error, Keypair, Result};
+use crate::{Keypair, Result};
 use futures::TryFutureExt;
-use helium_crypto::Sign;
 use helium_proto::{
     BlockchainStateChannelOfferV1, BlockchainStateChannelPacketV1, BlockchainTxnAddGatewayV1,
     BlockchainTxnStateChannelCloseV1, GatewayRegionParamsUpdateReqV1, Message,
 };
+use std::sync::Arc;
 
 #[async_trait::async_trait]
 pub trait MsgSign: Message + std::clone::Clone {
@@ -15,7 +15,7 @@ pub trait MsgSign: Message + std::clone::Clone {
 }
 
 macro_rules! impl_msg_sign {
-    ($txn_type:ty, $( $sig: ident ),+ ) => {
+    ($txn_type:ty, $subsystem:literal, $( $sig: ident ),+ ) => {
         #[async_trait::async_trait]
         impl MsgSign for $txn_type {
             async fn sign<T>(&self, keypair: T) -> Result<Vec<u8>>
@@ -24,7 +24,7 @@ macro_rules! impl_msg_sign {
                 $(txn.$sig = vec![];)+
                 let buf = txn.encode_to_vec();
                 let join_handle: tokio::task::JoinHandle<Result<Vec<u8>>> = tokio::task::spawn_blocking(move ||  {
-                    keypair.as_ref().sign(&buf).map_err(Error::from)
+                    keypair.as_ref().sign_audited(&buf, stringify!($txn_type), $subsystem)
                 });
                 join_handle.map_err(|err| helium_crypto::Error::from(signature::Error::from_source(err))).await?
             }
@@ -32,12 +32,62 @@ macro_rules! impl_msg_sign {
     };
 }
 
-impl_msg_sign!(GatewayRegionParamsUpdateReqV1, signature);
-impl_msg_sign!(BlockchainStateChannelPacketV1, signature);
-impl_msg_sign!(BlockchainStateChannelOfferV1, signature);
-impl_msg_sign!(BlockchainTxnStateChannelCloseV1, signature);
+/// A request that carries the public key of its signer and a signature over
+/// the rest of the request
+pub trait SignedRequest: MsgSign + Send + Sync + 'static {
+    fn set_signer(&mut self, signer: Vec<u8>);
+    fn set_signature(&mut self, signature: Vec<u8>);
+}
+
+macro_rules! impl_signed_request {
+    ($req_type:ty, $signer:ident, $sig:ident) => {
+        impl SignedRequest for $req_type {
+            fn set_signer(&mut self, signer: Vec<u8>) {
+                self.$signer = signer;
+            }
+
+            fn set_signature(&mut self, signature: Vec<u8>) {
+                self.$sig = signature;
+            }
+        }
+    };
+}
+
+/// Signs requests with the gateway keypair. Components that sign requests
+/// hold a signer rather than the keypair itself. None of the supported
+/// request types carry a timestamp.
+#[derive(Clone)]
+pub struct RequestSigner(Arc<Keypair>);
+
+impl From<Arc<Keypair>> for RequestSigner {
+    fn from(keypair: Arc<Keypair>) -> Self {
+        Self(keypair)
+    }
+}
+
+impl RequestSigner {
+    /// Sets the signer of the given request and signs it
+    pub async fn sign<R: SignedRequest>(&self, mut req: R) -> Result<R> {
+        req.set_signer(self.0.public_key().to_vec());
+        let signature = req.sign(self.0.clone()).await?;
+        req.set_signature(signature);
+        Ok(req)
+    }
+}
+
+impl_signed_request!(GatewayRegionParamsUpdateReqV1, address, signature);
+impl_signed_request!(BlockchainStateChannelPacketV1, hotspot, signature);
+impl_signed_request!(BlockchainStateChannelOfferV1, hotspot, signature);
+impl_signed_request!(BlockchainTxnStateChannelCloseV1, closer, signature);
+impl_signed_request!(BlockchainTxnAddGatewayV1, gateway, gateway_signature);
+
+impl_msg_sign!(GatewayRegionParamsUpdateReqV1, "gateway_service", signature);
+impl_msg_sign!(BlockchainStateChannelPacketV1, "router", signature);
+impl_msg_sign!(BlockchainStateChannelOfferV1, "router", signature);
+impl_msg_sign!(BlockchainTxnStateChannelCloseV1, "router", signature);
 impl_msg_sign!(
     BlockchainTxnAddGatewayV1,
+    "api",
     owner_signature,
     payer_signature,
     gateway_signature
@@ -16,7 +16,9 @@ macro_rules! impl_msg_verify {
                 let mut msg = self.clone();
                 msg.$sig = vec![];
                 msg.encode(&mut buf)?;
-                verifier.verify(&buf, &self.$sig).map_err(Error::from)
+                verifier
+                    .verify(&buf, &self.$sig)
+                    .map_err(|_| Error::unverified(verifier))
             }
         }
     };
@@ -6,7 +6,7 @@ use http::Uri;
 use releases::Channel;
 use slog::{error, info, o, warn, Logger};
 use std::{
-    env, io,
+    io,
     path::{Path, PathBuf},
 };
 use tokio::{process, time};
@@ -19,6 +19,7 @@ pub struct Updater {
     platform: String,
     interval: time::Duration,
     install_command: String,
+    runtime_dir: PathBuf,
 }
 
 impl Updater {
@@ -30,6 +31,7 @@ impl Updater {
             interval: time::Duration::from_secs(settings.update.interval as u64 * 60),
             uri: settings.update.uri.clone(),
             install_command: settings.update.command.clone(),
+            runtime_dir: settings.cache.runtime_dir(),
         })
     }
 
@@ -73,11 +75,11 @@ impl Updater {
         }
     }
 
-    /// Returns a temporary location to download a package into. Do _not_ return a
-    /// path that will be used for an actual update since a partial download may
-    /// remain after download failures.
+    /// Returns a temporary location in the runtime folder to download a
+    /// package into. Do _not_ return a path that will be used for an actual
+    /// update since a partial download may remain after download failures.
     pub fn download_path(&self, package_name: &str) -> PathBuf {
-        env::temp_dir().join(package_name)
+        self.runtime_dir.join(package_name)
     }
 
     /// Does a platform specific install of the given package. Some platform
@@ -0,0 +1,343 @@
+use gateway_rs::{
+    gateway,
+    router::{client, dispatcher, OuiUsage, RouterClient, RouterQos},
+    service::resolver::Resolver,
+    settings::TimeoutSettings,
+    storage::MemoryStore,
+    CacheSettings, KeyedUri, Keypair, MsgVerify, Packet, Region, RequestSigner,
+};
+use helium_crypto::{KeyTag, KeyType, Network};
+use helium_proto::{
+    blockchain_state_channel_message_v1::Msg,
+    services::router::{Router, RouterServer},
+    BlockchainStateChannelMessageV1, BlockchainStateChannelPacketV1,
+    BlockchainStateChannelPurchaseV1, BlockchainStateChannelResponseV1,
+};
+use rand::rngs::OsRng;
+use slog::{o, Logger};
+use std::{
+    collections::VecDeque,
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    net::{TcpListener, TcpStream},
+    sync::mpsc,
+    time,
+};
+use tonic::{transport::Server, Request, Response, Status};
+
+/// Scripted reply of the mock router to the next routed packet
+enum Reply {
+    Downlink(helium_proto::Packet),
+    NoDownlink,
+    Message(Msg),
+    Error(Status),
+}
+
+struct MockRouter {
+    replies: Mutex<VecDeque<Reply>>,
+    received: mpsc::Sender<BlockchainStateChannelPacketV1>,
+}
+
+#[tonic::async_trait]
+impl Router for MockRouter {
+    async fn route(
+        &self,
+        request: Request<BlockchainStateChannelMessageV1>,
+    ) -> Result<Response<BlockchainStateChannelMessageV1>, Status> {
+        match request.into_inner().msg {
+            Some(Msg::Packet(packet)) => {
+                let _ = self.received.send(packet).await;
+            }
+            _ => return Err(Status::invalid_argument("expected packet")),
+        }
+        let reply = self
+            .replies
+            .lock()
+            .expect("replies lock")
+            .pop_front()
+            .unwrap_or(Reply::NoDownlink);
+        let downlink = match reply {
+            Reply::Downlink(packet) => Some(packet),
+            Reply::NoDownlink => None,
+            Reply::Message(msg) => {
+                return Ok(Response::new(BlockchainStateChannelMessageV1 {
+                    msg: Some(msg),
+                }))
+            }
+            Reply::Error(status) => return Err(status),
+        };
+        Ok(Response::new(BlockchainStateChannelMessageV1 {
+            msg: Some(Msg::Response(BlockchainStateChannelResponseV1 {
+                accepted: true,
+                downlink,
+                ..Default::default()
+            })),
+        }))
+    }
+}
+
+fn keypair() -> Keypair {
+    helium_crypto::Keypair::generate(
+        KeyTag {
+            network: Network::MainNet,
+            key_type: KeyType::Ed25519,
+        },
+        &mut OsRng,
+    )
+    .into()
+}
+
+/// Returns a stream of the connections accepted by the given listener
+fn incoming(listener: TcpListener) -> impl futures::Stream<Item = io::Result<TcpStream>> {
+    futures::stream::unfold(listener, |listener| async move {
+        let accepted = listener.accept().await.map(|(stream, _)| stream);
+        Some((accepted, listener))
+    })
+}
+
+fn uplink(payload: &[u8]) -> Packet {
+    Packet::from(helium_proto::Packet {
+        payload: payload.to_vec(),
+        frequency: 904.3,
+        datarate: "SF10BW125".to_string(),
+        timestamp: 1000,
+        ..Default::default()
+    })
+}
+
+struct Harness {
+    gateway_keypair: Arc<Keypair>,
+    router_uri: KeyedUri,
+    qos: RouterQos,
+    usage: OuiUsage,
+    uplinks: client::MessageSender,
+    downlinks: gateway::MessageReceiver,
+    received: mpsc::Receiver<BlockchainStateChannelPacketV1>,
+    _events: dispatcher::EventBus,
+    _shutdown: triggered::Trigger,
+}
+
+/// Starts a mock router with the given scripted replies and a router client
+/// connected to it.
+async fn start(replies: Vec<Reply>) -> Harness {
+    let (shutdown_trigger, shutdown) = triggered::trigger();
+    let logger = Logger::root(slog::Discard, o!());
+    let router_keypair = keypair();
+    let gateway_keypair = Arc::new(keypair());
+
+    let (received_tx, received) = mpsc::channel(10);
+    let router = MockRouter {
+        replies: Mutex::new(replies.into()),
+        received: received_tx,
+    };
+    // The listener is bound before the router client is started so the
+    // client never connects before the mock router listens
+    let listener = TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("mock router listener");
+    let addr = listener.local_addr().expect("mock router address");
+    tokio::spawn(
+        Server::builder()
+            .add_service(RouterServer::new(router))
+            .serve_with_incoming_shutdown(incoming(listener), shutdown.clone()),
+    );
+
+    let uri = KeyedUri {
+        uri: format!("http://{addr}").parse().expect("router uri"),
+        pubkey: Arc::new(router_keypair.public_key().to_owned()),
+    };
+    let (downlinks_tx, downlinks) = gateway::message_channel(10);
+    let (uplinks, uplinks_rx) = client::message_channel(10);
+    let event_bus = dispatcher::EventBus::new(10);
+    let events = event_bus.subscribe();
+    let qos = RouterQos::default();
+    let usage = OuiUsage::new(Arc::new(MemoryStore::new(10)));
+    let mut client = RouterClient::new(
+        1,
+        Region::from_i32(helium_proto::Region::Us915.into()).expect("region"),
+        uri.clone(),
+        downlinks_tx,
+        RequestSigner::from(gateway_keypair.clone()),
+        CacheSettings {
+            max_packets: 10,
+            store: std::env::temp_dir().join("router_client_test"),
+            read_only: false,
+            runtime_dir: None,
+        },
+        Arc::new(MemoryStore::new(10)),
+        qos.clone(),
+        usage.clone(),
+        Resolver::default(),
+        TimeoutSettings::default(),
+        0,
+    )
+    .await
+    .expect("router client");
+    tokio::spawn(async move { client.run(uplinks_rx, events, shutdown, &logger).await });
+
+    Harness {
+        gateway_keypair,
+        router_uri: uri,
+        qos,
+        usage,
+        uplinks,
+        downlinks,
+        received,
+        _events: event_bus,
+        _shutdown: shutdown_trigger,
+    }
+}
+
+async fn recv<T>(rx: &mut mpsc::Receiver<T>) -> Option<T> {
+    time::timeout(Duration::from_secs(5), rx.recv())
+        .await
+        .ok()
+        .flatten()
+}
+
+#[tokio::test]
+async fn signed_uplink() {
+    let mut harness = start(vec![]).await;
+    harness
+        .uplinks
+        .uplink(uplink(b"uplink"), Instant::now())
+        .await
+        .expect("uplink");
+
+    let packet = recv(&mut harness.received).await.expect("routed packet");
+    assert_eq!(
+        b"uplink".to_vec(),
+        packet.packet.as_ref().expect("packet").payload
+    );
+    assert_eq!(
+        harness.gateway_keypair.public_key().to_vec(),
+        packet.hotspot
+    );
+    packet
+        .verify(harness.gateway_keypair.public_key())
+        .expect("valid signature");
+}
+
+#[tokio::test]
+async fn downlink() {
+    let downlink = helium_proto::Packet {
+        payload: b"downlink".to_vec(),
+        // rx1 window one second after the uplink
+        timestamp: 1_001_000,
+        ..uplink(b"").to_packet()
+    };
+    let mut harness = start(vec![Reply::Downlink(downlink)]).await;
+    harness
+        .uplinks
+        .uplink(uplink(b"uplink"), Instant::now())
+        .await
+        .expect("uplink");
+
+    match recv(&mut harness.downlinks).await {
+        Some(gateway::Message::Downlink { packet, .. }) => {
+            assert_eq!(b"downlink", packet.payload())
+        }
+        other => panic!("expected downlink, got {other:?}"),
+    }
+}
+
+#[tokio::test]
+async fn router_error() {
+    let downlink = helium_proto::Packet {
+        payload: b"downlink".to_vec(),
+        // rx1 window one second after the uplink
+        timestamp: 1_001_000,
+        ..uplink(b"").to_packet()
+    };
+    let mut harness = start(vec![
+        Reply::Error(Status::unavailable("router busy")),
+        Reply::Downlink(downlink),
+    ])
+    .await;
+
+    // The failed packet is dropped but the client keeps serving uplinks
+    for payload in ["first", "second"] {
+        harness
+            .uplinks
+            .uplink(uplink(payload.as_bytes()), Instant::now())
+            .await
+            .expect("uplink");
+        let packet = recv(&mut harness.received).await.expect("routed packet");
+        assert_eq!(
+            payload.as_bytes(),
+            packet.packet.expect("packet").payload.as_slice()
+        );
+    }
+    match recv(&mut harness.downlinks).await {
+        Some(gateway::Message::Downlink { packet, .. }) => {
+            assert_eq!(b"downlink", packet.payload())
+        }
+        other => panic!("expected downlink, got {other:?}"),
+    }
+    // The error counts against the router's quality and the failed packet
+    // is not counted as used
+    let stats = harness.qos.stats(&harness.router_uri);
+    assert_eq!(0.5, stats.rejection_rate());
+    let usage = harness.usage.buckets(SystemTime::now());
+    assert_eq!(1, usage[&1][0].usage.packets);
+}
+
+#[tokio::test]
+async fn router_retry() {
+    let mut harness = start(vec![
+        Reply::Error(Status::unavailable("router busy")),
+        Reply::Error(Status::unavailable("router busy")),
+    ])
+    .await;
+
+    // A failed packet is not sent again, and a later uplink is routed once
+    // the router recovers
+    for payload in ["first", "second", "third"] {
+        harness
+            .uplinks
+            .uplink(uplink(payload.as_bytes()), Instant::now())
+            .await
+            .expect("uplink");
+        let packet = recv(&mut harness.received).await.expect("routed packet");
+        assert_eq!(
+            payload.as_bytes(),
+            packet.packet.expect("packet").payload.as_slice()
+        );
+    }
+    let stats = harness.qos.stats(&harness.router_uri);
+    assert!(stats.rejection_rate() > 0.6);
+}
+
+#[tokio::test]
+async fn purchase_response() {
+    let mut harness = start(vec![Reply::Message(Msg::Purchase(
+        BlockchainStateChannelPurchaseV1::default(),
+    ))])
+    .await;
+
+    // A purchase is not a downlink, and the client keeps serving uplinks
+    // after it
+    for payload in ["first", "second"] {
+        harness
+            .uplinks
+            .uplink(uplink(payload.as_bytes()), Instant::now())
+            .await
+            .expect("uplink");
+        let packet = recv(&mut harness.received).await.expect("routed packet");
+        assert_eq!(
+            payload.as_bytes(),
+            packet.packet.expect("packet").payload.as_slice()
+        );
+    }
+    assert!(
+        time::timeout(Duration::from_millis(200), harness.downlinks.recv())
+            .await
+            .is_err()
+    );
+    // Both the purchased and the accepted packet count as used
+    let usage = harness.usage.buckets(SystemTime::now());
+    assert_eq!(2, usage[&1][0].usage.packets);
+}